@@ -498,6 +498,21 @@ class NativeBridgePlugin(private val activity: Activity): Plugin(activity) {
         invoke.resolve(ret)
     }
 
+    @Command
+    fun reset_screen_brightness(invoke: Invoke) {
+        val ret = JSObject()
+        try {
+            val layoutParams = activity.window.attributes
+            layoutParams.screenBrightness = WindowManager.LayoutParams.BRIGHTNESS_OVERRIDE_NONE
+            activity.window.attributes = layoutParams
+            ret.put("success", true)
+        } catch (e: Exception) {
+            ret.put("success", false)
+            ret.put("error", e.message)
+        }
+        invoke.resolve(ret)
+    }
+
     @Command
     fun iap_initialize(invoke: Invoke) {
         billingManager.initialize { success ->
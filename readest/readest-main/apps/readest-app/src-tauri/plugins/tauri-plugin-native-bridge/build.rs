@@ -17,8 +17,10 @@ const COMMANDS: &[&str] = &[
     "get_safe_area_insets",
     "get_screen_brightness",
     "set_screen_brightness",
+    "reset_screen_brightness",
     "get_external_sdcard_path",
     "request_manage_storage_permission",
+    "list_removable_volumes",
     "check_permissions",
     "request_permissions",
     "checkPermissions",
@@ -418,6 +418,7 @@ class NativeBridgePlugin: Plugin {
   private var currentOrientationMask: UIInterfaceOrientationMask = .all
   private var originalDelegate: UIApplicationDelegate?
   private var webViewLifecycleManager: WebViewLifecycleManager?
+  private var originalBrightness: CGFloat?
 
   @objc public override func load(webview: WKWebView) {
     self.webView = webview
@@ -808,10 +809,23 @@ class NativeBridgePlugin: Plugin {
     }
 
     DispatchQueue.main.async {
+      if self.originalBrightness == nil {
+        self.originalBrightness = UIScreen.main.brightness
+      }
       UIScreen.main.brightness = CGFloat(brightness)
     }
     invoke.resolve(["success": true])
   }
+
+  @objc public func reset_screen_brightness(_ invoke: Invoke) {
+    DispatchQueue.main.async {
+      if let brightness = self.originalBrightness {
+        UIScreen.main.brightness = brightness
+        self.originalBrightness = nil
+      }
+    }
+    invoke.resolve(["success": true])
+  }
 }
 
 @_cdecl("init_plugin_native_bridge")
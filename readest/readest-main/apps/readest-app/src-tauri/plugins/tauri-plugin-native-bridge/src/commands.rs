@@ -142,6 +142,13 @@ pub(crate) async fn set_screen_brightness<R: Runtime>(
     app.native_bridge().set_screen_brightness(payload)
 }
 
+#[command]
+pub(crate) async fn reset_screen_brightness<R: Runtime>(
+    app: AppHandle<R>,
+) -> Result<SetScreenBrightnessResponse> {
+    app.native_bridge().reset_screen_brightness()
+}
+
 #[command]
 pub(crate) async fn get_external_sdcard_path<R: Runtime>(
     app: AppHandle<R>,
@@ -155,3 +162,10 @@ pub(crate) async fn request_manage_storage_permission<R: Runtime>(
 ) -> Result<RequestManageStoragePermissionResponse> {
     app.native_bridge().request_manage_storage_permission()
 }
+
+#[command]
+pub(crate) async fn list_removable_volumes<R: Runtime>(
+    app: AppHandle<R>,
+) -> Result<ListRemovableVolumesResponse> {
+    app.native_bridge().list_removable_volumes()
+}
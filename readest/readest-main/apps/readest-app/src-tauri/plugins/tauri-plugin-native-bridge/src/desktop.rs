@@ -1,16 +1,69 @@
 use serde::de::DeserializeOwned;
 use std::collections::HashMap;
-use tauri::{plugin::PluginApi, AppHandle, Runtime};
+use std::sync::Mutex;
+use tauri::{plugin::PluginApi, AppHandle, Manager, Runtime};
 
 use crate::models::*;
+use crate::platform::fonts;
+
+#[cfg(target_os = "linux")]
+use crate::platform::linux::{backlight::Backlight, color_scheme, volumes};
+
+// The event emitted with a `GetSystemColorSchemeResponse` when the desktop switches
+// between light and dark.
+#[cfg(target_os = "linux")]
+const SYSTEM_COLOR_SCHEME_CHANGED_EVENT: &str = "system-color-scheme-changed";
+// The event emitted with a `RemovableVolumesChangedEvent` when volumes are plugged in or
+// unplugged.
+#[cfg(target_os = "linux")]
+const REMOVABLE_VOLUMES_CHANGED_EVENT: &str = "removable-volumes-changed";
 
 pub fn init<R: Runtime, C: DeserializeOwned>(
     app: &AppHandle<R>,
     _api: PluginApi<R, C>,
 ) -> crate::Result<NativeBridge<R>> {
+    #[cfg(target_os = "linux")]
+    {
+        use tauri::Emitter;
+        let handle = app.clone();
+        color_scheme::watch_color_scheme(move |scheme| {
+            let payload = GetSystemColorSchemeResponse {
+                color_scheme: scheme.as_str().to_string(),
+            };
+            let _ = handle.emit(SYSTEM_COLOR_SCHEME_CHANGED_EVENT, payload);
+        });
+        let handle = app.clone();
+        volumes::watch_removable_volumes(move |added, removed| {
+            let payload = RemovableVolumesChangedEvent { added, removed };
+            let _ = handle.emit(REMOVABLE_VOLUMES_CHANGED_EVENT, payload);
+        });
+        app.manage(Backlight::default());
+    }
+    app.manage(SysFontFaces::default());
     Ok(NativeBridge(app.clone()))
 }
 
+// The faces of the system fonts, which are read once for all the windows of the app as
+// reading every font file takes a while.
+#[derive(Default)]
+struct SysFontFaces(Mutex<Option<Vec<SysFontFace>>>);
+
+#[cfg(target_os = "linux")]
+impl From<crate::Result<()>> for SetScreenBrightnessResponse {
+    fn from(result: crate::Result<()>) -> Self {
+        match result {
+            Ok(()) => Self {
+                success: true,
+                error: None,
+            },
+            Err(e) => Self {
+                success: false,
+                error: Some(e.to_string()),
+            },
+        }
+    }
+}
+
 /// Access to the native-bridge APIs.
 pub struct NativeBridge<R: Runtime>(AppHandle<R>);
 
@@ -50,17 +103,38 @@ impl<R: Runtime> NativeBridge<R> {
     }
 
     pub fn get_sys_fonts_list(&self) -> crate::Result<GetSysFontsListResponse> {
-        let font_collection = font_enumeration::Collection::new().unwrap();
-        let mut fonts = HashMap::new();
-        for font in font_collection.all() {
-            if cfg!(target_os = "windows") {
-                // FIXME: temporarily disable font name with style for windows
-                fonts.insert(font.family_name.clone(), font.family_name.clone());
-            } else {
-                fonts.insert(font.font_name.clone(), font.family_name.clone());
+        let cache = self.0.state::<SysFontFaces>();
+        let mut cached = cache.0.lock().unwrap();
+        if cached.is_none() {
+            let faces = match fonts::system_font_faces() {
+                Ok(faces) => faces,
+                Err(e) => {
+                    return Ok(GetSysFontsListResponse {
+                        fonts: HashMap::new(),
+                        faces: Vec::new(),
+                        error: Some(e.to_string()),
+                    })
+                }
+            };
+            // The webview loads the font files of the faces through the asset protocol.
+            let scope = self.0.asset_protocol_scope();
+            for face in &faces {
+                if let Err(e) = scope.allow_file(&face.path) {
+                    log::warn!("Failed to allow font {} in the asset scope: {e}", face.path);
+                }
             }
+            *cached = Some(faces);
         }
-        Ok(GetSysFontsListResponse { fonts, error: None })
+        let faces = cached.clone().unwrap_or_default();
+        let fonts = faces
+            .iter()
+            .map(|face| (face.name.clone(), face.family.clone()))
+            .collect();
+        Ok(GetSysFontsListResponse {
+            fonts,
+            faces,
+            error: None,
+        })
     }
 
     pub fn intercept_keys(&self, _payload: InterceptKeysRequest) -> crate::Result<()> {
@@ -99,6 +173,14 @@ impl<R: Runtime> NativeBridge<R> {
         Err(crate::Error::UnsupportedPlatformError)
     }
 
+    #[cfg(target_os = "linux")]
+    pub fn get_system_color_scheme(&self) -> crate::Result<GetSystemColorSchemeResponse> {
+        Ok(GetSystemColorSchemeResponse {
+            color_scheme: color_scheme::system_color_scheme().as_str().to_string(),
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
     pub fn get_system_color_scheme(&self) -> crate::Result<GetSystemColorSchemeResponse> {
         Err(crate::Error::UnsupportedPlatformError)
     }
@@ -107,10 +189,30 @@ impl<R: Runtime> NativeBridge<R> {
         Err(crate::Error::UnsupportedPlatformError)
     }
 
+    #[cfg(target_os = "linux")]
+    pub fn get_screen_brightness(&self) -> crate::Result<GetScreenBrightnessResponse> {
+        let brightness = self.0.state::<Backlight>().brightness()?;
+        Ok(GetScreenBrightnessResponse { brightness })
+    }
+
+    #[cfg(not(target_os = "linux"))]
     pub fn get_screen_brightness(&self) -> crate::Result<GetScreenBrightnessResponse> {
         Err(crate::Error::UnsupportedPlatformError)
     }
 
+    #[cfg(target_os = "linux")]
+    pub fn set_screen_brightness(
+        &self,
+        payload: SetScreenBrightnessRequest,
+    ) -> crate::Result<SetScreenBrightnessResponse> {
+        let result = self
+            .0
+            .state::<Backlight>()
+            .set_brightness(payload.brightness);
+        Ok(SetScreenBrightnessResponse::from(result))
+    }
+
+    #[cfg(not(target_os = "linux"))]
     pub fn set_screen_brightness(
         &self,
         _payload: SetScreenBrightnessRequest,
@@ -118,6 +220,18 @@ impl<R: Runtime> NativeBridge<R> {
         Err(crate::Error::UnsupportedPlatformError)
     }
 
+    /// Gives the screen back the brightness it had before `set_screen_brightness`.
+    #[cfg(target_os = "linux")]
+    pub fn reset_screen_brightness(&self) -> crate::Result<SetScreenBrightnessResponse> {
+        let result = self.0.state::<Backlight>().restore();
+        Ok(SetScreenBrightnessResponse::from(result))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn reset_screen_brightness(&self) -> crate::Result<SetScreenBrightnessResponse> {
+        Err(crate::Error::UnsupportedPlatformError)
+    }
+
     pub fn get_external_sdcard_path(&self) -> crate::Result<GetExternalSDCardPathResponse> {
         Err(crate::Error::UnsupportedPlatformError)
     }
@@ -127,4 +241,16 @@ impl<R: Runtime> NativeBridge<R> {
     ) -> crate::Result<RequestManageStoragePermissionResponse> {
         Err(crate::Error::UnsupportedPlatformError)
     }
+
+    #[cfg(target_os = "linux")]
+    pub fn list_removable_volumes(&self) -> crate::Result<ListRemovableVolumesResponse> {
+        Ok(ListRemovableVolumesResponse {
+            volumes: volumes::removable_volumes()?,
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn list_removable_volumes(&self) -> crate::Result<ListRemovableVolumesResponse> {
+        Err(crate::Error::UnsupportedPlatformError)
+    }
 }
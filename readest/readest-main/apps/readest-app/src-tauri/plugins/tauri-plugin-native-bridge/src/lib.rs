@@ -55,8 +55,10 @@ pub fn init<R: Runtime>() -> TauriPlugin<R> {
             commands::get_safe_area_insets,
             commands::get_screen_brightness,
             commands::set_screen_brightness,
+            commands::reset_screen_brightness,
             commands::get_external_sdcard_path,
             commands::request_manage_storage_permission,
+            commands::list_removable_volumes,
         ])
         .setup(|app, api| {
             #[cfg(mobile)]
@@ -66,5 +68,12 @@ pub fn init<R: Runtime>() -> TauriPlugin<R> {
             app.manage(native_bridge);
             Ok(())
         })
+        .on_event(|_app, _event| {
+            // The backlight outlives the app on Linux, unlike the brightness of a window.
+            #[cfg(target_os = "linux")]
+            if let tauri::RunEvent::Exit = _event {
+                let _ = _app.native_bridge().reset_screen_brightness();
+            }
+        })
         .build()
 }
@@ -189,6 +189,14 @@ impl<R: Runtime> NativeBridge<R> {
     }
 }
 
+impl<R: Runtime> NativeBridge<R> {
+    pub fn reset_screen_brightness(&self) -> crate::Result<SetScreenBrightnessResponse> {
+        self.0
+            .run_mobile_plugin("reset_screen_brightness", ())
+            .map_err(Into::into)
+    }
+}
+
 impl<R: Runtime> NativeBridge<R> {
     pub fn get_external_sdcard_path(&self) -> crate::Result<GetExternalSDCardPathResponse> {
         self.0
@@ -197,6 +205,12 @@ impl<R: Runtime> NativeBridge<R> {
     }
 }
 
+impl<R: Runtime> NativeBridge<R> {
+    pub fn list_removable_volumes(&self) -> crate::Result<ListRemovableVolumesResponse> {
+        Err(crate::Error::UnsupportedPlatformError)
+    }
+}
+
 impl<R: Runtime> NativeBridge<R> {
     pub fn request_manage_storage_permission(
         &self,
@@ -71,9 +71,40 @@ pub struct GetStatusBarHeightResponse {
 #[serde(rename_all = "camelCase")]
 pub struct GetSysFontsListResponse {
     pub fonts: HashMap<String, String>,
+    #[serde(default)]
+    pub faces: Vec<SysFontFace>,
     pub error: Option<String>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FontScript {
+    Latin,
+    Greek,
+    Cyrillic,
+    Arabic,
+    Hebrew,
+    Devanagari,
+    Thai,
+    Han,
+    Kana,
+    Hangul,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SysFontFace {
+    pub family: String,
+    pub name: String,
+    pub style: String,
+    pub weight: u16,
+    pub italic: bool,
+    pub monospace: bool,
+    pub path: String,
+    pub index: u32, // The index of the face in a font collection file
+    pub scripts: Vec<FontScript>,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct InterceptKeysRequest {
@@ -156,7 +187,7 @@ pub struct IAPRestorePurchasesResponse {
     pub purchases: Vec<Purchase>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct GetSystemColorSchemeResponse {
     pub color_scheme: String, // "light" or "dark"
@@ -202,3 +233,34 @@ pub struct GetExternalSDCardPathResponse {
 pub struct RequestManageStoragePermissionResponse {
     pub manage_storage: String, // "granted", "denied", or "prompt"
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EReaderLayout {
+    Kobo,     // `.kobo/`
+    Kindle,   // `documents/` and `system/`
+    KOReader, // `koreader/`
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RemovableVolume {
+    pub path: String,   // Where the volume is mounted
+    pub device: String, // Like "/dev/sdb1"
+    pub label: Option<String>,
+    pub fs_type: String,
+    pub e_readers: Vec<EReaderLayout>, // The e-readers whose folders the volume has
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListRemovableVolumesResponse {
+    pub volumes: Vec<RemovableVolume>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RemovableVolumesChangedEvent {
+    pub added: Vec<RemovableVolume>,
+    pub removed: Vec<RemovableVolume>,
+}
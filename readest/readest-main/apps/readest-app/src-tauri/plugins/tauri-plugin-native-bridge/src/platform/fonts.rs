@@ -0,0 +1,117 @@
+//! The fonts installed on desktops.
+//!
+//! The font files are found with the font enumeration of the platform and every face in
+//! them is read with ttf-parser, so that the names are those of the font itself on every
+//! platform. The scripts a face covers are found by looking up sample characters of each
+//! script in its character map.
+
+use ttf_parser::{name_id, Face, Language};
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use crate::models::*;
+use crate::{Error, Result};
+
+// Characters a face needs to have glyphs for to cover a script, picked among the most
+// common letters so that fonts with a few symbols of a script are not counted.
+const SCRIPT_SAMPLES: &[(FontScript, &str)] = &[
+    (FontScript::Latin, "AZaz"),
+    (FontScript::Greek, "ΑΩαω"),
+    (FontScript::Cyrillic, "АЯая"),
+    (FontScript::Arabic, "ابمي"),
+    (FontScript::Hebrew, "אלש"),
+    (FontScript::Devanagari, "कनम"),
+    (FontScript::Thai, "กนม"),
+    (FontScript::Han, "的一是中国字"),
+    (FontScript::Kana, "あのアン"),
+    (FontScript::Hangul, "한국어"),
+];
+
+// A name of a face, in US English when it has names in several languages.
+fn face_name(face: &Face, ids: &[u16]) -> Option<String> {
+    ids.iter().find_map(|&id| {
+        let names = face.names().into_iter().filter(|name| name.name_id == id);
+        let mut fallback = None;
+        for name in names {
+            let Some(value) = name.to_string().filter(|value| !value.trim().is_empty()) else {
+                continue;
+            };
+            if name.language() == Language::English_UnitedStates {
+                return Some(value);
+            }
+            fallback.get_or_insert(value);
+        }
+        fallback
+    })
+}
+
+fn face_scripts(face: &Face) -> Vec<FontScript> {
+    SCRIPT_SAMPLES
+        .iter()
+        .filter(|(_, sample)| sample.chars().all(|c| face.glyph_index(c).is_some()))
+        .map(|(script, _)| *script)
+        .collect()
+}
+
+fn read_faces(path: &Path) -> Result<Vec<SysFontFace>> {
+    // Read rather than mapped, as a font file truncated while mapped would crash the app.
+    let data = std::fs::read(path)?;
+    let count = ttf_parser::fonts_in_collection(&data).unwrap_or(1);
+    let mut faces = Vec::new();
+    for index in 0..count {
+        let face = Face::parse(&data, index).map_err(|e| {
+            Error::NativeBridgeError(format!("Invalid font {}: {e}", path.display()))
+        })?;
+        // Typographic names group more than the four styles of the legacy names.
+        let Some(family) = face_name(&face, &[name_id::TYPOGRAPHIC_FAMILY, name_id::FAMILY]) else {
+            continue;
+        };
+        let style = face_name(&face, &[name_id::TYPOGRAPHIC_SUBFAMILY, name_id::SUBFAMILY])
+            .unwrap_or_else(|| "Regular".to_string());
+        let name =
+            face_name(&face, &[name_id::FULL_NAME]).unwrap_or_else(|| format!("{family} {style}"));
+        faces.push(SysFontFace {
+            family,
+            name,
+            style,
+            weight: face.weight().to_number(),
+            italic: face.is_italic() || face.is_oblique(),
+            monospace: face.is_monospaced(),
+            path: path.to_string_lossy().into_owned(),
+            index,
+            scripts: face_scripts(&face),
+        });
+    }
+    Ok(faces)
+}
+
+/// The faces of the fonts installed on the system, sorted by family.
+pub fn system_font_faces() -> Result<Vec<SysFontFace>> {
+    let collection = font_enumeration::Collection::new().map_err(|e| {
+        Error::NativeBridgeError(format!("Failed to enumerate the system fonts: {e}"))
+    })?;
+    // The enumeration lists every face of a collection file with the same path.
+    let mut paths = HashSet::new();
+    let paths = collection
+        .all()
+        .filter(|font| paths.insert(font.path.clone()))
+        .map(|font| font.path.clone())
+        .collect::<Vec<PathBuf>>();
+    let mut names = HashSet::new();
+    let mut faces = Vec::new();
+    for path in paths {
+        match read_faces(&path) {
+            // A face installed in several formats or folders is listed once.
+            Ok(read) => faces.extend(
+                read.into_iter()
+                    .filter(|face| names.insert(face.name.clone())),
+            ),
+            Err(e) => log::warn!("Failed to read font {}: {e}", path.display()),
+        }
+    }
+    faces.sort_by(|a, b| {
+        (&a.family, a.weight, a.italic, &a.name).cmp(&(&b.family, b.weight, b.italic, &b.name))
+    });
+    Ok(faces)
+}
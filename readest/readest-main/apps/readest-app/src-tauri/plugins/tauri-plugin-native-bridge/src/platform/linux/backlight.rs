@@ -0,0 +1,251 @@
+//! The screen brightness of Linux laptops, through the backlight class of sysfs.
+//!
+//! Brightness is written to the `brightness` file of the backlight device when the user
+//! may write it, as udev rules of some distributions allow the video group to. Otherwise
+//! it is set with the `SetBrightness` call of the logind session, which the user of the
+//! active local session may make without a password.
+//!
+//! The backlight is shared with the rest of the desktop, so the level it had before the
+//! first change is kept and put back by `restore`.
+
+use zbus::blocking::{Connection, Proxy};
+
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::{Error, Result};
+
+const BACKLIGHT_ROOT: &str = "/sys/class/backlight";
+
+fn read_value(path: &Path) -> Result<u32> {
+    let value = std::fs::read_to_string(path)?;
+    value.trim().parse().map_err(|e| {
+        Error::NativeBridgeError(format!(
+            "Invalid backlight value in {}: {e}",
+            path.display()
+        ))
+    })
+}
+
+// systemd-backlight prefers firmware interfaces, then those of the graphics driver, and
+// then raw registers, which often have no effect on laptops with the others.
+fn type_rank(device: &Path) -> u8 {
+    match std::fs::read_to_string(device.join("type"))
+        .unwrap_or_default()
+        .trim()
+    {
+        "firmware" => 0,
+        "platform" => 1,
+        "raw" => 2,
+        _ => 3,
+    }
+}
+
+pub struct Backlight {
+    root: PathBuf, // The backlight class directory, `/sys/class/backlight`
+    original: Mutex<Option<(PathBuf, u32)>>, // The device and level before the first change
+}
+
+impl Default for Backlight {
+    fn default() -> Self {
+        Self::new(BACKLIGHT_ROOT)
+    }
+}
+
+impl Backlight {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            original: Mutex::new(None),
+        }
+    }
+
+    fn device(&self) -> Result<PathBuf> {
+        let mut devices = std::fs::read_dir(&self.root)
+            .map_err(|e| match e.kind() {
+                ErrorKind::NotFound => Error::UnsupportedPlatformError,
+                _ => e.into(),
+            })?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|device| device.join("max_brightness").exists())
+            .collect::<Vec<_>>();
+        devices.sort_by_key(|device| (type_rank(device), device.clone()));
+        devices
+            .into_iter()
+            .next()
+            .ok_or(Error::UnsupportedPlatformError)
+    }
+
+    /// The brightness of the screen, from 0.0 to 1.0.
+    pub fn brightness(&self) -> Result<f64> {
+        let device = self.device()?;
+        let max = read_value(&device.join("max_brightness"))?;
+        // The brightness the hardware is at, which may lag the one last requested.
+        let value = read_value(&device.join("actual_brightness"))
+            .or_else(|_| read_value(&device.join("brightness")))?;
+        match max {
+            0 => Ok(1.0),
+            max => Ok((value as f64 / max as f64).clamp(0.0, 1.0)),
+        }
+    }
+
+    /// Sets the brightness of the screen, from 0.0 to 1.0.
+    pub fn set_brightness(&self, brightness: f64) -> Result<()> {
+        if !(0.0..=1.0).contains(&brightness) {
+            return Err(Error::NativeBridgeError(
+                "Brightness must be between 0.0 and 1.0".into(),
+            ));
+        }
+        let device = self.device()?;
+        let max = read_value(&device.join("max_brightness"))?;
+        {
+            let mut original = self.original.lock().unwrap();
+            if original.is_none() {
+                *original = Some((device.clone(), read_value(&device.join("brightness"))?));
+            }
+        }
+        // The lowest level turns the backlight off on some laptops, leaving a black screen.
+        let value = ((brightness * max as f64).round() as u32).max(max.min(1));
+        write_value(&device, value)
+    }
+
+    /// Puts the backlight back to the level it had before the first `set_brightness`.
+    pub fn restore(&self) -> Result<()> {
+        match self.original.lock().unwrap().take() {
+            Some((device, value)) => write_value(&device, value),
+            None => Ok(()),
+        }
+    }
+}
+
+fn write_value(device: &Path, value: u32) -> Result<()> {
+    match std::fs::write(device.join("brightness"), value.to_string()) {
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+            let name = device
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            set_brightness_with_logind(&name, value)
+        }
+        result => Ok(result?),
+    }
+}
+
+fn set_brightness_with_logind(device: &str, value: u32) -> Result<()> {
+    let set_brightness = || -> zbus::Result<()> {
+        let connection = Connection::system()?;
+        let session = Proxy::new(
+            &connection,
+            "org.freedesktop.login1",
+            "/org/freedesktop/login1/session/auto",
+            "org.freedesktop.login1.Session",
+        )?;
+        session.call::<_, _, ()>("SetBrightness", &("backlight", device, value))
+    };
+    set_brightness()
+        .map_err(|e| Error::NativeBridgeError(format!("Failed to set the brightness: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A backlight class directory with devices laid out like sysfs, removed on drop.
+    struct FakeSysfs(PathBuf);
+
+    impl FakeSysfs {
+        fn new(name: &str) -> Self {
+            let root = std::env::temp_dir().join(format!(
+                "native-bridge-backlight-{name}-{}",
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&root);
+            std::fs::create_dir_all(&root).unwrap();
+            Self(root)
+        }
+
+        fn device(&self, name: &str, kind: &str, max: u32, brightness: u32) -> PathBuf {
+            let device = self.0.join(name);
+            std::fs::create_dir_all(&device).unwrap();
+            std::fs::write(device.join("type"), format!("{kind}\n")).unwrap();
+            std::fs::write(device.join("max_brightness"), format!("{max}\n")).unwrap();
+            std::fs::write(device.join("brightness"), format!("{brightness}\n")).unwrap();
+            device
+        }
+
+        fn backlight(&self) -> Backlight {
+            Backlight::new(&self.0)
+        }
+    }
+
+    impl Drop for FakeSysfs {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn level(device: &Path) -> u32 {
+        read_value(&device.join("brightness")).unwrap()
+    }
+
+    #[test]
+    fn prefers_firmware_devices() {
+        let sysfs = FakeSysfs::new("prefers");
+        let raw = sysfs.device("intel_backlight", "raw", 1000, 500);
+        let firmware = sysfs.device("acpi_video0", "firmware", 10, 5);
+        std::fs::write(firmware.join("actual_brightness"), "3\n").unwrap();
+
+        let backlight = sysfs.backlight();
+        assert_eq!(backlight.brightness().unwrap(), 0.3);
+        backlight.set_brightness(0.8).unwrap();
+        assert_eq!(level(&firmware), 8);
+        assert_eq!(level(&raw), 500);
+    }
+
+    #[test]
+    fn restores_the_original_level() {
+        let sysfs = FakeSysfs::new("restores");
+        let device = sysfs.device("intel_backlight", "raw", 100, 40);
+
+        let backlight = sysfs.backlight();
+        backlight.set_brightness(0.9).unwrap();
+        backlight.set_brightness(0.0).unwrap();
+        // The backlight is never turned off.
+        assert_eq!(level(&device), 1);
+        backlight.restore().unwrap();
+        assert_eq!(level(&device), 40);
+
+        // Restoring again leaves changes made since by others alone.
+        std::fs::write(device.join("brightness"), "70").unwrap();
+        backlight.restore().unwrap();
+        assert_eq!(level(&device), 70);
+    }
+
+    #[test]
+    fn rejects_out_of_range_brightness() {
+        let sysfs = FakeSysfs::new("rejects");
+        let device = sysfs.device("intel_backlight", "raw", 100, 40);
+
+        let backlight = sysfs.backlight();
+        for brightness in [-0.1, 1.5, f64::NAN] {
+            assert!(backlight.set_brightness(brightness).is_err());
+        }
+        assert_eq!(level(&device), 40);
+        backlight.restore().unwrap();
+        assert_eq!(level(&device), 40);
+    }
+
+    #[test]
+    fn fails_without_devices() {
+        let sysfs = FakeSysfs::new("empty");
+        assert!(matches!(
+            sysfs.backlight().brightness(),
+            Err(Error::UnsupportedPlatformError)
+        ));
+        assert!(matches!(
+            Backlight::new(sysfs.0.join("missing")).set_brightness(0.5),
+            Err(Error::UnsupportedPlatformError)
+        ));
+    }
+}
@@ -0,0 +1,314 @@
+//! The color scheme of Linux desktops.
+//!
+//! The scheme is the `color-scheme` appearance setting of the XDG desktop portal, which
+//! GNOME, KDE and others switch for their automatic dark mode. Desktops without the
+//! portal, or with no preference set in it, are read from the name of the GTK theme,
+//! like `Adwaita-dark` or `Breeze:dark`.
+
+use zbus::blocking::{Connection, Proxy};
+use zbus::zvariant::{OwnedValue, Value};
+
+use std::path::PathBuf;
+use std::process::Command;
+
+const PORTAL_DESTINATION: &str = "org.freedesktop.portal.Desktop";
+const PORTAL_PATH: &str = "/org/freedesktop/portal/desktop";
+const SETTINGS_INTERFACE: &str = "org.freedesktop.portal.Settings";
+const APPEARANCE_NAMESPACE: &str = "org.freedesktop.appearance";
+const COLOR_SCHEME_KEY: &str = "color-scheme";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorScheme {
+    Light,
+    Dark,
+}
+
+impl ColorScheme {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ColorScheme::Light => "light",
+            ColorScheme::Dark => "dark",
+        }
+    }
+
+    // 1 is a preference for dark, 2 for light and 0 no preference.
+    fn from_portal(value: u32) -> Option<Self> {
+        match value {
+            1 => Some(ColorScheme::Dark),
+            2 => Some(ColorScheme::Light),
+            _ => None,
+        }
+    }
+}
+
+// The `u32` of a setting, which version 1 of the portal wraps in another variant.
+fn setting_u32(value: &Value) -> Option<u32> {
+    match value {
+        Value::U32(value) => Some(*value),
+        Value::Value(value) => setting_u32(value),
+        _ => None,
+    }
+}
+
+fn settings_proxy(connection: &Connection) -> zbus::Result<Proxy<'_>> {
+    Proxy::new(
+        connection,
+        PORTAL_DESTINATION,
+        PORTAL_PATH,
+        SETTINGS_INTERFACE,
+    )
+}
+
+fn portal_color_scheme(connection: &Connection) -> zbus::Result<Option<ColorScheme>> {
+    let proxy = settings_proxy(connection)?;
+    let key = (APPEARANCE_NAMESPACE, COLOR_SCHEME_KEY);
+    // `ReadOne` is new in version 2 of the settings interface.
+    let value: OwnedValue = match proxy.call("ReadOne", &key) {
+        Ok(value) => value,
+        Err(_) => proxy.call("Read", &key)?,
+    };
+    Ok(setting_u32(&value).and_then(ColorScheme::from_portal))
+}
+
+fn theme_scheme(theme: &str) -> ColorScheme {
+    match theme.to_lowercase().contains("dark") {
+        true => ColorScheme::Dark,
+        false => ColorScheme::Light,
+    }
+}
+
+// The value of `key` in the `[Settings]` of a GTK settings.ini.
+fn ini_setting(ini: &str, key: &str) -> Option<String> {
+    ini.lines()
+        .filter_map(|line| line.split_once('='))
+        .find(|(name, _)| name.trim() == key)
+        .map(|(_, value)| value.trim().trim_matches('"').to_string())
+}
+
+fn gsettings(key: &str) -> Option<String> {
+    let output = Command::new("gsettings")
+        .args(["get", "org.gnome.desktop.interface", key])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    let value = String::from_utf8_lossy(&output.stdout);
+    Some(value.trim().trim_matches('\'').to_string()).filter(|value| !value.is_empty())
+}
+
+// The color scheme of the GTK theme, from `GTK_THEME`, the GNOME settings or the GTK
+// settings.ini files, in the order GTK itself applies them.
+fn gtk_color_scheme() -> Option<ColorScheme> {
+    if let Some(theme) = std::env::var("GTK_THEME")
+        .ok()
+        .filter(|theme| !theme.is_empty())
+    {
+        return Some(theme_scheme(&theme));
+    }
+    if let Some(scheme) = gsettings("color-scheme") {
+        match scheme.as_str() {
+            "prefer-dark" => return Some(ColorScheme::Dark),
+            "prefer-light" => return Some(ColorScheme::Light),
+            _ => {}
+        }
+    }
+    if let Some(theme) = gsettings("gtk-theme") {
+        return Some(theme_scheme(&theme));
+    }
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    for gtk in ["gtk-4.0", "gtk-3.0"] {
+        let Ok(ini) = std::fs::read_to_string(config_dir.join(gtk).join("settings.ini")) else {
+            continue;
+        };
+        let prefer_dark = ini_setting(&ini, "gtk-application-prefer-dark-theme");
+        if matches!(prefer_dark.as_deref(), Some("1" | "true")) {
+            return Some(ColorScheme::Dark);
+        }
+        if let Some(theme) = ini_setting(&ini, "gtk-theme-name") {
+            return Some(theme_scheme(&theme));
+        }
+    }
+    None
+}
+
+// The color scheme of the portal on `connection`, or else of the GTK theme.
+fn color_scheme(connection: Option<&Connection>) -> ColorScheme {
+    connection
+        .and_then(|connection| portal_color_scheme(connection).ok().flatten())
+        .or_else(gtk_color_scheme)
+        .unwrap_or(ColorScheme::Light)
+}
+
+/// The color scheme of the desktop, light when it has none.
+pub fn system_color_scheme() -> ColorScheme {
+    color_scheme(Connection::session().ok().as_ref())
+}
+
+// Calls `on_change` with every change of the color scheme that the portal on `connection`
+// reports, until the connection closes.
+fn watch(connection: &Connection, on_change: impl Fn(ColorScheme)) -> zbus::Result<()> {
+    let proxy = settings_proxy(connection)?;
+    let signals = proxy.receive_signal("SettingChanged")?;
+    let mut current = color_scheme(Some(connection));
+    for message in signals {
+        let (namespace, key, value) = message
+            .body()
+            .deserialize::<(String, String, OwnedValue)>()?;
+        if namespace != APPEARANCE_NAMESPACE || key != COLOR_SCHEME_KEY {
+            continue;
+        }
+        let scheme = setting_u32(&value)
+            .and_then(ColorScheme::from_portal)
+            .or_else(gtk_color_scheme)
+            .unwrap_or(ColorScheme::Light);
+        if scheme != current {
+            current = scheme;
+            on_change(scheme);
+        }
+    }
+    Ok(())
+}
+
+/// Calls `on_change` with the new color scheme whenever the portal reports a change of
+/// the setting, until the session bus goes away.
+pub fn watch_color_scheme(on_change: impl Fn(ColorScheme) + Send + 'static) {
+    std::thread::spawn(move || {
+        if let Err(e) = Connection::session().and_then(|connection| watch(&connection, on_change)) {
+            log::warn!("Failed to watch the system color scheme: {e}");
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use zbus::blocking::connection;
+    use zbus::object_server::SignalEmitter;
+
+    // The settings interface of the portal, of version 2 or of version 1 without `ReadOne`.
+    struct MockSettings {
+        color_scheme: u32,
+        version: u32,
+    }
+
+    #[zbus::interface(name = "org.freedesktop.portal.Settings")]
+    impl MockSettings {
+        fn read_one(&self, namespace: &str, key: &str) -> zbus::fdo::Result<OwnedValue> {
+            if self.version < 2 {
+                return Err(zbus::fdo::Error::UnknownMethod("ReadOne".into()));
+            }
+            self.setting(namespace, key).map(OwnedValue::from)
+        }
+
+        // Version 1 wraps the value in another variant.
+        fn read(&self, namespace: &str, key: &str) -> zbus::fdo::Result<OwnedValue> {
+            let value = Value::Value(Box::new(Value::U32(self.setting(namespace, key)?)));
+            OwnedValue::try_from(value).map_err(|e| zbus::fdo::Error::Failed(e.to_string()))
+        }
+
+        #[zbus(signal)]
+        async fn setting_changed(
+            emitter: &SignalEmitter<'_>,
+            namespace: &str,
+            key: &str,
+            value: Value<'_>,
+        ) -> zbus::Result<()>;
+    }
+
+    impl MockSettings {
+        fn setting(&self, namespace: &str, key: &str) -> zbus::fdo::Result<u32> {
+            match (namespace, key) {
+                (APPEARANCE_NAMESPACE, COLOR_SCHEME_KEY) => Ok(self.color_scheme),
+                _ => Err(zbus::fdo::Error::Failed("No such setting".into())),
+            }
+        }
+    }
+
+    // Serves the mock portal on one end of a peer-to-peer connection, and returns it and
+    // a client connection to it.
+    fn connect(settings: MockSettings) -> (Connection, Connection) {
+        let (server, client) = UnixStream::pair().unwrap();
+        let server = std::thread::spawn(move || {
+            connection::Builder::async_io_unix_stream(server)
+                .server(zbus::Guid::generate())?
+                .p2p()
+                .serve_at(PORTAL_PATH, settings)?
+                .build()
+        });
+        let client = connection::Builder::async_io_unix_stream(client)
+            .p2p()
+            .build()
+            .unwrap();
+        (server.join().unwrap().unwrap(), client)
+    }
+
+    fn emit(server: &Connection, namespace: &str, key: &str, value: u32) {
+        let settings = server
+            .object_server()
+            .interface::<_, MockSettings>(PORTAL_PATH)
+            .unwrap();
+        let emitter = settings.signal_emitter();
+        zbus::block_on(MockSettings::setting_changed(
+            emitter,
+            namespace,
+            key,
+            Value::U32(value),
+        ))
+        .unwrap();
+    }
+
+    #[test]
+    fn reads_the_color_scheme_of_the_portal() {
+        for version in [1, 2] {
+            let (_server, client) = connect(MockSettings {
+                color_scheme: 1,
+                version,
+            });
+            assert_eq!(
+                portal_color_scheme(&client).unwrap(),
+                Some(ColorScheme::Dark)
+            );
+            let (_server, client) = connect(MockSettings {
+                color_scheme: 2,
+                version,
+            });
+            assert_eq!(
+                portal_color_scheme(&client).unwrap(),
+                Some(ColorScheme::Light)
+            );
+        }
+        let (_server, client) = connect(MockSettings {
+            color_scheme: 0,
+            version: 2,
+        });
+        assert_eq!(portal_color_scheme(&client).unwrap(), None);
+    }
+
+    #[test]
+    fn reports_changes_of_the_color_scheme() {
+        let (server, client) = connect(MockSettings {
+            color_scheme: 2,
+            version: 2,
+        });
+        let (sender, changes) = mpsc::channel();
+        std::thread::spawn(move || watch(&client, |scheme| sender.send(scheme).unwrap()));
+        // Signals sent before the watch has subscribed are missed, so send until one is
+        // received. The same scheme again is no change.
+        let dark = std::iter::repeat_with(|| {
+            emit(&server, APPEARANCE_NAMESPACE, COLOR_SCHEME_KEY, 1);
+            changes.recv_timeout(Duration::from_millis(50))
+        })
+        .take(100)
+        .find_map(|change| change.ok());
+        assert_eq!(dark, Some(ColorScheme::Dark));
+        emit(&server, APPEARANCE_NAMESPACE, COLOR_SCHEME_KEY, 1);
+        emit(&server, "org.gnome.desktop.interface", COLOR_SCHEME_KEY, 2);
+        emit(&server, APPEARANCE_NAMESPACE, COLOR_SCHEME_KEY, 2);
+        let change = changes.recv_timeout(Duration::from_secs(5));
+        assert_eq!(change, Ok(ColorScheme::Light));
+    }
+}
@@ -0,0 +1,3 @@
+pub mod backlight;
+pub mod color_scheme;
+pub mod volumes;
@@ -0,0 +1,369 @@
+//! Removable volumes mounted on Linux desktops, like e-readers and SD cards.
+//!
+//! The mounts are read from `/proc/self/mountinfo`. Whether the drive of a mount is
+//! removable, and the label of its filesystem, come from udisks2, which the file managers
+//! of desktops mount removable media with, and from sysfs when udisks2 is not running.
+//! The root of every volume is checked for the folder layouts of known e-readers.
+
+use zbus::blocking::{Connection, Proxy};
+use zbus::zvariant::{OwnedObjectPath, OwnedValue, Value};
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::ErrorKind;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use crate::models::*;
+use crate::Result;
+
+const MOUNTINFO: &str = "/proc/self/mountinfo";
+
+type Properties = HashMap<String, HashMap<String, OwnedValue>>; // By interface
+
+struct Mount {
+    device: String,    // Like `/dev/sdb1`
+    device_id: String, // The major:minor number of the device
+    mount_point: String,
+    fs_type: String,
+}
+
+// Undoes the octal escapes of spaces, tabs, newlines and backslashes in mountinfo fields.
+fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes.get(i + 1..i + 4).and_then(|digits| {
+            let digits = std::str::from_utf8(digits).ok()?;
+            u8::from_str_radix(digits, 8).ok()
+        });
+        match (bytes[i], octal) {
+            (b'\\', Some(byte)) => {
+                unescaped.push(byte);
+                i += 4;
+            }
+            (byte, _) => {
+                unescaped.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&unescaped).into_owned()
+}
+
+// The mounts of block devices in mountinfo. A line is
+// `id parent major:minor root mount-point options [optional fields] - type source options`.
+fn parse_mountinfo(mountinfo: &str) -> Vec<Mount> {
+    mountinfo
+        .lines()
+        .filter_map(|line| {
+            let (mount, filesystem) = line.split_once(" - ")?;
+            let mount = mount.split(' ').collect::<Vec<_>>();
+            let mut filesystem = filesystem.split(' ');
+            let fs_type = filesystem.next()?;
+            let device = unescape(filesystem.next()?);
+            // Bind mounts of a folder of the volume are not volumes of their own.
+            if !device.starts_with("/dev/") || mount.get(3) != Some(&"/") {
+                return None;
+            }
+            Some(Mount {
+                device,
+                device_id: mount.get(2)?.to_string(),
+                mount_point: unescape(mount.get(4)?),
+                fs_type: fs_type.to_string(),
+            })
+        })
+        .collect()
+}
+
+fn bool_property(properties: &HashMap<String, OwnedValue>, name: &str) -> bool {
+    matches!(
+        properties.get(name).map(|value| &**value),
+        Some(Value::Bool(true))
+    )
+}
+
+fn string_property(properties: &HashMap<String, OwnedValue>, name: &str) -> Option<String> {
+    match properties.get(name).map(|value| &**value) {
+        Some(Value::Str(value)) if !value.is_empty() => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+// A path of a byte array property, which udisks2 terminates with a null byte.
+fn path_property(value: &Value) -> Option<String> {
+    let Value::Array(bytes) = value else {
+        return None;
+    };
+    let bytes = bytes
+        .iter()
+        .map_while(|byte| match byte {
+            Value::U8(0) => None,
+            Value::U8(byte) => Some(*byte),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    Some(String::from_utf8_lossy(&bytes).into_owned()).filter(|path| !path.is_empty())
+}
+
+struct UDisksDevice {
+    label: Option<String>,
+    removable: bool,
+}
+
+// The block devices that udisks2 knows, by device path.
+fn udisks_devices() -> zbus::Result<HashMap<String, UDisksDevice>> {
+    let connection = Connection::system()?;
+    let manager = Proxy::new(
+        &connection,
+        "org.freedesktop.UDisks2",
+        "/org/freedesktop/UDisks2",
+        "org.freedesktop.DBus.ObjectManager",
+    )?;
+    let objects: HashMap<OwnedObjectPath, Properties> = manager.call("GetManagedObjects", &())?;
+    let mut devices = HashMap::new();
+    for properties in objects.values() {
+        let Some(block) = properties.get("org.freedesktop.UDisks2.Block") else {
+            continue;
+        };
+        let Some(device) = block.get("Device").and_then(|value| path_property(value)) else {
+            continue;
+        };
+        let drive = match block.get("Drive").map(|value| &**value) {
+            Some(Value::ObjectPath(drive)) => objects
+                .iter()
+                .find(|(path, _)| path.as_str() == drive.as_str())
+                .and_then(|(_, properties)| properties.get("org.freedesktop.UDisks2.Drive")),
+            _ => None,
+        };
+        let removable = drive.is_some_and(|drive| {
+            bool_property(drive, "Removable")
+                || bool_property(drive, "MediaRemovable")
+                || bool_property(drive, "Ejectable")
+        });
+        let label = string_property(block, "IdLabel");
+        devices.insert(device, UDisksDevice { label, removable });
+    }
+    Ok(devices)
+}
+
+// Whether the disk of a block device is removable, from sysfs. USB drives that report
+// fixed media, as e-readers may, are removable all the same.
+fn sysfs_removable(device_id: &str) -> bool {
+    let Ok(device) = std::fs::canonicalize(format!("/sys/dev/block/{device_id}")) else {
+        return false;
+    };
+    let path = device.to_string_lossy();
+    if path.contains("/usb") || path.contains("/mmc_host/") {
+        return true;
+    }
+    // A partition has the `removable` attribute in the directory of its disk.
+    let disk = match device.join("partition").exists() {
+        true => device.parent().map(Path::to_path_buf).unwrap_or(device),
+        false => device,
+    };
+    std::fs::read_to_string(disk.join("removable")).is_ok_and(|value| value.trim() == "1")
+}
+
+// The label of the filesystem of a device, from the links of `/dev/disk/by-label`.
+fn by_label(device: &str) -> Option<String> {
+    let device = std::fs::canonicalize(device).ok()?;
+    std::fs::read_dir("/dev/disk/by-label")
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .find(|entry| std::fs::canonicalize(entry.path()).is_ok_and(|path| path == device))
+        .map(|entry| unescape_udev(&entry.file_name().to_string_lossy()))
+}
+
+// Undoes the `\x20` escapes of udev link names.
+fn unescape_udev(name: &str) -> String {
+    let mut unescaped = Vec::new();
+    let mut rest = name.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let hex = tail
+            .strip_prefix(b"x")
+            .and_then(|hex| hex.get(..2))
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match (byte, hex) {
+            (b'\\', Some(byte)) => {
+                unescaped.push(byte);
+                rest = &tail[3..];
+            }
+            _ => {
+                unescaped.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&unescaped).into_owned()
+}
+
+// The e-readers whose folders are at the root of a volume. A Kobo or a Kindle may have
+// KOReader installed as well.
+fn e_reader_layouts(root: &Path) -> Vec<EReaderLayout> {
+    let is_dir = |name: &str| root.join(name).is_dir();
+    let mut layouts = Vec::new();
+    if is_dir(".kobo") {
+        layouts.push(EReaderLayout::Kobo);
+    }
+    // Other volumes have a `documents` folder too, but not next to `system`.
+    if is_dir("documents") && is_dir("system") {
+        layouts.push(EReaderLayout::Kindle);
+    }
+    if is_dir("koreader") || is_dir(".adds/koreader") {
+        layouts.push(EReaderLayout::KOReader);
+    }
+    layouts
+}
+
+/// The removable volumes that are mounted.
+pub fn removable_volumes() -> Result<Vec<RemovableVolume>> {
+    let mounts = parse_mountinfo(&std::fs::read_to_string(MOUNTINFO)?);
+    let udisks = udisks_devices().ok();
+    let mut volumes: Vec<RemovableVolume> = Vec::new();
+    for mount in mounts {
+        let (removable, label) = match udisks.as_ref() {
+            Some(udisks) => match udisks.get(&mount.device) {
+                Some(device) => (device.removable, device.label.clone()),
+                None => (false, None),
+            },
+            None => (sysfs_removable(&mount.device_id), by_label(&mount.device)),
+        };
+        // A volume mounted at several places is listed once.
+        if !removable || volumes.iter().any(|volume| volume.device == mount.device) {
+            continue;
+        }
+        let path = PathBuf::from(&mount.mount_point);
+        let label = label.or_else(|| {
+            path.file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+        });
+        volumes.push(RemovableVolume {
+            e_readers: e_reader_layouts(&path),
+            path: mount.mount_point,
+            device: mount.device,
+            label,
+            fs_type: mount.fs_type,
+        });
+    }
+    Ok(volumes)
+}
+
+/// Calls `on_change` with the volumes that were mounted and those that were unmounted,
+/// whenever the mounts change.
+pub fn watch_removable_volumes(
+    on_change: impl Fn(Vec<RemovableVolume>, Vec<RemovableVolume>) + Send + 'static,
+) {
+    std::thread::spawn(move || {
+        let watch = || -> Result<()> {
+            let mountinfo = File::open(MOUNTINFO)?;
+            let mut volumes = removable_volumes()?;
+            loop {
+                // The kernel flags mountinfo with POLLPRI when a mount is added or removed.
+                let mut poll = libc::pollfd {
+                    fd: mountinfo.as_raw_fd(),
+                    events: libc::POLLPRI,
+                    revents: 0,
+                };
+                // SAFETY: `poll` points to one `pollfd`, as the count says, whose file
+                // descriptor stays open while `mountinfo` is alive.
+                if unsafe { libc::poll(&mut poll, 1, -1) } < 0 {
+                    let e = std::io::Error::last_os_error();
+                    match e.kind() {
+                        ErrorKind::Interrupted => continue,
+                        _ => return Err(e.into()),
+                    }
+                }
+                // udisks2 mounts a volume when the device is plugged in and updates its
+                // properties a moment later.
+                std::thread::sleep(std::time::Duration::from_millis(500));
+                let current = removable_volumes()?;
+                let added = current
+                    .iter()
+                    .filter(|volume| !volumes.contains(volume))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                let removed = volumes
+                    .iter()
+                    .filter(|volume| !current.contains(volume))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                volumes = current;
+                if !added.is_empty() || !removed.is_empty() {
+                    on_change(added, removed);
+                }
+            }
+        };
+        if let Err(e) = watch() {
+            log::warn!("Failed to watch removable volumes: {e}");
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // From a laptop with a Kobo and a card whose label needs escaping plugged in.
+    const SAMPLE: &str = r"22 1 259:2 / / rw,relatime shared:1 - ext4 /dev/nvme0n1p2 rw
+25 22 0:23 / /proc rw,nosuid,nodev,noexec,relatime shared:12 - proc proc rw
+31 22 0:27 / /tmp rw,nosuid,nodev shared:14 - tmpfs tmpfs rw,size=8G
+40 22 259:2 /home/books /srv/books rw,relatime shared:1 - ext4 /dev/nvme0n1p2 rw
+512 22 8:17 / /run/media/reader/KOBOeReader rw,nosuid,nodev shared:300 - vfat /dev/sdb1 rw
+513 22 8:33 / /media/My\040Books\011\134 rw master:5 - exfat /dev/disk\040a rw
+";
+
+    #[test]
+    fn parses_the_mounts_of_block_devices() {
+        let mounts = parse_mountinfo(SAMPLE);
+        let fields = mounts
+            .iter()
+            .map(|mount| {
+                (
+                    mount.device.as_str(),
+                    mount.device_id.as_str(),
+                    mount.mount_point.as_str(),
+                    mount.fs_type.as_str(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            fields,
+            [
+                ("/dev/nvme0n1p2", "259:2", "/", "ext4"),
+                ("/dev/sdb1", "8:17", "/run/media/reader/KOBOeReader", "vfat"),
+                ("/dev/disk a", "8:33", "/media/My Books\t\\", "exfat"),
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_malformed_lines() {
+        let mountinfo = "garbage\n\n30 22 8:1 / /mnt rw - vfat\n";
+        assert!(parse_mountinfo(mountinfo).is_empty());
+    }
+
+    #[test]
+    fn unescapes_mountinfo_fields() {
+        assert_eq!(unescape(r"/media/My\040Books"), "/media/My Books");
+        assert_eq!(unescape(r"a\011b\012c\134d"), "a\tb\nc\\d");
+        // Backslashes without three octal digits are kept.
+        assert_eq!(unescape(r"a\04"), r"a\04");
+        assert_eq!(unescape(r"a\9999"), r"a\9999");
+        assert_eq!(unescape(r"trailing\"), r"trailing\");
+        assert_eq!(unescape("Ünïcode"), "Ünïcode");
+    }
+
+    #[test]
+    fn unescapes_udev_link_names() {
+        assert_eq!(unescape_udev(r"KOBO\x20eReader"), "KOBO eReader");
+        assert_eq!(unescape_udev(r"a\x2fb\x5c"), r"a/b\");
+        // Incomplete and invalid escapes are kept.
+        assert_eq!(unescape_udev(r"a\x2"), r"a\x2");
+        assert_eq!(unescape_udev(r"a\xzz"), r"a\xzz");
+        assert_eq!(unescape_udev(r"a\y20"), r"a\y20");
+        assert_eq!(unescape_udev(r"\"), r"\");
+        assert_eq!(unescape_udev("Kindle"), "Kindle");
+    }
+}
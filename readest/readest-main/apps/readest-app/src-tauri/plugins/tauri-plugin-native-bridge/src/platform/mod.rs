@@ -1,2 +1,6 @@
+#[cfg(desktop)]
+pub mod fonts;
+#[cfg(target_os = "linux")]
+pub mod linux;
 #[cfg(target_os = "macos")]
 pub mod macos;
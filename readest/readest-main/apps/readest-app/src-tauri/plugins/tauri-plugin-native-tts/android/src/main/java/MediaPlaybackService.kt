@@ -59,6 +59,7 @@ class MediaPlaybackService : MediaBrowserServiceCompat() {
         var currentTitle: String = "Read Aloud"
         var currentArtist: String = "Reading your content"
         var currentArtwork: Bitmap? = null
+        var sleepTimerText: String? = null
     }
 
     override fun onCreate() {
@@ -156,6 +157,7 @@ class MediaPlaybackService : MediaBrowserServiceCompat() {
             setContentTitle(currentTitle)
             setContentText(currentArtist)
             setLargeIcon(currentArtwork)
+            setSubText(sleepTimerText)
             setContentIntent(mediaSession!!.controller.sessionActivity)
             setDeleteIntent(MediaButtonReceiver.buildMediaButtonPendingIntent(this@MediaPlaybackService, PlaybackStateCompat.ACTION_STOP))
             setVisibility(NotificationCompat.VISIBILITY_PUBLIC)
@@ -238,6 +240,14 @@ class MediaPlaybackService : MediaBrowserServiceCompat() {
             
             mediaSession?.setMetadata(metadataBuilder.build())
 
+            showNotification(if (player.isPlaying) PlaybackStateCompat.STATE_PLAYING else PlaybackStateCompat.STATE_PAUSED)
+        } else if (intent?.action == "UPDATE_SLEEP_TIMER") {
+            val remaining = intent.getLongExtra("remaining", -1L) // in seconds
+            sleepTimerText = if (remaining >= 0) {
+                String.format("\u23FE %d:%02d", remaining / 60, remaining % 60)
+            } else {
+                null
+            }
             showNotification(if (player.isPlaying) PlaybackStateCompat.STATE_PLAYING else PlaybackStateCompat.STATE_PAUSED)
         } else if (intent?.action == "UPDATE_PLAYBACK_STATE") {
             val isPlaying = intent.getBooleanExtra("playing", false)
@@ -2,6 +2,10 @@ package com.readest.native_tts
 
 import android.Manifest
 import android.os.Bundle
+import android.os.SystemClock
+import android.text.SpannableString
+import android.text.Spanned
+import android.text.style.TtsSpan
 import android.app.Activity
 import android.content.Context
 import android.provider.Settings
@@ -51,13 +55,43 @@ data class TTSVoiceData(
 data class TTSMessageEvent(
     val code: String, // 'boundary' | 'error' | 'end'
     val message: String? = null,
-    val mark: String? = null
+    val mark: String? = null,
+    val charIndex: Int? = null, // UTF-16 offset of the word in the utterance text
+    val charLength: Int? = null,
+    val timestamp: Double? = null // Milliseconds since the utterance started
 )
 
+@InvokeArg
+class SpeechSegmentArgs {
+  var text: String = ""
+  var offset: Int = 0 // UTF-16 offset of the text in the whole utterance
+  var mark: String? = null
+  var pause: Int = 0 // in milliseconds
+  var rate: Float = 1.0f
+  var pitch: Float = 1.0f
+  var lang: String? = null
+  var sayAs: String? = null
+  var voice: String? = null // Routed by the voice map of the Rust plugin
+  var alias: String? = null // Spoken instead of the text, from a pronunciation lexicon
+  var phoneme: String? = null // Not supported by TextToSpeech, the text is spoken instead
+  var alphabet: String? = null
+}
+
 @InvokeArg
 class SpeakArgs(
     val text: String? = "",
-    val preload: Boolean? = false
+    val preload: Boolean? = false,
+    val segments: List<SpeechSegmentArgs>? = null // Parsed and routed to voices by the Rust plugin
+)
+
+// A part of an utterance that is queued on its own: a segment or the pause before it.
+data class UtterancePart(
+    val utteranceId: String,
+    val offset: Int,
+    val mark: String?,
+    val first: Boolean,
+    val last: Boolean,
+    val spokenAs: Int? = null // UTF-16 length of the text that an alias is spoken for
 )
 
 @InvokeArg
@@ -70,6 +104,19 @@ class SetPitchArgs(
     val pitch: Float? = 1.0f
 )
 
+@InvokeArg
+class SetVolumeArgs(
+    val volume: Float? = 1.0f
+)
+
+@InvokeArg
+class SleepTimerStateArgs {
+  var active: Boolean? = null
+  var ended: Boolean? = null
+  var remaining: Double? = null // in seconds
+  var paragraphs: Int? = null
+}
+
 @InvokeArg
 class SetVoiceArgs(
     val voice: String? = null
@@ -122,9 +169,12 @@ class NativeTTSPlugin(private val activity: Activity) : Plugin(activity) {
     private var isSpeaking = AtomicBoolean(false)
     private var currentRate = AtomicReference<Float>(1.0f)
     private var currentPitch = AtomicReference<Float>(1.0f)
+    private var currentVolume = AtomicReference<Float>(1.0f)
     
     private val eventChannels = ConcurrentHashMap<String, Channel<TTSMessageEvent>>()
     private val speakingJobs = ConcurrentHashMap<String, Job>()
+    private val utteranceStartTimes = ConcurrentHashMap<String, Long>()
+    private val utteranceParts = ConcurrentHashMap<String, UtterancePart>()
     private val coroutineScope = CoroutineScope(Dispatchers.Main + SupervisorJob())
 
     @Command
@@ -174,23 +224,34 @@ class NativeTTSPlugin(private val activity: Activity) : Plugin(activity) {
     private fun setupTTSListener() {
         textToSpeech?.setOnUtteranceProgressListener(object : UtteranceProgressListener() {
             override fun onStart(utteranceId: String?) {
-                utteranceId?.let { id ->
-                    isSpeaking.set(true)
-                    sendEvent(id, TTSMessageEvent("boundary", "start"))
+                utteranceId?.let { partId ->
+                    val part = utteranceParts[partId]
+                    val id = part?.utteranceId ?: partId
+                    if (part == null || part.first) {
+                        isSpeaking.set(true)
+                        utteranceStartTimes[id] = SystemClock.elapsedRealtime()
+                        sendEvent(id, TTSMessageEvent("boundary", "start"))
+                    }
+                    part?.mark?.let { sendEvent(id, TTSMessageEvent("boundary", "mark", it)) }
                 }
             }
             
             override fun onDone(utteranceId: String?) {
-                utteranceId?.let { id ->
-                    isSpeaking.set(false)
-                    sendEvent(id, TTSMessageEvent("end"))
-                    closeEventChannel(id)
+                utteranceId?.let { partId ->
+                    val part = utteranceParts.remove(partId)
+                    if (part == null || part.last) {
+                        val id = part?.utteranceId ?: partId
+                        isSpeaking.set(false)
+                        sendEvent(id, TTSMessageEvent("end"))
+                        closeEventChannel(id)
+                    }
                 }
             }
 
             @Deprecated("deprecated in API level 21")
             override fun onError(utteranceId: String?) {
-                utteranceId?.let { id ->
+                utteranceId?.let { partId ->
+                    val id = utteranceParts.remove(partId)?.utteranceId ?: partId
                     isSpeaking.set(false)
                     sendEvent(id, TTSMessageEvent("error", "TTS playback error"))
                     closeEventChannel(id)
@@ -198,7 +259,8 @@ class NativeTTSPlugin(private val activity: Activity) : Plugin(activity) {
             }
             
             override fun onError(utteranceId: String?, errorCode: Int) {
-                utteranceId?.let { id ->
+                utteranceId?.let { partId ->
+                    val id = utteranceParts.remove(partId)?.utteranceId ?: partId
                     isSpeaking.set(false)
                     sendEvent(id, TTSMessageEvent("error", "TTS playback error:$errorCode"))
                     closeEventChannel(id)
@@ -206,8 +268,19 @@ class NativeTTSPlugin(private val activity: Activity) : Plugin(activity) {
             }
             
             override fun onRangeStart(utteranceId: String?, start: Int, end: Int, frame: Int) {
-                utteranceId?.let { id ->
-                    sendEvent(id, TTSMessageEvent("boundary", "range", "pos:$start-$end"))
+                utteranceId?.let { partId ->
+                    val part = utteranceParts[partId]
+                    val id = part?.utteranceId ?: partId
+                    val offset = part?.offset ?: 0
+                    val startTime = utteranceStartTimes[id] ?: SystemClock.elapsedRealtime()
+                    val timestamp = (SystemClock.elapsedRealtime() - startTime).toDouble()
+                    // The words of an alias are all within the text it is spoken for.
+                    val event = part?.spokenAs?.let { length ->
+                        TTSMessageEvent("boundary", "word", null, offset, length, timestamp)
+                    } ?: TTSMessageEvent(
+                        "boundary", "word", null, offset + start, end - start, timestamp
+                    )
+                    sendEvent(id, event)
                 }
             }
         })
@@ -230,8 +303,16 @@ class NativeTTSPlugin(private val activity: Activity) : Plugin(activity) {
                 val eventChannel = Channel<TTSMessageEvent>(Channel.UNLIMITED)
                 eventChannels[utteranceId] = eventChannel
                 
+                val segments = args.segments.orEmpty()
                 val speakJob = launch {
-                    speakText(text, utteranceId, args.preload ?: false)
+                    if (args.preload == true) {
+                        // TextToSpeech synthesizes as it speaks, there is nothing to prepare.
+                        sendEvent(utteranceId, TTSMessageEvent("end"))
+                    } else if (segments.isEmpty()) {
+                        speakText(text, utteranceId)
+                    } else {
+                        speakSegments(segments, utteranceId)
+                    }
                 }
                 speakingJobs[utteranceId] = speakJob
                 
@@ -250,7 +331,7 @@ class NativeTTSPlugin(private val activity: Activity) : Plugin(activity) {
         }
     }
     
-    private suspend fun speakText(text: String, utteranceId: String, preload: Boolean) {
+    private suspend fun speakText(text: String, utteranceId: String) {
         withContext(Dispatchers.Main) {
             try {
                 textToSpeech?.apply {
@@ -260,11 +341,12 @@ class NativeTTSPlugin(private val activity: Activity) : Plugin(activity) {
                 
                 val params = Bundle().apply {
                     putString(TextToSpeech.Engine.KEY_PARAM_UTTERANCE_ID, utteranceId)
+                    putFloat(TextToSpeech.Engine.KEY_PARAM_VOLUME, currentVolume.get())
                 }
                 
                 val result = textToSpeech?.speak(
                     text,
-                    if (preload) TextToSpeech.QUEUE_ADD else TextToSpeech.QUEUE_FLUSH,
+                    TextToSpeech.QUEUE_FLUSH,
                     params,
                     utteranceId
                 )
@@ -278,6 +360,82 @@ class NativeTTSPlugin(private val activity: Activity) : Plugin(activity) {
         }
     }
     
+    // Queues every segment as an utterance of its own, with the rate, pitch and language
+    // that TextToSpeech captures when an utterance is queued. Pauses are queued as silence.
+    private suspend fun speakSegments(
+        segments: List<SpeechSegmentArgs>,
+        utteranceId: String
+    ) {
+        withContext(Dispatchers.Main) {
+            val tts = textToSpeech ?: return@withContext
+            val voice = tts.voice
+            try {
+                var queueMode = TextToSpeech.QUEUE_FLUSH
+                var first = true
+                segments.forEachIndexed { index, segment ->
+                    val last = index == segments.lastIndex
+                    if (segment.pause > 0) {
+                        val partId = "$utteranceId#pause$index"
+                        utteranceParts[partId] =
+                            UtterancePart(utteranceId, segment.offset, null, first, false)
+                        tts.playSilentUtterance(segment.pause.toLong(), queueMode, partId)
+                        queueMode = TextToSpeech.QUEUE_ADD
+                        first = false
+                    }
+
+                    val partId = "$utteranceId#$index"
+                    val alias = segment.alias?.takeIf { it.isNotBlank() }
+                    val spokenAs = alias?.let { segment.text.length }
+                    utteranceParts[partId] = UtterancePart(
+                        utteranceId, segment.offset, segment.mark, first, last, spokenAs
+                    )
+                    first = false
+                    if (segment.text.isBlank()) {
+                        tts.playSilentUtterance(0, queueMode, partId)
+                    } else {
+                        tts.setSpeechRate(currentRate.get() * segment.rate)
+                        tts.setPitch(currentPitch.get() * segment.pitch)
+                        val segmentVoice = segment.voice?.let { name ->
+                            tts.voices?.firstOrNull { it.name == name }
+                        }
+                        if (segmentVoice != null) {
+                            tts.setVoice(segmentVoice)
+                        } else if (segment.lang != null) {
+                            tts.setLanguage(Locale.forLanguageTag(segment.lang!!))
+                        } else if (voice != null) {
+                            tts.setVoice(voice)
+                        }
+                        val text: CharSequence = when {
+                            alias != null -> alias
+                            segment.sayAs in listOf("characters", "spell-out", "verbatim") ->
+                                SpannableString(segment.text).apply {
+                                    val span = TtsSpan.VerbatimBuilder(segment.text).build()
+                                    setSpan(span, 0, length, Spanned.SPAN_EXCLUSIVE_EXCLUSIVE)
+                                }
+                            else -> segment.text
+                        }
+                        val params = Bundle().apply {
+                            putString(TextToSpeech.Engine.KEY_PARAM_UTTERANCE_ID, partId)
+                            putFloat(TextToSpeech.Engine.KEY_PARAM_VOLUME, currentVolume.get())
+                        }
+                        val result = tts.speak(text, queueMode, params, partId)
+                        if (result != TextToSpeech.SUCCESS) {
+                            sendEvent(utteranceId, TTSMessageEvent("error", "Failed to start speech"))
+                            return@withContext
+                        }
+                    }
+                    queueMode = TextToSpeech.QUEUE_ADD
+                }
+            } catch (e: Exception) {
+                sendEvent(utteranceId, TTSMessageEvent("error", "Exception during speech: ${e.message}"))
+            } finally {
+                tts.setSpeechRate(currentRate.get())
+                tts.setPitch(currentPitch.get())
+                voice?.let { tts.setVoice(it) }
+            }
+        }
+    }
+    
     private fun startEventStream(utteranceId: String) {
         coroutineScope.launch {
             val channel = eventChannels[utteranceId] ?: return@launch
@@ -288,6 +446,9 @@ class NativeTTSPlugin(private val activity: Activity) : Plugin(activity) {
                         put("code", event.code)
                         event.message?.let { put("message", it) }
                         event.mark?.let { put("mark", it) }
+                        event.charIndex?.let { put("charIndex", it) }
+                        event.charLength?.let { put("charLength", it) }
+                        event.timestamp?.let { put("timestamp", it) }
                     }
                     trigger(CHANNEL_NAME, eventData)
                 }
@@ -309,6 +470,8 @@ class NativeTTSPlugin(private val activity: Activity) : Plugin(activity) {
             eventChannels.remove(utteranceId)
             speakingJobs[utteranceId]?.cancel()
             speakingJobs.remove(utteranceId)
+            utteranceStartTimes.remove(utteranceId)
+            utteranceParts.values.removeAll { it.utteranceId == utteranceId }
         }
     }
     
@@ -377,6 +540,45 @@ class NativeTTSPlugin(private val activity: Activity) : Plugin(activity) {
             invoke.reject("Exception setting pitch: ${e.message}")
         }
     }
+
+    // Called by the sleep timer to fade out. The volume applies from the next utterance.
+    @Command
+    fun set_volume(invoke: Invoke) {
+        val args = invoke.parseArgs(SetVolumeArgs::class.java)
+        try {
+            currentVolume.set((args.volume ?: 1.0f).coerceIn(0.0f, 1.0f))
+            invoke.resolve()
+        } catch (e: Exception) {
+            invoke.reject("Exception setting volume: ${e.message}")
+        }
+    }
+
+    // Called by the sleep timer, which runs in Rust, to report its state to the frontend
+    // and show the time left in the media notification.
+    @Command
+    fun update_sleep_timer(invoke: Invoke) {
+        val args = invoke.parseArgs(SleepTimerStateArgs::class.java)
+        try {
+            val state = JSObject().apply {
+                put("active", args.active ?: false)
+                put("ended", args.ended ?: false)
+                args.remaining?.let { put("remaining", it) }
+                args.paragraphs?.let { put("paragraphs", it) }
+            }
+            trigger("sleep-timer", state)
+
+            if (MediaPlaybackService.pluginEventTrigger != null) {
+                val intent = Intent(activity, MediaPlaybackService::class.java).apply {
+                    action = "UPDATE_SLEEP_TIMER"
+                    putExtra("remaining", args.remaining?.let { Math.ceil(it).toLong() } ?: -1L)
+                }
+                activity.startService(intent)
+            }
+            invoke.resolve()
+        } catch (e: Exception) {
+            invoke.reject("Failed to update sleep timer: ${e.message}")
+        }
+    }
     
     @Command
     fun set_voice(invoke: Invoke) {
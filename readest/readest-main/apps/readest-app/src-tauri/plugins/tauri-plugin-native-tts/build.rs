@@ -11,6 +11,22 @@ const COMMANDS: &[&str] = &[
     "set_media_session_active",
     "update_media_session_state",
     "update_media_session_metadata",
+    "start_sleep_timer",
+    "cancel_sleep_timer",
+    "get_sleep_timer",
+    "get_voice_map",
+    "set_voice_map",
+    "set_language_voice",
+    "get_lexicon",
+    "set_lexicon",
+    "import_lexicon",
+    "install_voice_pack",
+    "remove_voice_pack",
+    "export_audiobook",
+    "cancel_audiobook_export",
+    "get_synthesis_cache_info",
+    "set_synthesis_cache_limit",
+    "clear_synthesis_cache",
     "register_listener",
     "remove_listener",
     "check_permissions",
@@ -0,0 +1,472 @@
+//! Export of chapters to an audiobook file.
+//!
+//! Chapters are synthesized to PCM with the Piper voice, or with espeak-ng for the other
+//! voices since Speech Dispatcher only speaks to the sound card. The audio is then encoded
+//! with `ffmpeg` into M4B or Ogg Opus, with a chapter marker per chapter and the cover art.
+//! Like spoken utterances, chapters go through the pronunciation lexicons and the voice map
+//! first, and the runs of another voice are resampled to the sample rate of the first one.
+
+use base64::Engine as _;
+use tauri::ipc::Channel;
+
+use std::collections::hash_map::{Entry, HashMap};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::models::*;
+use crate::{Error, Result};
+
+// The picture type of a front cover in a FLAC picture block.
+const FRONT_COVER: u32 = 3;
+
+pub(crate) struct Audio {
+    pub samples: Vec<i16>, // Mono
+    pub sample_rate: u32,
+}
+
+// Synthesizes speech to memory instead of playing it.
+pub(crate) trait Renderer {
+    fn render(&mut self, segments: &[SpeechSegment]) -> Result<Audio>;
+}
+
+// Renders every run of segments with the renderer of its voice, created when first needed,
+// at the sample rate of the first voice rendered.
+pub(crate) struct RoutedRenderer<F> {
+    voice: Option<String>, // Of the segments routed to no voice
+    create: F,
+    renderers: HashMap<Option<String>, Box<dyn Renderer>>,
+    sample_rate: Option<u32>,
+}
+
+impl<F> RoutedRenderer<F>
+where
+    F: FnMut(Option<&str>) -> Result<Box<dyn Renderer>>,
+{
+    pub(crate) fn new(voice: Option<String>, create: F) -> Self {
+        Self {
+            voice,
+            create,
+            renderers: HashMap::new(),
+            sample_rate: None,
+        }
+    }
+}
+
+impl<F> Renderer for RoutedRenderer<F>
+where
+    F: FnMut(Option<&str>) -> Result<Box<dyn Renderer>>,
+{
+    fn render(&mut self, segments: &[SpeechSegment]) -> Result<Audio> {
+        let voice_of = |segment: &SpeechSegment| segment.voice.clone().or(self.voice.clone());
+        let mut runs: Vec<(Option<String>, usize, usize)> = Vec::new();
+        for (index, segment) in segments.iter().enumerate() {
+            let voice = voice_of(segment);
+            match runs.last_mut() {
+                Some((run_voice, _, end)) if *run_voice == voice => *end = index + 1,
+                _ => runs.push((voice, index, index + 1)),
+            }
+        }
+        if runs.is_empty() {
+            // A chapter without text still takes the sample rate of its voice.
+            runs.push((self.voice.clone(), 0, 0));
+        }
+        let mut samples = Vec::new();
+        for (voice, start, end) in runs {
+            let renderer = match self.renderers.entry(voice) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let renderer = (self.create)(entry.key().as_deref())?;
+                    entry.insert(renderer)
+                }
+            };
+            let audio = renderer.render(&segments[start..end])?;
+            let rate = *self.sample_rate.get_or_insert(audio.sample_rate);
+            samples.extend(resample(&audio.samples, audio.sample_rate, rate));
+        }
+        Ok(Audio {
+            samples,
+            sample_rate: self.sample_rate.unwrap_or(1),
+        })
+    }
+}
+
+// Resamples mono audio by linear interpolation, which is good enough for speech.
+fn resample(samples: &[i16], from: u32, to: u32) -> Vec<i16> {
+    if from == to || samples.is_empty() {
+        return samples.to_vec();
+    }
+    let len = (samples.len() as u64 * to as u64 / from as u64) as usize;
+    (0..len)
+        .map(|i| {
+            let position = i as f64 * from as f64 / to as f64;
+            let index = position as usize;
+            let next = samples.get(index + 1).unwrap_or(&samples[index]);
+            let fraction = position - index as f64;
+            (samples[index] as f64 * (1.0 - fraction) + *next as f64 * fraction).round() as i16
+        })
+        .collect()
+}
+
+struct ChapterMarker<'a> {
+    title: &'a str,
+    start: u64, // In milliseconds
+    end: u64,
+}
+
+// A directory for the intermediate files, removed with everything in it when dropped.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new() -> Result<Self> {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let name = format!(
+            "readest-audiobook-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        );
+        let dir = std::env::temp_dir().join(name);
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self(dir))
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+// Escapes the characters that are special in ffmpeg metadata files.
+fn escape_metadata(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '=' | ';' | '#' | '\\' | '\n') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// Reads the artwork of a request, a base64 data URL or a file path, into its MIME type and
+// data.
+pub(crate) fn read_artwork(artwork: &str) -> Result<(String, Vec<u8>)> {
+    if let Some(url) = artwork.strip_prefix("data:") {
+        let invalid = || Error::NativeTTSError("Invalid artwork data URL".into());
+        let (header, data) = url.split_once(',').ok_or_else(invalid)?;
+        let mime = header.strip_suffix(";base64").ok_or_else(invalid)?;
+        let data = base64::engine::general_purpose::STANDARD
+            .decode(data.trim())
+            .map_err(|_| invalid())?;
+        return Ok((mime.to_string(), data));
+    }
+    let path = Path::new(artwork);
+    let mime = match path.extension().and_then(|extension| extension.to_str()) {
+        Some(extension) if extension.eq_ignore_ascii_case("png") => "image/png",
+        _ => "image/jpeg",
+    };
+    Ok((mime.to_string(), std::fs::read(path)?))
+}
+
+// Builds the FLAC picture block that Ogg files carry their cover art in.
+fn picture_block(mime: &str, data: &[u8]) -> Vec<u8> {
+    let mut block = Vec::with_capacity(32 + mime.len() + data.len());
+    block.extend(FRONT_COVER.to_be_bytes());
+    block.extend((mime.len() as u32).to_be_bytes());
+    block.extend(mime.as_bytes());
+    // No description, and width, height, color depth and palette size left unknown.
+    block.extend([0u8; 20]);
+    block.extend((data.len() as u32).to_be_bytes());
+    block.extend(data);
+    block
+}
+
+fn write_metadata(
+    path: &Path,
+    request: &ExportAudiobookRequest,
+    chapters: &[ChapterMarker],
+    picture: Option<&[u8]>,
+) -> Result<()> {
+    let mut file = BufWriter::new(std::fs::File::create(path)?);
+    writeln!(file, ";FFMETADATA1")?;
+    let tags = [
+        ("title", &request.title),
+        ("artist", &request.artist),
+        ("album", &request.album),
+    ];
+    for (key, value) in tags {
+        if let Some(value) = value {
+            writeln!(file, "{key}={}", escape_metadata(value))?;
+        }
+    }
+    if let Some(picture) = picture {
+        let picture = base64::engine::general_purpose::STANDARD.encode(picture);
+        writeln!(file, "METADATA_BLOCK_PICTURE={}", escape_metadata(&picture))?;
+    }
+    for chapter in chapters {
+        writeln!(file, "\n[CHAPTER]\nTIMEBASE=1/1000")?;
+        writeln!(file, "START={}\nEND={}", chapter.start, chapter.end)?;
+        writeln!(file, "title={}", escape_metadata(chapter.title))?;
+    }
+    file.flush()?;
+    Ok(())
+}
+
+fn report(on_progress: &Channel<AudiobookProgress>, progress: AudiobookProgress) {
+    if let Err(e) = on_progress.send(progress) {
+        log::warn!("Failed to report audiobook progress: {e}");
+    }
+}
+
+// Synthesizes the chapters of `request` with `renderer` and encodes them into its file. The
+// segments of every chapter go through `prepare` first, and the export stops with an error
+// once `cancelled` is set.
+pub(crate) fn export(
+    renderer: &mut dyn Renderer,
+    prepare: impl Fn(Vec<SpeechSegment>) -> Vec<SpeechSegment>,
+    request: &ExportAudiobookRequest,
+    cancelled: &AtomicBool,
+    on_progress: &Channel<AudiobookProgress>,
+) -> Result<ExportAudiobookResponse> {
+    if request.chapters.is_empty() {
+        return Err(Error::NativeTTSError("No chapters to export".into()));
+    }
+    let temp_dir = TempDir::new()?;
+    let audio_path = temp_dir.0.join("audio.pcm");
+    let mut audio_file = BufWriter::new(std::fs::File::create(&audio_path)?);
+
+    let chapters = request.chapters.len() as u32;
+    let mut sample_rate = None;
+    let mut samples = 0u64;
+    let mut markers = Vec::new();
+    for (index, chapter) in request.chapters.iter().enumerate() {
+        if cancelled.load(Ordering::Relaxed) {
+            return Err(Error::ExportCancelled);
+        }
+        report(
+            on_progress,
+            AudiobookProgress {
+                stage: AudiobookStage::Synthesizing,
+                chapter: index as u32,
+                chapters,
+                progress: index as f64 / chapters as f64,
+            },
+        );
+        let segments = match chapter.ssml {
+            true => crate::ssml::parse(&chapter.text)?,
+            false => vec![SpeechSegment::new(chapter.text.clone())],
+        };
+        let audio = renderer.render(&prepare(segments))?;
+        let rate = *sample_rate.get_or_insert(audio.sample_rate);
+        if audio.sample_rate != rate {
+            return Err(Error::NativeTTSError(
+                "The voice changed its sample rate between chapters".into(),
+            ));
+        }
+        for sample in &audio.samples {
+            audio_file.write_all(&sample.to_le_bytes())?;
+        }
+        let start = samples * 1000 / rate as u64;
+        samples += audio.samples.len() as u64;
+        markers.push(ChapterMarker {
+            title: &chapter.title,
+            start,
+            end: samples * 1000 / rate as u64,
+        });
+    }
+    audio_file.flush()?;
+    drop(audio_file);
+    let sample_rate = sample_rate.unwrap_or(1);
+    let duration = samples as f64 / sample_rate as f64;
+
+    let artwork = request
+        .artwork
+        .as_deref()
+        .filter(|artwork| !artwork.is_empty())
+        .map(read_artwork)
+        .transpose()?;
+    let mut args = vec![
+        "-y".to_string(),
+        "-hide_banner".to_string(),
+        "-nostats".to_string(),
+        "-loglevel".to_string(),
+        "error".to_string(),
+        "-progress".to_string(),
+        "pipe:1".to_string(),
+        "-f".to_string(),
+        "s16le".to_string(),
+        "-ar".to_string(),
+        sample_rate.to_string(),
+        "-ac".to_string(),
+        "1".to_string(),
+        "-i".to_string(),
+        audio_path.to_string_lossy().into_owned(),
+        "-i".to_string(),
+    ];
+    let metadata_path = temp_dir.0.join("metadata.txt");
+    args.push(metadata_path.to_string_lossy().into_owned());
+    let mut output_args = vec!["-map", "0:a", "-map_metadata", "1", "-map_chapters", "1"];
+    match request.format {
+        AudiobookFormat::M4b => {
+            // MPEG-4 files carry the cover as an attached picture stream.
+            if let Some((mime, data)) = &artwork {
+                let extension = if mime == "image/png" { "png" } else { "jpg" };
+                let cover_path = temp_dir.0.join(format!("cover.{extension}"));
+                std::fs::write(&cover_path, data)?;
+                args.extend(["-i".to_string(), cover_path.to_string_lossy().into_owned()]);
+                output_args.extend(["-map", "2:v", "-c:v", "copy"]);
+                output_args.extend(["-disposition:v:0", "attached_pic"]);
+            }
+            write_metadata(&metadata_path, request, &markers, None)?;
+            output_args.extend(["-c:a", "aac", "-b:a", "64k", "-f", "ipod"]);
+        }
+        AudiobookFormat::Opus => {
+            let picture = artwork
+                .as_ref()
+                .map(|(mime, data)| picture_block(mime, data));
+            write_metadata(&metadata_path, request, &markers, picture.as_deref())?;
+            output_args.extend(["-c:a", "libopus", "-b:a", "32k", "-f", "opus"]);
+        }
+    }
+    args.extend(output_args.into_iter().map(str::to_string));
+
+    // Encode into a partial file, so that a failed export leaves no truncated audiobook.
+    let partial_path = PathBuf::from(format!("{}.part", request.path));
+    args.push(partial_path.to_string_lossy().into_owned());
+    let mut ffmpeg = Command::new("ffmpeg")
+        .args(&args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => {
+                Error::NativeTTSError("ffmpeg is needed to export audiobooks".into())
+            }
+            _ => e.into(),
+        })?;
+
+    // Read the errors on another thread, so that ffmpeg never blocks on a full pipe.
+    let stderr = ffmpeg.stderr.take().map(|mut pipe| {
+        std::thread::spawn(move || {
+            let mut stderr = String::new();
+            let _ = pipe.read_to_string(&mut stderr);
+            stderr
+        })
+    });
+    // ffmpeg reports how much it has encoded as `key=value` lines.
+    if let Some(stdout) = ffmpeg.stdout.take() {
+        for line in BufReader::new(stdout).lines() {
+            if cancelled.load(Ordering::Relaxed) {
+                let _ = ffmpeg.kill();
+                break;
+            }
+            let line = line?;
+            let Some(encoded) = line
+                .strip_prefix("out_time_us=")
+                .and_then(|time| time.parse::<f64>().ok())
+            else {
+                continue;
+            };
+            report(
+                on_progress,
+                AudiobookProgress {
+                    stage: AudiobookStage::Encoding,
+                    chapter: chapters - 1,
+                    chapters,
+                    progress: (encoded / 1e6 / duration.max(f64::EPSILON)).clamp(0.0, 1.0),
+                },
+            );
+        }
+    }
+    let status = ffmpeg.wait()?;
+    let stderr = stderr
+        .and_then(|reader| reader.join().ok())
+        .unwrap_or_default();
+    if cancelled.load(Ordering::Relaxed) {
+        let _ = std::fs::remove_file(&partial_path);
+        return Err(Error::ExportCancelled);
+    }
+    if !status.success() {
+        let _ = std::fs::remove_file(&partial_path);
+        return Err(Error::NativeTTSError(format!(
+            "ffmpeg failed with {status}: {}",
+            stderr.trim()
+        )));
+    }
+    std::fs::rename(&partial_path, &request.path)?;
+
+    Ok(ExportAudiobookResponse {
+        path: request.path.clone(),
+        duration,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Renders every segment as samples of its text length, as many as its bytes.
+    struct FakeRenderer {
+        sample_rate: u32,
+        rendered: std::rc::Rc<std::cell::RefCell<Vec<String>>>,
+    }
+
+    impl Renderer for FakeRenderer {
+        fn render(&mut self, segments: &[SpeechSegment]) -> Result<Audio> {
+            let mut rendered = self.rendered.borrow_mut();
+            rendered.push(segments.iter().map(|s| s.text.as_str()).collect());
+            Ok(Audio {
+                samples: segments
+                    .iter()
+                    .flat_map(|s| std::iter::repeat(s.text.len() as i16).take(s.text.len()))
+                    .collect(),
+                sample_rate: self.sample_rate,
+            })
+        }
+    }
+
+    fn segment(text: &str, voice: Option<&str>) -> SpeechSegment {
+        SpeechSegment {
+            voice: voice.map(str::to_string),
+            ..SpeechSegment::new(text.to_string())
+        }
+    }
+
+    #[test]
+    fn resamples_linearly() {
+        assert_eq!(resample(&[0, 100], 1, 1), [0, 100]);
+        assert_eq!(resample(&[0, 100, 200], 1, 2), [0, 50, 100, 150, 200, 200]);
+        assert_eq!(resample(&[0, 50, 100, 150], 2, 1), [0, 100]);
+        assert!(resample(&[], 1, 2).is_empty());
+    }
+
+    #[test]
+    fn renders_runs_with_the_renderers_of_their_voices() {
+        let rendered = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let mut created = Vec::new();
+        let mut renderer = RoutedRenderer::new(Some("a".to_string()), |voice: Option<&str>| {
+            created.push(voice.map(str::to_string));
+            Ok(Box::new(FakeRenderer {
+                sample_rate: if voice == Some("b") { 2 } else { 1 },
+                rendered: rendered.clone(),
+            }) as Box<dyn Renderer>)
+        });
+        let audio = renderer
+            .render(&[
+                segment("x", None),
+                segment("yy", Some("a")),
+                segment("zzz", Some("b")),
+                segment("w", None),
+            ])
+            .unwrap();
+        // The audio of the second voice is resampled to the rate of the first.
+        assert_eq!(audio.sample_rate, 1);
+        assert_eq!(audio.samples, [1, 2, 2, 3, 1]);
+        assert_eq!(*rendered.borrow(), ["xyy", "zzz", "w"]);
+        drop(renderer);
+        assert_eq!(created, [Some("a".to_string()), Some("b".to_string())]);
+    }
+}
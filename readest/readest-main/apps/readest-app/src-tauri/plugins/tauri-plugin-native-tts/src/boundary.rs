@@ -0,0 +1,380 @@
+//! Word boundaries for the desktop engines.
+//!
+//! Speech Dispatcher reports when each word is reached through SSML marks. The espeak-ng
+//! and Piper engines only know roughly when a word is spoken, so their boundaries are
+//! paced against a `PlaybackClock` that follows the audio as it is paused and resumed.
+
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::desktop::Listeners;
+use crate::models::{SpeechSegment, TTSMessageEvent};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Word {
+    pub offset: u32,  // In UTF-16 code units, like JavaScript string indices
+    pub length: u32,  // In UTF-16 code units
+    pub start: usize, // Byte offset in the text
+    pub end: usize,   // Byte offset in the text, exclusive
+}
+
+impl Word {
+    pub(crate) fn event(&self, timestamp: Duration) -> TTSMessageEvent {
+        TTSMessageEvent {
+            code: "boundary".into(),
+            message: Some("word".into()),
+            char_index: Some(self.offset),
+            char_length: Some(self.length),
+            timestamp: Some(timestamp.as_secs_f64() * 1000.0),
+            ..Default::default()
+        }
+    }
+}
+
+pub(crate) fn mark_event(name: &str) -> TTSMessageEvent {
+    TTSMessageEvent {
+        code: "boundary".into(),
+        message: Some("mark".into()),
+        mark: Some(name.to_string()),
+        ..Default::default()
+    }
+}
+
+// Han and kana are written without spaces, so every one of them is a word of its own.
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30ff}'
+        | '\u{3400}'..='\u{4dbf}'
+        | '\u{4e00}'..='\u{9fff}'
+        | '\u{f900}'..='\u{faff}'
+        | '\u{20000}'..='\u{2ffff}')
+}
+
+// Splits `text` into words, leaving out whitespace and punctuation around them.
+pub(crate) fn words(text: &str) -> Vec<Word> {
+    let mut words = Vec::new();
+    let mut current: Option<Word> = None;
+    let mut offset = 0;
+    for (index, c) in text.char_indices() {
+        let len = c.len_utf16() as u32;
+        let end = index + c.len_utf8();
+        let joins_word =
+            c.is_alphanumeric() || (current.is_some() && matches!(c, '\'' | '’' | '-'));
+
+        if is_cjk(c) || !joins_word {
+            words.extend(current.take());
+        }
+        if is_cjk(c) {
+            words.push(Word {
+                offset,
+                length: len,
+                start: index,
+                end,
+            });
+        } else if joins_word {
+            let word = current.get_or_insert(Word {
+                offset,
+                length: 0,
+                start: index,
+                end: index,
+            });
+            word.length += len;
+            word.end = end;
+        }
+        offset += len;
+    }
+    words.extend(current);
+
+    // A word does not end with the apostrophe or hyphen that joined it.
+    for word in words.iter_mut() {
+        let trimmed = text[word.start..word.end].trim_end_matches(['\'', '’', '-']);
+        let removed = &text[word.start + trimmed.len()..word.end];
+        word.length -= removed.encode_utf16().count() as u32;
+        word.end = word.start + trimmed.len();
+    }
+    words
+}
+
+// The words of a segment, with offsets in the text of the whole utterance. Text spoken
+// with an alias or phonemes is a single word.
+pub(crate) fn segment_words(segment: &SpeechSegment) -> Vec<Word> {
+    let mut words = words(&segment.text);
+    if !segment.is_pronounced_as_written() && words.len() > 1 {
+        let (first, last) = (&words[0], &words[words.len() - 1]);
+        let word = Word {
+            offset: first.offset,
+            length: last.offset + last.length - first.offset,
+            start: first.start,
+            end: last.end,
+        };
+        words = vec![word];
+    }
+    for word in words.iter_mut() {
+        word.offset += segment.offset;
+    }
+    words
+}
+
+#[derive(Default)]
+struct ClockState {
+    started: Option<Instant>,
+    paused_at: Option<Instant>,
+    paused: Duration, // Total time spent paused
+    stopped: bool,
+}
+
+// Measures how much of an utterance has been played.
+#[derive(Default)]
+pub(crate) struct PlaybackClock {
+    state: Mutex<ClockState>,
+    changed: Condvar,
+}
+
+impl PlaybackClock {
+    pub(crate) fn start(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.started.get_or_insert_with(Instant::now);
+        self.changed.notify_all();
+    }
+
+    pub(crate) fn pause(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.paused_at.get_or_insert_with(Instant::now);
+        self.changed.notify_all();
+    }
+
+    pub(crate) fn resume(&self) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(paused_at) = state.paused_at.take() {
+            state.paused += paused_at.elapsed();
+        }
+        self.changed.notify_all();
+    }
+
+    // Stops the clock at the current playback time.
+    pub(crate) fn stop(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.paused_at.get_or_insert_with(Instant::now);
+        state.stopped = true;
+        self.changed.notify_all();
+    }
+
+    // How much has been played so far.
+    pub(crate) fn position(&self) -> Duration {
+        Self::elapsed(&self.state.lock().unwrap()).unwrap_or_default()
+    }
+
+    fn elapsed(state: &ClockState) -> Option<Duration> {
+        let started = state.started?;
+        let now = state.paused_at.unwrap_or_else(Instant::now);
+        Some(now.duration_since(started).saturating_sub(state.paused))
+    }
+
+    // Waits until `at` has been played, and returns false if the clock stopped first.
+    fn wait_until(&self, at: Duration) -> bool {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let elapsed = Self::elapsed(&state);
+            if elapsed.is_some_and(|elapsed| elapsed >= at) {
+                return true;
+            }
+            if state.stopped {
+                return false;
+            }
+            let timeout = match (elapsed, state.paused_at) {
+                (Some(elapsed), None) => at - elapsed,
+                // Not playing, wait for `start` or `resume`.
+                _ => Duration::from_secs(1),
+            };
+            state = self.changed.wait_timeout(state, timeout).unwrap().0;
+        }
+    }
+}
+
+// Emits the events of an utterance when playback reaches them. Events are sent in order
+// along with the playback time they belong to.
+pub(crate) struct Pacer {
+    clock: Arc<PlaybackClock>,
+    sender: Option<mpsc::Sender<(Duration, TTSMessageEvent)>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Pacer {
+    pub(crate) fn new(clock: Arc<PlaybackClock>, listeners: Listeners, utterance_id: &str) -> Self {
+        let (sender, events) = mpsc::channel::<(Duration, TTSMessageEvent)>();
+        let thread = {
+            let clock = Arc::clone(&clock);
+            let utterance_id = utterance_id.to_string();
+            std::thread::spawn(move || {
+                for (at, event) in events {
+                    if !clock.wait_until(at) {
+                        return;
+                    }
+                    listeners.emit_tts_event(&utterance_id, event);
+                }
+            })
+        };
+        Self {
+            clock,
+            sender: Some(sender),
+            thread: Some(thread),
+        }
+    }
+
+    pub(crate) fn send(&self, at: Duration, event: TTSMessageEvent) {
+        if let Some(sender) = &self.sender {
+            let _ = sender.send((at, event));
+        }
+    }
+
+    // Waits for the events that are due by the end of playback. Those after it, which
+    // were estimated too late, are dropped.
+    pub(crate) fn finish(mut self) {
+        self.sender.take();
+        self.clock.stop();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::desktop::TTS_EVENTS;
+    use serde_json::Value;
+    use tauri::ipc::{Channel, InvokeResponseBody};
+
+    // The text, UTF-16 offset and UTF-16 length of every word.
+    fn spans(text: &str, words: &[Word]) -> Vec<(String, u32, u32)> {
+        words
+            .iter()
+            .map(|word| {
+                (
+                    text[word.start..word.end].to_string(),
+                    word.offset,
+                    word.length,
+                )
+            })
+            .collect()
+    }
+
+    fn span(text: &str, offset: u32, length: u32) -> (String, u32, u32) {
+        (text.to_string(), offset, length)
+    }
+
+    #[test]
+    fn leaves_out_punctuation_around_words() {
+        let text = "'Hello,' she said — it's well-known, the dogs' rock-";
+        assert_eq!(
+            spans(text, &words(text)),
+            [
+                span("Hello", 1, 5),
+                span("she", 9, 3),
+                span("said", 13, 4),
+                span("it's", 20, 4),
+                span("well-known", 25, 10),
+                span("the", 37, 3),
+                span("dogs", 41, 4),
+                span("rock", 47, 4),
+            ]
+        );
+    }
+
+    #[test]
+    fn counts_surrogate_pairs_as_two_code_units() {
+        let text = "Hi 😀 there 𝒳yz";
+        assert_eq!(
+            spans(text, &words(text)),
+            [span("Hi", 0, 2), span("there", 6, 5), span("𝒳yz", 12, 4)]
+        );
+        // Han beyond the Basic Multilingual Plane is a word of its own.
+        let text = "𠀀𠀁";
+        assert_eq!(
+            spans(text, &words(text)),
+            [span("𠀀", 0, 2), span("𠀁", 2, 2)]
+        );
+    }
+
+    #[test]
+    fn splits_cjk_runs_into_characters() {
+        let text = "我爱Rust语言。ひらがな";
+        let spans = spans(text, &words(text));
+        let texts = spans.iter().map(|span| span.0.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            texts,
+            ["我", "爱", "Rust", "语", "言", "ひ", "ら", "が", "な"]
+        );
+        assert_eq!(spans[2], span("Rust", 2, 4));
+        assert_eq!(spans[5], span("ひ", 9, 1));
+    }
+
+    #[test]
+    fn shifts_segment_words_by_the_segment_offset() {
+        let mut segment = SpeechSegment::new("Hello world".into());
+        segment.offset = 10;
+        let words = segment_words(&segment);
+        assert_eq!(
+            spans(&segment.text, &words),
+            [span("Hello", 10, 5), span("world", 16, 5)]
+        );
+
+        // Text spoken with an alias is a single word.
+        let mut segment = SpeechSegment::new("Dr. Smith".into());
+        segment.offset = 4;
+        segment.alias = Some("Doctor Smith".into());
+        let words = segment_words(&segment);
+        assert_eq!(spans(&segment.text, &words), [span("Dr. Smith", 4, 9)]);
+    }
+
+    #[test]
+    fn paces_boundaries_in_order() {
+        let clock = Arc::new(PlaybackClock::default());
+        let (sender, events) = mpsc::channel();
+        let listeners = Listeners::default();
+        let channel = {
+            let clock = Arc::clone(&clock);
+            Channel::new(move |body| {
+                if let InvokeResponseBody::Json(payload) = body {
+                    let event = serde_json::from_str::<Value>(&payload).unwrap();
+                    let _ = sender.send((clock.position(), event));
+                }
+                Ok(())
+            })
+        };
+        listeners.register(TTS_EVENTS.to_string(), channel);
+
+        let pacer = Pacer::new(Arc::clone(&clock), listeners, "utterance");
+        let text = "one two three four";
+        for (i, word) in words(text).iter().enumerate() {
+            let at = Duration::from_millis(100 * i as u64);
+            pacer.send(at, word.event(at));
+        }
+        let next = || events.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // Nothing is emitted before playback starts, nor while it is paused.
+        assert!(events.recv_timeout(Duration::from_millis(50)).is_err());
+        clock.start();
+        let (_, first) = next();
+        assert_eq!(first["charIndex"], 0);
+        clock.pause();
+        assert!(events.recv_timeout(Duration::from_millis(50)).is_err());
+        clock.resume();
+
+        let mut last = first["timestamp"].as_f64().unwrap();
+        for index in [4, 8, 14] {
+            let (position, event) = next();
+            assert_eq!(event["charIndex"], index);
+            let timestamp = event["timestamp"].as_f64().unwrap();
+            assert!(timestamp >= last);
+            assert!(position.as_secs_f64() * 1000.0 >= timestamp);
+            last = timestamp;
+        }
+
+        // Events after the end of playback are dropped.
+        pacer.send(Duration::from_secs(60), mark_event("late"));
+        pacer.finish();
+        assert!(events.try_recv().is_err());
+    }
+}
@@ -0,0 +1,455 @@
+//! A disk cache of synthesized utterances.
+//!
+//! Piper voices take a noticeable time to synthesize a sentence, which is heard as a pause
+//! between sentences when it happens just in time. Utterances spoken with `preload` are
+//! rendered in the background and stored here, keyed by voice, rate and text, so
+//! that speaking them later starts at once. The least recently used entries are evicted
+//! when the cache grows over its size limit.
+//!
+//! Every entry is a `<key>.pcm` file of 16-bit samples and a `<key>.json` file with the
+//! voice, the sample rate and the timed boundary events of the utterance. The size limit set
+//! by the user is kept in a `limit` file next to them.
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::audiobook::Audio;
+use crate::models::*;
+use crate::Result;
+
+pub(crate) const DEFAULT_LIMIT: u64 = 256 * 1024 * 1024;
+
+const LIMIT_FILE: &str = "limit";
+
+// How long to wait for the background render of an utterance that is about to be spoken.
+const RENDER_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TimedEvent {
+    at: f64, // Milliseconds from the start of the audio
+    event: TTSMessageEvent,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EntryInfo {
+    voice: String,
+    sample_rate: u32,
+    events: Vec<TimedEvent>,
+}
+
+// A synthesized utterance.
+pub(crate) struct Entry {
+    pub audio: Audio,
+    pub events: Vec<(Duration, TTSMessageEvent)>,
+}
+
+struct IndexEntry {
+    voice: String,
+    size: u64,      // Of both files, in bytes
+    last_used: u64, // Value of `State::uses` when last used
+}
+
+struct State {
+    entries: HashMap<u64, IndexEntry>,
+    size: u64,
+    limit: u64,
+    uses: u64,
+    rendering: HashSet<u64>, // Keys being rendered in the background
+}
+
+pub(crate) struct SynthesisCache {
+    dir: PathBuf,
+    state: Mutex<State>,
+    rendered: Condvar,
+}
+
+// Hashes what the audio of an utterance depends on, with 64-bit FNV-1a so that keys stay
+// the same across Rust versions. Piper voices have no pitch, so it is not part of the key.
+pub(crate) fn key(voice: &str, rate: f32, segments: &[SpeechSegment]) -> u64 {
+    let segments = serde_json::to_vec(segments).unwrap_or_default();
+    let parts: [&[u8]; 3] = [voice.as_bytes(), &rate.to_le_bytes(), &segments];
+    let mut hash = 0xcbf29ce484222325u64;
+    for part in parts {
+        for byte in (part.len() as u64).to_le_bytes().iter().chain(part) {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+fn file_size(path: &Path) -> u64 {
+    std::fs::metadata(path).map_or(0, |metadata| metadata.len())
+}
+
+impl SynthesisCache {
+    // Opens the cache in `dir`, indexing the entries left by previous sessions in the order
+    // they were last used.
+    pub(crate) fn open(dir: PathBuf) -> Self {
+        let mut found = Vec::new();
+        for entry in std::fs::read_dir(&dir).into_iter().flatten().flatten() {
+            let path = entry.path();
+            let Some((key, extension)) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.split_once('.'))
+                .and_then(|(key, extension)| Some((u64::from_str_radix(key, 16).ok()?, extension)))
+            else {
+                continue;
+            };
+            if extension != "json" {
+                // Audio is written before its JSON file, which a session may not have reached.
+                if extension == "pcm" && !path.with_extension("json").exists() {
+                    let _ = std::fs::remove_file(&path);
+                }
+                continue;
+            }
+            let info = std::fs::read(&path)
+                .ok()
+                .and_then(|data| serde_json::from_slice::<EntryInfo>(&data).ok());
+            let modified = entry
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            let size = file_size(&path) + file_size(&path.with_extension("pcm"));
+            match info {
+                Some(info) => found.push((modified, key, info.voice, size)),
+                None => {
+                    let _ = std::fs::remove_file(&path);
+                    let _ = std::fs::remove_file(path.with_extension("pcm"));
+                }
+            }
+        }
+        found.sort_by_key(|(modified, ..)| *modified);
+
+        let limit = std::fs::read_to_string(dir.join(LIMIT_FILE))
+            .ok()
+            .and_then(|limit| limit.trim().parse().ok())
+            .unwrap_or(DEFAULT_LIMIT);
+        let mut state = State {
+            entries: HashMap::new(),
+            size: 0,
+            limit,
+            uses: 0,
+            rendering: HashSet::new(),
+        };
+        for (_, key, voice, size) in found {
+            state.uses += 1;
+            state.size += size;
+            let last_used = state.uses;
+            state.entries.insert(
+                key,
+                IndexEntry {
+                    voice,
+                    size,
+                    last_used,
+                },
+            );
+        }
+        Self {
+            dir,
+            state: Mutex::new(state),
+            rendered: Condvar::new(),
+        }
+    }
+
+    fn path(&self, key: u64, extension: &str) -> PathBuf {
+        self.dir.join(format!("{key:016x}.{extension}"))
+    }
+
+    fn remove_files(&self, key: u64) {
+        for extension in ["json", "pcm"] {
+            let _ = std::fs::remove_file(self.path(key, extension));
+        }
+    }
+
+    // Removes the least recently used entries until the cache fits its limit.
+    fn evict(&self, state: &mut State) {
+        while state.size > state.limit {
+            let Some(key) = state
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| *key)
+            else {
+                break;
+            };
+            if let Some(entry) = state.entries.remove(&key) {
+                state.size -= entry.size;
+            }
+            self.remove_files(key);
+        }
+    }
+
+    fn read(&self, key: u64) -> Option<Entry> {
+        let info = std::fs::read(self.path(key, "json")).ok()?;
+        let info = serde_json::from_slice::<EntryInfo>(&info).ok()?;
+        let pcm = std::fs::read(self.path(key, "pcm")).ok()?;
+        let samples = pcm
+            .chunks_exact(2)
+            .map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+            .collect();
+        let events = info
+            .events
+            .into_iter()
+            .map(|timed| (Duration::from_secs_f64(timed.at / 1000.0), timed.event))
+            .collect();
+        Some(Entry {
+            audio: Audio {
+                samples,
+                sample_rate: info.sample_rate,
+            },
+            events,
+        })
+    }
+
+    // Returns the entry for `key`, waiting for it if it is being rendered.
+    pub(crate) fn get(&self, key: u64) -> Option<Entry> {
+        {
+            let state = self.state.lock().unwrap();
+            let (mut state, _) = self
+                .rendered
+                .wait_timeout_while(state, RENDER_TIMEOUT, |state| {
+                    state.rendering.contains(&key)
+                })
+                .unwrap();
+            state.uses += 1;
+            let uses = state.uses;
+            state.entries.get_mut(&key)?.last_used = uses;
+        }
+        match self.read(key) {
+            Some(entry) => {
+                // Keep the order of use for the next session.
+                let json = std::fs::File::options()
+                    .append(true)
+                    .open(self.path(key, "json"));
+                if let Ok(file) = json {
+                    let _ = file.set_modified(SystemTime::now());
+                }
+                Some(entry)
+            }
+            None => {
+                let mut state = self.state.lock().unwrap();
+                if let Some(entry) = state.entries.remove(&key) {
+                    state.size -= entry.size;
+                }
+                self.remove_files(key);
+                None
+            }
+        }
+    }
+
+    // Claims `key` for a background render, unless it is cached or already claimed.
+    pub(crate) fn begin(&self, key: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        !state.entries.contains_key(&key) && state.rendering.insert(key)
+    }
+
+    // Releases a key claimed with `begin`, storing what was rendered for it.
+    pub(crate) fn finish(&self, key: u64, voice: &str, entry: Option<&Entry>) {
+        if let Some(entry) = entry {
+            if let Err(e) = self.insert(key, voice, entry) {
+                log::warn!("Failed to cache synthesized speech: {e}");
+            }
+        }
+        self.state.lock().unwrap().rendering.remove(&key);
+        self.rendered.notify_all();
+    }
+
+    pub(crate) fn insert(&self, key: u64, voice: &str, entry: &Entry) -> Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        let info = EntryInfo {
+            voice: voice.to_string(),
+            sample_rate: entry.audio.sample_rate,
+            events: entry
+                .events
+                .iter()
+                .map(|(at, event)| TimedEvent {
+                    at: at.as_secs_f64() * 1000.0,
+                    event: event.clone(),
+                })
+                .collect(),
+        };
+        let pcm = entry
+            .audio
+            .samples
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect::<Vec<_>>();
+        std::fs::write(self.path(key, "pcm"), &pcm)?;
+        // The JSON file is written last, an entry without it is not indexed.
+        let mut json = std::fs::File::create(self.path(key, "json"))?;
+        json.write_all(&serde_json::to_vec(&info).map_err(std::io::Error::from)?)?;
+
+        let size = file_size(&self.path(key, "json")) + pcm.len() as u64;
+        let mut state = self.state.lock().unwrap();
+        state.uses += 1;
+        let last_used = state.uses;
+        let previous = state.entries.insert(
+            key,
+            IndexEntry {
+                voice: voice.to_string(),
+                size,
+                last_used,
+            },
+        );
+        state.size += size;
+        if let Some(previous) = previous {
+            state.size -= previous.size;
+        }
+        self.evict(&mut state);
+        Ok(())
+    }
+
+    pub(crate) fn info(&self) -> SynthesisCacheInfo {
+        let state = self.state.lock().unwrap();
+        SynthesisCacheInfo {
+            size: state.size,
+            limit: state.limit,
+            entries: state.entries.len() as u32,
+        }
+    }
+
+    pub(crate) fn set_limit(&self, limit: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.limit = limit;
+        self.evict(&mut state);
+        std::fs::create_dir_all(&self.dir)?;
+        std::fs::write(self.dir.join(LIMIT_FILE), limit.to_string())?;
+        Ok(())
+    }
+
+    // Removes the entries of `voice`, or all of them.
+    pub(crate) fn clear(&self, voice: Option<&str>) {
+        let mut state = self.state.lock().unwrap();
+        let keys = state
+            .entries
+            .iter()
+            .filter(|(_, entry)| match voice {
+                Some(voice) => entry.voice == voice,
+                None => true,
+            })
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+        for key in keys {
+            if let Some(entry) = state.entries.remove(&key) {
+                state.size -= entry.size;
+            }
+            self.remove_files(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A directory of its own for every test, removed when dropped.
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir()
+                .join(format!("readest-tts-cache-{name}-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            Self(dir)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn entry() -> Entry {
+        Entry {
+            audio: Audio {
+                samples: vec![0; 1000],
+                sample_rate: 22050,
+            },
+            events: Vec::new(),
+        }
+    }
+
+    fn keys(cache: &SynthesisCache) -> Vec<u64> {
+        let state = cache.state.lock().unwrap();
+        let mut keys = state.entries.keys().copied().collect::<Vec<_>>();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn evicts_least_recently_used_entries() {
+        let dir = TestDir::new("evict");
+        let cache = SynthesisCache::open(dir.0.clone());
+        cache.insert(1, "voice", &entry()).unwrap();
+        cache.insert(2, "voice", &entry()).unwrap();
+        cache.insert(3, "voice", &entry()).unwrap();
+        let size = cache.info().size / 3;
+        assert!(cache.get(1).is_some());
+        // Room for two entries leaves the two used last.
+        cache.set_limit(size * 2).unwrap();
+        assert_eq!(keys(&cache), [1, 3]);
+        assert!(!cache.path(2, "pcm").exists() && !cache.path(2, "json").exists());
+        cache.insert(4, "voice", &entry()).unwrap();
+        assert_eq!(keys(&cache), [1, 4]);
+        assert_eq!(cache.info().size, size * 2);
+    }
+
+    #[test]
+    fn reopens_entries_in_order_of_use() {
+        let dir = TestDir::new("reopen");
+        let cache = SynthesisCache::open(dir.0.clone());
+        cache.insert(1, "voice", &entry()).unwrap();
+        cache.insert(2, "voice", &entry()).unwrap();
+        let size = cache.info().size / 2;
+        cache.set_limit(size * 2).unwrap();
+        // Files written in the same instant would have no order to find again.
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(cache.get(1).is_some());
+        drop(cache);
+
+        let cache = SynthesisCache::open(dir.0.clone());
+        assert_eq!(cache.info().limit, size * 2);
+        assert_eq!(cache.info().entries, 2);
+        cache.insert(3, "voice", &entry()).unwrap();
+        assert_eq!(keys(&cache), [1, 3]);
+    }
+
+    #[test]
+    fn removes_audio_without_info() {
+        let dir = TestDir::new("orphan");
+        let cache = SynthesisCache::open(dir.0.clone());
+        cache.insert(1, "voice", &entry()).unwrap();
+        let orphan = cache.path(2, "pcm");
+        std::fs::write(&orphan, [0u8; 64]).unwrap();
+        drop(cache);
+
+        let cache = SynthesisCache::open(dir.0.clone());
+        assert!(!orphan.exists());
+        assert!(cache.path(1, "pcm").exists());
+        assert_eq!(keys(&cache), [1]);
+        assert_eq!(cache.info().limit, DEFAULT_LIMIT);
+    }
+
+    #[test]
+    fn clears_the_entries_of_a_voice() {
+        let dir = TestDir::new("clear");
+        let cache = SynthesisCache::open(dir.0.clone());
+        cache.insert(1, "a", &entry()).unwrap();
+        cache.insert(2, "b", &entry()).unwrap();
+        cache.clear(Some("a"));
+        assert_eq!(keys(&cache), [2]);
+        cache.clear(None);
+        assert_eq!(cache.info().entries, 0);
+        assert_eq!(cache.info().size, 0);
+    }
+}
@@ -1,3 +1,5 @@
+#[cfg(desktop)]
+use tauri::ipc::Channel;
 use tauri::{command, AppHandle, Runtime};
 
 use crate::models::*;
@@ -75,3 +77,146 @@ pub(crate) async fn update_media_session_metadata<R: Runtime>(
 ) -> Result<()> {
     app.native_tts().update_media_session_metadata(payload)
 }
+
+#[command]
+pub(crate) async fn start_sleep_timer<R: Runtime>(
+    app: AppHandle<R>,
+    payload: StartSleepTimerRequest,
+) -> Result<()> {
+    app.native_tts().start_sleep_timer(payload)
+}
+
+#[command]
+pub(crate) async fn cancel_sleep_timer<R: Runtime>(app: AppHandle<R>) -> Result<()> {
+    app.native_tts().cancel_sleep_timer()
+}
+
+#[command]
+pub(crate) async fn get_sleep_timer<R: Runtime>(app: AppHandle<R>) -> Result<SleepTimerState> {
+    app.native_tts().get_sleep_timer()
+}
+
+#[command]
+pub(crate) async fn get_voice_map<R: Runtime>(app: AppHandle<R>) -> Result<VoiceMap> {
+    app.native_tts().get_voice_map()
+}
+
+#[command]
+pub(crate) async fn set_voice_map<R: Runtime>(app: AppHandle<R>, payload: VoiceMap) -> Result<()> {
+    app.native_tts().set_voice_map(payload)
+}
+
+#[command]
+pub(crate) async fn set_language_voice<R: Runtime>(
+    app: AppHandle<R>,
+    payload: SetLanguageVoiceRequest,
+) -> Result<()> {
+    app.native_tts().set_language_voice(payload)
+}
+
+#[command]
+pub(crate) async fn get_lexicon<R: Runtime>(
+    app: AppHandle<R>,
+    payload: GetLexiconRequest,
+) -> Result<Lexicon> {
+    app.native_tts().get_lexicon(payload)
+}
+
+#[command]
+pub(crate) async fn set_lexicon<R: Runtime>(
+    app: AppHandle<R>,
+    payload: SetLexiconRequest,
+) -> Result<()> {
+    app.native_tts().set_lexicon(payload)
+}
+
+#[command]
+pub(crate) async fn import_lexicon<R: Runtime>(
+    app: AppHandle<R>,
+    payload: ImportLexiconRequest,
+) -> Result<Lexicon> {
+    app.native_tts().import_lexicon(payload)
+}
+
+#[cfg(desktop)]
+#[command]
+pub(crate) async fn install_voice_pack<R: Runtime>(
+    app: AppHandle<R>,
+    payload: InstallVoicePackRequest,
+) -> Result<InstallVoicePackResponse> {
+    app.native_tts().install_voice_pack(payload)
+}
+
+#[cfg(desktop)]
+#[command]
+pub(crate) async fn remove_voice_pack<R: Runtime>(
+    app: AppHandle<R>,
+    payload: RemoveVoicePackRequest,
+) -> Result<()> {
+    app.native_tts().remove_voice_pack(payload)
+}
+
+#[cfg(desktop)]
+#[command]
+pub(crate) async fn export_audiobook<R: Runtime>(
+    app: AppHandle<R>,
+    payload: ExportAudiobookRequest,
+    on_progress: Channel<AudiobookProgress>,
+) -> Result<ExportAudiobookResponse> {
+    app.native_tts().export_audiobook(payload, on_progress)
+}
+
+#[cfg(desktop)]
+#[command]
+pub(crate) async fn cancel_audiobook_export<R: Runtime>(app: AppHandle<R>) -> Result<()> {
+    app.native_tts().cancel_audiobook_export()
+}
+
+#[cfg(desktop)]
+#[command]
+pub(crate) async fn get_synthesis_cache_info<R: Runtime>(
+    app: AppHandle<R>,
+) -> Result<SynthesisCacheInfo> {
+    app.native_tts().get_synthesis_cache_info()
+}
+
+#[cfg(desktop)]
+#[command]
+pub(crate) async fn set_synthesis_cache_limit<R: Runtime>(
+    app: AppHandle<R>,
+    payload: SetSynthesisCacheLimitRequest,
+) -> Result<()> {
+    app.native_tts().set_synthesis_cache_limit(payload)
+}
+
+#[cfg(desktop)]
+#[command]
+pub(crate) async fn clear_synthesis_cache<R: Runtime>(
+    app: AppHandle<R>,
+    payload: ClearSynthesisCacheRequest,
+) -> Result<()> {
+    app.native_tts().clear_synthesis_cache(payload)
+}
+
+// The mobile plugin runtimes handle listener registration natively.
+#[cfg(desktop)]
+#[command]
+pub(crate) async fn register_listener<R: Runtime>(
+    app: AppHandle<R>,
+    event: String,
+    handler: Channel<serde_json::Value>,
+) -> Result<()> {
+    app.native_tts().register_listener(event, handler);
+    Ok(())
+}
+
+#[cfg(desktop)]
+#[command]
+pub(crate) async fn remove_listener<R: Runtime>(
+    app: AppHandle<R>,
+    event: String,
+    channel_id: u32,
+) -> Result<()> {
+    app.native_tts().remove_listener(&event, channel_id);
+    Ok(())
+}
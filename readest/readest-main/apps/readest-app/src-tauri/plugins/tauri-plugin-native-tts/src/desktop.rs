@@ -1,62 +1,797 @@
-use serde::de::DeserializeOwned;
-use tauri::{plugin::PluginApi, AppHandle, Runtime};
+use serde::{de::DeserializeOwned, Serialize};
+use tauri::{ipc::Channel, plugin::PluginApi, AppHandle, Manager, Runtime};
 
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::lexicon::Lexicons;
 use crate::models::*;
+use crate::sleep_timer::{Action, SleepTimer};
+use crate::voice_map::VoiceRouter;
+use crate::NativeTtsExt;
+
+pub(crate) const TTS_EVENTS: &str = "tts_events";
 
 pub fn init<R: Runtime, C: DeserializeOwned>(
     app: &AppHandle<R>,
     _api: PluginApi<R, C>,
 ) -> crate::Result<NativeTts<R>> {
-    Ok(NativeTts(app.clone()))
+    Ok(NativeTts {
+        app: app.clone(),
+        listeners: Listeners::default(),
+        engine: Mutex::new(None),
+        #[cfg(target_os = "linux")]
+        piper: Mutex::new(None),
+        #[cfg(target_os = "linux")]
+        media_session: Mutex::new(None),
+        settings: Mutex::new(VoiceSettings::default()),
+        sleep_timer: SleepTimer::default(),
+        router: VoiceRouter::load(
+            app.path()
+                .app_config_dir()
+                .ok()
+                .map(|dir| dir.join("tts-voice-map.json")),
+        ),
+        voices: Mutex::new(None),
+        lexicons: Lexicons::new(
+            app.path()
+                .app_config_dir()
+                .ok()
+                .map(|dir| dir.join("tts-lexicons")),
+        ),
+        next_utterance_id: AtomicU64::new(1),
+        export_cancelled: AtomicBool::new(false),
+    })
+}
+
+// The voice settings applied to every utterance.
+#[derive(Debug, Clone)]
+pub(crate) struct VoiceSettings {
+    pub rate: f32,   // 1.0 is the normal rate
+    pub pitch: f32,  // 1.0 is the normal pitch
+    pub volume: f32, // From 0 to 1
+    pub voice: Option<String>,
+}
+
+impl Default for VoiceSettings {
+    fn default() -> Self {
+        Self {
+            rate: 1.0,
+            pitch: 1.0,
+            volume: 1.0,
+            voice: None,
+        }
+    }
+}
+
+// A speech synthesizer that reports the progress of its utterances through `Listeners`.
+pub(crate) trait Engine: Send + Sync {
+    fn voices(&self) -> crate::Result<Vec<TTSVoice>>;
+    // Speaks `segments` in order, instead of what is being spoken. Segment marks are
+    // reported when their segment starts.
+    fn speak(
+        &self,
+        utterance_id: &str,
+        segments: &[SpeechSegment],
+        settings: &VoiceSettings,
+    ) -> crate::Result<()>;
+    // Prepares `segments` to be spoken soon without speaking them, and returns whether the
+    // engine does so. Engines that do report the end of the utterance once it is prepared.
+    fn preload(
+        &self,
+        _utterance_id: &str,
+        _segments: &[SpeechSegment],
+        _settings: &VoiceSettings,
+    ) -> crate::Result<bool> {
+        Ok(false)
+    }
+    // Changes the volume of what is being spoken. By default the volume changes from the
+    // next utterance on, whose `VoiceSettings` carry it.
+    fn set_volume(&self, _volume: f32) -> crate::Result<()> {
+        Ok(())
+    }
+    fn pause(&self) -> crate::Result<()>;
+    fn resume(&self) -> crate::Result<()>;
+    fn stop(&self) -> crate::Result<()>;
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TTSEventPayload<'a> {
+    utterance_id: &'a str,
+    #[serde(flatten)]
+    event: TTSMessageEvent,
+}
+
+// The channels registered through `addPluginListener`, by event name. The mobile backends
+// get this from the native plugin runtime, on desktop the plugin keeps them itself.
+#[derive(Clone, Default)]
+pub(crate) struct Listeners {
+    channels: Arc<Mutex<HashMap<String, Vec<Channel<serde_json::Value>>>>>,
+    sequence: Arc<Mutex<Option<Sequence>>>,
+}
+
+// Segments of an utterance that are spoken with the same voice.
+struct Run {
+    engine: Arc<dyn Engine>,
+    segments: Vec<SpeechSegment>,
+    settings: VoiceSettings,
+}
+
+// An utterance spoken with more than one voice, one run after another. The runs are spoken
+// as utterances of their own, whose events are reported as those of the utterance.
+struct Sequence {
+    utterance_id: String,
+    runs: VecDeque<Run>, // Those still to speak
+    current: String,     // The ID the current run is spoken with
+    index: usize,        // Of the current run
+    paused: bool,
+    pending: bool, // Whether the current run has ended while paused
+}
+
+impl Listeners {
+    pub(crate) fn register(&self, event: String, handler: Channel<serde_json::Value>) {
+        let mut listeners = self.channels.lock().unwrap();
+        listeners.entry(event).or_default().push(handler);
+    }
+
+    fn remove(&self, event: &str, channel_id: u32) {
+        let mut listeners = self.channels.lock().unwrap();
+        if let Some(channels) = listeners.get_mut(event) {
+            channels.retain(|channel| channel.id() != channel_id);
+        }
+    }
+
+    pub(crate) fn emit<T: Serialize>(&self, event: &str, payload: T) {
+        let Ok(payload) = serde_json::to_value(payload) else {
+            return;
+        };
+        let listeners = self.channels.lock().unwrap();
+        for channel in listeners.get(event).into_iter().flatten() {
+            if let Err(e) = channel.send(payload.clone()) {
+                log::warn!("Failed to send {event} event: {e}");
+            }
+        }
+    }
+
+    pub(crate) fn emit_tts_event(&self, utterance_id: &str, event: TTSMessageEvent) {
+        let mut sequence = self.sequence.lock().unwrap();
+        let Some(current) = sequence
+            .as_mut()
+            .filter(|sequence| sequence.current == utterance_id)
+        else {
+            drop(sequence);
+            self.emit(
+                TTS_EVENTS,
+                TTSEventPayload {
+                    utterance_id,
+                    event,
+                },
+            );
+            return;
+        };
+        let utterance_id = current.utterance_id.clone();
+        match event.code.as_str() {
+            "end" if !current.runs.is_empty() => {
+                match current.paused {
+                    true => current.pending = true,
+                    false => self.speak_next_run(current),
+                }
+                return;
+            }
+            "end" | "error" => *sequence = None,
+            // The utterance started with its first run.
+            "boundary" if current.index > 0 && event.message.as_deref() == Some("start") => {
+                return;
+            }
+            _ => {}
+        }
+        drop(sequence);
+        self.emit(
+            TTS_EVENTS,
+            TTSEventPayload {
+                utterance_id: &utterance_id,
+                event,
+            },
+        );
+    }
+
+    // Speaks the runs of an utterance one after another.
+    fn start_sequence(&self, utterance_id: &str, runs: Vec<Run>) -> crate::Result<()> {
+        let mut runs = VecDeque::from(runs);
+        let Some(first) = runs.pop_front() else {
+            return Ok(());
+        };
+        let current = format!("{utterance_id}#0");
+        *self.sequence.lock().unwrap() = Some(Sequence {
+            utterance_id: utterance_id.to_string(),
+            runs,
+            current: current.clone(),
+            index: 0,
+            paused: false,
+            pending: false,
+        });
+        first
+            .engine
+            .speak(&current, &first.segments, &first.settings)
+    }
+
+    // Starts the next run on a thread of its own, since the engine reporting the end of
+    // the current one may be needed to speak it.
+    fn speak_next_run(&self, sequence: &mut Sequence) {
+        let Some(run) = sequence.runs.pop_front() else {
+            return;
+        };
+        sequence.index += 1;
+        sequence.current = format!("{}#{}", sequence.utterance_id, sequence.index);
+        sequence.pending = false;
+        let id = sequence.current.clone();
+        let listeners = self.clone();
+        std::thread::spawn(move || {
+            let result = run.engine.speak(&id, &run.segments, &run.settings);
+            if let Err(e) = result {
+                listeners.emit_tts_event(
+                    &id,
+                    TTSMessageEvent {
+                        code: "error".into(),
+                        message: Some(e.to_string()),
+                        ..Default::default()
+                    },
+                );
+            } else if !listeners.is_current_run(&id) {
+                // The utterance was stopped while the run was being started.
+                let _ = run.engine.stop();
+            }
+        });
+    }
+
+    fn is_current_run(&self, id: &str) -> bool {
+        let sequence = self.sequence.lock().unwrap();
+        sequence
+            .as_ref()
+            .is_some_and(|sequence| sequence.current == id)
+    }
+
+    fn stop_sequence(&self) {
+        *self.sequence.lock().unwrap() = None;
+    }
+
+    fn set_sequence_paused(&self, paused: bool) {
+        let mut sequence = self.sequence.lock().unwrap();
+        if let Some(sequence) = sequence.as_mut() {
+            sequence.paused = paused;
+            if !paused && sequence.pending {
+                self.speak_next_run(sequence);
+            }
+        }
+    }
 }
 
 /// Access to the native-tts APIs.
-pub struct NativeTts<R: Runtime>(AppHandle<R>);
+pub struct NativeTts<R: Runtime> {
+    app: AppHandle<R>,
+    listeners: Listeners,
+    engine: Mutex<Option<Arc<dyn Engine>>>, // The system speech service
+    #[cfg(target_os = "linux")]
+    piper: Mutex<Option<Arc<crate::piper::Piper>>>,
+    #[cfg(target_os = "linux")]
+    media_session: Mutex<Option<crate::mpris::MediaSession>>,
+    settings: Mutex<VoiceSettings>,
+    sleep_timer: SleepTimer,
+    router: VoiceRouter,
+    voices: Mutex<Option<Vec<TTSVoice>>>, // Of all engines, listed when first needed
+    lexicons: Lexicons,
+    next_utterance_id: AtomicU64,
+    export_cancelled: AtomicBool, // Set to stop the audiobook export in progress
+}
 
 impl<R: Runtime> NativeTts<R> {
+    // All initialized engines, the system one first.
+    fn engines(&self) -> Vec<Arc<dyn Engine>> {
+        let mut engines = self
+            .engine
+            .lock()
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        #[cfg(target_os = "linux")]
+        if let Some(piper) = self.piper.lock().unwrap().clone() {
+            engines.push(piper);
+        }
+        engines
+    }
+
+    // The engine that speaks with `voice`.
+    fn engine(&self, voice: Option<&str>) -> crate::Result<Arc<dyn Engine>> {
+        #[cfg(target_os = "linux")]
+        if voice.is_some_and(|voice| voice.starts_with(crate::piper::VOICE_PREFIX)) {
+            return self.piper().map(|piper| piper as Arc<dyn Engine>);
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = voice;
+        self.engine
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| crate::Error::NativeTTSError("TTS is not initialized".into()))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn piper(&self) -> crate::Result<Arc<crate::piper::Piper>> {
+        let mut piper = self.piper.lock().unwrap();
+        if let Some(piper) = piper.as_ref() {
+            return Ok(Arc::clone(piper));
+        }
+        let dir = self
+            .app
+            .path()
+            .app_data_dir()
+            .map_err(|e| crate::Error::NativeTTSError(e.to_string()))?
+            .join("piper-voices");
+        let cache_dir = self
+            .app
+            .path()
+            .app_cache_dir()
+            .map_err(|e| crate::Error::NativeTTSError(e.to_string()))?
+            .join("tts-cache");
+        let listeners = self.listeners.clone();
+        let engine = Arc::new(crate::piper::Piper::new(dir, cache_dir, listeners));
+        *piper = Some(Arc::clone(&engine));
+        Ok(engine)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn create_engine(&self) -> Option<Arc<dyn Engine>> {
+        match crate::speechd::SpeechDispatcher::connect(self.listeners.clone()) {
+            Ok(engine) => return Some(Arc::new(engine)),
+            Err(e) => log::warn!("Speech Dispatcher is unavailable, trying espeak-ng: {e}"),
+        }
+        match crate::espeak::Espeak::new(self.listeners.clone()) {
+            Ok(engine) => Some(Arc::new(engine)),
+            Err(e) => {
+                log::warn!("espeak-ng is unavailable: {e}");
+                None
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn create_engine(&self) -> Option<Arc<dyn Engine>> {
+        None
+    }
+
     pub fn init(&self) -> crate::Result<InitResponse> {
-        Err(crate::Error::UnsupportedPlatformError)
+        if !cfg!(target_os = "linux") {
+            return Err(crate::Error::UnsupportedPlatformError);
+        }
+        {
+            let mut engine = self.engine.lock().unwrap();
+            if engine.is_none() {
+                *engine = self.create_engine();
+            }
+        }
+        // Piper voices only need the voices dir, so that they work without a speech service.
+        #[cfg(target_os = "linux")]
+        self.piper()?;
+        Ok(InitResponse {
+            success: !self.engines().is_empty(),
+        })
     }
-    pub fn speak(&self, _args: SpeakArgs) -> crate::Result<SpeakResponse> {
-        Err(crate::Error::UnsupportedPlatformError)
+    pub fn speak(&self, args: SpeakArgs) -> crate::Result<SpeakResponse> {
+        if args.text.is_empty() {
+            return Err(crate::Error::NativeTTSError("Text cannot be empty".into()));
+        }
+        if !self.sleep_timer.should_speak(&args) {
+            return Err(crate::Error::SleepTimerEnded);
+        }
+        let mut settings = self.settings.lock().unwrap().clone();
+        if let Some(voice) = &args.voice {
+            settings.voice = Some(voice.clone()).filter(|voice| !voice.is_empty());
+        }
+        let segments = self.lexicons.apply(
+            crate::ssml::segments(&args)?,
+            args.book.as_deref(),
+            args.lang.as_deref(),
+        );
+        let segments = self.router.route(
+            segments,
+            args.lang.as_deref(),
+            settings.voice.as_deref(),
+            || self.available_voices(),
+        );
+        let runs = self.runs(segments, &settings)?;
+        let id = self.next_utterance_id.fetch_add(1, Ordering::Relaxed);
+        let utterance_id = format!("utterance-{id}");
+        if args.preload {
+            let mut preloading = false;
+            for (i, run) in runs.iter().enumerate() {
+                let run_id = match runs.len() {
+                    1 => utterance_id.clone(),
+                    _ => format!("{utterance_id}#{i}"),
+                };
+                preloading |= run.engine.preload(&run_id, &run.segments, &run.settings)?;
+            }
+            // Engines report the end of a preload of a single run themselves.
+            if !preloading || runs.len() > 1 {
+                self.listeners.emit_tts_event(
+                    &utterance_id,
+                    TTSMessageEvent {
+                        code: "end".into(),
+                        message: None,
+                        ..Default::default()
+                    },
+                );
+            }
+            return Ok(SpeakResponse { utterance_id });
+        }
+        // The utterance replaces whatever another engine is speaking too.
+        self.listeners.stop_sequence();
+        for other in self.engines() {
+            if !Arc::ptr_eq(&other, &runs[0].engine) {
+                other.stop()?;
+            }
+        }
+        match runs.len() {
+            1 => {
+                let run = &runs[0];
+                run.engine
+                    .speak(&utterance_id, &run.segments, &run.settings)?;
+            }
+            _ => {
+                if let Err(e) = self.listeners.start_sequence(&utterance_id, runs) {
+                    self.listeners.stop_sequence();
+                    return Err(e);
+                }
+            }
+        }
+        Ok(SpeakResponse { utterance_id })
+    }
+    // Splits segments into runs of the same voice, a segment without one being spoken
+    // with the voice of `settings`.
+    fn runs(
+        &self,
+        segments: Vec<SpeechSegment>,
+        settings: &VoiceSettings,
+    ) -> crate::Result<Vec<Run>> {
+        let mut runs: Vec<Run> = Vec::new();
+        for segment in segments {
+            let voice = segment.voice.clone().or_else(|| settings.voice.clone());
+            match runs.last_mut() {
+                Some(run) if run.settings.voice == voice => run.segments.push(segment),
+                _ => {
+                    let settings = VoiceSettings {
+                        voice,
+                        ..settings.clone()
+                    };
+                    runs.push(Run {
+                        engine: self.engine(settings.voice.as_deref())?,
+                        segments: vec![segment],
+                        settings,
+                    });
+                }
+            }
+        }
+        if runs.is_empty() {
+            // SSML without text is an utterance that ends at once.
+            runs.push(Run {
+                engine: self.engine(settings.voice.as_deref())?,
+                segments: Vec::new(),
+                settings: settings.clone(),
+            });
+        }
+        Ok(runs)
+    }
+    // The voices of all engines, listed once and kept until voices are installed or removed.
+    fn available_voices(&self) -> Vec<TTSVoice> {
+        if let Some(voices) = &*self.voices.lock().unwrap() {
+            return voices.clone();
+        }
+        self.get_all_voices()
+            .map(|response| response.voices)
+            .unwrap_or_default()
     }
     pub fn pause(&self) -> crate::Result<()> {
-        Err(crate::Error::UnsupportedPlatformError)
+        self.listeners.set_sequence_paused(true);
+        self.engines().iter().try_for_each(|engine| engine.pause())
     }
     pub fn resume(&self) -> crate::Result<()> {
-        Err(crate::Error::UnsupportedPlatformError)
+        self.engines()
+            .iter()
+            .try_for_each(|engine| engine.resume())?;
+        self.listeners.set_sequence_paused(false);
+        Ok(())
     }
     pub fn stop(&self) -> crate::Result<()> {
+        self.listeners.stop_sequence();
+        self.engines().iter().try_for_each(|engine| engine.stop())
+    }
+    pub fn set_rate(&self, args: SetRateArgs) -> crate::Result<()> {
+        self.settings.lock().unwrap().rate = args.rate;
+        Ok(())
+    }
+    pub fn set_pitch(&self, args: SetPitchArgs) -> crate::Result<()> {
+        self.settings.lock().unwrap().pitch = args.pitch;
+        Ok(())
+    }
+    pub fn set_voice(&self, args: SetVoiceArgs) -> crate::Result<()> {
+        self.settings.lock().unwrap().voice = Some(args.voice).filter(|voice| !voice.is_empty());
+        Ok(())
+    }
+    pub(crate) fn set_volume(&self, args: SetVolumeArgs) -> crate::Result<()> {
+        let volume = args.volume.clamp(0.0, 1.0);
+        self.settings.lock().unwrap().volume = volume;
+        self.engines()
+            .iter()
+            .try_for_each(|engine| engine.set_volume(volume))
+    }
+    pub fn get_all_voices(&self) -> crate::Result<GetVoicesResponse> {
+        let mut voices = Vec::new();
+        for engine in self.engines() {
+            voices.extend(engine.voices()?);
+        }
+        *self.voices.lock().unwrap() = Some(voices.clone());
+        Ok(GetVoicesResponse { voices })
+    }
+    pub fn get_voice_map(&self) -> crate::Result<VoiceMap> {
+        Ok(self.router.get())
+    }
+    pub fn set_voice_map(&self, payload: VoiceMap) -> crate::Result<()> {
+        self.router.set(payload)
+    }
+    pub fn set_language_voice(&self, payload: SetLanguageVoiceRequest) -> crate::Result<()> {
+        self.router.set_language_voice(payload)
+    }
+    pub fn get_lexicon(&self, payload: GetLexiconRequest) -> crate::Result<Lexicon> {
+        Ok(self.lexicons.get(payload.book.as_deref()))
+    }
+    pub fn set_lexicon(&self, payload: SetLexiconRequest) -> crate::Result<()> {
+        self.lexicons.set(payload.book.as_deref(), payload.lexicon)
+    }
+    pub fn import_lexicon(&self, payload: ImportLexiconRequest) -> crate::Result<Lexicon> {
+        self.lexicons.import(payload)
+    }
+    #[cfg(target_os = "linux")]
+    pub fn install_voice_pack(
+        &self,
+        payload: InstallVoicePackRequest,
+    ) -> crate::Result<InstallVoicePackResponse> {
+        let config_path = payload.config_path.as_deref().map(std::path::Path::new);
+        let voice = self
+            .piper()?
+            .install_voice(std::path::Path::new(&payload.model_path), config_path)?;
+        *self.voices.lock().unwrap() = None;
+        Ok(InstallVoicePackResponse { voice })
+    }
+    #[cfg(target_os = "linux")]
+    pub fn remove_voice_pack(&self, payload: RemoveVoicePackRequest) -> crate::Result<()> {
+        let piper = self.piper()?;
+        let mut settings = self.settings.lock().unwrap();
+        if settings.voice.as_deref() == Some(payload.voice.as_str()) {
+            piper.stop()?;
+            settings.voice = None;
+        }
+        *self.voices.lock().unwrap() = None;
+        piper.remove_voice(&payload.voice)
+    }
+    #[cfg(target_os = "linux")]
+    pub fn export_audiobook(
+        &self,
+        payload: ExportAudiobookRequest,
+        on_progress: Channel<AudiobookProgress>,
+    ) -> crate::Result<ExportAudiobookResponse> {
+        use crate::audiobook::{Renderer, RoutedRenderer};
+
+        self.export_cancelled.store(false, Ordering::Relaxed);
+        let mut settings = self.settings.lock().unwrap().clone();
+        if let Some(voice) = &payload.voice {
+            settings.voice = Some(voice.clone()).filter(|voice| !voice.is_empty());
+        }
+        // A fading sleep timer is no reason for a quiet audiobook.
+        settings.volume = 1.0;
+        let mut renderer = RoutedRenderer::new(settings.voice.clone(), |voice: Option<&str>| {
+            let renderer: Box<dyn Renderer> = match voice {
+                Some(voice) if voice.starts_with(crate::piper::VOICE_PREFIX) => {
+                    Box::new(self.piper()?.renderer(voice, settings.rate)?)
+                }
+                _ => Box::new(crate::espeak::EspeakRenderer::new(&VoiceSettings {
+                    voice: voice.map(str::to_string),
+                    ..settings.clone()
+                })?),
+            };
+            Ok(renderer)
+        });
+        // Chapters are pronounced and routed to voices as they would be spoken.
+        let prepare = |segments| {
+            let segments =
+                self.lexicons
+                    .apply(segments, payload.book.as_deref(), payload.lang.as_deref());
+            self.router.route(
+                segments,
+                payload.lang.as_deref(),
+                settings.voice.as_deref(),
+                || self.available_voices(),
+            )
+        };
+        crate::audiobook::export(
+            &mut renderer,
+            prepare,
+            &payload,
+            &self.export_cancelled,
+            &on_progress,
+        )
+    }
+    #[cfg(target_os = "linux")]
+    pub fn cancel_audiobook_export(&self) -> crate::Result<()> {
+        self.export_cancelled.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+    #[cfg(target_os = "linux")]
+    pub fn get_synthesis_cache_info(&self) -> crate::Result<SynthesisCacheInfo> {
+        Ok(self.piper()?.cache().info())
+    }
+    #[cfg(target_os = "linux")]
+    pub fn set_synthesis_cache_limit(
+        &self,
+        payload: SetSynthesisCacheLimitRequest,
+    ) -> crate::Result<()> {
+        self.piper()?.cache().set_limit(payload.limit)
+    }
+    #[cfg(target_os = "linux")]
+    pub fn clear_synthesis_cache(&self, payload: ClearSynthesisCacheRequest) -> crate::Result<()> {
+        let voice = payload.voice.as_deref().map(|voice| {
+            voice
+                .strip_prefix(crate::piper::VOICE_PREFIX)
+                .unwrap_or(voice)
+        });
+        self.piper()?.cache().clear(voice);
+        Ok(())
+    }
+    #[cfg(not(target_os = "linux"))]
+    pub fn install_voice_pack(
+        &self,
+        _payload: InstallVoicePackRequest,
+    ) -> crate::Result<InstallVoicePackResponse> {
         Err(crate::Error::UnsupportedPlatformError)
     }
-    pub fn set_rate(&self, _args: SetRateArgs) -> crate::Result<()> {
+    #[cfg(not(target_os = "linux"))]
+    pub fn remove_voice_pack(&self, _payload: RemoveVoicePackRequest) -> crate::Result<()> {
         Err(crate::Error::UnsupportedPlatformError)
     }
-    pub fn set_pitch(&self, _args: SetPitchArgs) -> crate::Result<()> {
+    #[cfg(not(target_os = "linux"))]
+    pub fn export_audiobook(
+        &self,
+        _payload: ExportAudiobookRequest,
+        _on_progress: Channel<AudiobookProgress>,
+    ) -> crate::Result<ExportAudiobookResponse> {
         Err(crate::Error::UnsupportedPlatformError)
     }
-    pub fn set_voice(&self, _args: SetVoiceArgs) -> crate::Result<()> {
+    #[cfg(not(target_os = "linux"))]
+    pub fn cancel_audiobook_export(&self) -> crate::Result<()> {
         Err(crate::Error::UnsupportedPlatformError)
     }
-    pub fn get_all_voices(&self) -> crate::Result<GetVoicesResponse> {
+    #[cfg(not(target_os = "linux"))]
+    pub fn get_synthesis_cache_info(&self) -> crate::Result<SynthesisCacheInfo> {
+        Err(crate::Error::UnsupportedPlatformError)
+    }
+    #[cfg(not(target_os = "linux"))]
+    pub fn set_synthesis_cache_limit(
+        &self,
+        _payload: SetSynthesisCacheLimitRequest,
+    ) -> crate::Result<()> {
         Err(crate::Error::UnsupportedPlatformError)
     }
+    #[cfg(not(target_os = "linux"))]
+    pub fn clear_synthesis_cache(&self, _payload: ClearSynthesisCacheRequest) -> crate::Result<()> {
+        Err(crate::Error::UnsupportedPlatformError)
+    }
+    #[cfg(target_os = "linux")]
+    pub fn set_media_session_active(
+        &self,
+        payload: SetMediaSessionActiveRequest,
+    ) -> crate::Result<()> {
+        let mut media_session = self.media_session.lock().unwrap();
+        if !payload.active {
+            // Dropping the session releases its bus name, which removes the player.
+            *media_session = None;
+            return Ok(());
+        }
+        if media_session.is_none() {
+            let artwork_dir = self
+                .app
+                .path()
+                .app_cache_dir()
+                .map_err(|e| crate::Error::NativeTTSError(e.to_string()))?;
+            *media_session = Some(crate::mpris::MediaSession::start(
+                &self.app.package_info().name,
+                artwork_dir,
+                self.listeners.clone(),
+            )?);
+        }
+        Ok(())
+    }
+    #[cfg(target_os = "linux")]
+    pub fn update_media_session_state(
+        &self,
+        payload: UpdateMediaSessionStateRequest,
+    ) -> crate::Result<()> {
+        match &*self.media_session.lock().unwrap() {
+            Some(media_session) => media_session.update_state(&payload),
+            None => Ok(()),
+        }
+    }
+    #[cfg(target_os = "linux")]
+    pub fn update_media_session_metadata(
+        &self,
+        payload: UpdateMediaSessionMetadataRequest,
+    ) -> crate::Result<()> {
+        match &mut *self.media_session.lock().unwrap() {
+            Some(media_session) => media_session.update_metadata(&payload),
+            None => Ok(()),
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
     pub fn set_media_session_active(
         &self,
         _payload: SetMediaSessionActiveRequest,
     ) -> crate::Result<()> {
         Err(crate::Error::UnsupportedPlatformError)
     }
+    #[cfg(not(target_os = "linux"))]
     pub fn update_media_session_state(
         &self,
         _payload: UpdateMediaSessionStateRequest,
     ) -> crate::Result<()> {
         Err(crate::Error::UnsupportedPlatformError)
     }
+    #[cfg(not(target_os = "linux"))]
     pub fn update_media_session_metadata(
         &self,
         _payload: UpdateMediaSessionMetadataRequest,
     ) -> crate::Result<()> {
         Err(crate::Error::UnsupportedPlatformError)
     }
+    pub fn start_sleep_timer(&self, payload: StartSleepTimerRequest) -> crate::Result<()> {
+        // The timer thread outlives this borrow, so it reaches the plugin through the app.
+        let app = self.app.clone();
+        self.sleep_timer.start(payload, move |action| {
+            let native_tts = app.native_tts();
+            match action {
+                Action::SetVolume(volume) => {
+                    let _ = native_tts.set_volume(SetVolumeArgs { volume });
+                }
+                Action::Pause => {
+                    if let Err(e) = native_tts.pause() {
+                        log::warn!("Failed to pause for the sleep timer: {e}");
+                    }
+                }
+                Action::Report(state) => native_tts.report_sleep_timer(state),
+            }
+        });
+        Ok(())
+    }
+    pub fn cancel_sleep_timer(&self) -> crate::Result<()> {
+        self.sleep_timer.cancel();
+        Ok(())
+    }
+    pub fn get_sleep_timer(&self) -> crate::Result<SleepTimerState> {
+        Ok(self.sleep_timer.state())
+    }
+    fn report_sleep_timer(&self, state: SleepTimerState) {
+        #[cfg(target_os = "linux")]
+        if let Some(media_session) = &*self.media_session.lock().unwrap() {
+            let remaining = state.remaining.map(std::time::Duration::from_secs_f64);
+            if let Err(e) = media_session.set_sleep_timer(remaining) {
+                log::warn!("Failed to show the sleep timer: {e}");
+            }
+        }
+        self.listeners.emit("sleep-timer", state);
+    }
+    pub fn register_listener(&self, event: String, handler: Channel<serde_json::Value>) {
+        self.listeners.register(event, handler);
+    }
+    pub fn remove_listener(&self, event: &str, channel_id: u32) {
+        self.listeners.remove(event, channel_id);
+    }
 }
@@ -8,6 +8,10 @@ pub enum Error {
     UnsupportedPlatformError,
     #[error("Native tts error: {0}")]
     NativeTTSError(String),
+    #[error("The sleep timer has ended")]
+    SleepTimerEnded,
+    #[error("The audiobook export was cancelled")]
+    ExportCancelled,
     #[error(transparent)]
     Io(#[from] std::io::Error),
     #[cfg(mobile)]
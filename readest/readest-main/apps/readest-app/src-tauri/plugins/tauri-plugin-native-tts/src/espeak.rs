@@ -0,0 +1,440 @@
+//! Speak through the espeak-ng command line tool when Speech Dispatcher is not available.
+//!
+//! Utterances are queued to a worker thread that runs one espeak-ng process at a time.
+//! Pausing and resuming stop and continue that process with `SIGSTOP` and `SIGCONT`.
+//! Segments are passed on as SSML. espeak-ng does not report its progress, so word and
+//! mark boundaries are estimated from the speaking rate.
+
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+use crate::audiobook::{Audio, Renderer};
+use crate::boundary::{self, Pacer, PlaybackClock};
+use crate::desktop::{Engine, Listeners, VoiceSettings};
+use crate::models::*;
+use crate::{Error, Result};
+
+const BINARIES: &[&str] = &["espeak-ng", "espeak"];
+const DEFAULT_WORDS_PER_MINUTE: f32 = 175.0;
+const DEFAULT_PITCH: f32 = 50.0;
+
+struct Job {
+    generation: u64,
+    utterance_id: String,
+    segments: Vec<SpeechSegment>,
+    words_per_minute: f32,
+    args: Vec<String>,
+}
+
+// A running process that plays an utterance, and how far it has got.
+pub(crate) struct Playing {
+    pub pid: u32,
+    pub clock: Arc<PlaybackClock>,
+}
+
+impl Playing {
+    // Signals the process, pausing or resuming the clock along with it.
+    pub(crate) fn signal(&self, sig: libc::c_int) -> Result<()> {
+        match sig {
+            libc::SIGSTOP => self.clock.pause(),
+            libc::SIGCONT => self.clock.resume(),
+            _ => {}
+        }
+        signal(self.pid, sig)
+    }
+}
+
+pub(crate) struct Espeak {
+    binary: &'static str,
+    jobs: Mutex<mpsc::Sender<Job>>,
+    generation: Arc<AtomicU64>, // Bumped by `stop` to drop the queued jobs
+    current: Arc<Mutex<Option<Playing>>>,
+}
+
+pub(crate) fn signal(pid: u32, signal: libc::c_int) -> Result<()> {
+    // SAFETY: `kill` has no memory safety requirements.
+    if unsafe { libc::kill(pid as libc::pid_t, signal) } == 0 {
+        return Ok(());
+    }
+    match std::io::Error::last_os_error() {
+        // The process has just exited.
+        e if e.raw_os_error() == Some(libc::ESRCH) => Ok(()),
+        e => Err(e.into()),
+    }
+}
+
+fn run_jobs(
+    binary: &'static str,
+    jobs: mpsc::Receiver<Job>,
+    generation: Arc<AtomicU64>,
+    current: Arc<Mutex<Option<Playing>>>,
+    listeners: Listeners,
+) {
+    for job in jobs {
+        if job.generation != generation.load(Ordering::SeqCst) {
+            listeners.emit_tts_event(&job.utterance_id, end_event());
+            continue;
+        }
+        let event = match run_job(binary, &job, &generation, &current, &listeners) {
+            Ok(()) => end_event(),
+            Err(e) => TTSMessageEvent {
+                code: "error".into(),
+                message: Some(e.to_string()),
+                ..Default::default()
+            },
+        };
+        listeners.emit_tts_event(&job.utterance_id, event);
+    }
+}
+
+fn run_job(
+    binary: &str,
+    job: &Job,
+    generation: &AtomicU64,
+    current: &Mutex<Option<Playing>>,
+    listeners: &Listeners,
+) -> Result<()> {
+    let mut child = Command::new(binary)
+        .args(&job.args)
+        .args(["-m", "--stdin"])
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let clock = Arc::new(PlaybackClock::default());
+    *current.lock().unwrap() = Some(Playing {
+        pid: child.id(),
+        clock: Arc::clone(&clock),
+    });
+
+    // `stop` may have been called between taking the job and registering the process.
+    if job.generation != generation.load(Ordering::SeqCst) {
+        let _ = child.kill();
+    }
+
+    listeners.emit_tts_event(
+        &job.utterance_id,
+        TTSMessageEvent {
+            code: "boundary".into(),
+            message: Some("start".into()),
+            ..Default::default()
+        },
+    );
+    clock.start();
+    let pacer = Pacer::new(clock, listeners.clone(), &job.utterance_id);
+    let mut at = Duration::ZERO;
+    for segment in &job.segments {
+        at += Duration::from_millis(segment.pause.into());
+        if let Some(mark) = &segment.mark {
+            pacer.send(at, boundary::mark_event(mark));
+        }
+        let words_per_minute = job.words_per_minute * segment.rate;
+        let per_word = Duration::from_secs_f64(60.0 / words_per_minute.max(1.0) as f64);
+        for word in boundary::segment_words(segment) {
+            pacer.send(at, word.event(at));
+            at += per_word;
+        }
+    }
+    if let Some(mut stdin) = child.stdin.take() {
+        let _ = stdin.write_all(crate::ssml::render(&job.segments, &[]).as_bytes());
+    }
+
+    let status = child.wait();
+    *current.lock().unwrap() = None;
+    pacer.finish();
+    let status = status?;
+
+    // A process killed by `stop` ends the utterance like a completed one.
+    if status.success() || job.generation != generation.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+    let mut stderr = String::new();
+    if let Some(mut pipe) = child.stderr.take() {
+        let _ = pipe.read_to_string(&mut stderr);
+    }
+    Err(Error::NativeTTSError(format!(
+        "{binary} failed with {status}: {}",
+        stderr.trim()
+    )))
+}
+
+fn end_event() -> TTSMessageEvent {
+    TTSMessageEvent {
+        code: "end".into(),
+        message: None,
+        ..Default::default()
+    }
+}
+
+fn find_binary() -> Result<&'static str> {
+    BINARIES
+        .iter()
+        .copied()
+        .find(|binary| {
+            Command::new(binary)
+                .arg("--version")
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .is_ok_and(|status| status.success())
+        })
+        .ok_or_else(|| Error::NativeTTSError("espeak-ng is not installed".into()))
+}
+
+// The espeak-ng arguments for the rate, pitch, volume and voice of `settings`, and the rate in
+// words per minute. The volume is fixed for the utterance, so a sleep timer fading out is
+// heard sentence by sentence.
+fn voice_args(settings: &VoiceSettings) -> (Vec<String>, f32) {
+    let speed = (DEFAULT_WORDS_PER_MINUTE * settings.rate).clamp(80.0, 450.0);
+    let pitch = (DEFAULT_PITCH * settings.pitch).clamp(0.0, 99.0);
+    let mut args = vec![
+        "-b".to_string(),
+        "1".to_string(), // UTF-8 input
+        "-s".to_string(),
+        (speed as u32).to_string(),
+        "-p".to_string(),
+        (pitch as u32).to_string(),
+        "-a".to_string(),
+        ((settings.volume.clamp(0.0, 1.0) * 100.0).round() as u32).to_string(),
+    ];
+    if let Some(voice) = &settings.voice {
+        args.extend(["-v".to_string(), voice.clone()]);
+    }
+    (args, speed)
+}
+
+// Extracts the 16-bit samples from the WAV file that espeak-ng writes to stdout. The data
+// size is unknown when it starts writing, so the samples run to the end of the file.
+fn parse_wav(data: &[u8]) -> Result<Audio> {
+    let invalid = || Error::NativeTTSError("espeak-ng wrote an invalid WAV file".into());
+    if data.get(..4) != Some(b"RIFF") || data.get(8..12) != Some(b"WAVE") {
+        return Err(invalid());
+    }
+    let mut sample_rate = None;
+    let mut offset = 12;
+    while let Some(header) = data.get(offset..offset + 8) {
+        let size = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let body = &data[offset + 8..];
+        match &header[..4] {
+            b"fmt " => {
+                let rate = body.get(4..8).ok_or_else(invalid)?;
+                sample_rate = Some(u32::from_le_bytes([rate[0], rate[1], rate[2], rate[3]]));
+            }
+            b"data" => {
+                let body = &body[..size.min(body.len())];
+                return Ok(Audio {
+                    samples: body
+                        .chunks_exact(2)
+                        .map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+                        .collect(),
+                    sample_rate: sample_rate.ok_or_else(invalid)?,
+                });
+            }
+            _ => {}
+        }
+        // Chunks are padded to an even size.
+        offset += 8 + size + size % 2;
+    }
+    Err(invalid())
+}
+
+fn list_voices(binary: &str) -> Result<Vec<TTSVoice>> {
+    let output = Command::new(binary).arg("--voices").output()?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    // Columns: Pty Language Age/Gender VoiceName File Other Languages
+    let mut voices = output
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let (lang, name) = (fields.get(1)?, fields.get(3)?);
+            Some(TTSVoice {
+                id: lang.to_string(),
+                name: name.replace('_', " "),
+                lang: lang.to_string(),
+                disabled: false,
+            })
+        })
+        .collect::<Vec<_>>();
+    voices.dedup_by(|a, b| a.id == b.id);
+    Ok(voices)
+}
+
+// Renders segments to memory with espeak-ng, for audiobooks.
+pub(crate) struct EspeakRenderer {
+    binary: &'static str,
+    args: Vec<String>,
+}
+
+impl EspeakRenderer {
+    pub(crate) fn new(settings: &VoiceSettings) -> Result<Self> {
+        let binary = find_binary()?;
+        let mut settings = settings.clone();
+        // The voice may belong to Speech Dispatcher, which renders audio for the sound card
+        // only; espeak-ng speaks with its default voice then.
+        if let Some(voice) = &settings.voice {
+            if !list_voices(binary)?.iter().any(|v| &v.id == voice) {
+                log::warn!("espeak-ng has no voice {voice}, using its default voice");
+                settings.voice = None;
+            }
+        }
+        Ok(Self {
+            binary,
+            args: voice_args(&settings).0,
+        })
+    }
+}
+
+impl Renderer for EspeakRenderer {
+    fn render(&mut self, segments: &[SpeechSegment]) -> Result<Audio> {
+        let mut child = Command::new(self.binary)
+            .args(&self.args)
+            .args(["-m", "--stdout", "--stdin"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        // Write from another thread, espeak-ng starts writing audio before it has read all
+        // of the text.
+        let writer = child.stdin.take().map(|mut stdin| {
+            let ssml = crate::ssml::render(segments, &[]);
+            std::thread::spawn(move || stdin.write_all(ssml.as_bytes()))
+        });
+        let output = child.wait_with_output()?;
+        if let Some(writer) = writer {
+            let _ = writer.join();
+        }
+        if !output.status.success() {
+            return Err(Error::NativeTTSError(format!(
+                "{} failed with {}: {}",
+                self.binary,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        parse_wav(&output.stdout)
+    }
+}
+
+impl Espeak {
+    pub(crate) fn new(listeners: Listeners) -> Result<Self> {
+        let binary = find_binary()?;
+
+        let (sender, jobs) = mpsc::channel();
+        let generation = Arc::new(AtomicU64::new(0));
+        let current = Arc::new(Mutex::new(None));
+        {
+            let generation = Arc::clone(&generation);
+            let current = Arc::clone(&current);
+            std::thread::spawn(move || run_jobs(binary, jobs, generation, current, listeners));
+        }
+
+        Ok(Self {
+            binary,
+            jobs: Mutex::new(sender),
+            generation,
+            current,
+        })
+    }
+
+    fn signal_current(&self, sig: libc::c_int) -> Result<()> {
+        match &*self.current.lock().unwrap() {
+            Some(playing) => playing.signal(sig),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Engine for Espeak {
+    fn voices(&self) -> Result<Vec<TTSVoice>> {
+        list_voices(self.binary)
+    }
+
+    fn speak(
+        &self,
+        utterance_id: &str,
+        segments: &[SpeechSegment],
+        settings: &VoiceSettings,
+    ) -> Result<()> {
+        self.stop()?;
+
+        let (args, speed) = voice_args(settings);
+        let job = Job {
+            generation: self.generation.load(Ordering::SeqCst),
+            utterance_id: utterance_id.to_string(),
+            segments: segments.to_vec(),
+            words_per_minute: speed,
+            args,
+        };
+        self.jobs
+            .lock()
+            .unwrap()
+            .send(job)
+            .map_err(|_| Error::NativeTTSError("espeak-ng worker has stopped".into()))
+    }
+
+    fn pause(&self) -> Result<()> {
+        self.signal_current(libc::SIGSTOP)
+    }
+
+    fn resume(&self) -> Result<()> {
+        self.signal_current(libc::SIGCONT)
+    }
+
+    fn stop(&self) -> Result<()> {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        // SIGKILL also ends a paused process.
+        self.signal_current(libc::SIGKILL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A WAV file like the one espeak-ng streams, whose data size is not known yet.
+    fn wav(samples: &[i16]) -> Vec<u8> {
+        let mut data = b"RIFF\xff\xff\xff\xffWAVE".to_vec();
+        data.extend(b"fmt \x10\x00\x00\x00\x01\x00\x01\x00");
+        data.extend(22050u32.to_le_bytes());
+        data.extend(44100u32.to_le_bytes());
+        data.extend(b"\x02\x00\x10\x00");
+        data.extend(b"LIST\x03\x00\x00\x00abc\x00"); // Padded to an even size
+        data.extend(b"data\xff\xff\xff\xff");
+        data.extend(samples.iter().flat_map(|sample| sample.to_le_bytes()));
+        data
+    }
+
+    #[test]
+    fn parses_streamed_wav_files() {
+        let audio = parse_wav(&wav(&[0, 1, -1, i16::MAX])).unwrap();
+        assert_eq!(audio.sample_rate, 22050);
+        assert_eq!(audio.samples, [0, 1, -1, i16::MAX]);
+
+        assert!(parse_wav(b"RIFF\0\0\0\0WAVE").is_err());
+        assert!(parse_wav(&wav(&[])[..12]).is_err());
+        assert!(parse_wav(b"not a WAV file").is_err());
+    }
+
+    #[test]
+    fn clamps_the_voice_settings() {
+        let settings = VoiceSettings {
+            rate: 10.0,
+            pitch: 0.5,
+            volume: 0.5,
+            voice: Some("cs".into()),
+        };
+        let (args, speed) = voice_args(&settings);
+        assert_eq!(speed, 450.0);
+        assert_eq!(
+            args,
+            ["-b", "1", "-s", "450", "-p", "25", "-a", "50", "-v", "cs"]
+        );
+
+        let (args, speed) = voice_args(&VoiceSettings::default());
+        assert_eq!(speed, DEFAULT_WORDS_PER_MINUTE);
+        assert_eq!(args[2..], ["-s", "175", "-p", "50", "-a", "100"]);
+    }
+}
@@ -0,0 +1,554 @@
+//! Pronunciation lexicons.
+//!
+//! A lexicon maps words to the way they are spoken, as a respelling (an alias) or as
+//! phonemes, for names and other words that speech services get wrong. There is a global
+//! lexicon and one per book; the lexemes of the book come first. Lexicons are imported
+//! from W3C Pronunciation Lexicon (PLS) documents, which EPUB 3 books may embed; the reader
+//! imports those into the lexicon of the book when read aloud starts.
+//!
+//! Lexemes are applied to the segments of an utterance before it is spoken, by splitting
+//! the words they match off into segments with an alias or phonemes.
+
+use quick_xml::events::Event;
+use quick_xml::Reader;
+
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::models::*;
+use crate::voice_map::primary;
+use crate::{Error, Result};
+
+fn invalid(e: impl std::fmt::Display) -> Error {
+    Error::NativeTTSError(format!("Invalid pronunciation lexicon: {e}"))
+}
+
+// Parses a PLS document. Every grapheme of a lexeme becomes a lexeme of its own, with the
+// first pronunciation given for it.
+pub(crate) fn parse_pls(pls: &str) -> Result<Lexicon> {
+    let mut reader = Reader::from_str(pls);
+    let mut lexicon = Lexicon::default();
+    let mut alphabet = None; // Of the lexicon
+    let mut graphemes = Vec::new();
+    let mut alias = None;
+    let mut phoneme: Option<(String, Option<String>)> = None;
+    let mut text = String::new();
+    let mut phoneme_alphabet = None;
+    loop {
+        match reader.read_event().map_err(invalid)? {
+            Event::Start(start) => {
+                let attribute = |key: &str| -> Result<Option<String>> {
+                    match start.try_get_attribute(key).map_err(invalid)? {
+                        Some(value) => Ok(Some(value.unescape_value().map_err(invalid)?.into())),
+                        None => Ok(None),
+                    }
+                };
+                match start.local_name().as_ref() {
+                    b"lexicon" => {
+                        alphabet = attribute("alphabet")?;
+                        lexicon.lang = attribute("xml:lang")?;
+                    }
+                    b"lexeme" => {
+                        graphemes.clear();
+                        alias = None;
+                        phoneme = None;
+                    }
+                    b"phoneme" => phoneme_alphabet = attribute("alphabet")?,
+                    _ => {}
+                }
+                text.clear();
+            }
+            Event::Text(content) => text.push_str(&content.unescape().map_err(invalid)?),
+            Event::CData(content) => text.push_str(&String::from_utf8_lossy(&content)),
+            Event::End(end) => {
+                let value = text.trim().to_string();
+                match end.local_name().as_ref() {
+                    b"grapheme" if !value.is_empty() => graphemes.push(value),
+                    // The first pronunciation is the preferred one.
+                    b"alias" if alias.is_none() && !value.is_empty() => alias = Some(value),
+                    b"phoneme" if phoneme.is_none() && !value.is_empty() => {
+                        let alphabet = phoneme_alphabet.take().or_else(|| alphabet.clone());
+                        phoneme = Some((value, alphabet));
+                    }
+                    b"lexeme" => {
+                        for grapheme in graphemes.drain(..) {
+                            if alias.is_none() && phoneme.is_none() {
+                                continue;
+                            }
+                            lexicon.lexemes.push(Lexeme {
+                                grapheme,
+                                alias: alias.clone(),
+                                phoneme: phoneme.as_ref().map(|(phoneme, _)| phoneme.clone()),
+                                alphabet: phoneme
+                                    .as_ref()
+                                    .and_then(|(_, alphabet)| alphabet.clone()),
+                            });
+                        }
+                    }
+                    _ => {}
+                }
+                text.clear();
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(lexicon)
+}
+
+// Whether `c` is part of a word written with spaces between words, so that lexemes only
+// match whole words of it. Words of Chinese or Japanese are matched anywhere.
+fn is_spaced_word_char(c: char) -> bool {
+    c.is_alphanumeric() && !matches!(c as u32, 0x3040..=0x30FF | 0x3400..=0x4DBF | 0x4E00..=0x9FFF)
+}
+
+// The byte length of `grapheme` at the start of `text`, ignoring case.
+fn match_len(text: &str, grapheme: &str) -> Option<usize> {
+    let mut text_chars = text.char_indices();
+    for expected in grapheme.chars() {
+        let (_, c) = text_chars.next()?;
+        if c != expected && !c.to_lowercase().eq(expected.to_lowercase()) {
+            return None;
+        }
+    }
+    Some(text_chars.next().map_or(text.len(), |(index, _)| index))
+}
+
+// The lexeme that matches at byte `start` of `text`, and the length it matches.
+fn find_lexeme<'a>(
+    lexemes: &[&'a Lexeme],
+    text: &str,
+    start: usize,
+) -> Option<(&'a Lexeme, usize)> {
+    if text[..start]
+        .chars()
+        .next_back()
+        .is_some_and(is_spaced_word_char)
+        && text[start..]
+            .chars()
+            .next()
+            .is_some_and(is_spaced_word_char)
+    {
+        return None;
+    }
+    lexemes.iter().find_map(|lexeme| {
+        let len = match_len(&text[start..], &lexeme.grapheme)?;
+        let before_end = text[..start + len].chars().next_back();
+        let after_end = text[start + len..].chars().next();
+        if before_end.is_some_and(is_spaced_word_char) && after_end.is_some_and(is_spaced_word_char)
+        {
+            return None;
+        }
+        Some((*lexeme, len))
+    })
+}
+
+// Splits the words that `lexemes` match off `segment`, giving them their pronunciation.
+fn apply_to_segment(segment: SpeechSegment, lexemes: &[&Lexeme]) -> Vec<SpeechSegment> {
+    let text = &segment.text;
+    let mut parts: Vec<(usize, usize, Option<&Lexeme>)> = Vec::new(); // Start, end, lexeme
+    let mut plain_start = 0;
+    let mut index = 0;
+    while index < text.len() {
+        if let Some((lexeme, len)) = find_lexeme(lexemes, text, index) {
+            if plain_start < index {
+                parts.push((plain_start, index, None));
+            }
+            parts.push((index, index + len, Some(lexeme)));
+            index += len;
+            plain_start = index;
+            continue;
+        }
+        index += text[index..].chars().next().map_or(1, char::len_utf8);
+    }
+    if parts.is_empty() {
+        return vec![segment];
+    }
+    if plain_start < text.len() {
+        parts.push((plain_start, text.len(), None));
+    }
+
+    let mut offset = segment.offset;
+    let mut segments = Vec::new();
+    for (i, (start, end, lexeme)) in parts.into_iter().enumerate() {
+        let mut part = SpeechSegment {
+            text: text[start..end].to_string(),
+            offset,
+            ..segment.clone()
+        };
+        if i > 0 {
+            // The mark and the pause come before the first part.
+            part.mark = None;
+            part.pause = 0;
+        }
+        if let Some(lexeme) = lexeme {
+            part.alias = lexeme.alias.clone();
+            part.phoneme = lexeme.phoneme.clone();
+            part.alphabet = lexeme.alphabet.clone();
+        }
+        offset += part.text.encode_utf16().count() as u32;
+        segments.push(part);
+    }
+    segments
+}
+
+// The global lexicon and those of books, saved as JSON files in a directory.
+pub(crate) struct Lexicons {
+    dir: Option<PathBuf>,
+    lexicons: Mutex<HashMap<Option<String>, Lexicon>>, // By book, loaded when first used
+}
+
+impl Lexicons {
+    pub(crate) fn new(dir: Option<PathBuf>) -> Self {
+        Self {
+            dir,
+            lexicons: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn path(&self, book: Option<&str>) -> Option<PathBuf> {
+        let name = match book {
+            // Book keys are hashes, anything else is kept out of the file name.
+            Some(book) => format!(
+                "book-{}.json",
+                book.chars()
+                    .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+                    .collect::<String>()
+            ),
+            None => "global.json".to_string(),
+        };
+        self.dir.as_ref().map(|dir| dir.join(name))
+    }
+
+    fn load(&self, book: Option<&str>) -> Lexicon {
+        self.path(book)
+            .and_then(|path| std::fs::read(path).ok())
+            .and_then(|data| match serde_json::from_slice(&data) {
+                Ok(lexicon) => Some(lexicon),
+                Err(e) => {
+                    log::warn!("Ignoring an invalid pronunciation lexicon: {e}");
+                    None
+                }
+            })
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn get(&self, book: Option<&str>) -> Lexicon {
+        let mut lexicons = self.lexicons.lock().unwrap();
+        lexicons
+            .entry(book.map(str::to_string))
+            .or_insert_with(|| self.load(book))
+            .clone()
+    }
+
+    pub(crate) fn set(&self, book: Option<&str>, mut lexicon: Lexicon) -> Result<()> {
+        lexicon.lexemes.retain(|lexeme| {
+            !lexeme.grapheme.trim().is_empty()
+                && (lexeme.alias.is_some() || lexeme.phoneme.is_some())
+        });
+        // A later lexeme of the same grapheme replaces an earlier one.
+        let mut seen = HashSet::new();
+        lexicon.lexemes.reverse();
+        lexicon
+            .lexemes
+            .retain(|lexeme| seen.insert(lexeme.grapheme.to_lowercase()));
+        lexicon.lexemes.reverse();
+
+        if let Some(path) = self.path(book) {
+            match lexicon.lexemes.is_empty() {
+                true => {
+                    let _ = std::fs::remove_file(&path);
+                }
+                false => {
+                    if let Some(dir) = path.parent() {
+                        std::fs::create_dir_all(dir)?;
+                    }
+                    let data = serde_json::to_vec_pretty(&lexicon).map_err(std::io::Error::from)?;
+                    std::fs::write(path, data)?;
+                }
+            }
+        }
+        let mut lexicons = self.lexicons.lock().unwrap();
+        lexicons.insert(book.map(str::to_string), lexicon);
+        Ok(())
+    }
+
+    // Imports a PLS document into a lexicon, and returns the lexicon.
+    pub(crate) fn import(&self, request: ImportLexiconRequest) -> Result<Lexicon> {
+        let pls = match (request.pls, &request.path) {
+            (Some(pls), _) => pls,
+            (None, Some(path)) => std::fs::read_to_string(path)?,
+            (None, None) => return Err(Error::NativeTTSError("No lexicon to import".into())),
+        };
+        let imported = parse_pls(&pls)?;
+        let book = request.book.as_deref();
+        let mut lexicon = match request.replace {
+            true => Lexicon::default(),
+            false => self.get(book),
+        };
+        lexicon.lang = lexicon.lang.or(imported.lang);
+        let existing = match request.keep_existing {
+            true => lexicon
+                .lexemes
+                .iter()
+                .map(|lexeme| lexeme.grapheme.to_lowercase())
+                .collect(),
+            false => HashSet::new(),
+        };
+        lexicon.lexemes.extend(
+            imported
+                .lexemes
+                .into_iter()
+                .filter(|lexeme| !existing.contains(&lexeme.grapheme.to_lowercase())),
+        );
+        self.set(book, lexicon)?;
+        Ok(self.get(book))
+    }
+
+    // Gives the words of `segments` that the lexicons of `book` match their pronunciation.
+    // Segments already given one by SSML, or spelled out, are left as they are.
+    pub(crate) fn apply(
+        &self,
+        segments: Vec<SpeechSegment>,
+        book: Option<&str>,
+        lang: Option<&str>,
+    ) -> Vec<SpeechSegment> {
+        let lexicons = match book {
+            Some(book) => vec![self.get(Some(book)), self.get(None)],
+            None => vec![self.get(None)],
+        };
+        if lexicons.iter().all(|lexicon| lexicon.lexemes.is_empty()) {
+            return segments;
+        }
+        segments
+            .into_iter()
+            .flat_map(|segment| {
+                if !segment.is_pronounced_as_written() || segment.is_spelled_out() {
+                    return vec![segment];
+                }
+                let segment_lang = segment.lang.as_deref().or(lang);
+                let mut lexemes = lexicons
+                    .iter()
+                    .filter(|lexicon| match (&lexicon.lang, segment_lang) {
+                        (Some(lexicon_lang), Some(lang)) => primary(lexicon_lang) == primary(lang),
+                        _ => true,
+                    })
+                    .flat_map(|lexicon| &lexicon.lexemes)
+                    .collect::<Vec<_>>();
+                // The longest grapheme wins, like "Harry Potter" over "Harry".
+                lexemes.sort_by_key(|lexeme| std::cmp::Reverse(lexeme.grapheme.chars().count()));
+                apply_to_segment(segment, &lexemes)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lexeme(grapheme: &str, alias: &str) -> Lexeme {
+        Lexeme {
+            grapheme: grapheme.to_string(),
+            alias: Some(alias.to_string()),
+            phoneme: None,
+            alphabet: None,
+        }
+    }
+
+    fn lexicons(lexemes: Vec<Lexeme>) -> Lexicons {
+        let lexicons = Lexicons::new(None);
+        let lexicon = Lexicon {
+            lang: None,
+            lexemes,
+        };
+        lexicons.set(None, lexicon).unwrap();
+        lexicons
+    }
+
+    // The text, offset and alias of every segment.
+    fn parts(segments: &[SpeechSegment]) -> Vec<(&str, u32, Option<&str>)> {
+        segments
+            .iter()
+            .map(|segment| {
+                let alias = segment.alias.as_deref();
+                (segment.text.as_str(), segment.offset, alias)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parses_every_grapheme_of_a_lexeme() {
+        let lexicon = parse_pls(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+            <lexicon version="1.0" xmlns="http://www.w3.org/2005/01/pronunciation-lexicon"
+                alphabet="ipa" xml:lang="en-GB">
+              <lexeme>
+                <grapheme>Hermione</grapheme>
+                <grapheme>Hermy</grapheme>
+                <phoneme>hɜːˈmaɪ.əni</phoneme>
+                <phoneme>ˈhɜːmi</phoneme>
+              </lexeme>
+              <lexeme>
+                <grapheme>W3C</grapheme>
+                <alias>World Wide Web Consortium</alias>
+                <phoneme alphabet="x-sampa">dVb@l.ju: Tri: si:</phoneme>
+              </lexeme>
+              <lexeme><grapheme>Nothing</grapheme></lexeme>
+            </lexicon>"#,
+        )
+        .unwrap();
+        assert_eq!(lexicon.lang.as_deref(), Some("en-GB"));
+        let hermione = Lexeme {
+            grapheme: "Hermione".to_string(),
+            alias: None,
+            phoneme: Some("hɜːˈmaɪ.əni".to_string()),
+            alphabet: Some("ipa".to_string()),
+        };
+        let hermy = Lexeme {
+            grapheme: "Hermy".to_string(),
+            ..hermione.clone()
+        };
+        let w3c = Lexeme {
+            grapheme: "W3C".to_string(),
+            alias: Some("World Wide Web Consortium".to_string()),
+            phoneme: Some("dVb@l.ju: Tri: si:".to_string()),
+            alphabet: Some("x-sampa".to_string()),
+        };
+        // Lexemes without a pronunciation are left out.
+        assert_eq!(lexicon.lexemes, [hermione, hermy, w3c]);
+    }
+
+    #[test]
+    fn rejects_malformed_lexicons() {
+        assert!(parse_pls("<lexicon><lexeme><grapheme>a</lexeme></lexicon>").is_err());
+    }
+
+    #[test]
+    fn matches_graphemes_ignoring_case() {
+        assert_eq!(match_len("Hello world", "hello"), Some(5));
+        assert_eq!(match_len("ÉCOLE normale", "école"), Some(6));
+        assert_eq!(match_len("Hell", "hello"), None);
+        assert_eq!(match_len("Help", "hello"), None);
+        assert_eq!(match_len("hello", "hello"), Some(5));
+    }
+
+    #[test]
+    fn matches_whole_words_only_in_spaced_scripts() {
+        let potter = lexeme("Potter", "Potta");
+        let apply = |text: &str| apply_to_segment(SpeechSegment::new(text.into()), &[&potter]);
+        assert_eq!(apply("Potterish ways").len(), 1);
+        assert_eq!(apply("Spotter").len(), 1);
+        assert_eq!(
+            parts(&apply("Mr Potter's owl")),
+            [
+                ("Mr ", 0, None),
+                ("Potter", 3, Some("Potta")),
+                ("'s owl", 9, None)
+            ]
+        );
+
+        let beijing = lexeme("北京", "Běijīng");
+        let segments = apply_to_segment(SpeechSegment::new("我爱北京天安门".into()), &[&beijing]);
+        assert_eq!(
+            parts(&segments),
+            [
+                ("我爱", 0, None),
+                ("北京", 2, Some("Běijīng")),
+                ("天安门", 4, None)
+            ]
+        );
+    }
+
+    #[test]
+    fn prefers_the_longest_grapheme() {
+        let lexicons = lexicons(vec![
+            lexeme("Harry", "Hairy"),
+            lexeme("Harry Potter", "The boy who lived"),
+        ]);
+        let segments = vec![SpeechSegment::new("Harry Potter and Harry".into())];
+        let segments = lexicons.apply(segments, None, None);
+        assert_eq!(
+            parts(&segments),
+            [
+                ("Harry Potter", 0, Some("The boy who lived")),
+                (" and ", 12, None),
+                ("Harry", 17, Some("Hairy"))
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_offsets_marks_and_pauses() {
+        let tolkien = lexeme("Tolkien", "Tolkeen");
+        let segment = SpeechSegment {
+            offset: 10,
+            mark: Some("m".to_string()),
+            pause: 500,
+            ..SpeechSegment::new("😀 Tolkien wrote".into())
+        };
+        let segments = apply_to_segment(segment, &[&tolkien]);
+        // The emoji takes two UTF-16 code units.
+        assert_eq!(
+            parts(&segments),
+            [
+                ("😀 ", 10, None),
+                ("Tolkien", 13, Some("Tolkeen")),
+                (" wrote", 20, None)
+            ]
+        );
+        assert_eq!(segments[0].mark.as_deref(), Some("m"));
+        assert_eq!(segments[0].pause, 500);
+        assert!(segments[1..]
+            .iter()
+            .all(|segment| segment.mark.is_none() && segment.pause == 0));
+    }
+
+    #[test]
+    fn leaves_segments_with_a_pronunciation_alone() {
+        let lexicons = lexicons(vec![lexeme("Tolkien", "Tolkeen")]);
+        let segment = SpeechSegment {
+            alias: Some("JRR".to_string()),
+            ..SpeechSegment::new("Tolkien".into())
+        };
+        let segments = lexicons.apply(vec![segment.clone()], None, None);
+        assert_eq!(segments, [segment]);
+    }
+
+    #[test]
+    fn imports_without_replacing_existing_lexemes() {
+        let lexicons = lexicons(Vec::new());
+        lexicons
+            .set(
+                Some("book"),
+                Lexicon {
+                    lang: None,
+                    lexemes: vec![lexeme("Smaug", "Smowg")],
+                },
+            )
+            .unwrap();
+        let pls = r#"<lexicon alphabet="ipa">
+            <lexeme><grapheme>smaug</grapheme><alias>Smawg</alias></lexeme>
+            <lexeme><grapheme>Bilbo</grapheme><alias>Bilbo Baggins</alias></lexeme>
+            </lexicon>"#;
+        let request = |keep_existing| ImportLexiconRequest {
+            book: Some("book".to_string()),
+            pls: Some(pls.to_string()),
+            path: None,
+            replace: false,
+            keep_existing,
+        };
+        let lexicon = lexicons.import(request(true)).unwrap();
+        assert_eq!(
+            lexicon.lexemes,
+            [lexeme("Smaug", "Smowg"), lexeme("Bilbo", "Bilbo Baggins")]
+        );
+        // Otherwise the imported lexemes replace those of the same grapheme.
+        let lexicon = lexicons.import(request(false)).unwrap();
+        assert_eq!(
+            lexicon.lexemes,
+            [lexeme("smaug", "Smawg"), lexeme("Bilbo", "Bilbo Baggins")]
+        );
+    }
+}
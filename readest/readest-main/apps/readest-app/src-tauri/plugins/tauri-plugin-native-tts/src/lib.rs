@@ -5,14 +5,32 @@ use tauri::{
 
 pub use models::*;
 
+#[cfg(all(desktop, target_os = "linux"))]
+mod audiobook;
+#[cfg(all(desktop, target_os = "linux"))]
+mod boundary;
+#[cfg(all(desktop, target_os = "linux"))]
+mod cache;
 #[cfg(desktop)]
 mod desktop;
+#[cfg(all(desktop, target_os = "linux"))]
+mod espeak;
 #[cfg(mobile)]
 mod mobile;
+#[cfg(all(desktop, target_os = "linux"))]
+mod mpris;
+#[cfg(all(desktop, target_os = "linux"))]
+mod piper;
+#[cfg(all(desktop, target_os = "linux"))]
+mod speechd;
 
 mod commands;
 mod error;
+mod lexicon;
 mod models;
+mod sleep_timer;
+mod ssml;
+mod voice_map;
 
 pub use error::{Error, Result};
 
@@ -48,6 +66,33 @@ pub fn init<R: Runtime>() -> TauriPlugin<R> {
             commands::set_media_session_active,
             commands::update_media_session_state,
             commands::update_media_session_metadata,
+            commands::start_sleep_timer,
+            commands::cancel_sleep_timer,
+            commands::get_sleep_timer,
+            commands::get_voice_map,
+            commands::set_voice_map,
+            commands::set_language_voice,
+            commands::get_lexicon,
+            commands::set_lexicon,
+            commands::import_lexicon,
+            #[cfg(desktop)]
+            commands::install_voice_pack,
+            #[cfg(desktop)]
+            commands::remove_voice_pack,
+            #[cfg(desktop)]
+            commands::export_audiobook,
+            #[cfg(desktop)]
+            commands::cancel_audiobook_export,
+            #[cfg(desktop)]
+            commands::get_synthesis_cache_info,
+            #[cfg(desktop)]
+            commands::set_synthesis_cache_limit,
+            #[cfg(desktop)]
+            commands::clear_synthesis_cache,
+            #[cfg(desktop)]
+            commands::register_listener,
+            #[cfg(desktop)]
+            commands::remove_listener,
         ])
         .setup(|app, api| {
             #[cfg(mobile)]
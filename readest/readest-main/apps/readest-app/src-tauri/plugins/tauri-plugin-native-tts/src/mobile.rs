@@ -1,28 +1,41 @@
 use serde::de::DeserializeOwned;
 use tauri::{
     plugin::{PluginApi, PluginHandle},
-    AppHandle, Runtime,
+    AppHandle, Manager, Runtime,
 };
 
+use crate::lexicon::Lexicons;
 use crate::models::*;
+use crate::sleep_timer::{Action, SleepTimer};
+use crate::voice_map::VoiceRouter;
 
 #[cfg(target_os = "ios")]
 tauri::ios_plugin_binding!(init_plugin_native_tts);
 
 // initializes the Kotlin or Swift plugin classes
 pub fn init<R: Runtime, C: DeserializeOwned>(
-    _app: &AppHandle<R>,
+    app: &AppHandle<R>,
     api: PluginApi<R, C>,
 ) -> crate::Result<NativeTts<R>> {
     #[cfg(target_os = "android")]
     let handle = api.register_android_plugin("com.readest.native_tts", "NativeTTSPlugin")?;
     #[cfg(target_os = "ios")]
     let handle = api.register_ios_plugin(init_plugin_native_tts)?;
-    Ok(NativeTts(handle))
+    let config_dir = app.path().app_config_dir().ok();
+    let voice_map = config_dir
+        .as_ref()
+        .map(|dir| dir.join("tts-voice-map.json"));
+    let lexicons = config_dir.map(|dir| dir.join("tts-lexicons"));
+    Ok(NativeTts(
+        handle,
+        SleepTimer::default(),
+        VoiceRouter::load(voice_map),
+        Lexicons::new(lexicons),
+    ))
 }
 
 /// Access to the native-tts APIs.
-pub struct NativeTts<R: Runtime>(PluginHandle<R>);
+pub struct NativeTts<R: Runtime>(PluginHandle<R>, SleepTimer, VoiceRouter, Lexicons);
 
 impl<R: Runtime> NativeTts<R> {
     pub fn init(&self) -> crate::Result<InitResponse> {
@@ -31,7 +44,28 @@ impl<R: Runtime> NativeTts<R> {
 }
 
 impl<R: Runtime> NativeTts<R> {
-    pub fn speak(&self, payload: SpeakArgs) -> crate::Result<SpeakResponse> {
+    pub fn speak(&self, mut payload: SpeakArgs) -> crate::Result<SpeakResponse> {
+        if !self.1.should_speak(&payload) {
+            return Err(crate::Error::SleepTimerEnded);
+        }
+        // The native plugins speak the segments parsed and routed here, so that SSML and
+        // voices are handled the same way on every platform. The languages the voice map
+        // has no voice for are left to the speech service.
+        let segments = self.3.apply(
+            crate::ssml::segments(&payload)?,
+            payload.book.as_deref(),
+            payload.lang.as_deref(),
+        );
+        let segments = self.2.route(
+            segments,
+            payload.lang.as_deref(),
+            payload.voice.as_deref(),
+            Vec::new,
+        );
+        // Plain text left as it is is spoken without segments.
+        if payload.ssml || segments != [SpeechSegment::new(payload.text.clone())] {
+            payload.segments = segments;
+        }
         self.0
             .run_mobile_plugin("speak", payload)
             .map_err(Into::into)
@@ -120,3 +154,74 @@ impl<R: Runtime> NativeTts<R> {
             .map_err(Into::into)
     }
 }
+
+impl<R: Runtime> NativeTts<R> {
+    pub fn start_sleep_timer(&self, payload: StartSleepTimerRequest) -> crate::Result<()> {
+        let handle = self.0.clone();
+        self.1.start(payload, move |action| {
+            let result: crate::Result<()> = match action {
+                Action::SetVolume(volume) => handle
+                    .run_mobile_plugin("set_volume", SetVolumeArgs { volume })
+                    .map_err(Into::into),
+                Action::Pause => handle.run_mobile_plugin("pause", ()).map_err(Into::into),
+                // The native plugin shows the time left in the media notification.
+                Action::Report(state) => handle
+                    .run_mobile_plugin("update_sleep_timer", state)
+                    .map_err(Into::into),
+            };
+            if let Err(e) = result {
+                log::warn!("Sleep timer action failed: {e}");
+            }
+        });
+        Ok(())
+    }
+}
+
+impl<R: Runtime> NativeTts<R> {
+    pub fn cancel_sleep_timer(&self) -> crate::Result<()> {
+        self.1.cancel();
+        Ok(())
+    }
+}
+
+impl<R: Runtime> NativeTts<R> {
+    pub fn get_sleep_timer(&self) -> crate::Result<SleepTimerState> {
+        Ok(self.1.state())
+    }
+}
+
+impl<R: Runtime> NativeTts<R> {
+    pub fn get_voice_map(&self) -> crate::Result<VoiceMap> {
+        Ok(self.2.get())
+    }
+}
+
+impl<R: Runtime> NativeTts<R> {
+    pub fn set_voice_map(&self, payload: VoiceMap) -> crate::Result<()> {
+        self.2.set(payload)
+    }
+}
+
+impl<R: Runtime> NativeTts<R> {
+    pub fn set_language_voice(&self, payload: SetLanguageVoiceRequest) -> crate::Result<()> {
+        self.2.set_language_voice(payload)
+    }
+}
+
+impl<R: Runtime> NativeTts<R> {
+    pub fn get_lexicon(&self, payload: GetLexiconRequest) -> crate::Result<Lexicon> {
+        Ok(self.3.get(payload.book.as_deref()))
+    }
+}
+
+impl<R: Runtime> NativeTts<R> {
+    pub fn set_lexicon(&self, payload: SetLexiconRequest) -> crate::Result<()> {
+        self.3.set(payload.book.as_deref(), payload.lexicon)
+    }
+}
+
+impl<R: Runtime> NativeTts<R> {
+    pub fn import_lexicon(&self, payload: ImportLexiconRequest) -> crate::Result<Lexicon> {
+        self.3.import(payload)
+    }
+}
@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 
+use std::collections::BTreeMap;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct TTSVoice {
@@ -10,12 +12,20 @@ pub struct TTSVoice {
     pub disabled: bool,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct TTSMessageEvent {
     pub code: String, // 'boundary' | 'error' | 'end'
     pub message: Option<String>,
     pub mark: Option<String>,
+    // Word boundaries carry the position of the word in the utterance text, in UTF-16 code
+    // units like JavaScript string indices, and when it is reached.
+    #[serde(default)]
+    pub char_index: Option<u32>,
+    #[serde(default)]
+    pub char_length: Option<u32>,
+    #[serde(default)]
+    pub timestamp: Option<f64>, // Milliseconds since the utterance started
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -30,6 +40,78 @@ pub struct SpeakArgs {
     pub text: String,
     #[serde(default)]
     pub preload: bool,
+    // Whether `text` is SSML. It is parsed into `segments` before reaching the backends.
+    #[serde(default)]
+    pub ssml: bool,
+    #[serde(default)]
+    pub segments: Vec<SpeechSegment>,
+    // Speaks with this voice instead of the one set with `set_voice`, on desktop. On mobile
+    // it is the voice the voice map routes other languages away from.
+    #[serde(default)]
+    pub voice: Option<String>,
+    // The language of the utterance, that the voice map routes other scripts in it from.
+    #[serde(default)]
+    pub lang: Option<String>,
+    // The book whose pronunciation lexicon applies along with the global one.
+    #[serde(default)]
+    pub book: Option<String>,
+    // Where the utterance is in the book, for the sleep timer to notice the end of a
+    // chapter or paragraph.
+    #[serde(default)]
+    pub section: Option<u32>,
+    #[serde(default)]
+    pub paragraph: Option<u32>,
+}
+
+// A run of text spoken with the same prosody, language and interpretation.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SpeechSegment {
+    pub text: String,
+    pub offset: u32, // UTF-16 offset of `text` in the text of the whole utterance
+    pub mark: Option<String>, // Reached when the segment starts
+    pub pause: u32,  // Milliseconds of silence before the segment
+    pub rate: f32,   // Relative to the rate set with `set_rate`
+    pub pitch: f32,  // Relative to the pitch set with `set_pitch`
+    pub lang: Option<String>,
+    pub say_as: Option<String>, // The `interpret-as` of `<say-as>`
+    pub voice: Option<String>,  // Routed from `lang`, instead of the voice of the utterance
+    // How the text is pronounced, from `<sub>` and `<phoneme>` or a pronunciation lexicon.
+    pub alias: Option<String>,    // Spoken instead of the text
+    pub phoneme: Option<String>,  // Spoken instead of the text, by the engines that can
+    pub alphabet: Option<String>, // Of `phoneme`, IPA if not set
+}
+
+impl SpeechSegment {
+    pub fn new(text: String) -> Self {
+        Self {
+            text,
+            offset: 0,
+            mark: None,
+            pause: 0,
+            rate: 1.0,
+            pitch: 1.0,
+            lang: None,
+            say_as: None,
+            voice: None,
+            alias: None,
+            phoneme: None,
+            alphabet: None,
+        }
+    }
+
+    // Whether the text is spoken as written, rather than with an alias or phonemes.
+    pub fn is_pronounced_as_written(&self) -> bool {
+        self.alias.is_none() && self.phoneme.is_none()
+    }
+
+    // Whether `say_as` asks for the text to be read letter by letter.
+    pub fn is_spelled_out(&self) -> bool {
+        matches!(
+            self.say_as.as_deref(),
+            Some("characters" | "spell-out" | "verbatim")
+        )
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -89,3 +171,215 @@ pub struct UpdateMediaSessionMetadataRequest {
     pub album: Option<String>,
     pub artwork: Option<String>,
 }
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InstallVoicePackRequest {
+    pub model_path: String,          // Path of the voice's .onnx model
+    pub config_path: Option<String>, // Defaults to the .onnx.json file next to the model
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InstallVoicePackResponse {
+    pub voice: TTSVoice,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RemoveVoicePackRequest {
+    pub voice: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudiobookFormat {
+    M4b,  // AAC in an MPEG-4 audiobook
+    Opus, // Opus in Ogg
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AudiobookChapter {
+    pub title: String, // From the book TOC
+    pub text: String,
+    #[serde(default)]
+    pub ssml: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportAudiobookRequest {
+    pub path: String,
+    pub format: AudiobookFormat,
+    pub chapters: Vec<AudiobookChapter>,
+    pub voice: Option<String>, // Defaults to the voice set with `set_voice`
+    // Like those of `SpeakArgs`, for the voice map and the pronunciation lexicon of the book.
+    #[serde(default)]
+    pub lang: Option<String>,
+    #[serde(default)]
+    pub book: Option<String>,
+    // Like the media session metadata, the artwork is a data URL or a file path.
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub artwork: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportAudiobookResponse {
+    pub path: String,
+    pub duration: f64, // In seconds
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AudiobookStage {
+    Synthesizing,
+    Encoding,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AudiobookProgress {
+    pub stage: AudiobookStage,
+    pub chapter: u32, // Index of the chapter being synthesized
+    pub chapters: u32,
+    pub progress: f64, // Of the current stage, from 0 to 1
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SynthesisCacheInfo {
+    pub size: u64,  // In bytes
+    pub limit: u64, // In bytes
+    pub entries: u32,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SetSynthesisCacheLimitRequest {
+    pub limit: u64, // In bytes
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClearSynthesisCacheRequest {
+    pub voice: Option<String>, // Clears the whole cache if not set
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "camelCase")]
+pub enum SleepTimerMode {
+    Duration { minutes: f64 },
+    EndOfChapter,
+    Paragraphs { count: u32 }, // The paragraph being read counts as the first
+}
+
+fn default_fade_out() -> f64 {
+    30.0
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StartSleepTimerRequest {
+    #[serde(flatten)]
+    pub mode: SleepTimerMode,
+    #[serde(default = "default_fade_out")]
+    pub fade_out: f64, // Seconds over which a timed sleep fades out
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SleepTimerState {
+    pub active: bool,
+    pub ended: bool,             // Set when the timer has just stopped playback
+    pub remaining: Option<f64>,  // Seconds, of a timed sleep
+    pub paragraphs: Option<u32>, // Left to read, the current one included
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SetVolumeArgs {
+    pub volume: f32, // From 0 to 1
+}
+
+fn default_detect_script() -> bool {
+    true
+}
+
+// The voices that speak each language, keyed by lowercase BCP 47 tag such as `zh` or
+// `en-gb`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VoiceMap {
+    #[serde(default)]
+    pub voices: BTreeMap<String, String>,
+    // Whether runs of another script in text without a language are spoken in the
+    // language of that script, e.g. Chinese in an English sentence.
+    #[serde(default = "default_detect_script")]
+    pub detect_script: bool,
+}
+
+impl Default for VoiceMap {
+    fn default() -> Self {
+        Self {
+            voices: BTreeMap::new(),
+            detect_script: true,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SetLanguageVoiceRequest {
+    pub lang: String,
+    pub voice: Option<String>, // Removes the voice of the language if not set
+}
+
+// A pronunciation of words, like a `<lexeme>` of a W3C Pronunciation Lexicon.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Lexeme {
+    pub grapheme: String, // Matched as whole words, ignoring case
+    pub alias: Option<String>,
+    pub phoneme: Option<String>,
+    pub alphabet: Option<String>, // Of `phoneme`, IPA if not set
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Lexicon {
+    pub lang: Option<String>, // Only applies to text in this language, if set
+    pub lexemes: Vec<Lexeme>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetLexiconRequest {
+    pub book: Option<String>, // The global lexicon if not set
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SetLexiconRequest {
+    pub book: Option<String>,
+    pub lexicon: Lexicon,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportLexiconRequest {
+    pub book: Option<String>,
+    // The PLS document, given as its content or as the path of its file.
+    pub pls: Option<String>,
+    pub path: Option<String>,
+    // Whether the lexemes replace the lexicon instead of being added to it.
+    #[serde(default)]
+    pub replace: bool,
+    // Whether the lexemes of the lexicon win over imported ones of the same grapheme, like
+    // edits to those of a lexicon embedded in the book.
+    #[serde(default)]
+    pub keep_existing: bool,
+}
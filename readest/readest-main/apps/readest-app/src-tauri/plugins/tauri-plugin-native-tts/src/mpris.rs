@@ -0,0 +1,539 @@
+//! The media session of Linux desktops, an MPRIS2 player on the D-Bus session bus.
+//!
+//! While the media session is active, read aloud shows in the media controls of GNOME and
+//! KDE with the title, author and cover of the book, and media keys and Bluetooth headsets
+//! control it. Their commands are sent to the frontend as the `media-session-*` events
+//! that the Android media session sends.
+
+use serde_json::json;
+use zbus::blocking::object_server::InterfaceRef;
+use zbus::blocking::{connection, Connection};
+use zbus::interface;
+use zbus::object_server::SignalEmitter;
+use zbus::zvariant::{ObjectPath, Value};
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::desktop::Listeners;
+use crate::models::*;
+use crate::{Error, Result};
+
+const OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
+// Read aloud is a single track, whose title is the sentence being spoken.
+const TRACK_ID: &str = "/org/readest/NativeTTS/Track";
+
+fn dbus_error(e: zbus::Error) -> Error {
+    Error::NativeTTSError(format!("MPRIS: {e}"))
+}
+
+fn micros(duration: Duration) -> i64 {
+    duration.as_micros().min(i64::MAX as u128) as i64
+}
+
+// Percent-encodes a path into a `file://` URL.
+fn file_url(path: &Path) -> String {
+    let mut url = String::from("file://");
+    for byte in path.as_os_str().as_encoded_bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'/' | b'-' | b'.' | b'_' | b'~' => {
+                url.push(*byte as char)
+            }
+            _ => url.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    url
+}
+
+struct Root {
+    identity: String,
+}
+
+#[interface(name = "org.mpris.MediaPlayer2")]
+impl Root {
+    fn raise(&self) {}
+
+    fn quit(&self) {}
+
+    #[zbus(property)]
+    fn can_quit(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn can_raise(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn has_track_list(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn identity(&self) -> &str {
+        &self.identity
+    }
+
+    #[zbus(property)]
+    fn supported_uri_schemes(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    #[zbus(property)]
+    fn supported_mime_types(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+#[derive(Default)]
+struct Metadata {
+    title: Option<String>,
+    artist: Option<String>,
+    album: Option<String>,
+    art_url: Option<String>,
+}
+
+struct Player {
+    listeners: Listeners,
+    playing: bool,
+    position: Duration, // When `updated`
+    updated: Instant,
+    duration: Option<Duration>,
+    metadata: Metadata,
+    sleep_timer: Option<Duration>, // Time left until read aloud stops
+}
+
+impl Player {
+    fn current_position(&self) -> Duration {
+        match self.playing {
+            true => self.position + self.updated.elapsed(),
+            false => self.position,
+        }
+    }
+
+    fn emit(&self, event: &str) {
+        self.listeners.emit(event, json!({}));
+    }
+
+    fn seek_to(&self, position: i64) {
+        let position = Duration::from_micros(position.max(0) as u64);
+        if self.duration.is_some_and(|duration| position > duration) {
+            return;
+        }
+        self.listeners.emit(
+            "media-session-seek",
+            json!({ "position": position.as_millis() as u64 }),
+        );
+    }
+}
+
+#[interface(name = "org.mpris.MediaPlayer2.Player")]
+impl Player {
+    fn next(&self) {
+        self.emit("media-session-next");
+    }
+
+    fn previous(&self) {
+        self.emit("media-session-previous");
+    }
+
+    fn pause(&self) {
+        self.emit("media-session-pause");
+    }
+
+    fn play_pause(&self) {
+        match self.playing {
+            true => self.emit("media-session-pause"),
+            false => self.emit("media-session-play"),
+        }
+    }
+
+    fn stop(&self) {
+        self.emit("media-session-stop");
+    }
+
+    fn play(&self) {
+        self.emit("media-session-play");
+    }
+
+    fn seek(&self, offset: i64) {
+        self.seek_to(micros(self.current_position()).saturating_add(offset));
+    }
+
+    fn set_position(&self, track_id: ObjectPath<'_>, position: i64) {
+        if track_id.as_str() == TRACK_ID {
+            self.seek_to(position);
+        }
+    }
+
+    fn open_uri(&self, _uri: String) -> zbus::fdo::Result<()> {
+        Err(zbus::fdo::Error::NotSupported(
+            "Opening URIs is not supported".into(),
+        ))
+    }
+
+    #[zbus(signal)]
+    async fn seeked(emitter: &SignalEmitter<'_>, position: i64) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn playback_status(&self) -> &str {
+        match self.playing {
+            true => "Playing",
+            false => "Paused",
+        }
+    }
+
+    #[zbus(property)]
+    fn rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn minimum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn maximum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn metadata(&self) -> HashMap<&str, Value<'_>> {
+        let mut metadata = HashMap::new();
+        let track_id = ObjectPath::from_static_str_unchecked(TRACK_ID);
+        metadata.insert("mpris:trackid", Value::from(track_id));
+        if let Some(duration) = self.duration {
+            metadata.insert("mpris:length", Value::from(micros(duration)));
+        }
+        if let Some(title) = &self.metadata.title {
+            metadata.insert("xesam:title", Value::from(title.as_str()));
+        }
+        if let Some(artist) = &self.metadata.artist {
+            metadata.insert("xesam:artist", Value::from(vec![artist.as_str()]));
+        }
+        if let Some(album) = &self.metadata.album {
+            metadata.insert("xesam:album", Value::from(album.as_str()));
+        }
+        if let Some(art_url) = &self.metadata.art_url {
+            metadata.insert("mpris:artUrl", Value::from(art_url.as_str()));
+        }
+        // Shells show no timers of their own, so the time left goes in the comment.
+        if let Some(remaining) = self.sleep_timer {
+            let seconds = remaining.as_secs_f64().ceil() as u64;
+            let comment = format!("\u{23FE} {}:{:02}", seconds / 60, seconds % 60);
+            metadata.insert("xesam:comment", Value::from(vec![comment]));
+        }
+        metadata
+    }
+
+    #[zbus(property)]
+    fn volume(&self) -> f64 {
+        1.0
+    }
+
+    // Players only signal jumps of the position, with `Seeked`.
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn position(&self) -> i64 {
+        micros(self.current_position())
+    }
+
+    #[zbus(property)]
+    fn can_go_next(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_go_previous(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_play(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_pause(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_seek(&self) -> bool {
+        self.duration.is_some()
+    }
+
+    #[zbus(property)]
+    fn can_control(&self) -> bool {
+        true
+    }
+}
+
+// Builds the connection of `builder`, serving the player on it.
+fn serve(
+    builder: connection::Builder<'_>,
+    identity: &str,
+    listeners: Listeners,
+) -> zbus::Result<Connection> {
+    let root = Root {
+        identity: identity.to_string(),
+    };
+    let player = Player {
+        listeners,
+        playing: false,
+        position: Duration::ZERO,
+        updated: Instant::now(),
+        duration: None,
+        metadata: Metadata::default(),
+        sleep_timer: None,
+    };
+    builder
+        .serve_at(OBJECT_PATH, root)?
+        .serve_at(OBJECT_PATH, player)?
+        .build()
+}
+
+pub(crate) struct MediaSession {
+    connection: Connection,
+    artwork_dir: PathBuf,
+    artwork_count: u64, // Artwork files are renamed so that shells reload them
+}
+
+impl MediaSession {
+    // Publishes the player under a name of its own, so that several app instances can
+    // be controlled separately.
+    pub(crate) fn start(
+        identity: &str,
+        artwork_dir: PathBuf,
+        listeners: Listeners,
+    ) -> Result<Self> {
+        let name = identity
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .collect::<String>()
+            .to_lowercase();
+        let name = format!(
+            "org.mpris.MediaPlayer2.{name}.instance{}",
+            std::process::id()
+        );
+        let connection = connection::Builder::session()
+            .and_then(|builder| builder.name(name))
+            .and_then(|builder| serve(builder, identity, listeners))
+            .map_err(dbus_error)?;
+        Ok(Self {
+            connection,
+            artwork_dir,
+            artwork_count: 0,
+        })
+    }
+
+    fn player(&self) -> Result<InterfaceRef<Player>> {
+        self.connection
+            .object_server()
+            .interface::<_, Player>(OBJECT_PATH)
+            .map_err(dbus_error)
+    }
+
+    pub(crate) fn update_state(&self, state: &UpdateMediaSessionStateRequest) -> Result<()> {
+        let player = self.player()?;
+        let mut iface = player.get_mut();
+        iface.position = match state.position {
+            Some(position) => Duration::from_secs_f64(position.max(0.0) / 1000.0),
+            None => iface.current_position(),
+        };
+        iface.updated = Instant::now();
+        iface.playing = state.playing;
+        iface.duration = state
+            .duration
+            .map(|duration| Duration::from_secs_f64(duration.max(0.0) / 1000.0));
+
+        let emitter = player.signal_emitter();
+        zbus::block_on(async {
+            iface.playback_status_changed(emitter).await?;
+            iface.can_seek_changed(emitter).await?;
+            iface.metadata_changed(emitter).await?;
+            if state.position.is_some() {
+                Player::seeked(emitter, micros(iface.position)).await?;
+            }
+            Ok(())
+        })
+        .map_err(dbus_error)
+    }
+
+    pub(crate) fn update_metadata(
+        &mut self,
+        metadata: &UpdateMediaSessionMetadataRequest,
+    ) -> Result<()> {
+        // Empty artwork keeps the current one, like on Android.
+        let art_url = match metadata
+            .artwork
+            .as_deref()
+            .filter(|artwork| !artwork.is_empty())
+        {
+            Some(artwork) => Some(self.art_url(artwork)?),
+            None => None,
+        };
+        let player = self.player()?;
+        let mut iface = player.get_mut();
+        iface.metadata.title = metadata.title.clone();
+        iface.metadata.artist = metadata.artist.clone();
+        iface.metadata.album = metadata.album.clone();
+        if art_url.is_some() {
+            iface.metadata.art_url = art_url;
+        }
+        zbus::block_on(iface.metadata_changed(player.signal_emitter())).map_err(dbus_error)
+    }
+
+    pub(crate) fn set_sleep_timer(&self, remaining: Option<Duration>) -> Result<()> {
+        let player = self.player()?;
+        let mut iface = player.get_mut();
+        if iface.sleep_timer == remaining {
+            return Ok(());
+        }
+        iface.sleep_timer = remaining;
+        zbus::block_on(iface.metadata_changed(player.signal_emitter())).map_err(dbus_error)
+    }
+
+    // The URL of artwork given as a data URL, a file path or a web URL. Data URLs are
+    // written to a file, since MPRIS clients load artwork by URL.
+    fn art_url(&mut self, artwork: &str) -> Result<String> {
+        if artwork.starts_with("http://")
+            || artwork.starts_with("https://")
+            || artwork.starts_with("file://")
+        {
+            return Ok(artwork.to_string());
+        }
+        if !artwork.starts_with("data:") {
+            return Ok(file_url(Path::new(artwork)));
+        }
+        let (mime, data) = crate::audiobook::read_artwork(artwork)?;
+        let extension = match mime.as_str() {
+            "image/png" => "png",
+            "image/webp" => "webp",
+            _ => "jpg",
+        };
+
+        std::fs::create_dir_all(&self.artwork_dir)?;
+        for entry in std::fs::read_dir(&self.artwork_dir)?.flatten() {
+            if entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with("media-artwork-")
+            {
+                let _ = std::fs::remove_file(entry.path());
+            }
+        }
+        self.artwork_count += 1;
+        let path = self
+            .artwork_dir
+            .join(format!("media-artwork-{}.{extension}", self.artwork_count));
+        std::fs::write(&path, data)?;
+        Ok(file_url(&path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+    use std::sync::mpsc;
+    use tauri::ipc::{Channel, InvokeResponseBody};
+
+    const PLAYER: &str = "org.mpris.MediaPlayer2.Player";
+
+    // Serves a player on one end of a peer-to-peer connection and returns it, a client
+    // connection to it, and the events the player sends to the frontend.
+    fn connect() -> (Connection, Connection, mpsc::Receiver<(String, String)>) {
+        let (sender, events) = mpsc::channel();
+        let listeners = Listeners::default();
+        let names = ["play", "pause", "next", "previous", "seek"];
+        for event in names.map(|name| format!("media-session-{name}")) {
+            let sender = sender.clone();
+            let name = event.clone();
+            let channel = Channel::new(move |body| {
+                if let InvokeResponseBody::Json(payload) = body {
+                    let _ = sender.send((name.clone(), payload));
+                }
+                Ok(())
+            });
+            listeners.register(event, channel);
+        }
+        let (server, client) = UnixStream::pair().unwrap();
+        let server = std::thread::spawn(move || {
+            let builder = connection::Builder::async_io_unix_stream(server)
+                .server(zbus::Guid::generate())?
+                .p2p();
+            serve(builder, "Readest", listeners)
+        });
+        let client = connection::Builder::async_io_unix_stream(client)
+            .p2p()
+            .build()
+            .unwrap();
+        (server.join().unwrap().unwrap(), client, events)
+    }
+
+    fn call<B: serde::Serialize + zbus::zvariant::DynamicType>(
+        client: &Connection,
+        method: &str,
+        body: &B,
+    ) {
+        client
+            .call_method(None::<&str>, OBJECT_PATH, Some(PLAYER), method, body)
+            .unwrap();
+    }
+
+    fn next_event(events: &mpsc::Receiver<(String, String)>) -> (String, String) {
+        events.recv_timeout(Duration::from_secs(5)).unwrap()
+    }
+
+    #[test]
+    fn sends_player_commands_to_listeners() {
+        let (server, client, events) = connect();
+        call(&client, "PlayPause", &());
+        assert_eq!(next_event(&events).0, "media-session-play");
+
+        let player = server
+            .object_server()
+            .interface::<_, Player>(OBJECT_PATH)
+            .unwrap();
+        player.get_mut().playing = true;
+        call(&client, "PlayPause", &());
+        assert_eq!(next_event(&events).0, "media-session-pause");
+
+        call(&client, "Next", &());
+        assert_eq!(next_event(&events).0, "media-session-next");
+        call(&client, "Previous", &());
+        assert_eq!(next_event(&events).0, "media-session-previous");
+    }
+
+    #[test]
+    fn seeks_within_the_track() {
+        let (server, client, events) = connect();
+        let player = server
+            .object_server()
+            .interface::<_, Player>(OBJECT_PATH)
+            .unwrap();
+        player.get_mut().duration = Some(Duration::from_secs(10));
+        let track = ObjectPath::from_static_str_unchecked(TRACK_ID);
+        call(&client, "SetPosition", &(track.clone(), 2_500_000i64));
+        assert_eq!(
+            next_event(&events),
+            (
+                "media-session-seek".to_string(),
+                r#"{"position":2500}"#.to_string()
+            )
+        );
+        // Positions past the end of the track are ignored.
+        call(&client, "SetPosition", &(track, 20_000_000i64));
+        call(&client, "Seek", &1_000_000i64);
+        assert_eq!(
+            next_event(&events),
+            (
+                "media-session-seek".to_string(),
+                r#"{"position":1000}"#.to_string()
+            )
+        );
+    }
+}
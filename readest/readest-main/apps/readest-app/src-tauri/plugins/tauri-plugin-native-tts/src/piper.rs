@@ -0,0 +1,973 @@
+//! Offline neural speech with Piper voices.
+//!
+//! A Piper voice is an ONNX model plus a `.onnx.json` config that describes its phonemes
+//! and sample rate. Installed voices live in `piper-voices` under the app data dir. Text
+//! is turned into phonemes with espeak-ng, synthesized on the CPU with ONNX Runtime one
+//! sentence at a time, and the PCM audio is streamed to the sound server through `paplay`
+//! or `aplay` while the next sentence is synthesized. Word boundaries are placed within
+//! the audio of their sentence in proportion to their position in its text, breaks are
+//! played as silence.
+//!
+//! ONNX Runtime is loaded at runtime, either from `libonnxruntime.so` in the voices dir or
+//! from the system, so that the app starts without it and only Piper voices need it. The
+//! library is checked before ort loads it, as ort panics when it cannot, and Piper voices
+//! are not listed without it.
+
+use serde::Deserialize;
+
+use std::collections::{HashMap, VecDeque};
+use std::ffi::{CStr, CString};
+use std::io::Write;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use crate::audiobook::{Audio, Renderer};
+use crate::boundary::{self, Pacer, PlaybackClock};
+use crate::cache::{self, Entry, SynthesisCache};
+use crate::desktop::{Engine, Listeners, VoiceSettings};
+use crate::espeak::Playing;
+use crate::models::*;
+use crate::{Error, Result};
+
+// Prefix of the IDs of Piper voices, which tells them apart from the system voices.
+pub(crate) const VOICE_PREFIX: &str = "piper:";
+
+const MODEL_EXTENSION: &str = "onnx";
+const CONFIG_EXTENSION: &str = "onnx.json";
+const ONNX_RUNTIME_LIBRARY: &str = "libonnxruntime.so";
+
+// Preloaded utterances waiting to be rendered, beyond which the oldest are dropped.
+const MAX_PRELOADS: usize = 4;
+
+// Samples written to the player at the same volume, a few tenths of a second of audio.
+const PLAY_CHUNK: usize = 4096;
+
+// Phonemes that Piper models expect around and between the phonemes of a sentence.
+const PAD: &str = "_";
+const BOS: &str = "^";
+const EOS: &str = "$";
+
+#[derive(Debug, Deserialize)]
+struct AudioConfig {
+    sample_rate: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct EspeakConfig {
+    voice: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct InferenceConfig {
+    #[serde(default = "default_noise_scale")]
+    noise_scale: f32,
+    #[serde(default = "default_length_scale")]
+    length_scale: f32,
+    #[serde(default = "default_noise_w")]
+    noise_w: f32,
+}
+
+#[derive(Debug, Deserialize)]
+struct LanguageConfig {
+    code: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct VoiceConfig {
+    audio: AudioConfig,
+    espeak: EspeakConfig,
+    inference: InferenceConfig,
+    phoneme_id_map: HashMap<String, Vec<i64>>,
+    #[serde(default)]
+    num_speakers: u32,
+    language: Option<LanguageConfig>,
+    dataset: Option<String>,
+}
+
+fn default_noise_scale() -> f32 {
+    0.667
+}
+
+fn default_length_scale() -> f32 {
+    1.0
+}
+
+fn default_noise_w() -> f32 {
+    0.8
+}
+
+impl VoiceConfig {
+    fn load(path: &Path) -> Result<Self> {
+        let data = std::fs::read(path)?;
+        serde_json::from_slice(&data)
+            .map_err(|e| Error::NativeTTSError(format!("Invalid Piper voice config: {e}")))
+    }
+
+    // Maps the phonemes of a sentence to model IDs, with a pad after each of them.
+    fn phoneme_ids(&self, phonemes: &str) -> Vec<i64> {
+        let id = |phoneme: &str| self.phoneme_id_map.get(phoneme).into_iter().flatten();
+        let pad = id(PAD).copied().collect::<Vec<_>>();
+
+        let mut ids = id(BOS).copied().collect::<Vec<_>>();
+        ids.extend(&pad);
+        for phoneme in phonemes.chars() {
+            let phoneme_ids = id(phoneme.encode_utf8(&mut [0; 4])).copied();
+            let phoneme_ids = phoneme_ids.collect::<Vec<_>>();
+            if !phoneme_ids.is_empty() {
+                ids.extend(phoneme_ids);
+                ids.extend(&pad);
+            }
+        }
+        ids.extend(id(EOS));
+        ids
+    }
+}
+
+struct Voice {
+    id: String,
+    config: VoiceConfig,
+    session: ort::session::Session,
+}
+
+impl Voice {
+    fn load(dir: &Path, id: &str) -> Result<Self> {
+        let onnx_error = |e: ort::Error| Error::NativeTTSError(format!("ONNX Runtime: {e}"));
+        let config = VoiceConfig::load(&dir.join(format!("{id}.{CONFIG_EXTENSION}")))?;
+        init_onnx_runtime(dir)?;
+        let session = ort::session::Session::builder()
+            .and_then(|builder| {
+                builder.commit_from_file(dir.join(format!("{id}.{MODEL_EXTENSION}")))
+            })
+            .map_err(onnx_error)?;
+        Ok(Self {
+            id: id.to_string(),
+            config,
+            session,
+        })
+    }
+
+    // Synthesizes the phonemes of one sentence into 16-bit PCM samples.
+    fn synthesize(&mut self, phonemes: &str, rate: f32) -> Result<Vec<i16>> {
+        use ort::value::Tensor;
+
+        let onnx_error = |e: ort::Error| Error::NativeTTSError(format!("ONNX Runtime: {e}"));
+        let ids = self.config.phoneme_ids(phonemes);
+        let len = ids.len() as i64;
+        let inference = &self.config.inference;
+        let scales = vec![
+            inference.noise_scale,
+            inference.length_scale / rate.max(0.1),
+            inference.noise_w,
+        ];
+
+        let mut inputs = ort::inputs![
+            "input" => Tensor::from_array(([1, len as usize], ids)).map_err(onnx_error)?,
+            "input_lengths" => Tensor::from_array(([1], vec![len])).map_err(onnx_error)?,
+            "scales" => Tensor::from_array(([3], scales)).map_err(onnx_error)?,
+        ];
+        if self.config.num_speakers > 1 {
+            let sid = Tensor::from_array(([1], vec![0i64])).map_err(onnx_error)?;
+            inputs.push(("sid".into(), sid.into()));
+        }
+
+        let outputs = self.session.run(inputs).map_err(onnx_error)?;
+        let (_, audio) = outputs[0].try_extract_tensor::<f32>().map_err(onnx_error)?;
+
+        // Normalize the volume like Piper does before converting to 16-bit samples.
+        let peak = audio
+            .iter()
+            .fold(0.01f32, |peak, sample| peak.max(sample.abs()));
+        let scale = i16::MAX as f32 / peak;
+        Ok(audio
+            .iter()
+            .map(|sample| (sample * scale).clamp(i16::MIN as f32, i16::MAX as f32) as i16)
+            .collect())
+    }
+}
+
+// Splits `text` into the byte ranges of its sentences, leaving out blank ones.
+fn sentences(text: &str) -> Vec<Range<usize>> {
+    let mut sentences = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        let next_is_space = match chars.peek() {
+            Some((_, next)) => next.is_whitespace(),
+            None => true,
+        };
+        let ends_sentence = match c {
+            '\n' | '。' | '！' | '？' => true,
+            '.' | '!' | '?' => next_is_space,
+            _ => false,
+        };
+        if ends_sentence {
+            let end = index + c.len_utf8();
+            sentences.push(start..end);
+            start = end;
+        }
+    }
+    sentences.push(start..text.len());
+    sentences.retain(|range| !text[range.clone()].trim().is_empty());
+    sentences
+}
+
+// Phonemizes `text` with espeak-ng into one phoneme string.
+fn phonemize(text: &str, espeak_voice: &str) -> Result<String> {
+    let mut child = Command::new("espeak-ng")
+        .args(["-q", "-b", "1", "--ipa", "-v", espeak_voice, "--stdin"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(text.as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(Error::NativeTTSError(format!(
+            "espeak-ng failed to phonemize with voice {espeak_voice}"
+        )));
+    }
+    // espeak-ng puts every clause on a line of its own.
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join(" "))
+}
+
+// Starts a process that plays raw mono 16-bit PCM from its stdin.
+fn spawn_player(sample_rate: u32) -> Result<Child> {
+    let rate = sample_rate.to_string();
+    let players: [(&str, Vec<&str>); 2] = [
+        (
+            "paplay",
+            vec!["--raw", "--format=s16le", "--channels=1", "--rate", &rate],
+        ),
+        (
+            "aplay",
+            vec![
+                "-q", "-t", "raw", "-f", "S16_LE", "-c", "1", "-r", &rate, "-",
+            ],
+        ),
+    ];
+    let mut last_error = None;
+    for (binary, args) in players {
+        match Command::new(binary)
+            .args(args)
+            .stdin(Stdio::piped())
+            .spawn()
+        {
+            Ok(child) => return Ok(child),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.map_or_else(
+        || Error::NativeTTSError("No audio player".into()),
+        Into::into,
+    ))
+}
+
+// Spaces out the letters of `text` so that they are read one by one.
+fn spell_out(text: &str) -> String {
+    text.chars()
+        .filter(|c| !c.is_whitespace())
+        .map(String::from)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Writes samples to the player at the current `volume` and starts the clock with the first
+// of them. The volume is read again for every chunk, so that a fade is heard while a long
+// sentence plays. Returns false once `stop` has killed the player.
+fn play(
+    stdin: &mut Option<ChildStdin>,
+    samples: &[i16],
+    volume: &AtomicU32,
+    clock: &PlaybackClock,
+) -> bool {
+    let Some(stdin) = stdin.as_mut() else {
+        return false;
+    };
+    for chunk in samples.chunks(PLAY_CHUNK) {
+        let volume = f32::from_bits(volume.load(Ordering::Relaxed));
+        let bytes = chunk
+            .iter()
+            .map(|sample| match volume < 1.0 {
+                true => (*sample as f32 * volume.max(0.0)) as i16,
+                false => *sample,
+            })
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect::<Vec<_>>();
+        if stdin.write_all(&bytes).is_err() {
+            return false;
+        }
+        clock.start();
+    }
+    true
+}
+
+// Whether ONNX Runtime is loaded. A failure is not kept, so that a runtime installed since
+// is found.
+static ONNX_RUNTIME_LOADED: Mutex<bool> = Mutex::new(false);
+
+// The ONNX Runtime library to load: the one of the voices dir, or else the one ort looks
+// for, next to the executable and then on the library path.
+fn onnx_runtime_path(dir: &Path) -> String {
+    let bundled_runtime = dir.join(ONNX_RUNTIME_LIBRARY);
+    if bundled_runtime.exists() {
+        return bundled_runtime.to_string_lossy().into_owned();
+    }
+    match std::env::var("ORT_DYLIB_PATH") {
+        Ok(path) if !path.is_empty() => return path,
+        _ => {}
+    }
+    std::env::current_exe()
+        .ok()
+        .and_then(|exe| Some(exe.parent()?.join(ONNX_RUNTIME_LIBRARY)))
+        .filter(|path| path.exists())
+        .map(|path| path.to_string_lossy().into_owned())
+        .unwrap_or_else(|| ONNX_RUNTIME_LIBRARY.to_string())
+}
+
+fn dl_error() -> String {
+    // SAFETY: `dlerror` returns null or a C string that stays valid until the next call.
+    let error = unsafe { libc::dlerror() };
+    match error.is_null() {
+        true => "unknown error".to_string(),
+        // SAFETY: `error` is a C string, see above.
+        false => unsafe { CStr::from_ptr(error) }
+            .to_string_lossy()
+            .into_owned(),
+    }
+}
+
+// Checks that `path` is a version of ONNX Runtime that ort supports, which ort would
+// otherwise panic on when it loads the library. The library is left loaded for ort.
+fn check_onnx_runtime(path: &str) -> Result<()> {
+    let error = |reason: String| Error::NativeTTSError(format!("ONNX Runtime {path}: {reason}"));
+    let c_path = CString::new(path).map_err(|e| error(e.to_string()))?;
+    // SAFETY: `c_path` is a C string. Loading runs the initializers of the library, as
+    // loading it by ort would.
+    let library = unsafe { libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+    if library.is_null() {
+        return Err(error(dl_error()));
+    }
+    // SAFETY: `library` was returned by `dlopen` and the name is a C string.
+    let symbol = unsafe { libc::dlsym(library, c"OrtGetApiBase".as_ptr()) };
+    if symbol.is_null() {
+        // SAFETY: `library` was returned by `dlopen` and none of its symbols are used.
+        unsafe { libc::dlclose(library) };
+        return Err(error("not ONNX Runtime".into()));
+    }
+    // SAFETY: `OrtGetApiBase` has this signature and returns a valid `OrtApiBase`, whose
+    // `GetVersionString` returns a C string owned by the library, in every version.
+    let version = unsafe {
+        let get_api_base = std::mem::transmute::<
+            *mut libc::c_void,
+            unsafe extern "C" fn() -> *const ort::sys::OrtApiBase,
+        >(symbol);
+        CStr::from_ptr(((*get_api_base()).GetVersionString)())
+            .to_string_lossy()
+            .into_owned()
+    };
+    // Like ort, the minor version of the library must be at least the one of its API.
+    let minor = version
+        .split('.')
+        .nth(1)
+        .and_then(|minor| minor.parse::<u32>().ok());
+    match minor {
+        Some(minor) if minor >= ort::MINOR_VERSION => Ok(()),
+        _ => Err(error(format!(
+            "version {version} is older than 1.{}",
+            ort::MINOR_VERSION
+        ))),
+    }
+}
+
+// Loads ONNX Runtime, before any voice is loaded.
+fn init_onnx_runtime(dir: &Path) -> Result<()> {
+    let mut loaded = ONNX_RUNTIME_LOADED.lock().unwrap();
+    if *loaded {
+        return Ok(());
+    }
+    let path = onnx_runtime_path(dir);
+    check_onnx_runtime(&path)?;
+    ort::init_from(&path)
+        .commit()
+        .map_err(|e| Error::NativeTTSError(format!("ONNX Runtime: {e}")))?;
+    *loaded = true;
+    Ok(())
+}
+
+// Synthesizes segments a pause or sentence at a time. `output` gets the samples of each
+// along with the boundary events in them, timed from the start of the segments, and
+// returns false to stop. Returns whether all of the segments were synthesized.
+fn synthesize_segments(
+    voice: &mut Voice,
+    segments: &[SpeechSegment],
+    rate: f32,
+    mut output: impl FnMut(&[i16], Vec<(Duration, TTSMessageEvent)>) -> bool,
+) -> Result<bool> {
+    let sample_rate = voice.config.audio.sample_rate;
+    let espeak_voice = voice.config.espeak.voice.clone();
+    let time = |samples: usize| samples as f64 / sample_rate as f64;
+    let mut synthesized = 0;
+    // Piper voices speak one language, so the language of segments is ignored.
+    for segment in segments {
+        let silence = vec![0; segment.pause as usize * sample_rate as usize / 1000];
+        synthesized += silence.len();
+        let at = Duration::from_secs_f64(time(synthesized));
+        let marks = segment
+            .mark
+            .iter()
+            .map(|mark| (at, boundary::mark_event(mark)));
+        if !output(&silence, marks.collect()) {
+            return Ok(false);
+        }
+
+        let words = boundary::segment_words(segment);
+        let text = &segment.text;
+        // Text with a pronunciation of its own is spoken in one go.
+        let sentences = match segment.is_pronounced_as_written() {
+            true => sentences(text),
+            false => std::iter::once(0..text.len()).collect(),
+        };
+        for sentence in sentences {
+            let ipa = match segment.alphabet.as_deref() {
+                Some(alphabet) => alphabet.eq_ignore_ascii_case("ipa"),
+                None => true,
+            };
+            // Piper voices speak IPA, so IPA phonemes need no phonemizing.
+            let phonemes = match (&segment.phoneme, &segment.alias) {
+                (Some(phoneme), _) if ipa => phoneme.clone(),
+                (_, Some(alias)) => phonemize(alias, &espeak_voice)?,
+                _ => {
+                    let mut spoken = text[sentence.clone()].to_string();
+                    if segment.is_spelled_out() {
+                        spoken = spell_out(&spoken);
+                    }
+                    phonemize(&spoken, &espeak_voice)?
+                }
+            };
+            let samples = voice.synthesize(&phonemes, rate * segment.rate)?;
+
+            let start = time(synthesized);
+            let duration = time(samples.len());
+            let chars = |range: Range<usize>| text[range].chars().count() as f64;
+            let length = chars(sentence.clone()).max(1.0);
+            let events = words
+                .iter()
+                .filter(|word| sentence.contains(&word.start))
+                .map(|word| {
+                    let position = chars(sentence.start..word.start) / length;
+                    let at = Duration::from_secs_f64(start + duration * position);
+                    (at, word.event(at))
+                })
+                .collect();
+            synthesized += samples.len();
+            if !output(&samples, events) {
+                return Ok(false);
+            }
+        }
+    }
+    Ok(true)
+}
+
+// Loads `id` into `voice` unless it is loaded already.
+fn load_voice<'a>(voice: &'a mut Option<Voice>, dir: &Path, id: &str) -> Result<&'a mut Voice> {
+    if !matches!(voice, Some(voice) if voice.id == id) {
+        *voice = None;
+        *voice = Some(Voice::load(dir, id)?);
+    }
+    Ok(voice.as_mut().unwrap())
+}
+
+struct Job {
+    generation: u64,
+    utterance_id: String,
+    voice: String,
+    segments: Vec<SpeechSegment>,
+    rate: f32,
+    key: u64, // In the synthesis cache
+}
+
+struct Worker {
+    dir: PathBuf,
+    generation: Arc<AtomicU64>,
+    current: Arc<Mutex<Option<Playing>>>, // The player of the running job
+    listeners: Listeners,
+    cache: Arc<SynthesisCache>,
+    voice: Option<Voice>,   // The last used voice, kept loaded for the next job
+    volume: Arc<AtomicU32>, // Bits of the `f32` volume, applied when played
+}
+
+impl Worker {
+    fn run(mut self, jobs: mpsc::Receiver<Job>) {
+        for job in jobs {
+            let event = end_or_error(self.run_job(&job));
+            self.listeners.emit_tts_event(&job.utterance_id, event);
+        }
+    }
+
+    fn is_stopped(&self, job: &Job) -> bool {
+        job.generation != self.generation.load(Ordering::SeqCst)
+    }
+
+    fn run_job(&mut self, job: &Job) -> Result<()> {
+        if self.is_stopped(job) {
+            return Ok(());
+        }
+        // Preloaded utterances are played from the cache, others are synthesized while
+        // they are played and cached for the next time.
+        let cached = self.cache.get(job.key);
+        let mut voice = None;
+        let sample_rate = match &cached {
+            Some(entry) => entry.audio.sample_rate,
+            None => {
+                let loaded = load_voice(&mut self.voice, &self.dir, &job.voice)?;
+                let sample_rate = loaded.config.audio.sample_rate;
+                voice = Some(loaded);
+                sample_rate
+            }
+        };
+
+        let mut player = spawn_player(sample_rate)?;
+        let clock = Arc::new(PlaybackClock::default());
+        *self.current.lock().unwrap() = Some(Playing {
+            pid: player.id(),
+            clock: Arc::clone(&clock),
+        });
+        self.listeners.emit_tts_event(
+            &job.utterance_id,
+            TTSMessageEvent {
+                code: "boundary".into(),
+                message: Some("start".into()),
+                ..Default::default()
+            },
+        );
+
+        let pacer = Pacer::new(
+            Arc::clone(&clock),
+            self.listeners.clone(),
+            &job.utterance_id,
+        );
+        let mut recorded = Entry {
+            audio: Audio {
+                samples: Vec::new(),
+                sample_rate,
+            },
+            events: Vec::new(),
+        };
+        let generation = &self.generation;
+        let volume = &*self.volume;
+        let result = (|| {
+            let mut stdin = player.stdin.take();
+            let mut output = |samples: &[i16], events: Vec<(Duration, TTSMessageEvent)>| {
+                for (at, event) in &events {
+                    pacer.send(*at, event.clone());
+                }
+                recorded.audio.samples.extend_from_slice(samples);
+                recorded.events.extend(events);
+                job.generation == generation.load(Ordering::SeqCst)
+                    && play(&mut stdin, samples, volume, &clock)
+            };
+            let complete = match (&cached, voice) {
+                (Some(entry), _) => output(&entry.audio.samples, entry.events.clone()),
+                (None, Some(voice)) => synthesize_segments(voice, &job.segments, job.rate, output)?,
+                (None, None) => false,
+            };
+            // Closing stdin lets the player drain its buffer and exit.
+            drop(stdin);
+            player.wait()?;
+            Ok(complete)
+        })();
+
+        *self.current.lock().unwrap() = None;
+        pacer.finish();
+        match result {
+            Ok(complete) => {
+                if complete && cached.is_none() {
+                    if let Err(e) = self.cache.insert(job.key, &job.voice, &recorded) {
+                        log::warn!("Failed to cache synthesized speech: {e}");
+                    }
+                }
+                Ok(())
+            }
+            Err(e) => {
+                let _ = player.kill();
+                Err(e)
+            }
+        }
+    }
+}
+
+// An utterance to render into the cache ahead of being spoken.
+struct Preload {
+    utterance_id: String,
+    voice: String,
+    segments: Vec<SpeechSegment>,
+    rate: f32,
+    key: u64,
+}
+
+#[derive(Default)]
+struct PreloadQueue {
+    preloads: Mutex<VecDeque<Preload>>,
+    added: Condvar,
+}
+
+// Renders preloaded utterances into the cache, with a voice of its own so that it can run
+// while the worker plays.
+fn run_preloads(
+    dir: PathBuf,
+    queue: Arc<PreloadQueue>,
+    cache: Arc<SynthesisCache>,
+    listeners: Listeners,
+) {
+    let mut voice = None;
+    loop {
+        let preload = {
+            let preloads = queue.preloads.lock().unwrap();
+            let mut preloads = queue
+                .added
+                .wait_while(preloads, |preloads| preloads.is_empty())
+                .unwrap();
+            match preloads.pop_front() {
+                Some(preload) => preload,
+                None => continue,
+            }
+        };
+        let mut result = Ok(());
+        if cache.begin(preload.key) {
+            let rendered = load_voice(&mut voice, &dir, &preload.voice).and_then(|voice| {
+                let mut entry = Entry {
+                    audio: Audio {
+                        samples: Vec::new(),
+                        sample_rate: voice.config.audio.sample_rate,
+                    },
+                    events: Vec::new(),
+                };
+                synthesize_segments(voice, &preload.segments, preload.rate, |samples, events| {
+                    entry.audio.samples.extend_from_slice(samples);
+                    entry.events.extend(events);
+                    true
+                })?;
+                Ok(entry)
+            });
+            cache.finish(preload.key, &preload.voice, rendered.as_ref().ok());
+            result = rendered.map(|_| ());
+        }
+        listeners.emit_tts_event(&preload.utterance_id, end_or_error(result));
+    }
+}
+
+fn end_or_error(result: Result<()>) -> TTSMessageEvent {
+    match result {
+        Ok(()) => TTSMessageEvent {
+            code: "end".into(),
+            message: None,
+            ..Default::default()
+        },
+        Err(e) => TTSMessageEvent {
+            code: "error".into(),
+            message: Some(e.to_string()),
+            ..Default::default()
+        },
+    }
+}
+
+// Renders segments to memory with a Piper voice, for audiobooks.
+pub(crate) struct PiperRenderer {
+    voice: Voice,
+    rate: f32,
+}
+
+impl Renderer for PiperRenderer {
+    fn render(&mut self, segments: &[SpeechSegment]) -> Result<Audio> {
+        let mut samples = Vec::new();
+        synthesize_segments(&mut self.voice, segments, self.rate, |chunk, _| {
+            samples.extend_from_slice(chunk);
+            true
+        })?;
+        Ok(Audio {
+            samples,
+            sample_rate: self.voice.config.audio.sample_rate,
+        })
+    }
+}
+
+pub(crate) struct Piper {
+    dir: PathBuf,
+    jobs: Mutex<mpsc::Sender<Job>>,
+    generation: Arc<AtomicU64>,
+    current: Arc<Mutex<Option<Playing>>>,
+    cache: Arc<SynthesisCache>,
+    preloads: Arc<PreloadQueue>,
+    listeners: Listeners,
+    volume: Arc<AtomicU32>, // Of the worker, the cache keeps the full volume
+}
+
+impl Piper {
+    pub(crate) fn new(dir: PathBuf, cache_dir: PathBuf, listeners: Listeners) -> Self {
+        let (sender, jobs) = mpsc::channel();
+        let generation = Arc::new(AtomicU64::new(0));
+        let current = Arc::new(Mutex::new(None));
+        let cache = Arc::new(SynthesisCache::open(cache_dir));
+        let volume = Arc::new(AtomicU32::new(1f32.to_bits()));
+        let worker = Worker {
+            dir: dir.clone(),
+            generation: Arc::clone(&generation),
+            current: Arc::clone(&current),
+            listeners: listeners.clone(),
+            cache: Arc::clone(&cache),
+            voice: None,
+            volume: Arc::clone(&volume),
+        };
+        std::thread::spawn(move || worker.run(jobs));
+
+        let preloads = Arc::new(PreloadQueue::default());
+        {
+            let dir = dir.clone();
+            let preloads = Arc::clone(&preloads);
+            let cache = Arc::clone(&cache);
+            let listeners = listeners.clone();
+            std::thread::spawn(move || run_preloads(dir, preloads, cache, listeners));
+        }
+
+        Self {
+            dir,
+            jobs: Mutex::new(sender),
+            generation,
+            current,
+            cache,
+            preloads,
+            listeners,
+            volume,
+        }
+    }
+
+    pub(crate) fn cache(&self) -> &SynthesisCache {
+        &self.cache
+    }
+
+    // The voice ID of `settings` without the prefix, and the cache key of `segments`
+    // spoken with it.
+    fn voice_and_key(
+        settings: &VoiceSettings,
+        segments: &[SpeechSegment],
+    ) -> Result<(String, u64)> {
+        let voice = settings
+            .voice
+            .as_deref()
+            .and_then(|voice| voice.strip_prefix(VOICE_PREFIX))
+            .ok_or_else(|| Error::NativeTTSError("No Piper voice selected".into()))?;
+        let key = cache::key(voice, settings.rate, segments);
+        Ok((voice.to_string(), key))
+    }
+
+    fn signal_current(&self, sig: libc::c_int) -> Result<()> {
+        match &*self.current.lock().unwrap() {
+            Some(playing) => playing.signal(sig),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn renderer(&self, voice: &str, rate: f32) -> Result<PiperRenderer> {
+        let id = voice.strip_prefix(VOICE_PREFIX).unwrap_or(voice);
+        Ok(PiperRenderer {
+            voice: Voice::load(&self.dir, id)?,
+            rate,
+        })
+    }
+
+    fn voice(&self, id: &str) -> Result<TTSVoice> {
+        let config = VoiceConfig::load(&self.dir.join(format!("{id}.{CONFIG_EXTENSION}")))?;
+        let lang = config
+            .language
+            .map(|language| language.code)
+            .unwrap_or(config.espeak.voice)
+            .replace('_', "-");
+        Ok(TTSVoice {
+            id: format!("{VOICE_PREFIX}{id}"),
+            name: config.dataset.unwrap_or_else(|| id.to_string()),
+            lang,
+            disabled: false,
+        })
+    }
+
+    // Copies a voice model and its config into the voices dir. The config defaults to the
+    // `.onnx.json` file next to the model, as Piper voices are distributed.
+    pub(crate) fn install_voice(
+        &self,
+        model_path: &Path,
+        config_path: Option<&Path>,
+    ) -> Result<TTSVoice> {
+        let id = model_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(&format!(".{MODEL_EXTENSION}")))
+            .filter(|id| !id.is_empty())
+            .ok_or_else(|| Error::NativeTTSError("Voice models must be .onnx files".into()))?;
+        let config_path = match config_path {
+            Some(path) => path.to_path_buf(),
+            None => model_path.with_extension(CONFIG_EXTENSION),
+        };
+        // Validate the config before installing anything.
+        VoiceConfig::load(&config_path)?;
+
+        std::fs::create_dir_all(&self.dir)?;
+        std::fs::copy(
+            &config_path,
+            self.dir.join(format!("{id}.{CONFIG_EXTENSION}")),
+        )?;
+        std::fs::copy(model_path, self.dir.join(format!("{id}.{MODEL_EXTENSION}")))?;
+        self.voice(id)
+    }
+
+    pub(crate) fn remove_voice(&self, voice: &str) -> Result<()> {
+        let id = voice.strip_prefix(VOICE_PREFIX).unwrap_or(voice);
+        if id.is_empty() || id.contains(['/', '\\']) {
+            return Err(Error::NativeTTSError(format!("Invalid voice {voice}")));
+        }
+        for extension in [MODEL_EXTENSION, CONFIG_EXTENSION] {
+            match std::fs::remove_file(self.dir.join(format!("{id}.{extension}"))) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        self.cache.clear(Some(id));
+        Ok(())
+    }
+}
+
+impl Engine for Piper {
+    fn voices(&self) -> Result<Vec<TTSVoice>> {
+        let Ok(entries) = std::fs::read_dir(&self.dir) else {
+            return Ok(Vec::new());
+        };
+        // The voices cannot speak without ONNX Runtime.
+        if let Err(e) = init_onnx_runtime(&self.dir) {
+            log::warn!("Piper voices are unavailable: {e}");
+            return Ok(Vec::new());
+        }
+        let mut voices = entries
+            .filter_map(|entry| {
+                let name = entry.ok()?.file_name().into_string().ok()?;
+                let id = name.strip_suffix(&format!(".{MODEL_EXTENSION}"))?;
+                self.voice(id).ok()
+            })
+            .collect::<Vec<_>>();
+        voices.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(voices)
+    }
+
+    fn speak(
+        &self,
+        utterance_id: &str,
+        segments: &[SpeechSegment],
+        settings: &VoiceSettings,
+    ) -> Result<()> {
+        self.stop()?;
+        let (voice, key) = Self::voice_and_key(settings, segments)?;
+        self.set_volume(settings.volume)?;
+        let job = Job {
+            generation: self.generation.load(Ordering::SeqCst),
+            utterance_id: utterance_id.to_string(),
+            voice,
+            segments: segments.to_vec(),
+            rate: settings.rate,
+            key,
+        };
+        self.jobs
+            .lock()
+            .unwrap()
+            .send(job)
+            .map_err(|_| Error::NativeTTSError("Piper worker has stopped".into()))
+    }
+
+    fn preload(
+        &self,
+        utterance_id: &str,
+        segments: &[SpeechSegment],
+        settings: &VoiceSettings,
+    ) -> Result<bool> {
+        let (voice, key) = Self::voice_and_key(settings, segments)?;
+        let mut preloads = self.preloads.preloads.lock().unwrap();
+        // Only the next few utterances are worth rendering ahead.
+        while preloads.len() >= MAX_PRELOADS {
+            if let Some(dropped) = preloads.pop_front() {
+                self.listeners
+                    .emit_tts_event(&dropped.utterance_id, end_or_error(Ok(())));
+            }
+        }
+        preloads.push_back(Preload {
+            utterance_id: utterance_id.to_string(),
+            voice,
+            segments: segments.to_vec(),
+            rate: settings.rate,
+            key,
+        });
+        self.preloads.added.notify_one();
+        Ok(true)
+    }
+
+    fn set_volume(&self, volume: f32) -> Result<()> {
+        self.volume.store(volume.to_bits(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn pause(&self) -> Result<()> {
+        self.signal_current(libc::SIGSTOP)
+    }
+
+    fn resume(&self) -> Result<()> {
+        self.signal_current(libc::SIGCONT)
+    }
+
+    fn stop(&self) -> Result<()> {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.signal_current(libc::SIGKILL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_missing_onnx_runtimes() {
+        let e = check_onnx_runtime("/nonexistent/libonnxruntime.so").unwrap_err();
+        assert!(e.to_string().contains("/nonexistent/libonnxruntime.so"));
+        assert!(check_onnx_runtime("bad\0path").is_err());
+    }
+
+    #[test]
+    fn reports_libraries_that_are_not_onnx_runtime() {
+        let e = check_onnx_runtime("libc.so.6").unwrap_err();
+        assert!(e.to_string().contains("not ONNX Runtime"));
+    }
+
+    #[test]
+    fn prefers_the_runtime_of_the_voices_dir() {
+        let dir = std::env::temp_dir().join(format!("piper-runtime-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        assert_ne!(
+            onnx_runtime_path(&dir),
+            dir.join(ONNX_RUNTIME_LIBRARY).to_string_lossy()
+        );
+        // A broken runtime is an error rather than a panic of ort.
+        std::fs::write(dir.join(ONNX_RUNTIME_LIBRARY), "not a library").unwrap();
+        let path = onnx_runtime_path(&dir);
+        let result = init_onnx_runtime(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(path, dir.join(ONNX_RUNTIME_LIBRARY).to_string_lossy());
+        assert!(result.is_err());
+        assert!(!*ONNX_RUNTIME_LOADED.lock().unwrap());
+    }
+}
@@ -0,0 +1,341 @@
+//! The sleep timer of read aloud.
+//!
+//! The timer runs here rather than in the webview, whose timers are throttled while the app
+//! is in the background or the screen is locked. A timed sleep fades the volume out over
+//! its last seconds and then pauses playback. The end of a chapter or of a number of
+//! paragraphs is noticed when the first utterance past it is about to be spoken, which is
+//! then not spoken at all.
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::models::*;
+
+// Steps of the volume while fading out.
+const VOLUME_STEP: f32 = 0.05;
+
+// What the timer asks of the platform it runs on.
+pub(crate) enum Action {
+    SetVolume(f32),
+    Pause,
+    Report(SleepTimerState),
+}
+
+type Act = Arc<dyn Fn(Action) + Send + Sync>;
+
+// The book position of the utterance being spoken.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Position {
+    section: Option<u32>,
+    paragraph: Option<u32>,
+}
+
+struct Timer {
+    mode: SleepTimerMode,
+    deadline: Option<Instant>,
+    section: Option<u32>, // The chapter being read when the timer started
+    paragraphs: u32,      // Left to read, the current one included
+    act: Act,
+}
+
+#[derive(Default)]
+struct State {
+    timer: Option<Timer>,
+    generation: u64, // Changed whenever the timer is started or cancelled
+    position: Position,
+}
+
+#[derive(Default)]
+pub(crate) struct SleepTimer {
+    state: Arc<(Mutex<State>, Condvar)>,
+}
+
+impl Timer {
+    fn state(&self) -> SleepTimerState {
+        SleepTimerState {
+            active: true,
+            ended: false,
+            remaining: self.deadline.map(|deadline| {
+                deadline
+                    .saturating_duration_since(Instant::now())
+                    .as_secs_f64()
+            }),
+            paragraphs: match self.mode {
+                SleepTimerMode::Paragraphs { .. } => Some(self.paragraphs),
+                _ => None,
+            },
+        }
+    }
+}
+
+fn ended_state() -> SleepTimerState {
+    SleepTimerState {
+        ended: true,
+        ..Default::default()
+    }
+}
+
+impl SleepTimer {
+    pub(crate) fn start(
+        &self,
+        request: StartSleepTimerRequest,
+        act: impl Fn(Action) + Send + Sync + 'static,
+    ) {
+        let act: Act = Arc::new(act);
+        let (lock, changed) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        state.generation += 1;
+        let deadline = match request.mode {
+            SleepTimerMode::Duration { minutes } => {
+                Some(Instant::now() + Duration::from_secs_f64(minutes.max(0.0) * 60.0))
+            }
+            _ => None,
+        };
+        let paragraphs = match request.mode {
+            SleepTimerMode::Paragraphs { count } => count.max(1),
+            _ => 0,
+        };
+        let timer = Timer {
+            mode: request.mode,
+            deadline,
+            section: state.position.section,
+            paragraphs,
+            act: Arc::clone(&act),
+        };
+        let report = timer.state();
+        state.timer = Some(timer);
+        changed.notify_all();
+
+        if let Some(deadline) = deadline {
+            let shared = Arc::clone(&self.state);
+            let generation = state.generation;
+            let fade_out = Duration::from_secs_f64(request.fade_out.max(0.0));
+            let act = Arc::clone(&act);
+            std::thread::spawn(move || run(shared, generation, deadline, fade_out, act));
+        }
+        drop(state);
+        act(Action::Report(report));
+    }
+
+    pub(crate) fn cancel(&self) {
+        let (lock, changed) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        state.generation += 1;
+        if let Some(timer) = state.timer.take() {
+            drop(state);
+            changed.notify_all();
+            (timer.act)(Action::Report(SleepTimerState::default()));
+        }
+    }
+
+    pub(crate) fn state(&self) -> SleepTimerState {
+        let state = self.state.0.lock().unwrap();
+        state.timer.as_ref().map(Timer::state).unwrap_or_default()
+    }
+
+    // Notes the position of an utterance about to be spoken, and returns false if the
+    // timer ends before it.
+    pub(crate) fn should_speak(&self, args: &SpeakArgs) -> bool {
+        if args.preload {
+            return true;
+        }
+        let (lock, _) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        let position = Position {
+            section: args.section,
+            paragraph: args.paragraph,
+        };
+        let previous = std::mem::replace(&mut state.position, position);
+        let Some(timer) = state.timer.as_mut() else {
+            return true;
+        };
+
+        let mut report = None;
+        let ended = match timer.mode {
+            SleepTimerMode::Duration { .. } => false,
+            SleepTimerMode::EndOfChapter => match (timer.section, position.section) {
+                (Some(start), Some(current)) => current != start,
+                (None, current) => {
+                    timer.section = current;
+                    false
+                }
+                _ => false,
+            },
+            SleepTimerMode::Paragraphs { .. } => {
+                let next = previous.paragraph.is_some() && position.paragraph != previous.paragraph;
+                if next {
+                    timer.paragraphs = timer.paragraphs.saturating_sub(1);
+                    report = Some(timer.state());
+                }
+                timer.paragraphs == 0
+            }
+        };
+        let act = Arc::clone(&timer.act);
+        if ended {
+            state.timer = None;
+            state.generation += 1;
+        }
+        drop(state);
+
+        match ended {
+            true => act(Action::Report(ended_state())),
+            false => report
+                .into_iter()
+                .for_each(|report| act(Action::Report(report))),
+        }
+        !ended
+    }
+}
+
+// Counts down a timed sleep, reporting the time left every second.
+fn run(
+    shared: Arc<(Mutex<State>, Condvar)>,
+    generation: u64,
+    deadline: Instant,
+    fade_out: Duration,
+    act: Act,
+) {
+    let (lock, changed) = &*shared;
+    let mut volume = 1.0;
+    let mut reported = None;
+    loop {
+        let state = lock.lock().unwrap();
+        if state.generation != generation {
+            drop(state);
+            if volume != 1.0 {
+                act(Action::SetVolume(1.0));
+            }
+            return;
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let report = state.timer.as_ref().map(Timer::state);
+        drop(state);
+
+        if remaining.is_zero() {
+            {
+                let mut state = lock.lock().unwrap();
+                if state.generation != generation {
+                    continue;
+                }
+                state.timer = None;
+                state.generation += 1;
+            }
+            act(Action::Pause);
+            // Playback resumes at the normal volume.
+            act(Action::SetVolume(1.0));
+            act(Action::Report(ended_state()));
+            return;
+        }
+
+        let target = match remaining < fade_out {
+            true => remaining.as_secs_f32() / fade_out.as_secs_f32(),
+            false => 1.0,
+        };
+        if (volume - target).abs() >= VOLUME_STEP {
+            volume = (target / VOLUME_STEP).ceil() * VOLUME_STEP;
+            act(Action::SetVolume(volume));
+        }
+        let seconds = remaining.as_secs_f64().ceil() as u64;
+        if reported != Some(seconds) {
+            reported = Some(seconds);
+            report
+                .into_iter()
+                .for_each(|report| act(Action::Report(report)));
+        }
+
+        // Wake up on the next whole second left, or when the timer changes.
+        let tick = remaining - Duration::from_secs(seconds.saturating_sub(1));
+        let state = lock.lock().unwrap();
+        if state.generation == generation {
+            let _ = changed.wait_timeout(state, tick.max(Duration::from_millis(10)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    fn start(timer: &SleepTimer, mode: SleepTimerMode) -> mpsc::Receiver<SleepTimerState> {
+        let (sender, reports) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let request = StartSleepTimerRequest {
+            mode,
+            fade_out: 0.0,
+        };
+        timer.start(request, move |action| {
+            if let Action::Report(state) = action {
+                let _ = sender.lock().unwrap().send(state);
+            }
+        });
+        reports
+    }
+
+    fn speak(timer: &SleepTimer, section: Option<u32>, paragraph: Option<u32>) -> bool {
+        let args = serde_json::from_value::<SpeakArgs>(serde_json::json!({
+            "text": "Text",
+            "section": section,
+            "paragraph": paragraph,
+        }))
+        .unwrap();
+        timer.should_speak(&args)
+    }
+
+    #[test]
+    fn ends_at_the_next_chapter() {
+        let timer = SleepTimer::default();
+        assert!(speak(&timer, Some(3), Some(7)));
+        let reports = start(&timer, SleepTimerMode::EndOfChapter);
+        assert!(reports.recv().unwrap().active);
+        assert!(speak(&timer, Some(3), Some(8)));
+        assert!(speak(&timer, Some(3), None));
+        assert!(!speak(&timer, Some(4), Some(0)));
+        assert!(reports.recv().unwrap().ended);
+        // The timer is gone once it has ended.
+        assert!(!timer.state().active);
+        assert!(speak(&timer, Some(5), Some(0)));
+    }
+
+    #[test]
+    fn takes_the_chapter_of_the_first_utterance_when_started_before_it() {
+        let timer = SleepTimer::default();
+        let _reports = start(&timer, SleepTimerMode::EndOfChapter);
+        assert!(speak(&timer, Some(2), Some(0)));
+        assert!(speak(&timer, Some(2), Some(1)));
+        assert!(!speak(&timer, Some(3), Some(0)));
+    }
+
+    #[test]
+    fn counts_the_current_paragraph_as_the_first() {
+        let timer = SleepTimer::default();
+        assert!(speak(&timer, Some(1), Some(5)));
+        let reports = start(&timer, SleepTimerMode::Paragraphs { count: 2 });
+        assert_eq!(reports.recv().unwrap().paragraphs, Some(2));
+        // More sentences of the same paragraph count nothing.
+        assert!(speak(&timer, Some(1), Some(5)));
+        assert!(speak(&timer, Some(1), Some(6)));
+        assert_eq!(reports.recv().unwrap().paragraphs, Some(1));
+        assert!(speak(&timer, Some(1), Some(6)));
+        assert!(!speak(&timer, Some(1), Some(7)));
+        assert!(reports.recv().unwrap().ended);
+    }
+
+    #[test]
+    fn ignores_preloads_and_cancelled_timers() {
+        let timer = SleepTimer::default();
+        assert!(speak(&timer, Some(1), Some(0)));
+        let _reports = start(&timer, SleepTimerMode::Paragraphs { count: 1 });
+        let preload = serde_json::from_value::<SpeakArgs>(serde_json::json!({
+            "text": "Text",
+            "preload": true,
+            "section": 1,
+            "paragraph": 1,
+        }))
+        .unwrap();
+        assert!(timer.should_speak(&preload));
+        timer.cancel();
+        assert!(speak(&timer, Some(1), Some(1)));
+        assert!(speak(&timer, Some(2), Some(0)));
+    }
+}
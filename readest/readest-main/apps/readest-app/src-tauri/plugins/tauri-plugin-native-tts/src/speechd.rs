@@ -0,0 +1,538 @@
+//! A client for Speech Dispatcher, the speech service of Linux desktops.
+//!
+//! It talks SSIP, Speech Dispatcher's text protocol, over its Unix socket, so that no
+//! native library is needed at build time. Replies to commands and the notifications about
+//! the messages being spoken arrive on the same socket; a reader thread routes the former
+//! back to the waiting command and turns the latter into `TTSMessageEvent`s.
+//!
+//! Segments are sent as SSML with a mark before every word, so that the index mark
+//! notifications tell which word and segment are being spoken.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+use crate::boundary::{self, PlaybackClock, Word};
+use crate::desktop::{Engine, Listeners, VoiceSettings};
+use crate::models::*;
+use crate::ssml::{self, MARK_PREFIX, WORD_MARK_PREFIX};
+use crate::{Error, Result};
+
+// Time given to a Speech Dispatcher started by us to create its socket.
+const SPAWN_TIMEOUT: Duration = Duration::from_secs(3);
+
+// Reply to `SPEAK` carrying the ID of the queued message.
+const MESSAGE_QUEUED: u16 = 225;
+const INDEX_MARK: u16 = 700;
+const BEGIN: u16 = 701;
+const END: u16 = 702;
+const CANCELED: u16 = 703;
+const PAUSED: u16 = 704;
+const RESUMED: u16 = 705;
+
+#[derive(Debug)]
+struct Reply {
+    code: u16,
+    lines: Vec<String>,
+}
+
+impl Reply {
+    fn is_ok(&self) -> bool {
+        (200..300).contains(&self.code)
+    }
+}
+
+struct Utterance {
+    id: String,
+    words: Vec<Word>,
+    marks: Vec<Option<String>>, // The marks of the segments
+    clock: PlaybackClock,
+}
+
+#[derive(Default)]
+struct Messages {
+    speaking: Option<Utterance>, // Utterance whose `SPEAK` awaits its reply
+    utterances: HashMap<String, Utterance>, // By message ID
+}
+
+struct Connection {
+    stream: UnixStream,
+    replies: mpsc::Receiver<Reply>,
+}
+
+pub(crate) struct SpeechDispatcher {
+    connection: Mutex<Connection>,
+    messages: Arc<Mutex<Messages>>,
+}
+
+fn socket_path() -> Option<PathBuf> {
+    if let Ok(address) = std::env::var("SPEECHD_ADDRESS") {
+        return address.strip_prefix("unix_socket:").map(PathBuf::from);
+    }
+    let runtime_dir = std::env::var_os("XDG_RUNTIME_DIR")?;
+    Some(PathBuf::from(runtime_dir).join("speech-dispatcher/speechd.sock"))
+}
+
+// Maps a rate or pitch factor, 1.0 being normal, onto SSIP's -100..=100 scale so that
+// doubling and halving reach the ends of the scale.
+fn ssip_scale(factor: f32) -> i32 {
+    (factor.max(0.01).log2() * 100.0)
+        .round()
+        .clamp(-100.0, 100.0) as i32
+}
+
+// Maps a volume from 0 to 1 onto SSIP's -100..=100 scale, with 100 the normal volume.
+fn ssip_volume(volume: f32) -> i32 {
+    (volume.clamp(0.0, 1.0) * 200.0 - 100.0).round() as i32
+}
+
+// SSIP commands end at a line break, so one in a voice name would end the command early
+// and have the rest of the name run as another command.
+fn ssip_voice(voice: &str) -> Result<&str> {
+    if voice.trim().is_empty() || voice.contains(['\r', '\n']) {
+        return Err(Error::NativeTTSError(format!(
+            "Invalid Speech Dispatcher voice: {voice:?}"
+        )));
+    }
+    Ok(voice)
+}
+
+fn connect_socket() -> Result<UnixStream> {
+    let path =
+        socket_path().ok_or_else(|| Error::NativeTTSError("No Speech Dispatcher socket".into()))?;
+    if let Ok(stream) = UnixStream::connect(&path) {
+        return Ok(stream);
+    }
+
+    // Like libspeechd, start the user's Speech Dispatcher on demand.
+    Command::new("speech-dispatcher").arg("--spawn").status()?;
+    let deadline = std::time::Instant::now() + SPAWN_TIMEOUT;
+    loop {
+        match UnixStream::connect(&path) {
+            Ok(stream) => return Ok(stream),
+            Err(e) if std::time::Instant::now() >= deadline => return Err(e.into()),
+            Err(_) => std::thread::sleep(Duration::from_millis(100)),
+        }
+    }
+}
+
+// Reads replies and notifications until the connection closes.
+fn read_replies(
+    stream: UnixStream,
+    replies: mpsc::Sender<Reply>,
+    messages: Arc<Mutex<Messages>>,
+    listeners: Listeners,
+) {
+    let mut reader = BufReader::new(stream);
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match reader.read_line(&mut line) {
+            Ok(0) | Err(_) => return,
+            Ok(_) => {}
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        // Every line is `NNN-text` except for the last one of a reply, which is `NNN text`.
+        let (Some(code), Some(separator)) = (
+            line.get(..3).and_then(|code| code.parse::<u16>().ok()),
+            line.get(3..4),
+        ) else {
+            continue;
+        };
+        lines.push(line.get(4..).unwrap_or_default().to_string());
+        if separator == "-" {
+            continue;
+        }
+
+        let reply = Reply {
+            code,
+            lines: std::mem::take(&mut lines),
+        };
+        if (INDEX_MARK..800).contains(&code) {
+            notify(&reply, &messages, &listeners);
+            continue;
+        }
+
+        let speaking = messages.lock().unwrap().speaking.take();
+        if let (MESSAGE_QUEUED, Some(utterance)) = (code, speaking) {
+            if let Some(message_id) = reply.lines.first() {
+                let mut messages = messages.lock().unwrap();
+                messages.utterances.insert(message_id.clone(), utterance);
+            }
+        }
+        if replies.send(reply).is_err() {
+            return;
+        }
+    }
+}
+
+fn notify(reply: &Reply, messages: &Mutex<Messages>, listeners: &Listeners) {
+    let Some(message_id) = reply.lines.first() else {
+        return;
+    };
+    let (utterance_id, event) = {
+        let mut messages = messages.lock().unwrap();
+        let Some(utterance) = messages.utterances.get_mut(message_id) else {
+            return;
+        };
+        match reply.code {
+            BEGIN => utterance.clock.start(),
+            PAUSED => utterance.clock.pause(),
+            RESUMED => utterance.clock.resume(),
+            _ => {}
+        }
+        let event = event(reply, utterance);
+        let utterance_id = utterance.id.clone();
+        if matches!(reply.code, END | CANCELED) {
+            messages.utterances.remove(message_id);
+        }
+        (utterance_id, event)
+    };
+    if let Some(event) = event {
+        listeners.emit_tts_event(&utterance_id, event);
+    }
+}
+
+fn event(reply: &Reply, utterance: &Utterance) -> Option<TTSMessageEvent> {
+    let index = |prefix: &str| {
+        let mark = reply.lines.get(2)?;
+        mark.strip_prefix(prefix)?.parse::<usize>().ok()
+    };
+    let event = match reply.code {
+        INDEX_MARK => {
+            if let Some(word) = index(WORD_MARK_PREFIX).and_then(|i| utterance.words.get(i)) {
+                word.event(utterance.clock.position())
+            } else {
+                let mark = index(MARK_PREFIX).and_then(|i| utterance.marks.get(i)?.as_ref())?;
+                boundary::mark_event(mark)
+            }
+        }
+        BEGIN => TTSMessageEvent {
+            code: "boundary".into(),
+            message: Some("start".into()),
+            ..Default::default()
+        },
+        END | CANCELED => TTSMessageEvent {
+            code: "end".into(),
+            message: None,
+            ..Default::default()
+        },
+        // Pausing and resuming need no event.
+        _ => return None,
+    };
+    Some(event)
+}
+
+// The voices of a `LIST SYNTHESIS_VOICES` reply, every line of which but the final status
+// is `name\tlanguage\tvariant`.
+fn voice_list(reply: &Reply) -> Vec<TTSVoice> {
+    let lines = &reply.lines[..reply.lines.len().saturating_sub(1)];
+    lines
+        .iter()
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            let name = fields.next()?.to_string();
+            let lang = fields.next().unwrap_or_default().to_string();
+            Some(TTSVoice {
+                id: name.clone(),
+                name,
+                lang,
+                disabled: false,
+            })
+        })
+        .collect()
+}
+
+impl SpeechDispatcher {
+    pub(crate) fn connect(listeners: Listeners) -> Result<Self> {
+        let stream = connect_socket()?;
+        let messages = Arc::new(Mutex::new(Messages::default()));
+        let (sender, replies) = mpsc::channel();
+        {
+            let stream = stream.try_clone()?;
+            let messages = Arc::clone(&messages);
+            std::thread::spawn(move || read_replies(stream, sender, messages, listeners));
+        }
+
+        let client = Self {
+            connection: Mutex::new(Connection { stream, replies }),
+            messages,
+        };
+        let user = std::env::var("USER").unwrap_or_else(|_| "user".into());
+        client.command(&format!("SET SELF CLIENT_NAME {user}:readest:tts"))?;
+        client.command("SET SELF NOTIFICATION ALL ON")?;
+        client.command("SET SELF SSML_MODE on")?;
+        Ok(client)
+    }
+
+    fn send(connection: &mut Connection, data: &str) -> Result<Reply> {
+        connection.stream.write_all(data.as_bytes())?;
+        let reply = connection
+            .replies
+            .recv_timeout(Duration::from_secs(5))
+            .map_err(|_| Error::NativeTTSError("Speech Dispatcher did not reply".into()))?;
+        if !reply.is_ok() {
+            return Err(Error::NativeTTSError(format!(
+                "Speech Dispatcher error {}: {}",
+                reply.code,
+                reply.lines.join(" ")
+            )));
+        }
+        Ok(reply)
+    }
+
+    fn command(&self, command: &str) -> Result<Reply> {
+        let mut connection = self.connection.lock().unwrap();
+        Self::send(&mut connection, &format!("{command}\r\n"))
+    }
+}
+
+impl Engine for SpeechDispatcher {
+    fn voices(&self) -> Result<Vec<TTSVoice>> {
+        let reply = self.command("LIST SYNTHESIS_VOICES")?;
+        Ok(voice_list(&reply))
+    }
+
+    fn speak(
+        &self,
+        utterance_id: &str,
+        segments: &[SpeechSegment],
+        settings: &VoiceSettings,
+    ) -> Result<()> {
+        self.command("CANCEL SELF")?;
+        self.command(&format!("SET SELF RATE {}", ssip_scale(settings.rate)))?;
+        self.command(&format!("SET SELF PITCH {}", ssip_scale(settings.pitch)))?;
+        self.set_volume(settings.volume)?;
+        if let Some(voice) = &settings.voice {
+            let voice = ssip_voice(voice)?;
+            self.command(&format!("SET SELF SYNTHESIS_VOICE {voice}"))?;
+        }
+
+        let words = segments
+            .iter()
+            .map(boundary::segment_words)
+            .collect::<Vec<_>>();
+        let ssml = ssml::render(segments, &words);
+
+        // Lines starting with a dot are escaped with another one, a lone dot ends the text.
+        let mut data = ssml
+            .lines()
+            .map(|line| match line.starts_with('.') {
+                true => format!(".{line}\r\n"),
+                false => format!("{line}\r\n"),
+            })
+            .collect::<String>();
+        data.push_str(".\r\n");
+
+        let mut connection = self.connection.lock().unwrap();
+        Self::send(&mut connection, "SPEAK\r\n")?;
+        self.messages.lock().unwrap().speaking = Some(Utterance {
+            id: utterance_id.to_string(),
+            words: words.into_iter().flatten().collect(),
+            marks: segments
+                .iter()
+                .map(|segment| segment.mark.clone())
+                .collect(),
+            clock: PlaybackClock::default(),
+        });
+        Self::send(&mut connection, &data).map(|_| ())
+    }
+
+    // Speech Dispatcher passes the volume on to output modules that apply it to the audio
+    // they are playing.
+    fn set_volume(&self, volume: f32) -> Result<()> {
+        self.command(&format!("SET SELF VOLUME {}", ssip_volume(volume)))
+            .map(|_| ())
+    }
+
+    fn pause(&self) -> Result<()> {
+        self.command("PAUSE SELF").map(|_| ())
+    }
+
+    fn resume(&self) -> Result<()> {
+        self.command("RESUME SELF").map(|_| ())
+    }
+
+    fn stop(&self) -> Result<()> {
+        self.command("CANCEL SELF").map(|_| ())
+    }
+}
+
+impl Drop for SpeechDispatcher {
+    fn drop(&mut self) {
+        if let Ok(connection) = self.connection.get_mut() {
+            let _ = connection.stream.write_all(b"QUIT\r\n");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::desktop::TTS_EVENTS;
+    use serde_json::Value;
+    use tauri::ipc::{Channel, InvokeResponseBody};
+
+    struct Reader {
+        stream: UnixStream, // What Speech Dispatcher writes to
+        replies: mpsc::Receiver<Reply>,
+        messages: Arc<Mutex<Messages>>,
+        events: mpsc::Receiver<Value>,
+    }
+
+    // Reads replies from one end of a socket pair, as from Speech Dispatcher's socket.
+    fn reader() -> Reader {
+        let (sender, events) = mpsc::channel();
+        let listeners = Listeners::default();
+        let channel = Channel::new(move |body| {
+            if let InvokeResponseBody::Json(payload) = body {
+                let _ = sender.send(serde_json::from_str(&payload).unwrap());
+            }
+            Ok(())
+        });
+        listeners.register(TTS_EVENTS.to_string(), channel);
+
+        let (stream, client) = UnixStream::pair().unwrap();
+        let messages = Arc::new(Mutex::new(Messages::default()));
+        let (sender, replies) = mpsc::channel();
+        {
+            let messages = Arc::clone(&messages);
+            std::thread::spawn(move || read_replies(client, sender, messages, listeners));
+        }
+        Reader {
+            stream,
+            replies,
+            messages,
+            events,
+        }
+    }
+
+    impl Reader {
+        fn write(&mut self, lines: &[&str]) {
+            let data = lines.iter().map(|line| format!("{line}\r\n"));
+            self.stream
+                .write_all(data.collect::<String>().as_bytes())
+                .unwrap();
+        }
+
+        fn reply(&self) -> Reply {
+            self.replies.recv_timeout(Duration::from_secs(5)).unwrap()
+        }
+
+        fn event(&self) -> Value {
+            self.events.recv_timeout(Duration::from_secs(5)).unwrap()
+        }
+
+        // Queues an utterance of "Hello world" with a mark as message `message_id`.
+        fn queue(&mut self, message_id: &str) {
+            self.messages.lock().unwrap().speaking = Some(Utterance {
+                id: "utterance".into(),
+                words: boundary::words("Hello world"),
+                marks: vec![Some("sentence-1".into())],
+                clock: PlaybackClock::default(),
+            });
+            self.write(&[&format!("225-{message_id}"), "225 OK MESSAGE QUEUED"]);
+            let reply = self.reply();
+            assert_eq!(reply.code, MESSAGE_QUEUED);
+            assert_eq!(reply.lines, [message_id, "OK MESSAGE QUEUED"]);
+        }
+
+        // Sends a notification about `message_id` like Speech Dispatcher does.
+        fn notify(&mut self, code: u16, message_id: &str, mark: Option<&str>, text: &str) {
+            let mut lines = vec![format!("{code}-{message_id}"), format!("{code}-7")];
+            lines.extend(mark.map(|mark| format!("{code}-{mark}")));
+            lines.push(format!("{code} {text}"));
+            self.write(&lines.iter().map(String::as_str).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn reads_multi_line_replies() {
+        let mut reader = reader();
+        reader.write(&[
+            "garbage",
+            "249-espeak-ng\ten\tnone",
+            "249-Czech\tcs\tMALE1",
+            "249 OK VOICE LIST SENT",
+            "208 OK ALL COMMANDS CANCELLED",
+            "410 ERR MISSING PARAMETER",
+        ]);
+
+        let reply = reader.reply();
+        assert_eq!(reply.code, 249);
+        assert!(reply.is_ok());
+        let voices = voice_list(&reply);
+        let voices = voices
+            .iter()
+            .map(|voice| (voice.id.as_str(), voice.lang.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(voices, [("espeak-ng", "en"), ("Czech", "cs")]);
+
+        let reply = reader.reply();
+        assert_eq!((reply.code, reply.lines.len()), (208, 1));
+        let reply = reader.reply();
+        assert_eq!(reply.lines, ["ERR MISSING PARAMETER"]);
+        assert!(!reply.is_ok());
+    }
+
+    #[test]
+    fn turns_notifications_into_events() {
+        let mut reader = reader();
+        reader.queue("21");
+
+        reader.notify(BEGIN, "21", None, "BEGIN");
+        let event = reader.event();
+        assert_eq!(event["utteranceId"], "utterance");
+        assert_eq!(
+            (&event["code"], &event["message"]),
+            (&"boundary".into(), &"start".into())
+        );
+
+        reader.notify(INDEX_MARK, "21", Some("m0"), "INDEX MARK");
+        let event = reader.event();
+        assert_eq!(
+            (&event["message"], &event["mark"]),
+            (&"mark".into(), &"sentence-1".into())
+        );
+
+        reader.notify(PAUSED, "21", None, "PAUSED");
+        reader.notify(RESUMED, "21", None, "RESUMED");
+        reader.notify(INDEX_MARK, "21", Some("w1"), "INDEX MARK");
+        let event = reader.event();
+        assert_eq!(event["message"], "word");
+        assert_eq!(
+            (&event["charIndex"], &event["charLength"]),
+            (&6.into(), &5.into())
+        );
+
+        // Notifications about other messages and unknown marks are ignored.
+        reader.notify(BEGIN, "22", None, "BEGIN");
+        reader.notify(INDEX_MARK, "21", Some("w9"), "INDEX MARK");
+        reader.notify(END, "21", None, "END");
+        assert_eq!(reader.event()["code"], "end");
+        assert!(reader.messages.lock().unwrap().utterances.is_empty());
+        assert!(reader.events.try_recv().is_err());
+    }
+
+    #[test]
+    fn ends_cancelled_messages() {
+        let mut reader = reader();
+        reader.queue("5");
+        reader.notify(CANCELED, "5", None, "CANCELED");
+        assert_eq!(reader.event()["code"], "end");
+        assert!(reader.messages.lock().unwrap().utterances.is_empty());
+    }
+
+    #[test]
+    fn rejects_voices_that_would_break_commands() {
+        assert_eq!(ssip_voice("Czech").unwrap(), "Czech");
+        for voice in ["", " ", "Czech\r\nQUIT", "Czech\nSPEAK"] {
+            assert!(ssip_voice(voice).is_err());
+        }
+    }
+}
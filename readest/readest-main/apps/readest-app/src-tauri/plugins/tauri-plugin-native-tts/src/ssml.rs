@@ -0,0 +1,495 @@
+//! SSML support shared by all backends.
+//!
+//! SSML is parsed here rather than by each platform's speech service, so that `<mark>`,
+//! `<break>`, `<prosody>`, `<lang>`, `<voice>`, `<say-as>`, `<sub>` and `<phoneme>` behave
+//! the same everywhere. The input is split into `SpeechSegment`s that the backends speak
+//! one after another; other elements only contribute their text.
+
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+
+#[cfg(all(desktop, target_os = "linux"))]
+use crate::boundary::Word;
+use crate::models::SpeechSegment;
+use crate::{Error, Result};
+
+// Prefixes of the names of the marks written by `render`.
+#[cfg(all(desktop, target_os = "linux"))]
+pub(crate) const MARK_PREFIX: &str = "m";
+#[cfg(all(desktop, target_os = "linux"))]
+pub(crate) const WORD_MARK_PREFIX: &str = "w";
+
+// Default pause of `<break>` by `strength`, in milliseconds.
+const BREAK_STRENGTHS: &[(&str, u32)] = &[
+    ("none", 0),
+    ("x-weak", 100),
+    ("weak", 250),
+    ("medium", 500),
+    ("strong", 750),
+    ("x-strong", 1000),
+];
+
+#[derive(Debug, Clone)]
+struct Style {
+    rate: f32,
+    pitch: f32,
+    lang: Option<String>,
+    say_as: Option<String>,
+    voice: Option<String>,
+    alias: Option<String>,
+    phoneme: Option<String>,
+    alphabet: Option<String>,
+}
+
+struct Parser {
+    styles: Vec<Style>, // One per open element, the innermost last
+    segments: Vec<SpeechSegment>,
+    current: SpeechSegment,
+    offset: u32, // UTF-16 length of the text so far
+}
+
+fn invalid(e: impl std::fmt::Display) -> Error {
+    Error::NativeTTSError(format!("Invalid SSML: {e}"))
+}
+
+fn attribute(element: &BytesStart, name: &str) -> Result<Option<String>> {
+    match element.try_get_attribute(name).map_err(invalid)? {
+        Some(attribute) => Ok(Some(
+            attribute.unescape_value().map_err(invalid)?.into_owned(),
+        )),
+        None => Ok(None),
+    }
+}
+
+// Parses a `<break>` duration such as `500ms` or `1.5s`.
+fn parse_time(time: &str) -> Option<u32> {
+    let time = time.trim();
+    let (value, scale) = match time.strip_suffix("ms") {
+        Some(value) => (value, 1.0),
+        None => (time.strip_suffix('s')?, 1000.0),
+    };
+    let value = value.trim().parse::<f32>().ok()?;
+    (value >= 0.0).then(|| (value * scale).round() as u32)
+}
+
+// Parses a `<prosody>` rate into a factor of the current rate.
+fn parse_rate(rate: &str) -> Option<f32> {
+    let rate = rate.trim();
+    let factor = match rate {
+        "x-slow" => 0.5,
+        "slow" => 0.75,
+        "medium" | "default" => 1.0,
+        "fast" => 1.25,
+        "x-fast" => 1.75,
+        _ => match rate.strip_suffix('%') {
+            // `+10%` and `-10%` change the rate, `150%` is a multiple of it.
+            Some(percent) if percent.starts_with(['+', '-']) => {
+                1.0 + percent.parse::<f32>().ok()? / 100.0
+            }
+            Some(percent) => percent.parse::<f32>().ok()? / 100.0,
+            None => rate.parse::<f32>().ok()?,
+        },
+    };
+    (factor > 0.0).then_some(factor)
+}
+
+// Parses a `<prosody>` pitch into a factor of the current pitch. Absolute pitches in Hz
+// depend on the voice and are ignored.
+fn parse_pitch(pitch: &str) -> Option<f32> {
+    let pitch = pitch.trim();
+    let factor = match pitch {
+        "x-low" => 0.5,
+        "low" => 0.75,
+        "medium" | "default" => 1.0,
+        "high" => 1.25,
+        "x-high" => 1.5,
+        _ => {
+            if let Some(semitones) = pitch.strip_suffix("st") {
+                2f32.powf(semitones.parse::<f32>().ok()? / 12.0)
+            } else if let Some(percent) = pitch.strip_suffix('%') {
+                1.0 + percent.parse::<f32>().ok()? / 100.0
+            } else {
+                return None;
+            }
+        }
+    };
+    (factor > 0.0).then_some(factor)
+}
+
+impl Parser {
+    fn style(&self) -> &Style {
+        // The root style is never popped.
+        self.styles.last().unwrap()
+    }
+
+    fn segment(&self) -> SpeechSegment {
+        let style = self.style();
+        SpeechSegment {
+            offset: self.offset,
+            rate: style.rate,
+            pitch: style.pitch,
+            lang: style.lang.clone(),
+            say_as: style.say_as.clone(),
+            voice: style.voice.clone(),
+            alias: style.alias.clone(),
+            phoneme: style.phoneme.clone(),
+            alphabet: style.alphabet.clone(),
+            ..SpeechSegment::new(String::new())
+        }
+    }
+
+    // Ends the current segment, so that what follows starts a new one.
+    fn flush(&mut self) {
+        let next = self.segment();
+        let segment = std::mem::replace(&mut self.current, next);
+        if !segment.text.is_empty() || segment.mark.is_some() || segment.pause > 0 {
+            self.segments.push(segment);
+        }
+    }
+
+    fn text(&mut self, text: &str) {
+        if self.current.text.is_empty() {
+            // Apply the style in effect where the text starts.
+            let mut segment = self.segment();
+            segment.mark = self.current.mark.take();
+            segment.pause = self.current.pause;
+            self.current = segment;
+        }
+        self.current.text.push_str(text);
+        self.offset += text.encode_utf16().count() as u32;
+    }
+
+    fn start(&mut self, element: &BytesStart) -> Result<()> {
+        let mut style = self.style().clone();
+        if let Some(lang) = attribute(element, "xml:lang")? {
+            style.lang = Some(lang);
+        }
+        match element.local_name().as_ref() {
+            b"prosody" => {
+                if let Some(rate) = attribute(element, "rate")?.as_deref().and_then(parse_rate) {
+                    style.rate *= rate;
+                }
+                if let Some(pitch) = attribute(element, "pitch")?
+                    .as_deref()
+                    .and_then(parse_pitch)
+                {
+                    style.pitch *= pitch;
+                }
+            }
+            b"say-as" => style.say_as = attribute(element, "interpret-as")?,
+            b"voice" => {
+                if let Some(name) = attribute(element, "name")? {
+                    style.voice = Some(name).filter(|name| !name.is_empty());
+                }
+            }
+            b"sub" => style.alias = attribute(element, "alias")?,
+            b"phoneme" => {
+                style.phoneme = attribute(element, "ph")?;
+                style.alphabet = attribute(element, "alphabet")?;
+            }
+            _ => {}
+        }
+        self.styles.push(style);
+        self.restyle();
+        Ok(())
+    }
+
+    fn end(&mut self) {
+        if self.styles.len() > 1 {
+            self.styles.pop();
+        }
+        self.restyle();
+    }
+
+    // Starts a new segment if the style has changed under the current one.
+    fn restyle(&mut self) {
+        let style = self.style();
+        let current = &self.current;
+        if current.text.is_empty() {
+            return;
+        }
+        if current.rate != style.rate
+            || current.pitch != style.pitch
+            || current.lang != style.lang
+            || current.say_as != style.say_as
+            || current.voice != style.voice
+            || current.alias != style.alias
+            || current.phoneme != style.phoneme
+            || current.alphabet != style.alphabet
+        {
+            self.flush();
+        }
+    }
+
+    fn empty(&mut self, element: &BytesStart) -> Result<()> {
+        match element.local_name().as_ref() {
+            b"mark" => {
+                let name = attribute(element, "name")?.unwrap_or_default();
+                if !self.current.text.is_empty() || self.current.mark.is_some() {
+                    self.flush();
+                }
+                self.current.mark = Some(name);
+            }
+            b"break" => {
+                let time = attribute(element, "time")?.as_deref().and_then(parse_time);
+                let strength = attribute(element, "strength")?;
+                let pause = time.unwrap_or_else(|| {
+                    let strength = strength.as_deref().unwrap_or("medium");
+                    BREAK_STRENGTHS
+                        .iter()
+                        .find(|(name, _)| *name == strength)
+                        .map_or(500, |(_, pause)| *pause)
+                });
+                if !self.current.text.is_empty() || self.current.mark.is_some() {
+                    self.flush();
+                }
+                self.current.pause += pause;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+// Parses SSML into the segments to speak. The text of the segments put together is the
+// text content of the SSML, which the offsets of word boundaries refer to.
+pub(crate) fn parse(ssml: &str) -> Result<Vec<SpeechSegment>> {
+    let mut reader = Reader::from_str(ssml);
+    let root = Style {
+        rate: 1.0,
+        pitch: 1.0,
+        lang: None,
+        say_as: None,
+        voice: None,
+        alias: None,
+        phoneme: None,
+        alphabet: None,
+    };
+    let mut parser = Parser {
+        styles: vec![root],
+        segments: Vec::new(),
+        current: SpeechSegment::new(String::new()),
+        offset: 0,
+    };
+    loop {
+        match reader.read_event().map_err(invalid)? {
+            Event::Start(element) => match element.local_name().as_ref() {
+                // Marks and breaks have no content, even when written with an end tag.
+                b"mark" | b"break" => parser.empty(&element)?,
+                _ => parser.start(&element)?,
+            },
+            Event::End(element) => match element.local_name().as_ref() {
+                b"mark" | b"break" => {}
+                _ => parser.end(),
+            },
+            Event::Empty(element) => parser.empty(&element)?,
+            Event::Text(text) => parser.text(&text.unescape().map_err(invalid)?),
+            Event::CData(text) => parser.text(&String::from_utf8_lossy(&text)),
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    parser.flush();
+    Ok(parser.segments)
+}
+
+// The segments of `args`, parsing its text if it is SSML.
+pub(crate) fn segments(args: &crate::models::SpeakArgs) -> Result<Vec<SpeechSegment>> {
+    if !args.segments.is_empty() {
+        return Ok(args.segments.clone());
+    }
+    if args.ssml {
+        return parse(&args.text);
+    }
+    Ok(vec![SpeechSegment::new(args.text.clone())])
+}
+
+// Writes segments back as SSML for the engines that parse it themselves. The mark of the
+// segment at index `i` is named `m<i>`, and the words of `words`, given by segment, get a
+// mark named `w<j>` with `j` counted over all of them.
+#[cfg(all(desktop, target_os = "linux"))]
+pub(crate) fn render(segments: &[SpeechSegment], words: &[Vec<Word>]) -> String {
+    use quick_xml::escape::escape;
+
+    let mut ssml = String::from("<speak>");
+    let mut index = 0;
+    for (i, segment) in segments.iter().enumerate() {
+        if segment.pause > 0 {
+            ssml.push_str(&format!("<break time=\"{}ms\"/>", segment.pause));
+        }
+        if segment.mark.is_some() {
+            ssml.push_str(&format!("<mark name=\"{MARK_PREFIX}{i}\"/>"));
+        }
+        let mut close = Vec::new();
+        if let Some(lang) = &segment.lang {
+            ssml.push_str(&format!("<voice xml:lang=\"{}\">", escape(lang)));
+            close.push("</voice>");
+        }
+        if segment.rate != 1.0 || segment.pitch != 1.0 {
+            ssml.push_str(&format!(
+                "<prosody rate=\"{:.0}%\" pitch=\"{:+.0}%\">",
+                segment.rate * 100.0,
+                (segment.pitch - 1.0) * 100.0
+            ));
+            close.push("</prosody>");
+        }
+        if let Some(say_as) = &segment.say_as {
+            ssml.push_str(&format!("<say-as interpret-as=\"{}\">", escape(say_as)));
+            close.push("</say-as>");
+        }
+
+        // The content of `<sub>` and `<phoneme>` is text only, so their word mark goes
+        // before them.
+        if !segment.is_pronounced_as_written() {
+            if words.get(i).is_some_and(|words| !words.is_empty()) {
+                ssml.push_str(&format!("<mark name=\"{WORD_MARK_PREFIX}{index}\"/>"));
+                index += 1;
+            }
+            if let Some(alias) = &segment.alias {
+                ssml.push_str(&format!("<sub alias=\"{}\">", escape(alias)));
+                close.push("</sub>");
+            } else if let Some(phoneme) = &segment.phoneme {
+                let alphabet = segment.alphabet.as_deref().unwrap_or("ipa");
+                ssml.push_str(&format!(
+                    "<phoneme alphabet=\"{}\" ph=\"{}\">",
+                    escape(alphabet),
+                    escape(phoneme)
+                ));
+                close.push("</phoneme>");
+            }
+            ssml.push_str(&escape(&segment.text));
+            close.iter().rev().for_each(|tag| ssml.push_str(tag));
+            continue;
+        }
+
+        let mut end = 0;
+        for word in words.get(i).into_iter().flatten() {
+            ssml.push_str(&escape(&segment.text[end..word.start]));
+            ssml.push_str(&format!("<mark name=\"{WORD_MARK_PREFIX}{index}\"/>"));
+            ssml.push_str(&escape(&segment.text[word.start..word.end]));
+            end = word.end;
+            index += 1;
+        }
+        ssml.push_str(&escape(&segment.text[end..]));
+        close.iter().rev().for_each(|tag| ssml.push_str(tag));
+    }
+    ssml.push_str("</speak>");
+    ssml
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(segments: &[SpeechSegment]) -> Vec<&str> {
+        segments
+            .iter()
+            .map(|segment| segment.text.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn parses_times() {
+        assert_eq!(parse_time("500ms"), Some(500));
+        assert_eq!(parse_time(" 1.5s "), Some(1500));
+        assert_eq!(parse_time("0s"), Some(0));
+        assert_eq!(parse_time("-1s"), None);
+        assert_eq!(parse_time("500"), None);
+        assert_eq!(parse_time("fast"), None);
+    }
+
+    #[test]
+    fn parses_rates() {
+        assert_eq!(parse_rate("x-slow"), Some(0.5));
+        assert_eq!(parse_rate("default"), Some(1.0));
+        assert_eq!(parse_rate("+10%"), Some(1.1));
+        assert_eq!(parse_rate("-50%"), Some(0.5));
+        assert_eq!(parse_rate("150%"), Some(1.5));
+        assert_eq!(parse_rate("2"), Some(2.0));
+        assert_eq!(parse_rate("-100%"), None);
+        assert_eq!(parse_rate("0"), None);
+        assert_eq!(parse_rate("quick"), None);
+    }
+
+    #[test]
+    fn parses_pitches() {
+        assert_eq!(parse_pitch("x-high"), Some(1.5));
+        assert_eq!(parse_pitch("+20%"), Some(1.2));
+        assert_eq!(parse_pitch("-20%"), Some(0.8));
+        assert_eq!(parse_pitch("12st"), Some(2.0));
+        assert_eq!(parse_pitch("-12st"), Some(0.5));
+        assert_eq!(parse_pitch("200Hz"), None);
+        assert_eq!(parse_pitch("-100%"), None);
+    }
+
+    #[test]
+    fn multiplies_nested_prosody() {
+        let segments = parse(concat!(
+            "<speak>Hello <prosody rate=\"150%\">big ",
+            "<prosody rate=\"x-slow\" pitch=\"+10%\">world</prosody></prosody>!</speak>"
+        ))
+        .unwrap();
+        assert_eq!(texts(&segments), ["Hello ", "big ", "world", "!"]);
+        let prosody = segments
+            .iter()
+            .map(|segment| (segment.rate, segment.pitch))
+            .collect::<Vec<_>>();
+        assert_eq!(prosody, [(1.0, 1.0), (1.5, 1.0), (0.75, 1.1), (1.0, 1.0)]);
+    }
+
+    #[test]
+    fn reads_marks_and_breaks_with_end_tags() {
+        let segments = parse(concat!(
+            "<speak>a<mark name=\"x\"></mark>b<break time=\"300ms\"></break>c",
+            "<break strength=\"weak\"/><break/><mark name=\"y\"/></speak>"
+        ))
+        .unwrap();
+        assert_eq!(texts(&segments), ["a", "b", "c", ""]);
+        assert_eq!(segments[1].mark.as_deref(), Some("x"));
+        assert_eq!(segments[2].pause, 300);
+        // Pauses in a row add up, and a mark at the end is kept without text.
+        assert_eq!(segments[3].pause, 750);
+        assert_eq!(segments[3].mark.as_deref(), Some("y"));
+    }
+
+    #[test]
+    fn counts_offsets_in_utf16() {
+        let segments = parse("<speak>😀 caf&amp;é<mark name=\"m\"/>next</speak>").unwrap();
+        assert_eq!(texts(&segments), ["😀 caf&é", "next"]);
+        assert_eq!(segments[0].offset, 0);
+        // The emoji takes two code units and the entity one character.
+        assert_eq!(segments[1].offset, 8);
+    }
+
+    #[test]
+    fn inherits_xml_lang() {
+        let segments = parse(concat!(
+            "<speak xml:lang=\"fr\">Bonjour <voice xml:lang=\"en\">hello ",
+            "<emphasis>there</emphasis></voice> <s>ami</s></speak>"
+        ))
+        .unwrap();
+        assert_eq!(texts(&segments), ["Bonjour ", "hello there", " ami"]);
+        let langs = segments
+            .iter()
+            .map(|segment| segment.lang.as_deref())
+            .collect::<Vec<_>>();
+        assert_eq!(langs, [Some("fr"), Some("en"), Some("fr")]);
+    }
+
+    #[test]
+    fn splits_segments_on_alphabet() {
+        let segments = parse(concat!(
+            "<speak><phoneme alphabet=\"ipa\" ph=\"a\">x",
+            "<phoneme alphabet=\"x-sampa\" ph=\"a\">y</phoneme></phoneme></speak>"
+        ))
+        .unwrap();
+        assert_eq!(texts(&segments), ["x", "y"]);
+        assert_eq!(segments[0].alphabet.as_deref(), Some("ipa"));
+        assert_eq!(segments[1].alphabet.as_deref(), Some("x-sampa"));
+    }
+
+    #[test]
+    fn rejects_malformed_ssml() {
+        assert!(parse("<speak><prosody rate=\"fast>a</speak>").is_err());
+    }
+}
@@ -0,0 +1,561 @@
+//! Routing of the languages of an utterance to voices.
+//!
+//! Every segment is given the voice of its language from the voice map, so that a book
+//! mixing languages is read by a voice of each within one utterance. The language of a
+//! segment is its `xml:lang`, or, for text without one, the language of its script:
+//! runs of another script than that of the utterance are split off into segments of
+//! their own. Languages without a voice in the map fall back to the voice of the
+//! utterance when it speaks them, then to the first voice that does, and finally to the
+//! voice of the utterance with the language left for the speech service to handle.
+
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::models::*;
+use crate::Result;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Script {
+    Latin,
+    Han,
+    Kana,
+    Hangul,
+    Cyrillic,
+    Greek,
+    Arabic,
+    Hebrew,
+    Thai,
+    Devanagari,
+}
+
+impl Script {
+    // Runs of fewer letters are read in the script around them, so that a stray letter,
+    // like the A of "A型", does not switch voices.
+    fn min_run(self) -> usize {
+        match self {
+            Script::Han | Script::Kana | Script::Hangul => 1,
+            _ => 2,
+        }
+    }
+
+    // The language that text in the script is most likely in.
+    fn lang(self) -> &'static str {
+        match self {
+            Script::Latin => "en",
+            Script::Han => "zh",
+            Script::Kana => "ja",
+            Script::Hangul => "ko",
+            Script::Cyrillic => "ru",
+            Script::Greek => "el",
+            Script::Arabic => "ar",
+            Script::Hebrew => "he",
+            Script::Thai => "th",
+            Script::Devanagari => "hi",
+        }
+    }
+
+    // Whether the script is written as part of `other`, like kanji in Japanese.
+    fn is_part_of(self, other: Script) -> bool {
+        self == other || (self == Script::Han && matches!(other, Script::Kana | Script::Hangul))
+    }
+}
+
+// The script of a letter, or None for spaces, digits, punctuation and symbols, which
+// belong to the run around them.
+fn script(c: char) -> Option<Script> {
+    let script = match c as u32 {
+        0x0370..=0x03FF | 0x1F00..=0x1FFF => Script::Greek,
+        0x0400..=0x052F => Script::Cyrillic,
+        0x0590..=0x05FF => Script::Hebrew,
+        0x0600..=0x06FF | 0x0750..=0x077F => Script::Arabic,
+        0x0900..=0x097F => Script::Devanagari,
+        0x0E00..=0x0E7F => Script::Thai,
+        0x1100..=0x11FF | 0x3130..=0x318F | 0xAC00..=0xD7AF => Script::Hangul,
+        0x3040..=0x30FF | 0x31F0..=0x31FF | 0xFF66..=0xFF9F => Script::Kana,
+        0x3400..=0x4DBF | 0x4E00..=0x9FFF | 0xF900..=0xFAFF | 0x20000..=0x2FA1F => Script::Han,
+        _ if c.is_alphabetic() && (c as u32) < 0x0250 => Script::Latin,
+        0x1E00..=0x1EFF => Script::Latin,
+        _ => return None,
+    };
+    Some(script)
+}
+
+// The script that a language is written in.
+fn script_of_lang(lang: &str) -> Script {
+    match primary(lang).as_str() {
+        "zh" | "yue" => Script::Han,
+        "ja" => Script::Kana,
+        "ko" => Script::Hangul,
+        "ru" | "uk" | "be" | "bg" | "sr" | "mk" | "kk" | "mn" => Script::Cyrillic,
+        "el" => Script::Greek,
+        "ar" | "fa" | "ur" => Script::Arabic,
+        "he" | "yi" => Script::Hebrew,
+        "th" => Script::Thai,
+        "hi" | "mr" | "ne" | "sa" => Script::Devanagari,
+        _ => Script::Latin,
+    }
+}
+
+// Lowercases a language tag and separates its subtags with hyphens, so that `zh_CN` and
+// `zh-cn` are the same.
+pub(crate) fn normalize(lang: &str) -> String {
+    lang.trim().replace('_', "-").to_lowercase()
+}
+
+// The primary subtag of a language tag, `zh` of `zh-Hant-TW`.
+pub(crate) fn primary(lang: &str) -> String {
+    let lang = normalize(lang);
+    match lang.split_once('-') {
+        Some((primary, _)) => primary.to_string(),
+        None => lang,
+    }
+}
+
+// The script most of the letters of `text` are in.
+fn dominant_script(text: &str) -> Option<Script> {
+    let mut counts: Vec<(Script, usize)> = Vec::new();
+    for script in text.chars().filter_map(script) {
+        match counts.iter_mut().find(|(counted, _)| *counted == script) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((script, 1)),
+        }
+    }
+    counts
+        .into_iter()
+        .max_by_key(|(_, count)| *count)
+        .map(|(script, _)| script)
+}
+
+// Splits the text of a segment into runs by script. Runs in `base`, or too short to be
+// read on their own, get no language.
+fn split_by_script(text: &str, base: Script) -> Vec<(&str, Option<Script>)> {
+    let mut runs: Vec<(usize, Option<Script>, usize)> = Vec::new(); // Start, script, letters
+    for (index, c) in text.char_indices() {
+        let Some(script) = script(c) else {
+            continue;
+        };
+        let script = Some(script).filter(|script| !script.is_part_of(base));
+        match runs.last_mut() {
+            Some((_, last, letters)) if *last == script => *letters += 1,
+            // Neutral characters before the first letter belong to its run.
+            None => runs.push((0, script, 1)),
+            Some(_) => runs.push((index, script, 1)),
+        }
+    }
+    for run in runs.iter_mut() {
+        if run.1.is_some_and(|script| run.2 < script.min_run()) {
+            run.1 = None;
+        }
+    }
+    runs.dedup_by(|next, run| {
+        if next.1 == run.1 {
+            run.2 += next.2;
+            true
+        } else {
+            false
+        }
+    });
+    let mut split = Vec::new();
+    for (i, (start, script, _)) in runs.iter().enumerate() {
+        let end = runs.get(i + 1).map_or(text.len(), |next| next.0);
+        split.push((&text[*start..end], *script));
+    }
+    split
+}
+
+// Splits the runs of other scripts than `base` off `segment`, giving them their language.
+fn detect_languages(segment: SpeechSegment, base: Option<Script>) -> Vec<SpeechSegment> {
+    let Some(base) = base.or_else(|| dominant_script(&segment.text)) else {
+        return vec![segment];
+    };
+    let runs = split_by_script(&segment.text, base);
+    if runs.len() < 2 {
+        return vec![segment];
+    }
+    let mut offset = segment.offset;
+    let mut segments = Vec::new();
+    for (i, (text, script)) in runs.into_iter().enumerate() {
+        let mut part = SpeechSegment {
+            text: text.to_string(),
+            offset,
+            lang: script.map(|script| script.lang().to_string()),
+            ..segment.clone()
+        };
+        if i > 0 {
+            // The mark and the pause come before the first run.
+            part.mark = None;
+            part.pause = 0;
+        }
+        offset += text.encode_utf16().count() as u32;
+        segments.push(part);
+    }
+    segments
+}
+
+// The voice that speaks `lang` in a voice list, preferring an exact match of the tag.
+fn voice_of_lang(voices: &[TTSVoice], lang: &str) -> Option<String> {
+    let lang = normalize(lang);
+    let primary = primary(&lang);
+    let usable = || voices.iter().filter(|voice| !voice.disabled);
+    usable()
+        .find(|voice| normalize(&voice.lang) == lang)
+        .or_else(|| usable().find(|voice| self::primary(&voice.lang) == primary))
+        .map(|voice| voice.id.clone())
+}
+
+pub(crate) struct VoiceRouter {
+    map: Mutex<VoiceMap>,
+    path: Option<PathBuf>, // Where the map is saved
+}
+
+impl VoiceRouter {
+    // Loads the map saved at `path`, if there is one.
+    pub(crate) fn load(path: Option<PathBuf>) -> Self {
+        let map = path
+            .as_ref()
+            .and_then(|path| std::fs::read(path).ok())
+            .and_then(|data| match serde_json::from_slice(&data) {
+                Ok(map) => Some(map),
+                Err(e) => {
+                    log::warn!("Ignoring the invalid voice map: {e}");
+                    None
+                }
+            })
+            .unwrap_or_default();
+        Self {
+            map: Mutex::new(map),
+            path,
+        }
+    }
+
+    pub(crate) fn get(&self) -> VoiceMap {
+        self.map.lock().unwrap().clone()
+    }
+
+    pub(crate) fn set(&self, mut map: VoiceMap) -> Result<()> {
+        map.voices = map
+            .voices
+            .into_iter()
+            .filter(|(lang, voice)| !lang.trim().is_empty() && !voice.is_empty())
+            .map(|(lang, voice)| (normalize(&lang), voice))
+            .collect();
+        let mut current = self.map.lock().unwrap();
+        self.save(&map)?;
+        *current = map;
+        Ok(())
+    }
+
+    pub(crate) fn set_language_voice(&self, request: SetLanguageVoiceRequest) -> Result<()> {
+        let mut map = self.get();
+        let lang = normalize(&request.lang);
+        match request.voice.filter(|voice| !voice.is_empty()) {
+            Some(voice) => map.voices.insert(lang, voice),
+            None => map.voices.remove(&lang),
+        };
+        self.set(map)
+    }
+
+    fn save(&self, map: &VoiceMap) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let data = serde_json::to_vec_pretty(map).map_err(std::io::Error::from)?;
+        std::fs::write(path, data)?;
+        Ok(())
+    }
+
+    // The voice of `lang` in the map, trying the tag without its last subtags and then
+    // any tag of the same primary language.
+    fn mapped_voice(map: &VoiceMap, lang: &str) -> Option<String> {
+        let mut tag = normalize(lang);
+        loop {
+            if let Some(voice) = map.voices.get(&tag) {
+                return Some(voice.clone());
+            }
+            match tag.rsplit_once('-') {
+                Some((shorter, _)) => tag = shorter.to_string(),
+                None => break,
+            }
+        }
+        map.voices
+            .iter()
+            .find(|(mapped, _)| primary(mapped) == tag)
+            .map(|(_, voice)| voice.clone())
+    }
+
+    // Gives the segments of an utterance in `lang` the voices of their languages. Segments
+    // left without a voice are spoken with `default_voice`, the voice of the utterance.
+    // `voices` lists the available voices, for languages the map has no voice for.
+    pub(crate) fn route(
+        &self,
+        segments: Vec<SpeechSegment>,
+        lang: Option<&str>,
+        default_voice: Option<&str>,
+        voices: impl Fn() -> Vec<TTSVoice>,
+    ) -> Vec<SpeechSegment> {
+        let map = self.get();
+        let base = lang.map(script_of_lang);
+        let segments = segments
+            .into_iter()
+            .flat_map(
+                |segment| match segment.lang.is_none() && map.detect_script {
+                    true => detect_languages(segment, base),
+                    false => vec![segment],
+                },
+            )
+            .collect::<Vec<_>>();
+
+        let mut available: Option<Vec<TTSVoice>> = None;
+        let mut resolve = |lang: &str| -> Option<String> {
+            if let Some(voice) = Self::mapped_voice(&map, lang) {
+                return Some(voice);
+            }
+            let available = available.get_or_insert_with(&voices);
+            if let Some(default_voice) = default_voice {
+                let speaks_lang = available.iter().any(|voice| {
+                    voice.id == default_voice && primary(&voice.lang) == primary(lang)
+                });
+                if speaks_lang {
+                    return None;
+                }
+            }
+            voice_of_lang(available, lang)
+        };
+        // Text in the language of the utterance is spoken with its voice, or with the voice
+        // of the language if the utterance has none.
+        let utterance_voice = match (default_voice, lang) {
+            (None, Some(lang)) => resolve(lang),
+            _ => None,
+        };
+        segments
+            .into_iter()
+            .map(|mut segment| {
+                if segment.voice.is_none() {
+                    segment.voice = match segment.lang.as_deref() {
+                        Some(segment_lang)
+                            if !lang.is_some_and(|lang| primary(lang) == primary(segment_lang)) =>
+                        {
+                            resolve(segment_lang)
+                        }
+                        _ => utterance_voice.clone(),
+                    };
+                }
+                segment
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn voice(id: &str, lang: &str) -> TTSVoice {
+        TTSVoice {
+            id: id.into(),
+            name: id.into(),
+            lang: lang.into(),
+            disabled: false,
+        }
+    }
+
+    fn voices() -> Vec<TTSVoice> {
+        vec![
+            voice("en-voice", "en-US"),
+            voice("zh-voice", "zh-CN"),
+            voice("ja-voice", "ja-JP"),
+        ]
+    }
+
+    // The text, language and voice of every segment.
+    fn routed(segments: &[SpeechSegment]) -> Vec<(&str, Option<&str>, Option<&str>)> {
+        segments
+            .iter()
+            .map(|segment| {
+                let text = segment.text.as_str();
+                (text, segment.lang.as_deref(), segment.voice.as_deref())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn splits_mixed_scripts() {
+        assert_eq!(
+            split_by_script("我喜欢 Rust 和 Python。", Script::Han),
+            [
+                ("我喜欢 ", None),
+                ("Rust ", Some(Script::Latin)),
+                ("和 ", None),
+                ("Python。", Some(Script::Latin)),
+            ]
+        );
+        assert_eq!(
+            split_by_script("“Hello 世界”, again.", Script::Latin),
+            [
+                ("“Hello ", None),
+                ("世界”, ", Some(Script::Han)),
+                ("again.", None)
+            ]
+        );
+    }
+
+    #[test]
+    fn folds_short_runs_into_the_text_around_them() {
+        // A single letter is read in the script around it.
+        assert_eq!(
+            split_by_script("A型の血液", Script::Kana),
+            [("A型の血液", None)]
+        );
+        assert_eq!(
+            split_by_script("维生素C和钙", Script::Han),
+            [("维生素C和钙", None)]
+        );
+        // Kanji are part of Japanese and hanja of Korean.
+        assert_eq!(
+            split_by_script("漢字とMP3", Script::Kana),
+            [("漢字と", None), ("MP3", Some(Script::Latin))]
+        );
+        assert_eq!(
+            split_by_script("韓國語 한국어", Script::Hangul),
+            [("韓國語 한국어", None)]
+        );
+    }
+
+    #[test]
+    fn gives_runs_their_language_and_offset() {
+        let mut segment = SpeechSegment::new("😀 Read 你好世界 today".into());
+        segment.offset = 5;
+        segment.mark = Some("sentence".into());
+        segment.pause = 300;
+        let segments = detect_languages(segment, Some(Script::Latin));
+        assert_eq!(
+            routed(&segments),
+            [
+                ("😀 Read ", None, None),
+                ("你好世界 ", Some("zh"), None),
+                ("today", None, None),
+            ]
+        );
+        // Offsets count UTF-16 code units, two for the emoji.
+        let offsets = segments.iter().map(|segment| segment.offset);
+        assert_eq!(offsets.collect::<Vec<_>>(), [5, 13, 18]);
+        // The mark and the pause come before the first run only.
+        let marks = segments
+            .iter()
+            .map(|segment| (segment.mark.as_deref(), segment.pause));
+        assert_eq!(
+            marks.collect::<Vec<_>>(),
+            [(Some("sentence"), 300), (None, 0), (None, 0)]
+        );
+
+        // Without a language for the utterance, the most common script is its own.
+        let segment = SpeechSegment::new("这是 Rust 写的程序".into());
+        assert_eq!(
+            routed(&detect_languages(segment, None)),
+            [
+                ("这是 ", None, None),
+                ("Rust ", Some("en"), None),
+                ("写的程序", None, None)
+            ]
+        );
+    }
+
+    #[test]
+    fn shortens_tags_to_find_mapped_voices() {
+        let mut map = VoiceMap::default();
+        map.voices.insert("zh".into(), "mapped-zh".into());
+        map.voices.insert("en-gb".into(), "mapped-gb".into());
+        let voice = |lang| VoiceRouter::mapped_voice(&map, lang);
+        assert_eq!(voice("zh-Hant-TW").as_deref(), Some("mapped-zh"));
+        assert_eq!(voice("en_GB").as_deref(), Some("mapped-gb"));
+        // Any voice of the primary language is better than none.
+        assert_eq!(voice("en-US").as_deref(), Some("mapped-gb"));
+        assert_eq!(voice("fr"), None);
+    }
+
+    #[test]
+    fn routes_segments_to_the_voices_of_their_languages() {
+        let router = VoiceRouter::load(None);
+        let segments = vec![SpeechSegment::new("Hello 你好世界, and こんにちは.".into())];
+        let segments = router.route(segments, Some("en-US"), Some("en-voice"), voices);
+        assert_eq!(
+            routed(&segments),
+            [
+                ("Hello ", None, None),
+                ("你好世界, ", Some("zh"), Some("zh-voice")),
+                ("and ", None, None),
+                ("こんにちは.", Some("ja"), Some("ja-voice")),
+            ]
+        );
+
+        // Mapped voices come first, without listing the available ones.
+        let request = SetLanguageVoiceRequest {
+            lang: "zh".into(),
+            voice: Some("mapped-zh".into()),
+        };
+        router.set_language_voice(request).unwrap();
+        let listed = Cell::new(false);
+        let segments = vec![SpeechSegment::new("Hello 你好世界".into())];
+        let segments = router.route(segments, Some("en"), Some("en-voice"), || {
+            listed.set(true);
+            voices()
+        });
+        assert_eq!(segments[1].voice.as_deref(), Some("mapped-zh"));
+        assert!(!listed.get());
+    }
+
+    #[test]
+    fn keeps_the_default_voice_when_it_speaks_the_language() {
+        let router = VoiceRouter::load(None);
+        let mut segment = SpeechSegment::new("你好".into());
+        segment.lang = Some("zh-TW".into());
+        let segments = router.route(vec![segment], Some("en"), Some("zh-voice"), voices);
+        assert_eq!(routed(&segments), [("你好", Some("zh-TW"), None)]);
+    }
+
+    #[test]
+    fn falls_back_to_the_voice_of_the_utterance() {
+        let router = VoiceRouter::load(None);
+        let mut french = SpeechSegment::new("Bonjour".into());
+        french.lang = Some("fr".into());
+        let mut chosen = SpeechSegment::new("你好".into());
+        chosen.voice = Some("chosen".into());
+        let segments = router.route(vec![french, chosen], Some("zh"), None, voices);
+        // The voice of the utterance's language is used when the utterance has none, and
+        // languages without any voice are left to the speech service.
+        assert_eq!(
+            routed(&segments),
+            [
+                ("Bonjour", Some("fr"), None),
+                ("你好", None, Some("chosen"))
+            ]
+        );
+
+        let segment = SpeechSegment::new("你好 world".into());
+        let segments = router.route(vec![segment], Some("zh"), None, voices);
+        assert_eq!(
+            routed(&segments),
+            [
+                ("你好 ", None, Some("zh-voice")),
+                ("world", Some("en"), Some("en-voice"))
+            ]
+        );
+    }
+
+    #[test]
+    fn leaves_scripts_alone_when_detection_is_off() {
+        let router = VoiceRouter::load(None);
+        let map = VoiceMap {
+            detect_script: false,
+            ..router.get()
+        };
+        router.set(map).unwrap();
+        let segments = vec![SpeechSegment::new("Hello 你好世界".into())];
+        let segments = router.route(segments, Some("en"), Some("en-voice"), voices);
+        assert_eq!(routed(&segments), [("Hello 你好世界", None, None)]);
+    }
+}
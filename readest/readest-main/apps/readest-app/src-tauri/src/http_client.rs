@@ -0,0 +1,272 @@
+//! The HTTP client shared by all Rust networking in the app.
+//!
+//! The client honours a configurable proxy, extra root certificates (e.g. the private CA of a
+//! self-hosted sync server), connect and read timeouts and the user agent. The configuration
+//! is set from the frontend and saved to the app data dir, so that transfers resumed at
+//! startup already use it.
+
+use serde::{Deserialize, Serialize};
+use tauri::{command, AppHandle, Manager, State};
+
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use crate::transfer_file::{Error, Result};
+
+const CONFIG_FILE: &str = "http_client.json";
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_READ_TIMEOUT_SECS: u64 = 60;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HttpClientConfig {
+    pub proxy: Option<String>, // e.g. "http://proxy.corp:8080"
+    #[serde(default)]
+    pub no_proxy: Vec<String>, // Hosts, domains and CIDR ranges that bypass the proxy
+    // PEM encoded certificates trusted in addition to the system ones
+    #[serde(default)]
+    pub root_certificates: Vec<String>,
+    pub connect_timeout: Option<u64>, // Seconds
+    pub read_timeout: Option<u64>,    // Seconds without receiving any data
+    pub user_agent: Option<String>,
+}
+
+impl HttpClientConfig {
+    fn build(&self) -> Result<reqwest::Client> {
+        let connect_timeout = self.connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT_SECS);
+        let read_timeout = self.read_timeout.unwrap_or(DEFAULT_READ_TIMEOUT_SECS);
+        let user_agent = self
+            .user_agent
+            .clone()
+            .unwrap_or_else(|| format!("Readest/{}", env!("CARGO_PKG_VERSION")));
+
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(connect_timeout))
+            .read_timeout(Duration::from_secs(read_timeout))
+            .user_agent(user_agent);
+
+        if let Some(proxy) = self.proxy.as_deref().filter(|p| !p.trim().is_empty()) {
+            let no_proxy = reqwest::NoProxy::from_string(&self.no_proxy.join(","));
+            builder = builder.proxy(reqwest::Proxy::all(proxy.trim())?.no_proxy(no_proxy));
+        }
+
+        for pem in self
+            .root_certificates
+            .iter()
+            .filter(|pem| !pem.trim().is_empty())
+        {
+            let certificates = reqwest::Certificate::from_pem_bundle(pem.as_bytes())
+                .map_err(|e| Error::InvalidCertificate(e.to_string()))?;
+            // Text without any PEM block parses as an empty bundle, which would trust nothing.
+            if certificates.is_empty() {
+                return Err(Error::InvalidCertificate(
+                    "no PEM encoded certificate found".into(),
+                ));
+            }
+            for certificate in certificates {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+
+        builder.build().map_err(Into::into)
+    }
+}
+
+pub struct HttpClient {
+    path: PathBuf,
+    config: RwLock<HttpClientConfig>,
+    client: RwLock<reqwest::Client>,
+}
+
+impl HttpClient {
+    // Loads the configuration saved in the app data dir, falling back to the defaults if it
+    // is missing or no longer valid.
+    pub fn load(app: &AppHandle) -> Result<Self> {
+        Self::load_from(app.path().app_data_dir()?.join(CONFIG_FILE))
+    }
+
+    fn load_from(path: PathBuf) -> Result<Self> {
+        let saved = std::fs::read(&path)
+            .ok()
+            .and_then(|data| serde_json::from_slice::<HttpClientConfig>(&data).ok());
+
+        let saved = saved.and_then(|config| match config.build() {
+            Ok(client) => Some((config, client)),
+            Err(e) => {
+                log::error!("Invalid HTTP client config, using the defaults: {e}");
+                None
+            }
+        });
+        let (config, client) = match saved {
+            Some(saved) => saved,
+            None => (
+                HttpClientConfig::default(),
+                HttpClientConfig::default().build()?,
+            ),
+        };
+
+        Ok(Self {
+            path,
+            config: RwLock::new(config),
+            client: RwLock::new(client),
+        })
+    }
+
+    // Returns the current client, which is cheap to clone and shares its connection pool.
+    pub fn client(&self) -> reqwest::Client {
+        self.client.read().unwrap().clone()
+    }
+
+    pub fn config(&self) -> HttpClientConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    // Applies a new configuration to the transfers started from now on.
+    pub fn configure(&self, config: HttpClientConfig) -> Result<()> {
+        let client = config.build()?;
+
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let data = serde_json::to_vec_pretty(&config).map_err(std::io::Error::from)?;
+        std::fs::write(&self.path, data)?;
+
+        *self.client.write().unwrap() = client;
+        *self.config.write().unwrap() = config;
+        Ok(())
+    }
+}
+
+#[command]
+pub fn get_http_client_config(http_client: State<'_, HttpClient>) -> HttpClientConfig {
+    http_client.config()
+}
+
+#[command]
+pub fn set_http_client_config(
+    config: HttpClientConfig,
+    http_client: State<'_, HttpClient>,
+) -> std::result::Result<(), Error> {
+    http_client.configure(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    const CERTIFICATE: &str = "-----BEGIN CERTIFICATE-----
+MIIBijCCATGgAwIBAgIUcby7/dYZDhm82yRyLvnHHBo0SKEwCgYIKoZIzj0EAwIw
+GjEYMBYGA1UEAwwPUmVhZGVzdCBUZXN0IENBMCAXDTI2MTAxODA5MTcyMVoYDzIx
+MjYwOTI0MDkxNzIxWjAaMRgwFgYDVQQDDA9SZWFkZXN0IFRlc3QgQ0EwWTATBgcq
+hkjOPQIBBggqhkjOPQMBBwNCAAS72EtyCgmc6+CuGut9LdUmI2SLPc2NNrh8Vb4L
+7PDbG/czg5mMaCOpxHdFw/F+5H4JCo/Pdt9mGZO6rp2NbdAfo1MwUTAdBgNVHQ4E
+FgQUKvnu9+etuW6IFrb5yyTMsXCynYEwHwYDVR0jBBgwFoAUKvnu9+etuW6IFrb5
+yyTMsXCynYEwDwYDVR0TAQH/BAUwAwEB/zAKBggqhkjOPQQDAgNHADBEAiAWqaMK
+Dz6gozAgcLgVJkaG4SQAcEQQpyVUdmSFf/uOiwIgWkzjHtw8NBUKbIKOoegjtfH5
+wPQa1QBgI3hF1HkFSF4=
+-----END CERTIFICATE-----
+";
+
+    fn config_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("readest-http-{}", std::process::id()));
+        let path = dir.join(name).join(CONFIG_FILE);
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    // Answers every HTTP request on a local port and reports its request line.
+    fn serve() -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (sender, requests) = mpsc::channel();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let _ = sender.send(line.trim_end().to_string());
+                while reader.read_line(&mut line).unwrap() > 0 && !line.ends_with("\r\n\r\n") {}
+                let response = "HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        (address, requests)
+    }
+
+    #[test]
+    fn saves_and_loads_the_config() {
+        let path = config_path("round-trip");
+        let client = HttpClient::load_from(path.clone()).unwrap();
+        assert_eq!(client.config(), HttpClientConfig::default());
+
+        let config = HttpClientConfig {
+            proxy: Some("http://proxy.example.com:8080".into()),
+            no_proxy: vec!["localhost".into(), "192.168.0.0/16".into()],
+            root_certificates: vec![CERTIFICATE.into()],
+            connect_timeout: Some(5),
+            read_timeout: Some(10),
+            user_agent: Some("Test".into()),
+        };
+        client.configure(config.clone()).unwrap();
+        assert_eq!(client.config(), config);
+        assert_eq!(
+            HttpClient::load_from(path.clone()).unwrap().config(),
+            config
+        );
+
+        // A saved config that no longer builds is replaced with the defaults.
+        std::fs::write(&path, r#"{"proxy": "not a proxy url"}"#).unwrap();
+        let client = HttpClient::load_from(path).unwrap();
+        assert_eq!(client.config(), HttpClientConfig::default());
+    }
+
+    #[test]
+    fn reports_invalid_certificates() {
+        let path = config_path("invalid-pem");
+        let client = HttpClient::load_from(path.clone()).unwrap();
+        let corrupted = CERTIFICATE.replace("MIIB", "!!!!");
+        for pem in ["not a certificate", corrupted.as_str()] {
+            let config = HttpClientConfig {
+                root_certificates: vec![pem.into()],
+                ..Default::default()
+            };
+            assert!(matches!(
+                client.configure(config),
+                Err(Error::InvalidCertificate(_))
+            ));
+        }
+        // The invalid config is neither applied nor saved.
+        assert_eq!(client.config(), HttpClientConfig::default());
+        assert!(!path.exists());
+
+        let config = HttpClientConfig {
+            root_certificates: vec![format!("{CERTIFICATE}{CERTIFICATE}"), " ".into()],
+            ..Default::default()
+        };
+        assert!(config.build().is_ok());
+    }
+
+    #[tokio::test]
+    async fn bypasses_the_proxy_for_no_proxy_hosts() {
+        let (proxy, proxied) = serve();
+        let (server, direct) = serve();
+        let url = format!("http://{server}/book.epub");
+        let mut config = HttpClientConfig {
+            proxy: Some(format!("http://{proxy}")),
+            ..Default::default()
+        };
+
+        config.build().unwrap().get(&url).send().await.unwrap();
+        assert_eq!(proxied.recv().unwrap(), format!("GET {url} HTTP/1.1"));
+
+        config.no_proxy = vec!["127.0.0.1".into()];
+        config.build().unwrap().get(&url).send().await.unwrap();
+        assert_eq!(direct.recv().unwrap(), "GET /book.epub HTTP/1.1");
+        assert!(proxied.try_recv().is_err());
+    }
+}
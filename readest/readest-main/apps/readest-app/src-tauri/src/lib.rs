@@ -13,16 +13,33 @@ use tauri::TitleBarStyle;
 #[cfg(desktop)]
 use std::path::PathBuf;
 #[cfg(desktop)]
-use tauri::{AppHandle, Listener, Manager, Url};
+use tauri::{AppHandle, Listener, Url};
 #[cfg(desktop)]
 use tauri_plugin_fs::FsExt;
 
+mod http_client;
 #[cfg(target_os = "macos")]
 mod macos;
+mod send_to_device;
 mod transfer_file;
-use tauri::{command, Emitter, WebviewUrl, WebviewWindowBuilder, Window};
+mod transfer_manager;
+mod transfer_multipart;
+mod transfer_queue;
+mod transfer_throttle;
+use http_client::{get_http_client_config, set_http_client_config, HttpClient};
+use send_to_device::send_books_to_device;
+use tauri::{command, Emitter, Manager, WebviewUrl, WebviewWindowBuilder, Window};
 use tauri_plugin_oauth::start;
 use transfer_file::{download_file, upload_file};
+use transfer_manager::{
+    cancel_transfer, dismiss_transfer, list_transfers, pause_transfer, TransferManager,
+};
+use transfer_multipart::{abort_file_multipart, upload_file_multipart};
+use transfer_queue::{
+    enqueue_transfer, list_queued_transfers, remove_queued_transfer, retry_queued_transfer,
+    TransferQueue,
+};
+use transfer_throttle::{get_bandwidth_limit, set_bandwidth_limit, BandwidthLimiter};
 
 #[cfg(desktop)]
 fn allow_file_in_scopes(app: &AppHandle, files: Vec<PathBuf>) {
@@ -139,10 +156,27 @@ pub fn run() {
     let builder = tauri::Builder::default()
         .plugin(tauri_plugin_process::init())
         .plugin(tauri_plugin_oauth::init())
+        .manage(TransferManager::default())
+        .manage(BandwidthLimiter::default())
         .invoke_handler(tauri::generate_handler![
             start_server,
             download_file,
             upload_file,
+            upload_file_multipart,
+            abort_file_multipart,
+            cancel_transfer,
+            pause_transfer,
+            dismiss_transfer,
+            list_transfers,
+            enqueue_transfer,
+            list_queued_transfers,
+            retry_queued_transfer,
+            remove_queued_transfer,
+            set_bandwidth_limit,
+            get_bandwidth_limit,
+            get_http_client_config,
+            set_http_client_config,
+            send_books_to_device,
             get_environment_variable,
             get_executable_dir,
             #[cfg(target_os = "macos")]
@@ -198,6 +232,10 @@ pub fn run() {
 
     builder
         .setup(|#[allow(unused_variables)] app| {
+            app.manage(HttpClient::load(app.handle())?);
+            app.manage(TransferQueue::load(app.handle())?);
+            transfer_queue::resume(app.handle());
+
             #[cfg(desktop)]
             {
                 let files = get_files_from_argv(std::env::args().collect());
@@ -0,0 +1,742 @@
+//! Send books to an e-reader plugged in over USB.
+//!
+//! Books are copied into the folder the e-reader reads books from. EPUBs sent to a Kobo
+//! are converted to KEPUBs, which its own reader paginates faster and keeps reading
+//! statistics for. Books sent to an e-reader with KOReader get a KOReader sidecar with
+//! their metadata and reading progress. Kindles only open their own formats over USB,
+//! so EPUBs are only sent to them when KOReader is installed.
+//!
+//! Kobos and Kindles read the title and authors of sideloaded books from the books
+//! themselves and keep no metadata files next to them that could be written ahead, so
+//! KOReader is the only e-reader that gets a sidecar.
+
+use quick_xml::events::{BytesEnd, BytesStart, BytesText, Event};
+use quick_xml::{Reader, Writer};
+use serde::{ser::Serializer, Deserialize, Serialize};
+use tauri::{command, ipc::Channel, AppHandle};
+use tauri_plugin_native_bridge::{EReaderLayout, NativeBridgeExt, RemovableVolume};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use crate::transfer_file::{ProgressPayload, TransferStats};
+
+type Result<T> = std::result::Result<T, Error>;
+
+// Formats that Kindles open from their `documents` folder.
+const KINDLE_FORMATS: &[&str] = &["azw", "azw3", "kfx", "mobi", "pdf", "txt"];
+
+// Elements whose text is not part of the flow of the book, or is laid out by itself.
+const KEPUB_SKIPPED: &[&[u8]] = &[
+    b"script",
+    b"style",
+    b"svg",
+    b"math",
+    b"noscript",
+    b"textarea",
+    b"rt",
+    b"rp",
+];
+
+// Elements that start a new paragraph of `koboSpan` ids.
+const KEPUB_BLOCKS: &[&[u8]] = &[
+    b"p",
+    b"div",
+    b"h1",
+    b"h2",
+    b"h3",
+    b"h4",
+    b"h5",
+    b"h6",
+    b"li",
+    b"dt",
+    b"dd",
+    b"blockquote",
+    b"pre",
+    b"td",
+    b"th",
+    b"caption",
+    b"figcaption",
+    b"section",
+    b"aside",
+];
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Zip(#[from] zip::result::ZipError),
+    #[error(transparent)]
+    Xml(#[from] quick_xml::Error),
+    #[error(transparent)]
+    Tauri(#[from] tauri::Error),
+    #[error(transparent)]
+    NativeBridge(#[from] tauri_plugin_native_bridge::Error),
+    #[error("no removable volume is mounted at {0}")]
+    NotMounted(String),
+    #[error("the e-reader cannot open {0} books")]
+    UnsupportedFormat(String),
+    #[error("{0} is not a folder of the volume")]
+    InvalidFolder(String),
+}
+
+impl Serialize for Error {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.to_string().as_ref())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BookToSend {
+    file_path: String,
+    title: String,
+    author: Option<String>,
+    language: Option<String>,
+    progress: Option<f64>, // The fraction of the book read, for the KOReader sidecar
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SentBook {
+    file_path: String,
+    device_path: Option<String>, // Where the book was written to, unless it failed
+    error: Option<String>,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SendProgressPayload {
+    #[serde(flatten)]
+    transfer: ProgressPayload, // Of the bytes of all books
+    book: usize, // The index of the book being sent
+    books: usize,
+}
+
+struct Sender<'a> {
+    e_readers: &'a [EReaderLayout],
+    dir: PathBuf,           // The folder the books are written to
+    names: HashSet<String>, // The file names in `dir` and given to books, in lowercase
+    stats: TransferStats,
+    total: u64,
+    book: usize,
+    books: usize,
+    on_progress: &'a Channel<SendProgressPayload>,
+}
+
+impl Sender<'_> {
+    fn record(&mut self, len: u64, force: bool) {
+        self.stats.record_chunk_transfer(len as usize);
+        let (book, books) = (self.book, self.books);
+        self.stats
+            .report_with(self.on_progress, self.total, force, |transfer| {
+                SendProgressPayload {
+                    transfer,
+                    book,
+                    books,
+                }
+            });
+    }
+
+    fn has(&self, e_reader: EReaderLayout) -> bool {
+        self.e_readers.contains(&e_reader)
+    }
+
+    fn send(&mut self, book: &BookToSend) -> Result<PathBuf> {
+        let source = Path::new(&book.file_path);
+        let ext = source
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let kepub = ext == "epub" && self.has(EReaderLayout::Kobo);
+        if self.has(EReaderLayout::Kindle)
+            && !self.has(EReaderLayout::KOReader)
+            && !KINDLE_FORMATS.contains(&ext.as_str())
+        {
+            return Err(Error::UnsupportedFormat(ext.to_uppercase()));
+        }
+        let suffix = match (kepub, ext.is_empty()) {
+            (true, _) => ".kepub.epub".to_string(),
+            (false, true) => String::new(),
+            (false, false) => format!(".{ext}"),
+        };
+        let name = unique_name(&mut self.names, &file_stem(book), &suffix);
+        let dest = self.dir.join(name);
+        // Written next to the book and renamed into place, so that an unplugged e-reader
+        // is not left with a truncated book.
+        let partial = dest.with_file_name(format!(
+            ".{}.part",
+            dest.file_name().unwrap_or_default().to_string_lossy()
+        ));
+        let written = match kepub {
+            true => self.write_kepub(source, &partial),
+            false => self.copy(source, &partial),
+        };
+        if let Err(e) = written {
+            let _ = std::fs::remove_file(&partial);
+            return Err(e);
+        }
+        std::fs::rename(&partial, &dest)?;
+        if self.has(EReaderLayout::KOReader) {
+            write_koreader_sidecar(&dest, book)?;
+        }
+        Ok(dest)
+    }
+
+    fn copy(&mut self, source: &Path, dest: &Path) -> Result<()> {
+        let mut reader = File::open(source)?;
+        let mut writer = File::create(dest)?;
+        let mut buffer = vec![0; 256 * 1024];
+        loop {
+            let len = reader.read(&mut buffer)?;
+            if len == 0 {
+                break;
+            }
+            writer.write_all(&buffer[..len])?;
+            self.record(len as u64, false);
+        }
+        writer.sync_all()?;
+        Ok(())
+    }
+
+    // Converts an EPUB into a KEPUB, whose XHTML documents have the text of every sentence
+    // in a `koboSpan` and their body in the columns that Kobo lays pages out in.
+    fn write_kepub(&mut self, source: &Path, dest: &Path) -> Result<()> {
+        let mut archive = ZipArchive::new(File::open(source)?)?;
+        let mut writer = ZipWriter::new(File::create(dest)?);
+        for i in 0..archive.len() {
+            let name = archive.name_for_index(i).unwrap_or_default().to_string();
+            let lower = name.to_lowercase();
+            let is_xhtml = [".xhtml", ".html", ".htm"]
+                .iter()
+                .any(|ext| lower.ends_with(ext));
+            let entry = archive.by_index_raw(i)?;
+            let compressed_size = entry.compressed_size();
+            match (name.as_str(), is_xhtml) {
+                // The mimetype is the first entry of an EPUB, and is not compressed.
+                ("mimetype", _) => {
+                    drop(entry);
+                    let mut mimetype = Vec::new();
+                    archive.by_index(i)?.read_to_end(&mut mimetype)?;
+                    let options =
+                        SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+                    writer.start_file(name, options)?;
+                    writer.write_all(&mimetype)?;
+                }
+                (_, true) => {
+                    drop(entry);
+                    let mut xhtml = Vec::new();
+                    archive.by_index(i)?.read_to_end(&mut xhtml)?;
+                    let options = SimpleFileOptions::default()
+                        .compression_method(CompressionMethod::Deflated);
+                    writer.start_file(name, options)?;
+                    writer.write_all(&kepubify(&xhtml)?)?;
+                }
+                _ => writer.raw_copy_file(entry)?,
+            }
+            self.record(compressed_size, false);
+        }
+        writer.finish()?.sync_all()?;
+        Ok(())
+    }
+}
+
+// The file name of a book on the e-reader, without its extension. Characters that FAT
+// file systems do not allow are replaced.
+fn file_stem(book: &BookToSend) -> String {
+    let name = match book.author.as_deref().map(str::trim) {
+        Some(author) if !author.is_empty() => format!("{} - {author}", book.title.trim()),
+        _ => book.title.trim().to_string(),
+    };
+    let name = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .take(120)
+        .collect::<String>();
+    let name = name.trim_end_matches(['.', ' ']).trim_start_matches('.');
+    match name.is_empty() {
+        true => "Untitled".to_string(),
+        false => name.to_string(),
+    }
+}
+
+// A file name that no book sent nor file on the e-reader has, so that books with the same
+// title and author do not overwrite each other. FAT file systems ignore case, so `taken`
+// holds names in lowercase.
+fn unique_name(taken: &mut HashSet<String>, stem: &str, suffix: &str) -> String {
+    let mut name = format!("{stem}{suffix}");
+    let mut copy = 1;
+    while taken.contains(&name.to_lowercase()) {
+        copy += 1;
+        name = format!("{stem} ({copy}){suffix}");
+    }
+    taken.insert(name.to_lowercase());
+    name
+}
+
+// The length of the stop, closing quote or closing bracket that `text` starts with, which
+// may be escaped as an entity.
+fn closer_len(text: &str) -> Option<usize> {
+    let is_closer = |c| {
+        matches!(
+            c,
+            '.' | '!' | '?' | '"' | '\'' | ')' | ']' | '”' | '’' | '»' | '」' | '』'
+        )
+    };
+    let c = text.chars().next()?;
+    if is_closer(c) {
+        return Some(c.len_utf8());
+    }
+    let entity = &text[..text.find(';').filter(|_| c == '&')? + 1];
+    let unescaped = quick_xml::escape::unescape(entity).ok()?;
+    let mut chars = unescaped.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if is_closer(c) => Some(entity.len()),
+        _ => None,
+    }
+}
+
+// Splits text into sentences, each with the punctuation, closing quotes and spaces that
+// end it. The text is split as escaped in the document, which keeps entities whole.
+fn split_sentences(text: &str) -> Vec<&str> {
+    let mut sentences = Vec::new();
+    let (mut start, mut end) = (0, 0);
+    while let Some(c) = text[end..].chars().next() {
+        end += c.len_utf8();
+        let full_width = matches!(c, '。' | '！' | '？' | '…');
+        if !full_width && !matches!(c, '.' | '!' | '?') {
+            continue;
+        }
+        while let Some(len) = closer_len(&text[end..]) {
+            end += len;
+        }
+        // Like the points of "3.14" and "e.g.", stops not followed by a space do not end
+        // sentences of languages written with spaces.
+        let spaced = match text[end..].chars().next() {
+            Some(c) => c.is_whitespace(),
+            None => true,
+        };
+        if !full_width && !spaced {
+            continue;
+        }
+        end = text.len() - text[end..].trim_start().len();
+        sentences.push(&text[start..end]);
+        start = end;
+    }
+    if start < text.len() {
+        sentences.push(&text[start..]);
+    }
+    sentences
+}
+
+// Adds the `koboSpan`s and columns of a KEPUB to an XHTML document.
+fn kepubify(xhtml: &[u8]) -> Result<Vec<u8>> {
+    // Already converted, by Calibre or kepubify.
+    if xhtml.windows(8).any(|window| window == b"koboSpan") {
+        return Ok(xhtml.to_vec());
+    }
+    let mut reader = Reader::from_reader(xhtml);
+    reader.config_mut().check_end_names = false;
+    let mut writer = Writer::new(Vec::with_capacity(xhtml.len() * 3 / 2));
+    let mut in_body = false;
+    let mut skipped_depth = 0; // Of the elements within a skipped one
+    let (mut paragraph, mut sentence) = (0, 0);
+    let columns = [("id", "book-columns")];
+    let inner = [("id", "book-inner")];
+    loop {
+        match reader.read_event()? {
+            Event::Start(start) => {
+                let name = start.local_name().as_ref().to_ascii_lowercase();
+                if skipped_depth > 0 || KEPUB_SKIPPED.contains(&name.as_slice()) {
+                    skipped_depth += 1;
+                }
+                if KEPUB_BLOCKS.contains(&name.as_slice()) {
+                    paragraph += 1;
+                    sentence = 0;
+                }
+                writer.write_event(Event::Start(start))?;
+                if name == b"body" {
+                    in_body = true;
+                    writer.write_event(Event::Start(
+                        BytesStart::new("div").with_attributes(columns),
+                    ))?;
+                    writer
+                        .write_event(Event::Start(BytesStart::new("div").with_attributes(inner)))?;
+                }
+            }
+            Event::End(end) => {
+                if end.local_name().as_ref().eq_ignore_ascii_case(b"body") {
+                    in_body = false;
+                    writer.write_event(Event::End(BytesEnd::new("div")))?;
+                    writer.write_event(Event::End(BytesEnd::new("div")))?;
+                }
+                skipped_depth = i32::max(skipped_depth - 1, 0);
+                writer.write_event(Event::End(end))?;
+            }
+            Event::Text(text) if in_body && skipped_depth == 0 => {
+                let text = String::from_utf8_lossy(&text).into_owned();
+                for part in split_sentences(&text) {
+                    if part.trim().is_empty() {
+                        writer.write_event(Event::Text(BytesText::from_escaped(part)))?;
+                        continue;
+                    }
+                    paragraph = i32::max(paragraph, 1);
+                    sentence += 1;
+                    let id = format!("kobo.{paragraph}.{sentence}");
+                    let span = BytesStart::new("span")
+                        .with_attributes([("class", "koboSpan"), ("id", id.as_str())]);
+                    writer.write_event(Event::Start(span))?;
+                    writer.write_event(Event::Text(BytesText::from_escaped(part)))?;
+                    writer.write_event(Event::End(BytesEnd::new("span")))?;
+                }
+            }
+            Event::Eof => break,
+            event => writer.write_event(event)?,
+        }
+    }
+    Ok(writer.into_inner())
+}
+
+fn lua_string(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r");
+    format!("\"{escaped}\"")
+}
+
+// KOReader keeps the settings of `name.ext` in `name.sdr/metadata.ext.lua`. A sidecar
+// that KOReader already keeps for the book is left as it is.
+fn write_koreader_sidecar(book_path: &Path, book: &BookToSend) -> Result<()> {
+    let (Some(stem), Some(ext)) = (book_path.file_stem(), book_path.extension()) else {
+        return Ok(());
+    };
+    let sidecar_dir = book_path.with_file_name(format!("{}.sdr", stem.to_string_lossy()));
+    let sidecar = sidecar_dir.join(format!("metadata.{}.lua", ext.to_string_lossy()));
+    if sidecar.exists() {
+        return Ok(());
+    }
+    let mut doc_props = vec![format!(
+        "        [\"title\"] = {},",
+        lua_string(&book.title)
+    )];
+    if let Some(author) = &book.author {
+        doc_props.push(format!("        [\"authors\"] = {},", lua_string(author)));
+    }
+    if let Some(language) = &book.language {
+        doc_props.push(format!(
+            "        [\"language\"] = {},",
+            lua_string(language)
+        ));
+    }
+    let mut lua = format!(
+        "-- we can read Lua syntax here!\nreturn {{\n    [\"doc_props\"] = {{\n{}\n    }},\n",
+        doc_props.join("\n")
+    );
+    if let Some(progress) = book.progress.filter(|progress| *progress > 0.0) {
+        let status = match progress >= 1.0 {
+            true => "complete",
+            false => "reading",
+        };
+        lua.push_str(&format!(
+            "    [\"percent_finished\"] = {},\n",
+            progress.min(1.0)
+        ));
+        lua.push_str(&format!(
+            "    [\"summary\"] = {{\n        [\"status\"] = \"{status}\",\n    }},\n"
+        ));
+    }
+    lua.push_str("}\n");
+    std::fs::create_dir_all(&sidecar_dir)?;
+    std::fs::write(sidecar, lua)?;
+    Ok(())
+}
+
+// The folder that the e-readers of a volume read books from. A folder given by the user
+// must stay within the volume.
+fn books_dir(volume: &Path, e_readers: &[EReaderLayout], folder: Option<&str>) -> Result<PathBuf> {
+    if let Some(folder) = folder.map(|folder| folder.trim_matches('/')) {
+        if !folder.is_empty() {
+            let within = Path::new(folder)
+                .components()
+                .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+            return match within {
+                true => Ok(volume.join(folder)),
+                false => Err(Error::InvalidFolder(folder.to_string())),
+            };
+        }
+    }
+    match e_readers.contains(&EReaderLayout::Kindle) {
+        true => Ok(volume.join("documents")),
+        false => Ok(volume.to_path_buf()),
+    }
+}
+
+// The removable volume mounted at `volume` among those the native bridge lists, so that
+// books are only written to e-readers and other removable media.
+fn find_volume(volumes: Vec<RemovableVolume>, volume: &str) -> Result<RemovableVolume> {
+    volumes
+        .into_iter()
+        .find(|mounted| Path::new(&mounted.path) == Path::new(volume))
+        .ok_or_else(|| Error::NotMounted(volume.to_string()))
+}
+
+fn send_books(
+    volume: &str,
+    e_readers: &[EReaderLayout],
+    books: &[BookToSend],
+    folder: Option<&str>,
+    on_progress: &Channel<SendProgressPayload>,
+) -> Result<Vec<SentBook>> {
+    let volume_path = Path::new(volume);
+    if !volume_path.is_dir() {
+        return Err(Error::NotMounted(volume.to_string()));
+    }
+    let dir = books_dir(volume_path, e_readers, folder)?;
+    std::fs::create_dir_all(&dir)?;
+    let names = std::fs::read_dir(&dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().to_lowercase())
+        .collect();
+    let total = books
+        .iter()
+        .filter_map(|book| std::fs::metadata(&book.file_path).ok())
+        .map(|metadata| metadata.len())
+        .sum();
+    let mut sender = Sender {
+        e_readers,
+        dir,
+        names,
+        stats: TransferStats::default(),
+        total,
+        book: 0,
+        books: books.len(),
+        on_progress,
+    };
+    let mut sent = Vec::new();
+    for (index, book) in books.iter().enumerate() {
+        sender.book = index;
+        let result = sender.send(book);
+        if let Err(e) = &result {
+            log::warn!("Failed to send {} to the e-reader: {e}", book.file_path);
+        }
+        sent.push(SentBook {
+            file_path: book.file_path.clone(),
+            device_path: result
+                .as_ref()
+                .ok()
+                .map(|path| path.to_string_lossy().into_owned()),
+            error: result.err().map(|e| e.to_string()),
+        });
+    }
+    sender.record(0, true);
+    Ok(sent)
+}
+
+// Sends books to the e-reader mounted at `volume`, which must be one of the removable
+// volumes that `list_removable_volumes` of the native bridge reports, laid out for the
+// e-readers it found there. A book that fails does not stop the others from being sent,
+// the results tell which were.
+#[command]
+pub async fn send_books_to_device(
+    app: AppHandle,
+    volume: String,
+    books: Vec<BookToSend>,
+    folder: Option<String>,
+    on_progress: Channel<SendProgressPayload>,
+) -> Result<Vec<SentBook>> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let volumes = app.native_bridge().list_removable_volumes()?.volumes;
+        let volume = find_volume(volumes, &volume)?;
+        send_books(
+            &volume.path,
+            &volume.e_readers,
+            &books,
+            folder.as_deref(),
+            &on_progress,
+        )
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kepub(xhtml: &str) -> String {
+        String::from_utf8(kepubify(xhtml.as_bytes()).unwrap()).unwrap()
+    }
+
+    fn book(title: &str, author: Option<&str>) -> BookToSend {
+        BookToSend {
+            file_path: format!("/library/{title}.epub"),
+            title: title.to_string(),
+            author: author.map(str::to_string),
+            language: None,
+            progress: None,
+        }
+    }
+
+    #[test]
+    fn splits_sentences_with_their_trailing_punctuation_and_spaces() {
+        assert_eq!(
+            split_sentences("“Stop!” he said. Pi is 3.14… ok?! Yes"),
+            ["“Stop!” ", "he said. ", "Pi is 3.14… ", "ok?! ", "Yes"]
+        );
+        assert_eq!(
+            split_sentences("He said \"no.\" Then (he left.) End."),
+            ["He said \"no.\" ", "Then (he left.) ", "End."]
+        );
+        assert_eq!(split_sentences("一。二！三"), ["一。", "二！", "三"]);
+        assert_eq!(split_sentences("a.b.c"), ["a.b.c"]);
+        assert!(split_sentences("").is_empty());
+    }
+
+    #[test]
+    fn keeps_entities_whole() {
+        assert_eq!(
+            split_sentences("Tom &amp; Jerry. &#8220;Hi.&#8221; &quot;Yo!&quot;) Bye &amp;c."),
+            [
+                "Tom &amp; Jerry. ",
+                "&#8220;Hi.&#8221; ",
+                "&quot;Yo!&quot;) ",
+                "Bye &amp;c."
+            ]
+        );
+        assert_eq!(
+            kepub("<html><body><p>Tom &amp; Jerry. &lt;Hi&gt;!</p></body></html>"),
+            "<html><body><div id=\"book-columns\"><div id=\"book-inner\"><p>\
+             <span class=\"koboSpan\" id=\"kobo.1.1\">Tom &amp; Jerry. </span>\
+             <span class=\"koboSpan\" id=\"kobo.1.2\">&lt;Hi&gt;!</span>\
+             </p></div></div></body></html>"
+        );
+    }
+
+    #[test]
+    fn numbers_sentences_by_paragraph() {
+        assert_eq!(
+            kepub("<html><head><title>T. T.</title></head><body><p>One. Two.</p>\n<p>Three.</p></body></html>"),
+            "<html><head><title>T. T.</title></head><body><div id=\"book-columns\">\
+             <div id=\"book-inner\"><p><span class=\"koboSpan\" id=\"kobo.1.1\">One. </span>\
+             <span class=\"koboSpan\" id=\"kobo.1.2\">Two.</span></p>\n\
+             <p><span class=\"koboSpan\" id=\"kobo.2.1\">Three.</span></p>\
+             </div></div></body></html>"
+        );
+    }
+
+    #[test]
+    fn leaves_the_text_of_nested_skipped_elements_alone() {
+        let xhtml = "<html><body><p>Before. <svg><text>Not.</text><style>a{}</style></svg>\
+                     After.</p><script>if (a) { b. c. }</script><ruby>漢<rt>かん</rt></ruby>字。\
+                     </body></html>";
+        assert_eq!(
+            kepub(xhtml),
+            "<html><body><div id=\"book-columns\"><div id=\"book-inner\"><p>\
+             <span class=\"koboSpan\" id=\"kobo.1.1\">Before. </span>\
+             <svg><text>Not.</text><style>a{}</style></svg>\
+             <span class=\"koboSpan\" id=\"kobo.1.2\">After.</span></p>\
+             <script>if (a) { b. c. }</script>\
+             <ruby><span class=\"koboSpan\" id=\"kobo.1.3\">漢</span><rt>かん</rt></ruby>\
+             <span class=\"koboSpan\" id=\"kobo.1.4\">字。</span></div></div></body></html>"
+        );
+    }
+
+    #[test]
+    fn leaves_converted_documents_alone() {
+        let xhtml = "<html><body><div id=\"book-columns\"><div id=\"book-inner\"><p>\
+                     <span class=\"koboSpan\" id=\"kobo.1.1\">One.</span></p></div></div>\
+                     </body></html>";
+        assert_eq!(kepub(xhtml), xhtml);
+    }
+
+    #[test]
+    fn names_books_by_title_and_author() {
+        assert_eq!(
+            file_stem(&book(" A/B: C? ", Some(" Ann "))),
+            "A_B_ C_ - Ann"
+        );
+        assert_eq!(file_stem(&book("...Dots...", Some(""))), "Dots");
+        assert_eq!(file_stem(&book(" . ", None)), "Untitled");
+    }
+
+    #[test]
+    fn gives_books_with_the_same_name_unique_names() {
+        let mut taken = HashSet::from(["book - ann.kepub.epub".to_string()]);
+        let names = [
+            ("Book - Ann", ".kepub.epub"),
+            ("Book - Ann", ".kepub.epub"),
+            ("BOOK - ANN", ".kepub.epub"),
+            ("Untitled", ".pdf"),
+            ("Untitled", ".pdf"),
+            ("Untitled", ""),
+        ]
+        .map(|(stem, suffix)| unique_name(&mut taken, stem, suffix));
+        assert_eq!(
+            names,
+            [
+                "Book - Ann (2).kepub.epub",
+                "Book - Ann (3).kepub.epub",
+                "BOOK - ANN (4).kepub.epub",
+                "Untitled.pdf",
+                "Untitled (2).pdf",
+                "Untitled",
+            ]
+        );
+    }
+
+    #[test]
+    fn only_sends_to_removable_volumes() {
+        let kobo = RemovableVolume {
+            path: "/run/media/reader/KOBOeReader".to_string(),
+            device: "/dev/sdb1".to_string(),
+            label: Some("KOBOeReader".to_string()),
+            fs_type: "vfat".to_string(),
+            e_readers: vec![EReaderLayout::Kobo],
+        };
+        let volume = find_volume(vec![kobo.clone()], "/run/media/reader/KOBOeReader/").unwrap();
+        assert_eq!(volume.e_readers, [EReaderLayout::Kobo]);
+        for volume in [
+            "/home/reader",
+            "/run/media/reader",
+            "/run/media/reader/KOBOeReader/..",
+        ] {
+            assert!(matches!(
+                find_volume(vec![kobo.clone()], volume),
+                Err(Error::NotMounted(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn keeps_books_within_the_volume() {
+        let volume = Path::new("/media/reader");
+        let kindle = [EReaderLayout::Kindle];
+        let dir = |e_readers, folder| books_dir(volume, e_readers, folder).ok();
+        assert_eq!(dir(&kindle, None), Some(volume.join("documents")));
+        assert_eq!(dir(&[], Some("/")), Some(volume.to_path_buf()));
+        assert_eq!(
+            dir(&[], Some("/Books/New/")),
+            Some(volume.join("Books/New"))
+        );
+        assert_eq!(dir(&[], Some("./Books")), Some(volume.join("./Books")));
+        for folder in ["..", "Books/../..", "../other"] {
+            assert!(matches!(
+                books_dir(volume, &kindle, Some(folder)),
+                Err(Error::InvalidFolder(_))
+            ));
+        }
+    }
+}
@@ -7,61 +7,162 @@
 //! Download files from a remote HTTP server to disk.
 
 use futures_util::TryStreamExt;
-use serde::{ser::Serializer, Serialize};
-use tauri::{command, ipc::Channel};
+use md5::Md5;
+use serde::{ser::Serializer, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tauri::{command, ipc::Channel, State};
 use tokio::{
-    fs::File,
-    io::{AsyncWriteExt, BufWriter},
+    fs::{File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufWriter},
 };
 use tokio_util::codec::{BytesCodec, FramedRead};
 
 use read_progress_stream::ReadProgressStream;
 
-use std::time::Instant;
-use std::{collections::HashMap, sync::Arc};
+use std::time::{Duration, Instant};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::http_client::HttpClient;
+use crate::transfer_manager::{TransferKind, TransferManager};
+use crate::transfer_throttle::{BandwidthLimiter, Throttle};
+
+pub(crate) type Result<T> = std::result::Result<T, Error>;
+
+// Suffixes of the sidecar files kept next to `file_path` while a ranged download is in progress.
+const PARTIAL_SUFFIX: &str = ".part";
+const MANIFEST_SUFFIX: &str = ".part.json";
+
+// Returns the paths of the partial file and manifest kept next to `file_path`.
+pub(crate) fn partial_paths(file_path: &str) -> (PathBuf, PathBuf) {
+    (
+        PathBuf::from(format!("{file_path}{PARTIAL_SUFFIX}")),
+        PathBuf::from(format!("{file_path}{MANIFEST_SUFFIX}")),
+    )
+}
 
-type Result<T> = std::result::Result<T, Error>;
+// Retry policy for a single part of a ranged transfer, the delay doubles after every attempt.
+const PART_MAX_RETRIES: u32 = 5;
+const PART_RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
 
-// The TransferStats struct tracks both transfer speed and cumulative transfer progress.
+// Weight of the latest sample in the exponentially weighted moving average of the speed.
+const SPEED_SMOOTHING: f64 = 0.3;
+
+// The TransferStats struct tracks transfer speed, cumulative progress and elapsed time,
+// and decides how often progress is worth reporting.
 pub struct TransferStats {
-    accumulated_chunk_len: usize, // Total length of chunks transferred in the current period
-    accumulated_time: u128,       // Total time taken for the transfers in the current period
-    pub transfer_speed: u64,      // Calculated transfer speed in bytes per second
+    pub transfer_speed: u64,      // Smoothed transfer speed in bytes per second
     pub total_transferred: u64,   // Cumulative total of all transferred data
-    start_time: Instant,          // Time when the current period started
-    granularity: u32, // Time period (in milliseconds) over which the transfer speed is calculated
+    window_bytes: u64,            // Bytes transferred in the current sampling window
+    window_start: Instant,        // Time when the current sampling window started
+    smoothed_speed: Option<f64>,  // EWMA of the per-window speeds, once a window has completed
+    start_time: Instant,          // Time when the transfer started
+    last_report: Option<Instant>, // Time when progress was last reported
+    granularity: Duration,        // Length of a sampling window
+    report_interval: Duration,    // Minimum time between two progress reports
 }
 
 impl TransferStats {
-    // Initializes a new TransferStats instance with the specified granularity.
+    // Initializes a new TransferStats instance with the specified granularity in milliseconds.
     pub fn start(granularity: u32) -> Self {
+        let now = Instant::now();
         Self {
-            accumulated_chunk_len: 0,
-            accumulated_time: 0,
             transfer_speed: 0,
             total_transferred: 0,
-            start_time: Instant::now(),
-            granularity,
+            window_bytes: 0,
+            window_start: now,
+            smoothed_speed: None,
+            start_time: now,
+            last_report: None,
+            granularity: Duration::from_millis(granularity as u64),
+            report_interval: Duration::from_millis(250),
+        }
+    }
+
+    // Initializes a TransferStats instance for a transfer resuming after `total_transferred` bytes.
+    pub fn resumed(total_transferred: u64) -> Self {
+        Self {
+            total_transferred,
+            ..Self::default()
         }
     }
+
     // Records the transfer of a data chunk and updates both transfer speed and total progress.
     pub fn record_chunk_transfer(&mut self, chunk_len: usize) {
         let now = Instant::now();
-        let it_took = now.duration_since(self.start_time).as_millis();
-        self.accumulated_chunk_len += chunk_len;
         self.total_transferred += chunk_len as u64;
-        self.accumulated_time += it_took;
-
-        // Calculate transfer speed if accumulated time exceeds granularity.
-        if self.accumulated_time >= self.granularity as u128 {
-            self.transfer_speed =
-                (self.accumulated_chunk_len as u128 / self.accumulated_time * 1024) as u64;
-            self.accumulated_chunk_len = 0;
-            self.accumulated_time = 0;
+        self.window_bytes += chunk_len as u64;
+
+        let window = now.duration_since(self.window_start);
+        if window >= self.granularity {
+            let sample = self.window_bytes as f64 / window.as_secs_f64();
+            let speed = match self.smoothed_speed {
+                Some(speed) => SPEED_SMOOTHING * sample + (1.0 - SPEED_SMOOTHING) * speed,
+                None => sample,
+            };
+            self.smoothed_speed = Some(speed);
+            self.transfer_speed = speed.round() as u64;
+            self.window_bytes = 0;
+            self.window_start = now;
+        } else if self.smoothed_speed.is_none() {
+            // Until the first window completes, report the average speed so far.
+            let elapsed = now.duration_since(self.start_time).as_secs_f64();
+            if elapsed > 0.0 {
+                self.transfer_speed = (self.window_bytes as f64 / elapsed).round() as u64;
+            }
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.start_time.elapsed()
+    }
+
+    // Estimated seconds until `total` bytes have been transferred at the current speed.
+    pub fn eta(&self, total: u64) -> Option<f64> {
+        if total == 0 || self.transfer_speed == 0 {
+            return None;
+        }
+        let remaining = total.saturating_sub(self.total_transferred);
+        Some(remaining as f64 / self.transfer_speed as f64)
+    }
+
+    pub fn progress(&self, total: u64) -> ProgressPayload {
+        ProgressPayload {
+            progress: self.total_transferred,
+            total,
+            transfer_speed: self.transfer_speed,
+            elapsed: self.elapsed().as_secs_f64(),
+            eta: self.eta(total),
         }
+    }
 
-        // Reset the start time for the next period.
-        self.start_time = now;
+    // Sends the progress to `channel` unless progress was reported less than the report
+    // interval ago, so that fast transfers do not flood IPC with an event per chunk.
+    // `force` is meant for the final report of a transfer.
+    pub fn report(&mut self, channel: &Channel<ProgressPayload>, total: u64, force: bool) {
+        self.report_with(channel, total, force, |progress| progress);
+    }
+
+    // Like `report`, for payloads that carry the progress along with more details.
+    pub fn report_with<T: Serialize + Clone>(
+        &mut self,
+        channel: &Channel<T>,
+        total: u64,
+        force: bool,
+        payload: impl FnOnce(ProgressPayload) -> T,
+    ) {
+        let now = Instant::now();
+        let due = match self.last_report {
+            Some(last) => now.duration_since(last) >= self.report_interval,
+            None => true,
+        };
+        if force || due {
+            self.last_report = Some(now);
+            let _ = channel.send(payload(self.progress(total)));
+        }
     }
 }
 
@@ -80,8 +181,50 @@ pub enum Error {
     Request(#[from] reqwest::Error),
     #[error("{0}")]
     ContentLength(String),
+    #[error("invalid HTTP method: {0}")]
+    InvalidMethod(String),
+    #[error("invalid multipart upload: {0}")]
+    InvalidMultipartUpload(String),
+    #[error("invalid root certificate: {0}")]
+    InvalidCertificate(String),
+    #[error(transparent)]
+    Tauri(#[from] tauri::Error),
     #[error("request failed with status code {0}: {1}")]
     HttpErrorCode(u16, String),
+    #[error("download incomplete: received {0} of {1} bytes")]
+    Incomplete(u64, u64),
+    #[error("failed to download bytes {start}-{end} after {attempts} attempts: {source}")]
+    RangeFailed {
+        start: u64,
+        end: u64,
+        attempts: u32,
+        #[source]
+        source: Box<Error>,
+    },
+    #[error("transfer {0} was cancelled")]
+    Cancelled(u32),
+    #[error("transfer {0} was paused")]
+    Paused(u32),
+    #[error("transfer {0} is already in progress")]
+    DuplicateTransfer(u32),
+    #[error("integrity check failed: expected {algorithm} {expected} but got {actual}")]
+    IntegrityMismatch {
+        algorithm: DigestAlgorithm,
+        expected: String,
+        actual: String,
+    },
+}
+
+impl Error {
+    // Network errors, server errors, timeouts and rate limiting are worth another attempt,
+    // other client errors (e.g. 403 or 404) will not go away by retrying.
+    fn is_retryable(&self) -> bool {
+        match self {
+            Error::Request(_) | Error::ContentLength(_) => true,
+            Error::HttpErrorCode(code, _) => *code >= 500 || *code == 408 || *code == 429,
+            _ => false,
+        }
+    }
 }
 
 impl Serialize for Error {
@@ -98,25 +241,336 @@ impl Serialize for Error {
 pub struct ProgressPayload {
     progress: u64,
     total: u64,
-    transfer_speed: u64,
+    transfer_speed: u64, // Bytes per second
+    elapsed: f64,        // Seconds since the transfer started
+    eta: Option<f64>,    // Estimated seconds remaining, unknown until the speed is known
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DigestAlgorithm {
+    Md5,
+    Sha256,
+}
+
+impl std::fmt::Display for DigestAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DigestAlgorithm::Md5 => f.write_str("MD5"),
+            DigestAlgorithm::Sha256 => f.write_str("SHA-256"),
+        }
+    }
+}
+
+// The digest a downloaded file is expected to have, as a hex string.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExpectedDigest {
+    algorithm: DigestAlgorithm,
+    value: String,
+}
+
+// The validators the server reported for the downloaded file.
+#[derive(Debug, Default, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadResponse {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl DownloadResponse {
+    fn from_headers(headers: &reqwest::header::HeaderMap) -> Self {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+        Self {
+            etag: header(reqwest::header::ETAG),
+            last_modified: header(reqwest::header::LAST_MODIFIED),
+        }
+    }
+}
+
+enum Hasher {
+    Md5(Md5),
+    Sha256(Sha256),
+}
+
+// The DigestVerifier hashes a download in order, even when its parts arrive out of order.
+struct DigestVerifier {
+    expected: ExpectedDigest,
+    hasher: Hasher,
+    offset: u64, // Number of leading bytes already fed to the hasher
+}
+
+impl DigestVerifier {
+    fn new(expected: ExpectedDigest) -> Self {
+        let hasher = match expected.algorithm {
+            DigestAlgorithm::Md5 => Hasher::Md5(Md5::new()),
+            DigestAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+        };
+        Self {
+            expected,
+            hasher,
+            offset: 0,
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match &mut self.hasher {
+            Hasher::Md5(hasher) => hasher.update(data),
+            Hasher::Sha256(hasher) => hasher.update(data),
+        }
+        self.offset += data.len() as u64;
+    }
+
+    // Hashes the bytes of `file` between the current offset and `until`.
+    async fn update_from_file(&mut self, file: &mut File, until: u64) -> Result<()> {
+        let mut buf = vec![0; 64 * 1024];
+        while self.offset < until {
+            let len = buf.len().min((until - self.offset) as usize);
+            file.seek(std::io::SeekFrom::Start(self.offset)).await?;
+            file.read_exact(&mut buf[..len]).await?;
+            self.update(&buf[..len]);
+        }
+        Ok(())
+    }
+
+    fn verify(self) -> Result<()> {
+        let digest = match self.hasher {
+            Hasher::Md5(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+        };
+        let actual = digest
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>();
+        if actual.eq_ignore_ascii_case(self.expected.value.trim()) {
+            Ok(())
+        } else {
+            Err(Error::IntegrityMismatch {
+                algorithm: self.expected.algorithm,
+                expected: self.expected.value,
+                actual,
+            })
+        }
+    }
+}
+
+// The PartialManifest records which byte ranges of a ranged download are already on disk,
+// so that an interrupted download can be resumed by fetching only the missing parts.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PartialManifest {
+    url: String,
+    total: u64,
+    part_size: u64,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    completed: Vec<(u64, u64)>, // Sorted, merged and inclusive byte ranges
+}
+
+impl PartialManifest {
+    fn new(url: &str, total: u64, part_size: u64, validators: &DownloadResponse) -> Self {
+        Self {
+            url: url.to_string(),
+            total,
+            part_size,
+            etag: validators.etag.clone(),
+            last_modified: validators.last_modified.clone(),
+            completed: Vec::new(),
+        }
+    }
+
+    // Loads a manifest from disk, treating a missing or unreadable one as absent.
+    async fn load(path: &Path) -> Option<Self> {
+        let data = tokio::fs::read(path).await.ok()?;
+        serde_json::from_slice(&data).ok()
+    }
+
+    async fn save(&self, path: &Path) -> Result<()> {
+        let data = serde_json::to_vec(self).map_err(std::io::Error::from)?;
+        tokio::fs::write(path, data).await?;
+        Ok(())
+    }
+
+    // A manifest can only be resumed if it describes the same, unchanged remote file split
+    // the same way.
+    fn matches(
+        &self,
+        url: &str,
+        total: u64,
+        part_size: u64,
+        validators: &DownloadResponse,
+    ) -> bool {
+        self.url == url
+            && self.total == total
+            && self.part_size == part_size
+            && self.etag == validators.etag
+            && self.last_modified == validators.last_modified
+    }
+
+    fn is_completed(&self, start: u64, end: u64) -> bool {
+        self.completed.iter().any(|&(s, e)| s <= start && end <= e)
+    }
+
+    fn mark_completed(&mut self, start: u64, end: u64) {
+        self.completed.push((start, end));
+        self.completed.sort_unstable();
+
+        let mut merged: Vec<(u64, u64)> = Vec::with_capacity(self.completed.len());
+        for &(s, e) in &self.completed {
+            match merged.last_mut() {
+                Some(last) if s <= last.1.saturating_add(1) => last.1 = last.1.max(e),
+                _ => merged.push((s, e)),
+            }
+        }
+        self.completed = merged;
+    }
+
+    fn completed_len(&self) -> u64 {
+        self.completed.iter().map(|&(s, e)| e - s + 1).sum()
+    }
+
+    // Length of the completed prefix of the file, i.e. the bytes that can be hashed in order.
+    fn contiguous_len(&self) -> u64 {
+        match self.completed.first() {
+            Some(&(0, end)) => end + 1,
+            _ => 0,
+        }
+    }
+}
+
+// Fetches the inclusive byte range `start..=end` of `url`.
+async fn fetch_part(
+    client: &reqwest::Client,
+    url: &str,
+    headers: &HashMap<String, String>,
+    start: u64,
+    end: u64,
+    throttle: &Throttle,
+) -> Result<bytes::Bytes> {
+    let mut request = client
+        .get(url)
+        .header("Range", format!("bytes={start}-{end}"));
+    for (key, value) in headers.iter() {
+        request = request.header(key, value);
+    }
+
+    let response = request.send().await?;
+    if !response.status().is_success() {
+        return Err(Error::HttpErrorCode(
+            response.status().as_u16(),
+            response.text().await.unwrap_or_default(),
+        ));
+    }
+
+    // A server that ignores the range would hand us the whole file, which must not be
+    // written at this part's offset.
+    let expected = end - start + 1;
+    let mut bytes = bytes::BytesMut::with_capacity(expected as usize);
+    let mut stream = response.bytes_stream();
+    while let Some(chunk) = stream.try_next().await? {
+        throttle.consume(chunk.len()).await;
+        bytes.extend_from_slice(&chunk);
+        if bytes.len() as u64 > expected {
+            break;
+        }
+    }
+    check_part_len(start, end, bytes.len() as u64)?;
+    Ok(bytes.freeze())
+}
+
+// Checks that the body of the part `start..=end` has exactly the length of the range.
+fn check_part_len(start: u64, end: u64, received: u64) -> Result<()> {
+    let expected = end - start + 1;
+    if received != expected {
+        return Err(Error::ContentLength(format!(
+            "expected {expected} bytes for range but received {received}"
+        )));
+    }
+    Ok(())
+}
+
+// Runs `attempt` for the part `start..=end` of a ranged transfer, retrying transient
+// failures with exponential backoff.
+pub(crate) async fn retry_part<T, F, Fut>(start: u64, end: u64, mut attempt: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T>>,
+{
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        match attempt().await {
+            Ok(value) => return Ok(value),
+            Err(e) if e.is_retryable() && attempts <= PART_MAX_RETRIES => {
+                let delay = PART_RETRY_BASE_DELAY * 2u32.pow(attempts - 1);
+                log::warn!("Retrying bytes {start}-{end} in {delay:?}: {e}");
+                tokio::time::sleep(delay).await;
+            }
+            Err(e) => {
+                return Err(Error::RangeFailed {
+                    start,
+                    end,
+                    attempts,
+                    source: Box::new(e),
+                })
+            }
+        }
+    }
 }
 
 #[command]
+#[allow(clippy::too_many_arguments)]
 pub async fn download_file(
+    id: u32,
     url: &str,
     file_path: &str,
     headers: HashMap<String, String>,
     body: Option<String>,
+    digest: Option<ExpectedDigest>,
+    bandwidth_limit: Option<u64>,
     on_progress: Channel<ProgressPayload>,
-) -> Result<()> {
-    use futures::stream::{self, StreamExt};
+    http_client: State<'_, HttpClient>,
+    limiter: State<'_, BandwidthLimiter>,
+    manager: State<'_, TransferManager>,
+) -> Result<DownloadResponse> {
+    let client = http_client.client();
+    let throttle = limiter.throttle(bandwidth_limit);
+    let transfer = download(
+        &client,
+        url,
+        file_path,
+        headers,
+        body,
+        digest,
+        throttle,
+        on_progress,
+    );
+    manager
+        .run(id, TransferKind::Download, url, file_path, transfer)
+        .await
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn download(
+    client: &reqwest::Client,
+    url: &str,
+    file_path: &str,
+    headers: HashMap<String, String>,
+    body: Option<String>,
+    digest: Option<ExpectedDigest>,
+    throttle: Throttle,
+    on_progress: Channel<ProgressPayload>,
+) -> Result<DownloadResponse> {
+    use futures::stream;
     use std::cmp::min;
-    use tokio::io::AsyncSeekExt;
 
     const PART_SIZE: u64 = 1024 * 1024;
 
-    let client = reqwest::Client::new();
-
     // Check if server supports range requests
     let range_resp = client.get(url).header("Range", "bytes=0-0").send().await?;
     let accept_ranges = range_resp
@@ -132,6 +586,14 @@ pub async fn download_file(
         .and_then(|s| s.split('/').nth(1))
         .and_then(|s| s.parse::<u64>().ok())
         .unwrap_or(0);
+    let validators = DownloadResponse::from_headers(range_resp.headers());
+    let mut verifier = digest.map(DigestVerifier::new);
+
+    // Both paths below write into a partial file next to `file_path` and only move it into
+    // place once it is complete, so an existing file is never left truncated.
+    let (partial_path, manifest_path) = partial_paths(file_path);
+    let partial_path = partial_path.as_path();
+    let manifest_path = manifest_path.as_path();
 
     if !accept_ranges || total == 0 {
         // Fallback to original single-threaded logic
@@ -153,110 +615,238 @@ pub async fn download_file(
             ));
         }
 
-        let total = response.content_length().unwrap_or(0);
-        let mut file = BufWriter::new(File::create(file_path).await?);
-        let mut stream = response.bytes_stream();
-
-        let mut stats = TransferStats::default();
-        while let Some(chunk) = stream.try_next().await? {
-            file.write_all(&chunk).await?;
-            stats.record_chunk_transfer(chunk.len());
-            let _ = on_progress.send(ProgressPayload {
-                progress: stats.total_transferred,
-                total,
-                transfer_speed: stats.transfer_speed,
-            });
+        let validators = DownloadResponse::from_headers(response.headers());
+
+        // This download cannot be resumed, so a manifest left by an earlier ranged attempt
+        // no longer describes the partial file.
+        let _ = tokio::fs::remove_file(manifest_path).await;
+
+        let result =
+            stream_to_file(response, partial_path, verifier, &throttle, &on_progress).await;
+        if let Err(e) = result {
+            let _ = tokio::fs::remove_file(partial_path).await;
+            return Err(e);
         }
-        file.flush().await?;
-        return Ok(());
+
+        replace_file(partial_path, file_path).await?;
+        return Ok(validators);
     }
 
-    // Multi-part download with range access
-    let part_count = total.div_ceil(PART_SIZE);
-    let file = File::create(file_path).await?;
+    // Multi-part download with range access, resumable through the manifest of the partial file
+
+    let manifest = match PartialManifest::load(manifest_path).await {
+        Some(manifest)
+            if manifest.matches(url, total, PART_SIZE, &validators)
+                && tokio::fs::try_exists(partial_path).await.unwrap_or(false) =>
+        {
+            manifest
+        }
+        _ => PartialManifest::new(url, total, PART_SIZE, &validators),
+    };
+
+    // The partial file is also read back to hash the parts in order.
+    let mut file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .write(true)
+        .truncate(false)
+        .open(partial_path)
+        .await?;
     file.set_len(total).await?;
+    manifest.save(manifest_path).await?;
 
-    let file = Arc::new(tokio::sync::Mutex::new(file));
-    let progress = Arc::new(tokio::sync::Mutex::new(TransferStats::default()));
+    let part_count = total.div_ceil(PART_SIZE);
+    let pending_parts = (0..part_count)
+        .map(|i| (i * PART_SIZE, min((i + 1) * PART_SIZE, total) - 1))
+        .filter(|&(start, end)| !manifest.is_completed(start, end))
+        .collect::<Vec<_>>();
+
+    let stats = TransferStats::resumed(manifest.completed_len());
+
+    // Parts completed by an earlier attempt can be hashed right away.
+    if let Some(verifier) = verifier.as_mut() {
+        verifier
+            .update_from_file(&mut file, manifest.contiguous_len())
+            .await?;
+    }
 
-    stream::iter(0..part_count)
-        .for_each_concurrent(8, |i| {
-            let client = client.clone();
+    let file = Arc::new(tokio::sync::Mutex::new(file));
+    let progress = Arc::new(tokio::sync::Mutex::new(stats));
+    let manifest = Arc::new(tokio::sync::Mutex::new(manifest));
+    let verifier = verifier.map(tokio::sync::Mutex::new);
+
+    let headers = &headers;
+    let throttle = &throttle;
+    let result = stream::iter(pending_parts.into_iter().map(Ok))
+        .try_for_each_concurrent(8, |(start, end)| {
             let file = Arc::clone(&file);
             let progress = Arc::clone(&progress);
-            let headers = headers.clone();
-            let url = url.to_string();
+            let manifest = Arc::clone(&manifest);
             let on_progress = on_progress.clone();
+            let verifier = verifier.as_ref();
 
             async move {
-                let start = i * PART_SIZE;
-                let end = min(start + PART_SIZE - 1, total - 1);
-                let range_header = format!("bytes={start}-{end}");
-
-                let mut req = client.get(&url).header("Range", range_header);
-                for (key, value) in headers {
-                    req = req.header(key, value);
-                }
-
-                let resp = match req.send().await {
-                    Ok(r) => r,
-                    Err(_) => return,
-                };
+                let bytes = retry_part(start, end, || {
+                    fetch_part(client, url, headers, start, end, throttle)
+                })
+                .await?;
 
-                if !resp.status().is_success()
-                    && resp.status() != reqwest::StatusCode::PARTIAL_CONTENT
                 {
-                    return;
+                    let mut f = file.lock().await;
+                    f.seek(std::io::SeekFrom::Start(start)).await?;
+                    f.write_all(&bytes).await?;
+                    f.flush().await?;
                 }
 
-                let bytes = match resp.bytes().await {
-                    Ok(b) => b,
-                    Err(_) => return,
+                // Only record the part once its bytes have been handed to the OS.
+                let contiguous_len = {
+                    let mut manifest = manifest.lock().await;
+                    manifest.mark_completed(start, end);
+                    manifest.save(manifest_path).await?;
+                    manifest.contiguous_len()
                 };
 
-                {
+                if let Some(verifier) = verifier {
+                    let mut verifier = verifier.lock().await;
                     let mut f = file.lock().await;
-                    f.seek(std::io::SeekFrom::Start(start)).await.unwrap();
-                    f.write_all(&bytes).await.unwrap();
+                    verifier.update_from_file(&mut f, contiguous_len).await?;
                 }
 
                 {
                     let mut stat = progress.lock().await;
                     stat.record_chunk_transfer(bytes.len());
-                    let _ = on_progress.send(ProgressPayload {
-                        progress: stat.total_transferred,
-                        total,
-                        transfer_speed: stat.transfer_speed,
-                    });
+                    let finished = stat.total_transferred >= total;
+                    stat.report(&on_progress, total, finished);
                 }
+
+                Ok::<(), Error>(())
             }
         })
         .await;
 
+    // Keep the partial file and manifest around so that the next call can resume.
+    result?;
+    let received = manifest.lock().await.completed_len();
+    if received < total {
+        return Err(Error::Incomplete(received, total));
+    }
+
+    file.lock().await.sync_all().await?;
+    drop(file);
+
+    if let Some(verifier) = verifier {
+        if let Err(e) = verifier.into_inner().verify() {
+            let _ = tokio::fs::remove_file(partial_path).await;
+            let _ = tokio::fs::remove_file(manifest_path).await;
+            return Err(e);
+        }
+    }
+
+    replace_file(partial_path, file_path).await?;
+    let _ = tokio::fs::remove_file(manifest_path).await;
+
+    Ok(validators)
+}
+
+// Streams the body of `response` into a new file at `path`, verifying its digest if one is
+// expected, and makes sure the content has reached the disk before returning.
+async fn stream_to_file(
+    response: reqwest::Response,
+    path: &Path,
+    mut verifier: Option<DigestVerifier>,
+    throttle: &Throttle,
+    on_progress: &Channel<ProgressPayload>,
+) -> Result<()> {
+    let total = response.content_length().unwrap_or(0);
+    let mut file = BufWriter::new(File::create(path).await?);
+    let mut stream = response.bytes_stream();
+
+    let mut stats = TransferStats::default();
+    while let Some(chunk) = stream.try_next().await? {
+        throttle.consume(chunk.len()).await;
+        file.write_all(&chunk).await?;
+        if let Some(verifier) = verifier.as_mut() {
+            verifier.update(&chunk);
+        }
+        stats.record_chunk_transfer(chunk.len());
+        stats.report(on_progress, total, false);
+    }
+    stats.report(on_progress, total, true);
+    file.flush().await?;
+    file.into_inner().sync_all().await?;
+
+    match verifier {
+        Some(verifier) => verifier.verify(),
+        None => Ok(()),
+    }
+}
+
+// Atomically replaces `file_path` with the completed partial file, which lives in the same
+// directory and therefore on the same file system.
+async fn replace_file(partial_path: &Path, file_path: &str) -> Result<()> {
+    tokio::fs::rename(partial_path, file_path).await?;
+
+    // Persist the rename itself, which is recorded in the directory entry.
+    #[cfg(unix)]
+    if let Some(dir) = Path::new(file_path).parent() {
+        if let Ok(dir) = File::open(dir).await {
+            let _ = dir.sync_all().await;
+        }
+    }
     Ok(())
 }
 
 #[command]
+#[allow(clippy::too_many_arguments)]
 pub async fn upload_file(
+    id: u32,
     url: &str,
     file_path: &str,
     method: &str,
     headers: HashMap<String, String>,
+    bandwidth_limit: Option<u64>,
+    on_progress: Channel<ProgressPayload>,
+    http_client: State<'_, HttpClient>,
+    limiter: State<'_, BandwidthLimiter>,
+    manager: State<'_, TransferManager>,
+) -> Result<String> {
+    let client = http_client.client();
+    let throttle = limiter.throttle(bandwidth_limit);
+    let transfer = upload(
+        &client,
+        url,
+        file_path,
+        method,
+        headers,
+        throttle,
+        on_progress,
+    );
+    manager
+        .run(id, TransferKind::Upload, url, file_path, transfer)
+        .await
+}
+
+pub(crate) async fn upload(
+    client: &reqwest::Client,
+    url: &str,
+    file_path: &str,
+    method: &str,
+    headers: HashMap<String, String>,
+    throttle: Throttle,
     on_progress: Channel<ProgressPayload>,
 ) -> Result<String> {
     let file = File::open(file_path).await?;
-    let file_len = file.metadata().await.unwrap().len();
+    let file_len = file.metadata().await?.len();
 
-    let client = reqwest::Client::new();
     let mut request = match method.to_uppercase().as_str() {
         "POST" => client.post(url),
         "PUT" => client.put(url),
-        _ => return Err(Error::ContentLength("Invalid HTTP method".into())),
+        _ => return Err(Error::InvalidMethod(method.to_string())),
     };
 
     request = request
         .header(reqwest::header::CONTENT_LENGTH, file_len)
-        .body(file_to_body(on_progress.clone(), file, file_len));
+        .body(file_to_body(on_progress.clone(), file, file_len, &throttle));
 
     for (key, value) in headers {
         request = request.header(&key, value);
@@ -273,19 +863,78 @@ pub async fn upload_file(
     }
 }
 
-fn file_to_body(channel: Channel<ProgressPayload>, file: File, file_len: u64) -> reqwest::Body {
+fn file_to_body(
+    channel: Channel<ProgressPayload>,
+    file: File,
+    file_len: u64,
+    throttle: &Throttle,
+) -> reqwest::Body {
     let stream = FramedRead::new(file, BytesCodec::new()).map_ok(|r| r.freeze());
+    let stream = throttle.stream(stream);
 
     let mut stats = TransferStats::default();
     reqwest::Body::wrap_stream(ReadProgressStream::new(
         stream,
         Box::new(move |progress_chunk, _progress_total| {
             stats.record_chunk_transfer(progress_chunk as usize);
-            let _ = channel.send(ProgressPayload {
-                progress: stats.total_transferred,
-                total: file_len,
-                transfer_speed: stats.transfer_speed,
-            });
+            let finished = stats.total_transferred >= file_len;
+            stats.report(&channel, file_len, finished);
         }),
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest() -> PartialManifest {
+        PartialManifest::new(
+            "https://example.com/book.epub",
+            100,
+            10,
+            &Default::default(),
+        )
+    }
+
+    #[test]
+    fn mark_completed_merges_adjacent_and_overlapping_ranges() {
+        let mut manifest = manifest();
+        manifest.mark_completed(20, 29);
+        manifest.mark_completed(0, 9);
+        manifest.mark_completed(40, 49);
+        assert_eq!(manifest.completed, vec![(0, 9), (20, 29), (40, 49)]);
+
+        manifest.mark_completed(10, 19);
+        manifest.mark_completed(45, 59);
+        assert_eq!(manifest.completed, vec![(0, 29), (40, 59)]);
+        assert_eq!(manifest.completed_len(), 50);
+        assert!(manifest.is_completed(10, 19));
+        assert!(!manifest.is_completed(30, 39));
+    }
+
+    #[test]
+    fn contiguous_len_counts_only_the_leading_range() {
+        let mut manifest = manifest();
+        assert_eq!(manifest.contiguous_len(), 0);
+        manifest.mark_completed(10, 19);
+        assert_eq!(manifest.contiguous_len(), 0);
+        manifest.mark_completed(0, 9);
+        manifest.mark_completed(30, 39);
+        assert_eq!(manifest.contiguous_len(), 20);
+    }
+
+    #[test]
+    fn check_part_len_rejects_bodies_of_another_length() {
+        assert!(check_part_len(0, 9, 10).is_ok());
+        assert!(check_part_len(90, 99, 10).is_ok());
+        // A server that ignores the range sends the whole file.
+        assert!(matches!(
+            check_part_len(10, 19, 100),
+            Err(Error::ContentLength(_))
+        ));
+        assert!(matches!(
+            check_part_len(10, 19, 9),
+            Err(Error::ContentLength(_))
+        ));
+    }
+}
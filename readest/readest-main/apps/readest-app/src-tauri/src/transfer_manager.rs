@@ -0,0 +1,327 @@
+//! Track in-flight transfers so that they can be listed, paused or cancelled.
+//!
+//! Every `download_file`/`upload_file` call registers itself under the ID chosen by the
+//! frontend. Pausing or cancelling a transfer aborts its future, which drops the in-flight
+//! reqwest streams. A paused download keeps its partial file and manifest so that calling
+//! `download_file` again resumes it, a cancelled one has them removed. Paused and cancelled
+//! transfers stay listed until they are dismissed or, for a paused one, resumed.
+
+use futures::future::{AbortHandle, AbortRegistration, Abortable};
+use serde::{Deserialize, Serialize};
+use tauri::{command, State};
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+
+use crate::transfer_file::{partial_paths, Error};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TransferKind {
+    Download,
+    Upload,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TransferState {
+    Active,
+    Paused,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TransferInfo {
+    pub id: u32,
+    pub kind: TransferKind,
+    pub url: String,
+    pub file_path: String,
+    pub state: TransferState,
+}
+
+struct TransferEntry {
+    info: TransferInfo,
+    abort_handle: AbortHandle,
+}
+
+#[derive(Default)]
+pub struct TransferManager {
+    transfers: Mutex<HashMap<u32, TransferEntry>>,
+}
+
+impl TransferManager {
+    // Runs `transfer` under `id` until it completes or is paused or cancelled.
+    pub async fn run<T, F>(
+        &self,
+        id: u32,
+        kind: TransferKind,
+        url: &str,
+        file_path: &str,
+        transfer: F,
+    ) -> Result<T, Error>
+    where
+        F: Future<Output = Result<T, Error>>,
+    {
+        let registration = self.register(id, kind, url, file_path)?;
+        let result = Abortable::new(transfer, registration).await;
+        let state = self.unregister(id);
+
+        match result {
+            Ok(result) => result,
+            Err(_) if state == Some(TransferState::Paused) => Err(Error::Paused(id)),
+            Err(_) => {
+                if kind == TransferKind::Download {
+                    let (partial_path, manifest_path) = partial_paths(file_path);
+                    let _ = tokio::fs::remove_file(partial_path).await;
+                    let _ = tokio::fs::remove_file(manifest_path).await;
+                }
+                Err(Error::Cancelled(id))
+            }
+        }
+    }
+
+    fn register(
+        &self,
+        id: u32,
+        kind: TransferKind,
+        url: &str,
+        file_path: &str,
+    ) -> Result<AbortRegistration, Error> {
+        let mut transfers = self.transfers.lock().unwrap();
+        // A paused or cancelled transfer may be started again under the same ID.
+        if transfers
+            .get(&id)
+            .is_some_and(|entry| entry.info.state == TransferState::Active)
+        {
+            return Err(Error::DuplicateTransfer(id));
+        }
+
+        let (abort_handle, registration) = AbortHandle::new_pair();
+        let info = TransferInfo {
+            id,
+            kind,
+            url: url.to_string(),
+            file_path: file_path.to_string(),
+            state: TransferState::Active,
+        };
+        transfers.insert(id, TransferEntry { info, abort_handle });
+        Ok(registration)
+    }
+
+    // Forgets a finished transfer, but keeps a paused or cancelled one listed.
+    fn unregister(&self, id: u32) -> Option<TransferState> {
+        let mut transfers = self.transfers.lock().unwrap();
+        let state = transfers.get(&id).map(|entry| entry.info.state);
+        if state == Some(TransferState::Active) {
+            transfers.remove(&id);
+        }
+        state
+    }
+
+    fn abort(&self, id: u32, state: TransferState) -> bool {
+        let mut transfers = self.transfers.lock().unwrap();
+        match transfers.get_mut(&id) {
+            Some(entry) if entry.info.state == TransferState::Active => {
+                entry.info.state = state;
+                entry.abort_handle.abort();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn cancel(&self, id: u32) -> bool {
+        if self.abort(id, TransferState::Cancelled) {
+            return true;
+        }
+        // A paused transfer has no future left to abort, only its partial file to remove.
+        let mut transfers = self.transfers.lock().unwrap();
+        match transfers.get_mut(&id) {
+            Some(entry) if entry.info.state == TransferState::Paused => {
+                entry.info.state = TransferState::Cancelled;
+                if entry.info.kind == TransferKind::Download {
+                    let (partial_path, manifest_path) = partial_paths(&entry.info.file_path);
+                    let _ = std::fs::remove_file(partial_path);
+                    let _ = std::fs::remove_file(manifest_path);
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn pause(&self, id: u32) -> bool {
+        self.abort(id, TransferState::Paused)
+    }
+
+    // Removes a paused or cancelled transfer from the list.
+    pub fn dismiss(&self, id: u32) -> bool {
+        let mut transfers = self.transfers.lock().unwrap();
+        match transfers.get(&id) {
+            Some(entry) if entry.info.state != TransferState::Active => {
+                transfers.remove(&id);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn list(&self) -> Vec<TransferInfo> {
+        let transfers = self.transfers.lock().unwrap();
+        let mut list = transfers
+            .values()
+            .map(|entry| entry.info.clone())
+            .collect::<Vec<_>>();
+        list.sort_by_key(|info| info.id);
+        list
+    }
+}
+
+#[command]
+pub fn cancel_transfer(id: u32, manager: State<'_, TransferManager>) -> bool {
+    manager.cancel(id)
+}
+
+#[command]
+pub fn pause_transfer(id: u32, manager: State<'_, TransferManager>) -> bool {
+    manager.pause(id)
+}
+
+#[command]
+pub fn dismiss_transfer(id: u32, manager: State<'_, TransferManager>) -> bool {
+    manager.dismiss(id)
+}
+
+#[command]
+pub fn list_transfers(manager: State<'_, TransferManager>) -> Vec<TransferInfo> {
+    manager.list()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::pin::pin;
+
+    const URL: &str = "https://example.com/book.epub";
+
+    // A download whose partial file and manifest are in a temporary directory.
+    fn partial_download(name: &str) -> (String, PathBuf, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("readest-transfers-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file_path = dir.join(name).to_string_lossy().into_owned();
+        let (partial_path, manifest_path) = partial_paths(&file_path);
+        std::fs::write(&partial_path, b"partial").unwrap();
+        std::fs::write(&manifest_path, b"{}").unwrap();
+        (file_path, partial_path, manifest_path)
+    }
+
+    fn states(manager: &TransferManager) -> Vec<(u32, TransferState)> {
+        manager
+            .list()
+            .iter()
+            .map(|info| (info.id, info.state))
+            .collect()
+    }
+
+    #[test]
+    fn keeps_track_of_active_transfers() {
+        let manager = TransferManager::default();
+        let _first = manager.register(2, TransferKind::Upload, URL, "b").unwrap();
+        let _second = manager
+            .register(1, TransferKind::Download, URL, "a")
+            .unwrap();
+        assert!(matches!(
+            manager.register(1, TransferKind::Download, URL, "a"),
+            Err(Error::DuplicateTransfer(1))
+        ));
+        assert_eq!(
+            states(&manager),
+            [(1, TransferState::Active), (2, TransferState::Active)]
+        );
+        let info = &manager.list()[1];
+        assert_eq!(
+            (info.kind, info.file_path.as_str()),
+            (TransferKind::Upload, "b")
+        );
+
+        assert_eq!(manager.unregister(1), Some(TransferState::Active));
+        assert_eq!(manager.unregister(1), None);
+        assert_eq!(states(&manager), [(2, TransferState::Active)]);
+    }
+
+    #[tokio::test]
+    async fn forgets_finished_transfers() {
+        let manager = TransferManager::default();
+        let result = manager
+            .run(1, TransferKind::Download, URL, "a", async { Ok(42) })
+            .await;
+        assert_eq!(result.unwrap(), 42);
+        assert!(manager.list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn removes_partial_files_of_cancelled_downloads() {
+        let manager = TransferManager::default();
+        let (file_path, partial_path, manifest_path) = partial_download("cancelled.epub");
+        let transfer = std::future::pending::<Result<(), Error>>();
+        let mut run = pin!(manager.run(1, TransferKind::Download, URL, &file_path, transfer));
+        assert!(futures::poll!(run.as_mut()).is_pending());
+
+        assert!(manager.cancel(1));
+        assert!(matches!(run.await, Err(Error::Cancelled(1))));
+        assert!(!partial_path.exists() && !manifest_path.exists());
+        assert_eq!(states(&manager), [(1, TransferState::Cancelled)]);
+        assert!(!manager.cancel(1));
+        assert!(manager.dismiss(1));
+        assert!(manager.list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn keeps_paused_downloads_resumable() {
+        let manager = TransferManager::default();
+        let (file_path, partial_path, manifest_path) = partial_download("paused.epub");
+        let transfer = std::future::pending::<Result<(), Error>>();
+        let mut run = pin!(manager.run(1, TransferKind::Download, URL, &file_path, transfer));
+        assert!(futures::poll!(run.as_mut()).is_pending());
+
+        assert!(manager.pause(1));
+        assert!(matches!(run.await, Err(Error::Paused(1))));
+        assert!(partial_path.exists() && manifest_path.exists());
+        assert_eq!(states(&manager), [(1, TransferState::Paused)]);
+        assert!(!manager.pause(1));
+
+        // Starting the transfer again resumes it under the same ID.
+        let result = manager
+            .run(1, TransferKind::Download, URL, &file_path, async { Ok(()) })
+            .await;
+        assert!(result.is_ok());
+        assert!(manager.list().is_empty());
+
+        // Cancelling a paused download removes what it has downloaded.
+        let transfer = std::future::pending::<Result<(), Error>>();
+        let mut run = pin!(manager.run(2, TransferKind::Download, URL, &file_path, transfer));
+        assert!(futures::poll!(run.as_mut()).is_pending());
+        assert!(manager.pause(2));
+        assert!(matches!(run.await, Err(Error::Paused(2))));
+        assert!(manager.cancel(2));
+        assert!(!partial_path.exists() && !manifest_path.exists());
+        assert_eq!(states(&manager), [(2, TransferState::Cancelled)]);
+    }
+
+    #[tokio::test]
+    async fn does_not_dismiss_running_transfers() {
+        let manager = TransferManager::default();
+        let transfer = std::future::pending::<Result<(), Error>>();
+        let mut run = pin!(manager.run(1, TransferKind::Upload, URL, "a", transfer));
+        assert!(futures::poll!(run.as_mut()).is_pending());
+
+        assert!(!manager.dismiss(1));
+        assert!(!manager.dismiss(2));
+        assert_eq!(states(&manager), [(1, TransferState::Active)]);
+        assert!(manager.cancel(1));
+        assert!(matches!(run.await, Err(Error::Cancelled(1))));
+    }
+}
@@ -0,0 +1,456 @@
+//! Upload large files to S3-compatible storage (R2, MinIO, ...) as a multipart upload.
+//!
+//! The storage credentials stay on the server, which creates the multipart upload and
+//! presigns one `UploadPart` URL per part plus the `CompleteMultipartUpload` URL. The file
+//! is split into parts of `part_size` bytes that are uploaded in parallel. The ETag of every
+//! confirmed part is saved to a manifest in the app cache dir, keyed by the upload ID, so
+//! that calling `upload_file_multipart` again for the same upload only sends the parts that
+//! the server has not confirmed yet. A cancelled upload is aborted through the presigned
+//! `AbortMultipartUpload` URL, so that the storage drops the parts it already has, and its
+//! manifest is removed.
+
+use futures::stream::{self, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use tauri::{command, ipc::Channel, AppHandle, Manager, State};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::http_client::HttpClient;
+use crate::transfer_file::{retry_part, Error, ProgressPayload, Result, TransferStats};
+use crate::transfer_manager::{TransferKind, TransferManager};
+use crate::transfer_throttle::{BandwidthLimiter, Throttle};
+
+// S3 rejects parts smaller than 5 MiB, except for the last one.
+const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+const CONCURRENT_PARTS: usize = 4;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MultipartUpload {
+    pub upload_id: String,
+    pub part_size: u64,
+    pub part_urls: Vec<String>, // Presigned `UploadPart` URLs, in part number order
+    pub complete_url: String,   // Presigned `CompleteMultipartUpload` URL
+    #[serde(default)]
+    pub abort_url: Option<String>, // Presigned `AbortMultipartUpload` URL
+}
+
+// The MultipartManifest records the ETags of the parts the server has confirmed.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MultipartManifest {
+    file_len: u64,
+    part_size: u64,
+    etags: BTreeMap<usize, String>, // Part number to ETag
+}
+
+impl MultipartManifest {
+    async fn load(path: &Path) -> Option<Self> {
+        let data = tokio::fs::read(path).await.ok()?;
+        serde_json::from_slice(&data).ok()
+    }
+
+    async fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let data = serde_json::to_vec(self).map_err(std::io::Error::from)?;
+        tokio::fs::write(path, data).await?;
+        Ok(())
+    }
+
+    fn confirmed_len(&self) -> u64 {
+        self.etags
+            .keys()
+            .map(|&part_number| part_range(part_number, self.part_size, self.file_len))
+            .map(|(start, end)| end - start + 1)
+            .sum()
+    }
+
+    fn complete_request_body(&self) -> String {
+        let parts = self
+            .etags
+            .iter()
+            .map(|(part_number, etag)| {
+                format!("<Part><PartNumber>{part_number}</PartNumber><ETag>{etag}</ETag></Part>")
+            })
+            .collect::<String>();
+        format!("<CompleteMultipartUpload>{parts}</CompleteMultipartUpload>")
+    }
+}
+
+// Inclusive byte range of the 1-based `part_number`.
+fn part_range(part_number: usize, part_size: u64, file_len: u64) -> (u64, u64) {
+    let start = (part_number as u64 - 1) * part_size;
+    let end = (start + part_size).min(file_len) - 1;
+    (start, end)
+}
+
+fn manifest_path(app: &AppHandle, upload_id: &str) -> Result<PathBuf> {
+    // Upload IDs are opaque, keep only the characters that are safe in a file name.
+    let name = upload_id
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect::<String>();
+    let dir = app.path().app_cache_dir()?.join("multipart_uploads");
+    Ok(dir.join(format!("{name}.json")))
+}
+
+async fn upload_part(
+    client: &reqwest::Client,
+    url: &str,
+    headers: &HashMap<String, String>,
+    data: bytes::Bytes,
+    throttle: &Throttle,
+) -> Result<String> {
+    let mut request = client
+        .put(url)
+        .header(reqwest::header::CONTENT_LENGTH, data.len())
+        .body(throttle.body(data));
+    for (key, value) in headers.iter() {
+        request = request.header(key, value);
+    }
+
+    let response = request.send().await?;
+    if !response.status().is_success() {
+        return Err(Error::HttpErrorCode(
+            response.status().as_u16(),
+            response.text().await.unwrap_or_default(),
+        ));
+    }
+
+    response
+        .headers()
+        .get(reqwest::header::ETAG)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+        .ok_or_else(|| Error::InvalidMultipartUpload("part response has no ETag".into()))
+}
+
+async fn complete_upload(
+    client: &reqwest::Client,
+    url: &str,
+    manifest: &MultipartManifest,
+) -> Result<String> {
+    let response = client
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/xml")
+        .body(manifest.complete_request_body())
+        .send()
+        .await?;
+    let status = response.status();
+    let text = response.text().await.unwrap_or_default();
+
+    // S3 may report a failed completion with a 200 status and an error document.
+    if !status.is_success() || text.contains("<Error>") {
+        return Err(Error::HttpErrorCode(status.as_u16(), text));
+    }
+    Ok(text)
+}
+
+async fn abort_upload(client: &reqwest::Client, url: &str) -> Result<()> {
+    let response = client.delete(url).send().await?;
+    let status = response.status();
+    // The upload is gone already when it was completed or aborted before.
+    if !status.is_success() && status != reqwest::StatusCode::NOT_FOUND {
+        return Err(Error::HttpErrorCode(
+            status.as_u16(),
+            response.text().await.unwrap_or_default(),
+        ));
+    }
+    Ok(())
+}
+
+// Aborts an upload, if it has an abort URL, and removes its manifest.
+async fn discard_upload(
+    client: &reqwest::Client,
+    abort_url: Option<&str>,
+    manifest_path: &Path,
+) -> Result<()> {
+    let _ = tokio::fs::remove_file(manifest_path).await;
+    match abort_url {
+        Some(url) => abort_upload(client, url).await,
+        None => Ok(()),
+    }
+}
+
+async fn upload_multipart(
+    client: &reqwest::Client,
+    file_path: &str,
+    upload: MultipartUpload,
+    headers: HashMap<String, String>,
+    manifest_path: PathBuf,
+    throttle: Throttle,
+    on_progress: Channel<ProgressPayload>,
+) -> Result<String> {
+    let file_len = File::open(file_path).await?.metadata().await?.len();
+
+    if file_len == 0 || upload.part_size == 0 {
+        return Err(Error::InvalidMultipartUpload(
+            "cannot split an empty file or into empty parts".into(),
+        ));
+    }
+    if upload.part_size < MIN_PART_SIZE && upload.part_urls.len() > 1 {
+        return Err(Error::InvalidMultipartUpload(format!(
+            "part size {} is below the minimum of {MIN_PART_SIZE} bytes",
+            upload.part_size
+        )));
+    }
+    let part_count = file_len.div_ceil(upload.part_size) as usize;
+    if upload.part_urls.len() != part_count {
+        return Err(Error::InvalidMultipartUpload(format!(
+            "expected {part_count} part URLs but got {}",
+            upload.part_urls.len()
+        )));
+    }
+
+    let manifest = match MultipartManifest::load(&manifest_path).await {
+        Some(manifest)
+            if manifest.file_len == file_len && manifest.part_size == upload.part_size =>
+        {
+            manifest
+        }
+        _ => MultipartManifest {
+            file_len,
+            part_size: upload.part_size,
+            etags: BTreeMap::new(),
+        },
+    };
+
+    let pending_parts = (1..=part_count)
+        .filter(|part_number| !manifest.etags.contains_key(part_number))
+        .collect::<Vec<_>>();
+
+    let stats = TransferStats::resumed(manifest.confirmed_len());
+
+    let progress = Arc::new(tokio::sync::Mutex::new(stats));
+    let manifest = Arc::new(tokio::sync::Mutex::new(manifest));
+    let headers = &headers;
+    let upload = &upload;
+    let throttle = &throttle;
+    let manifest_path = manifest_path.as_path();
+
+    stream::iter(pending_parts.into_iter().map(Ok))
+        .try_for_each_concurrent(CONCURRENT_PARTS, |part_number| {
+            let progress = Arc::clone(&progress);
+            let manifest = Arc::clone(&manifest);
+            let on_progress = on_progress.clone();
+
+            async move {
+                let (start, end) = part_range(part_number, upload.part_size, file_len);
+                let mut data = vec![0; (end - start + 1) as usize];
+                let mut file = File::open(file_path).await?;
+                file.seek(std::io::SeekFrom::Start(start)).await?;
+                file.read_exact(&mut data).await?;
+                let data = bytes::Bytes::from(data);
+
+                let url = &upload.part_urls[part_number - 1];
+                let etag = retry_part(start, end, || {
+                    upload_part(client, url, headers, data.clone(), throttle)
+                })
+                .await?;
+
+                {
+                    let mut manifest = manifest.lock().await;
+                    manifest.etags.insert(part_number, etag);
+                    manifest.save(manifest_path).await?;
+                }
+
+                {
+                    let mut stat = progress.lock().await;
+                    stat.record_chunk_transfer(data.len());
+                    let finished = stat.total_transferred >= file_len;
+                    stat.report(&on_progress, file_len, finished);
+                }
+
+                Ok::<(), Error>(())
+            }
+        })
+        .await?;
+
+    let manifest = manifest.lock().await;
+    let response = complete_upload(client, &upload.complete_url, &manifest).await?;
+    let _ = tokio::fs::remove_file(manifest_path).await;
+    Ok(response)
+}
+
+#[command]
+#[allow(clippy::too_many_arguments)]
+pub async fn upload_file_multipart(
+    id: u32,
+    file_path: &str,
+    upload: MultipartUpload,
+    headers: HashMap<String, String>,
+    bandwidth_limit: Option<u64>,
+    on_progress: Channel<ProgressPayload>,
+    app: AppHandle,
+    http_client: State<'_, HttpClient>,
+    limiter: State<'_, BandwidthLimiter>,
+    manager: State<'_, TransferManager>,
+) -> Result<String> {
+    let client = http_client.client();
+    let throttle = limiter.throttle(bandwidth_limit);
+    let manifest_path = manifest_path(&app, &upload.upload_id)?;
+    let url = upload.complete_url.clone();
+    let abort_url = upload.abort_url.clone();
+    let transfer = upload_multipart(
+        &client,
+        file_path,
+        upload,
+        headers,
+        manifest_path.clone(),
+        throttle,
+        on_progress,
+    );
+    let result = manager
+        .run(id, TransferKind::Upload, &url, file_path, transfer)
+        .await;
+    if let Err(Error::Cancelled(_)) = result {
+        if let Err(e) = discard_upload(&client, abort_url.as_deref(), &manifest_path).await {
+            log::warn!("Failed to abort the multipart upload of {file_path}: {e}");
+        }
+    }
+    result
+}
+
+// Aborts a multipart upload that will not be completed, e.g. one that failed or was
+// paused, and removes its manifest.
+#[command]
+pub async fn abort_file_multipart(
+    upload_id: &str,
+    abort_url: Option<String>,
+    app: AppHandle,
+    http_client: State<'_, HttpClient>,
+) -> Result<()> {
+    let manifest_path = manifest_path(&app, upload_id)?;
+    discard_upload(&http_client.client(), abort_url.as_deref(), &manifest_path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusty_s3::actions::CreateMultipartUpload;
+    use rusty_s3::{Bucket, Credentials, S3Action, UrlStyle};
+
+    use std::time::Duration;
+
+    use crate::transfer_throttle::BandwidthLimiter;
+
+    // Presigned URLs are valid for longer than the test runs.
+    const EXPIRES_IN: Duration = Duration::from_secs(600);
+
+    // Runs against a MinIO server given by `MINIO_ENDPOINT`, `MINIO_ACCESS_KEY`,
+    // `MINIO_SECRET_KEY` and `MINIO_BUCKET`, e.g. one started with
+    // `docker run -p 9000:9000 minio/minio server /data` and a bucket created in it:
+    // `MINIO_ENDPOINT=http://127.0.0.1:9000 ... cargo test -- --ignored minio`. The URLs
+    // are presigned with rusty-s3, as the server presigns them for the app.
+    #[tokio::test]
+    #[ignore = "needs a MinIO server"]
+    async fn minio_multipart_upload_completes_and_aborts() {
+        let env = |name: &str| std::env::var(name).unwrap_or_else(|_| panic!("{name} is unset"));
+        let endpoint = env("MINIO_ENDPOINT").parse().unwrap();
+        let bucket =
+            Bucket::new(endpoint, UrlStyle::Path, env("MINIO_BUCKET"), "us-east-1").unwrap();
+        let credentials = Credentials::new(env("MINIO_ACCESS_KEY"), env("MINIO_SECRET_KEY"));
+        let credentials = Some(&credentials);
+        let client = reqwest::Client::new();
+        let throttle = BandwidthLimiter::default().throttle(None);
+        let dir = std::env::temp_dir().join(format!("readest-minio-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // Three parts, the last one shorter.
+        let data = (0..MIN_PART_SIZE * 2 + 1024)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let file_path = dir.join("book.epub");
+        std::fs::write(&file_path, &data).unwrap();
+
+        let create = |key: &'static str| {
+            let url = bucket
+                .create_multipart_upload(credentials, key)
+                .sign(EXPIRES_IN);
+            let client = client.clone();
+            async move {
+                let response = client.post(url).send().await.unwrap();
+                assert!(response.status().is_success());
+                let text = response.text().await.unwrap();
+                let created = CreateMultipartUpload::parse_response(&text).unwrap();
+                created.upload_id().to_string()
+            }
+        };
+        let presign_upload = |key: &str, upload_id: &str, part_count: u16| MultipartUpload {
+            upload_id: upload_id.to_string(),
+            part_size: MIN_PART_SIZE,
+            part_urls: (1..=part_count)
+                .map(|part_number| {
+                    let part = bucket.upload_part(credentials, key, part_number, upload_id);
+                    part.sign(EXPIRES_IN).to_string()
+                })
+                .collect(),
+            complete_url: bucket
+                .complete_multipart_upload(credentials, key, upload_id, std::iter::empty())
+                .sign(EXPIRES_IN)
+                .to_string(),
+            abort_url: Some(
+                bucket
+                    .abort_multipart_upload(credentials, key, upload_id)
+                    .sign(EXPIRES_IN)
+                    .to_string(),
+            ),
+        };
+
+        // A complete upload reassembles the file.
+        let key = "multipart/book.epub";
+        let id = create(key).await;
+        let manifest_path = dir.join("complete.json");
+        upload_multipart(
+            &client,
+            file_path.to_str().unwrap(),
+            presign_upload(key, &id, 3),
+            HashMap::new(),
+            manifest_path.clone(),
+            throttle.clone(),
+            Channel::new(|_| Ok(())),
+        )
+        .await
+        .unwrap();
+        assert!(!manifest_path.exists());
+        let url = bucket.get_object(credentials, key).sign(EXPIRES_IN);
+        let uploaded = client.get(url).send().await.unwrap().bytes().await.unwrap();
+        assert_eq!(uploaded.as_ref(), data.as_slice());
+
+        // An aborted upload drops its parts, and its manifest.
+        let key = "multipart/aborted.epub";
+        let id = create(key).await;
+        let upload = presign_upload(key, &id, 3);
+        let part = bytes::Bytes::copy_from_slice(&data[..MIN_PART_SIZE as usize]);
+        upload_part(
+            &client,
+            &upload.part_urls[0],
+            &HashMap::new(),
+            part,
+            &throttle,
+        )
+        .await
+        .unwrap();
+        let manifest_path = dir.join("aborted.json");
+        std::fs::write(&manifest_path, "{}").unwrap();
+        discard_upload(&client, upload.abort_url.as_deref(), &manifest_path)
+            .await
+            .unwrap();
+        assert!(!manifest_path.exists());
+        let list_parts = bucket.list_parts(credentials, key, &id).sign(EXPIRES_IN);
+        let response = client.get(list_parts).send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,524 @@
+//! A persistent queue of background downloads and uploads.
+//!
+//! Queued transfers are saved to the app data dir so that they survive restarts, and the
+//! ones that were pending, interrupted or failed are started again when the app launches.
+//! Credentials in the request headers are never written to disk, so a transfer that had
+//! them waits after a restart until the frontend supplies them again through
+//! `retry_queued_transfer`. Paused transfers stay paused until they are retried.
+//! Queued transfers use the shared [`HttpClient`], and the number of transfers running at the
+//! same time is limited both globally and per host. Queued transfers run through the
+//! [`TransferManager`], so they can be paused or cancelled like any other transfer.
+
+use serde::{Deserialize, Serialize};
+use tauri::{
+    command,
+    ipc::{Channel, InvokeResponseBody},
+    AppHandle, Emitter, Manager, State,
+};
+use tokio::sync::Semaphore;
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::http_client::HttpClient;
+use crate::transfer_file::{download, upload, Error, ExpectedDigest};
+use crate::transfer_manager::{TransferKind, TransferManager};
+use crate::transfer_throttle::BandwidthLimiter;
+
+const QUEUE_FILE: &str = "transfer_queue.json";
+const MAX_CONCURRENT_TRANSFERS: usize = 4;
+const MAX_CONCURRENT_TRANSFERS_PER_HOST: usize = 2;
+
+// Request headers that carry credentials, which are kept in memory only.
+const CREDENTIAL_HEADERS: &[&str] = &["authorization", "proxy-authorization", "cookie"];
+
+// Event emitted with a `TransferQueueEvent` whenever a queued transfer changes.
+pub const TRANSFER_QUEUE_EVENT: &str = "transfer-queue";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueuedTransfer {
+    pub id: u32,
+    pub kind: TransferKind,
+    pub url: String,
+    pub file_path: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub method: Option<String>, // HTTP method of uploads, "PUT" when absent
+    #[serde(default)]
+    pub body: Option<String>, // Request body of downloads
+    #[serde(default)]
+    pub digest: Option<ExpectedDigest>,
+    #[serde(default)]
+    pub bandwidth_limit: Option<u64>, // Bytes per second
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum QueueItemStatus {
+    Pending,
+    Running,
+    Paused,
+    Failed,
+    CredentialsRequired, // The credential headers were dropped when the queue was saved
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueueItem {
+    #[serde(flatten)]
+    pub transfer: QueuedTransfer,
+    pub status: QueueItemStatus,
+    pub attempts: u32,
+    pub error: Option<String>,
+    #[serde(default)]
+    pub credential_headers: Vec<String>, // Names of the credential headers of the transfer
+}
+
+fn is_credential_header(name: &str) -> bool {
+    CREDENTIAL_HEADERS.contains(&name.to_ascii_lowercase().as_str())
+}
+
+impl QueueItem {
+    fn new(transfer: QueuedTransfer) -> Self {
+        let credential_headers = transfer
+            .headers
+            .keys()
+            .filter(|name| is_credential_header(name))
+            .cloned()
+            .collect();
+        Self {
+            transfer,
+            status: QueueItemStatus::Pending,
+            attempts: 0,
+            error: None,
+            credential_headers,
+        }
+    }
+
+    // The item as it is saved to disk, without the values of its credential headers.
+    fn without_credentials(&self) -> Self {
+        let mut item = self.clone();
+        item.transfer
+            .headers
+            .retain(|name, _| !is_credential_header(name));
+        item
+    }
+
+    fn has_credentials(&self) -> bool {
+        self.credential_headers.iter().all(|name| {
+            self.transfer
+                .headers
+                .keys()
+                .any(|key| key.eq_ignore_ascii_case(name))
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TransferQueueEvent {
+    pub id: u32,
+    // 'pending' | 'running' | 'progress' | 'completed' | 'paused' | 'failed' | 'cancelled'
+    // | 'credentialsRequired'
+    pub status: String,
+    pub progress: Option<serde_json::Value>,
+    pub error: Option<String>,
+}
+
+pub struct TransferQueue {
+    path: PathBuf,
+    items: Mutex<Vec<QueueItem>>,
+    global_limit: Arc<Semaphore>,
+    host_limits: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl TransferQueue {
+    // Loads the queue saved in the app data dir, if any.
+    pub fn load(app: &AppHandle) -> tauri::Result<Self> {
+        let path = app.path().app_data_dir()?.join(QUEUE_FILE);
+        let items = std::fs::read(&path)
+            .ok()
+            .and_then(|data| serde_json::from_slice::<Vec<QueueItem>>(&data).ok())
+            .unwrap_or_default();
+
+        Ok(Self {
+            path,
+            items: Mutex::new(items),
+            global_limit: Arc::new(Semaphore::new(MAX_CONCURRENT_TRANSFERS)),
+            host_limits: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn save(&self, items: &[QueueItem]) {
+        let items = items
+            .iter()
+            .map(QueueItem::without_credentials)
+            .collect::<Vec<_>>();
+        let result = serde_json::to_vec_pretty(&items)
+            .map_err(std::io::Error::from)
+            .and_then(|data| {
+                if let Some(parent) = self.path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                let tmp_path = self.path.with_extension("json.tmp");
+                std::fs::write(&tmp_path, data)?;
+                std::fs::rename(&tmp_path, &self.path)
+            });
+        if let Err(e) = result {
+            log::error!("Failed to save transfer queue: {e}");
+        }
+    }
+
+    fn update<T>(&self, f: impl FnOnce(&mut Vec<QueueItem>) -> T) -> T {
+        let mut items = self.items.lock().unwrap();
+        let result = f(&mut items);
+        self.save(&items);
+        result
+    }
+
+    fn set_status(&self, id: u32, status: QueueItemStatus, error: Option<String>) {
+        self.update(|items| {
+            if let Some(item) = items.iter_mut().find(|item| item.transfer.id == id) {
+                if status == QueueItemStatus::Running {
+                    item.attempts += 1;
+                }
+                item.status = status;
+                item.error = error;
+            }
+        });
+    }
+
+    fn remove(&self, id: u32) -> bool {
+        self.update(|items| {
+            let len = items.len();
+            items.retain(|item| item.transfer.id != id);
+            items.len() != len
+        })
+    }
+
+    fn host_limit(&self, url: &str) -> Arc<Semaphore> {
+        let host = reqwest::Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+            .unwrap_or_default();
+        let mut host_limits = self.host_limits.lock().unwrap();
+        host_limits
+            .entry(host)
+            .or_insert_with(|| Arc::new(Semaphore::new(MAX_CONCURRENT_TRANSFERS_PER_HOST)))
+            .clone()
+    }
+
+    pub fn list(&self) -> Vec<QueueItem> {
+        self.items.lock().unwrap().clone()
+    }
+}
+
+fn emit_event(app: &AppHandle, event: TransferQueueEvent) {
+    if let Err(e) = app.emit(TRANSFER_QUEUE_EVENT, event) {
+        log::error!("Failed to emit transfer queue event: {e}");
+    }
+}
+
+fn emit_status(app: &AppHandle, id: u32, status: &str, error: Option<String>) {
+    emit_event(
+        app,
+        TransferQueueEvent {
+            id,
+            status: status.to_string(),
+            progress: None,
+            error,
+        },
+    );
+}
+
+// Forwards the progress of a queued transfer as `TransferQueueEvent`s.
+fn progress_channel<T>(app: &AppHandle, id: u32) -> Channel<T> {
+    let app = app.clone();
+    Channel::new(move |body| {
+        if let InvokeResponseBody::Json(json) = body {
+            let progress = serde_json::from_str(&json).ok();
+            emit_event(
+                &app,
+                TransferQueueEvent {
+                    id,
+                    status: "progress".to_string(),
+                    progress,
+                    error: None,
+                },
+            );
+        }
+        Ok(())
+    })
+}
+
+async fn run_transfer(app: &AppHandle, transfer: &QueuedTransfer) -> Result<(), Error> {
+    let client = &app.state::<HttpClient>().client();
+    let manager = app.state::<TransferManager>();
+    let limiter = app.state::<BandwidthLimiter>();
+    let on_progress = progress_channel(app, transfer.id);
+    let QueuedTransfer {
+        id,
+        kind,
+        url,
+        file_path,
+        headers,
+        method,
+        body,
+        digest,
+        bandwidth_limit,
+    } = transfer.clone();
+    let throttle = limiter.throttle(bandwidth_limit);
+
+    match kind {
+        TransferKind::Download => {
+            let transfer = download(
+                client,
+                &url,
+                &file_path,
+                headers,
+                body,
+                digest,
+                throttle,
+                on_progress,
+            );
+            manager
+                .run(id, kind, &url, &file_path, transfer)
+                .await
+                .map(|_| ())
+        }
+        TransferKind::Upload => {
+            let method = method.unwrap_or_else(|| "PUT".to_string());
+            let transfer = upload(
+                client,
+                &url,
+                &file_path,
+                &method,
+                headers,
+                throttle,
+                on_progress,
+            );
+            manager
+                .run(id, kind, &url, &file_path, transfer)
+                .await
+                .map(|_| ())
+        }
+    }
+}
+
+// Waits for a free slot on the transfer's host and globally, then runs the transfer.
+async fn process(app: AppHandle, id: u32) {
+    let queue = app.state::<TransferQueue>();
+    let Some(transfer) = queue
+        .list()
+        .into_iter()
+        .find(|item| item.transfer.id == id)
+        .map(|item| item.transfer)
+    else {
+        return;
+    };
+
+    // Take the host slot first so that a busy host does not hold on to a global slot.
+    let host_limit = queue.host_limit(&transfer.url);
+    let Ok(_host_permit) = host_limit.acquire_owned().await else {
+        return;
+    };
+    let Ok(_global_permit) = queue.global_limit.clone().acquire_owned().await else {
+        return;
+    };
+
+    // The transfer may have been removed from the queue while waiting for a slot.
+    if !queue.list().iter().any(|item| item.transfer.id == id) {
+        return;
+    }
+
+    queue.set_status(id, QueueItemStatus::Running, None);
+    emit_status(&app, id, "running", None);
+
+    match run_transfer(&app, &transfer).await {
+        Ok(()) => {
+            queue.remove(id);
+            emit_status(&app, id, "completed", None);
+        }
+        Err(Error::Cancelled(_)) => {
+            queue.remove(id);
+            app.state::<TransferManager>().dismiss(id);
+            emit_status(&app, id, "cancelled", None);
+        }
+        Err(Error::Paused(_)) => {
+            queue.set_status(id, QueueItemStatus::Paused, None);
+            emit_status(&app, id, "paused", None);
+        }
+        Err(e) => {
+            log::warn!("Queued transfer {id} failed: {e}");
+            queue.set_status(id, QueueItemStatus::Failed, Some(e.to_string()));
+            emit_status(&app, id, "failed", Some(e.to_string()));
+        }
+    }
+}
+
+fn spawn(app: &AppHandle, id: u32) {
+    tauri::async_runtime::spawn(process(app.clone(), id));
+}
+
+// Restarts the queued transfers that failed or were interrupted when the app last quit,
+// except the paused ones and those whose credentials have to be supplied again.
+pub fn resume(app: &AppHandle) {
+    let queue = app.state::<TransferQueue>();
+    let (ids, waiting) = queue.update(|items| {
+        let mut ids = Vec::new();
+        let mut waiting = Vec::new();
+        for item in items.iter_mut() {
+            if item.status == QueueItemStatus::Paused {
+                continue;
+            }
+            if item.has_credentials() {
+                item.status = QueueItemStatus::Pending;
+                ids.push(item.transfer.id);
+            } else {
+                item.status = QueueItemStatus::CredentialsRequired;
+                waiting.push(item.transfer.id);
+            }
+        }
+        (ids, waiting)
+    });
+    for id in waiting {
+        emit_status(app, id, "credentialsRequired", None);
+    }
+    for id in ids {
+        spawn(app, id);
+    }
+}
+
+#[command]
+pub fn enqueue_transfer(
+    transfer: QueuedTransfer,
+    app: AppHandle,
+    queue: State<'_, TransferQueue>,
+) -> Result<(), Error> {
+    let id = transfer.id;
+    queue.update(|items| {
+        if items.iter().any(|item| item.transfer.id == id) {
+            return Err(Error::DuplicateTransfer(id));
+        }
+        items.push(QueueItem::new(transfer));
+        Ok(())
+    })?;
+
+    emit_status(&app, id, "pending", None);
+    spawn(&app, id);
+    Ok(())
+}
+
+#[command]
+pub fn list_queued_transfers(queue: State<'_, TransferQueue>) -> Vec<QueueItem> {
+    queue.list()
+}
+
+// Starts a failed or paused transfer again, or one waiting for its credentials, which
+// are passed in `headers` along with any other header to replace.
+#[command]
+pub fn retry_queued_transfer(
+    id: u32,
+    headers: Option<HashMap<String, String>>,
+    app: AppHandle,
+    queue: State<'_, TransferQueue>,
+) -> bool {
+    let retry = queue.update(|items| {
+        let Some(item) = items.iter_mut().find(|item| {
+            item.transfer.id == id
+                && matches!(
+                    item.status,
+                    QueueItemStatus::Failed
+                        | QueueItemStatus::Paused
+                        | QueueItemStatus::CredentialsRequired
+                )
+        }) else {
+            return false;
+        };
+        if let Some(headers) = headers {
+            for name in headers.keys().filter(|name| is_credential_header(name)) {
+                if !item
+                    .credential_headers
+                    .iter()
+                    .any(|known| known.eq_ignore_ascii_case(name))
+                {
+                    item.credential_headers.push(name.clone());
+                }
+            }
+            item.transfer.headers.extend(headers);
+        }
+        if !item.has_credentials() {
+            item.status = QueueItemStatus::CredentialsRequired;
+            return false;
+        }
+        item.status = QueueItemStatus::Pending;
+        item.error = None;
+        true
+    });
+    if retry {
+        emit_status(&app, id, "pending", None);
+        spawn(&app, id);
+    }
+    retry
+}
+
+#[command]
+pub fn remove_queued_transfer(
+    id: u32,
+    queue: State<'_, TransferQueue>,
+    manager: State<'_, TransferManager>,
+) -> bool {
+    manager.cancel(id);
+    queue.remove(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item() -> QueueItem {
+        QueueItem::new(QueuedTransfer {
+            id: 1,
+            kind: TransferKind::Upload,
+            url: "https://example.com/books/book.epub".to_string(),
+            file_path: "/tmp/book.epub".to_string(),
+            headers: HashMap::from([
+                ("Authorization".to_string(), "Bearer token".to_string()),
+                (
+                    "Content-Type".to_string(),
+                    "application/epub+zip".to_string(),
+                ),
+            ]),
+            method: None,
+            body: None,
+            digest: None,
+            bandwidth_limit: None,
+        })
+    }
+
+    #[test]
+    fn credentials_are_not_saved() {
+        let item = item();
+        assert!(item.has_credentials());
+
+        let saved = serde_json::to_string(&item.without_credentials()).unwrap();
+        assert!(!saved.contains("Bearer token"));
+        assert!(saved.contains("application/epub+zip"));
+
+        let loaded: QueueItem = serde_json::from_str(&saved).unwrap();
+        assert_eq!(loaded.credential_headers, vec!["Authorization".to_string()]);
+        assert!(!loaded.has_credentials());
+    }
+
+    #[test]
+    fn credentials_match_header_names_in_any_case() {
+        let mut item = item().without_credentials();
+        item.transfer
+            .headers
+            .insert("authorization".to_string(), "Bearer new".to_string());
+        assert!(item.has_credentials());
+    }
+}
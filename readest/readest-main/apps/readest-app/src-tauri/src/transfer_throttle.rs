@@ -0,0 +1,249 @@
+//! Limit the bandwidth used by transfers.
+//!
+//! Every transfer can have its own bytes-per-second limit, and all active transfers also
+//! share a global limit that can be changed at runtime. Both are token buckets: a chunk that
+//! is larger than the available tokens is let through and the transfer then waits until the
+//! bucket has refilled, so that the average rate stays within the limit.
+
+use bytes::Bytes;
+use futures::{stream, Stream, StreamExt, TryStreamExt};
+use tauri::{command, State};
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Size of the chunks that in-memory bodies are split into, so that they are throttled
+// smoothly rather than in one burst.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+struct Bucket {
+    rate: u64,   // Bytes per second, 0 for unlimited
+    tokens: f64, // Negative while a chunk larger than the available tokens is paid back
+    last_refill: Instant,
+}
+
+pub struct TokenBucket {
+    bucket: Mutex<Bucket>,
+}
+
+impl TokenBucket {
+    pub fn new(rate: Option<u64>) -> Self {
+        let rate = rate.unwrap_or(0);
+        Self {
+            bucket: Mutex::new(Bucket {
+                rate,
+                tokens: rate as f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        let rate = self.bucket.lock().unwrap().rate;
+        (rate > 0).then_some(rate)
+    }
+
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.rate = rate.unwrap_or(0);
+        // Start over so that the debt accumulated under the old limit does not carry over.
+        bucket.tokens = bucket.rate as f64;
+        bucket.last_refill = Instant::now();
+    }
+
+    // Takes `len` tokens and returns how long the caller has to wait for them. The bucket
+    // holds at most one second worth of tokens.
+    fn take(&self, len: usize) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        if bucket.rate == 0 {
+            return Duration::ZERO;
+        }
+
+        let now = Instant::now();
+        let rate = bucket.rate as f64;
+        let refill = now.duration_since(bucket.last_refill).as_secs_f64() * rate;
+        bucket.tokens = (bucket.tokens + refill).min(rate) - len as f64;
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / rate)
+        }
+    }
+
+    async fn acquire(&self, len: usize) {
+        let delay = self.take(len);
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+// The limits that apply to a single transfer.
+#[derive(Clone)]
+pub struct Throttle {
+    limit: Option<Arc<TokenBucket>>,
+    global: Arc<TokenBucket>,
+}
+
+impl Throttle {
+    // Waits until `len` bytes may be transferred under both the transfer's own and the
+    // global limit.
+    pub async fn consume(&self, len: usize) {
+        if let Some(limit) = &self.limit {
+            limit.acquire(len).await;
+        }
+        self.global.acquire(len).await;
+    }
+
+    // Throttles every chunk of `stream` as it is polled.
+    pub fn stream<S, E>(
+        &self,
+        stream: S,
+    ) -> impl Stream<Item = std::result::Result<Bytes, E>> + Send + Unpin + 'static
+    where
+        S: Stream<Item = std::result::Result<Bytes, E>> + Send + 'static,
+        E: Send + 'static,
+    {
+        let throttle = self.clone();
+        stream
+            .and_then(move |chunk| {
+                let throttle = throttle.clone();
+                async move {
+                    throttle.consume(chunk.len()).await;
+                    Ok(chunk)
+                }
+            })
+            .boxed()
+    }
+
+    // Turns an in-memory `data` into a request body that is sent at the throttled rate.
+    pub fn body(&self, data: Bytes) -> reqwest::Body {
+        let chunks = (0..data.len())
+            .step_by(CHUNK_SIZE)
+            .map(move |start| {
+                let end = (start + CHUNK_SIZE).min(data.len());
+                Ok::<_, std::io::Error>(data.slice(start..end))
+            })
+            .collect::<Vec<_>>();
+        reqwest::Body::wrap_stream(self.stream(stream::iter(chunks)))
+    }
+}
+
+// The global limit shared by all transfers, managed as Tauri state.
+pub struct BandwidthLimiter {
+    global: Arc<TokenBucket>,
+}
+
+impl Default for BandwidthLimiter {
+    fn default() -> Self {
+        Self {
+            global: Arc::new(TokenBucket::new(None)),
+        }
+    }
+}
+
+impl BandwidthLimiter {
+    // Returns the throttle of a transfer limited to `limit` bytes per second, if any.
+    pub fn throttle(&self, limit: Option<u64>) -> Throttle {
+        Throttle {
+            limit: limit
+                .filter(|&limit| limit > 0)
+                .map(|limit| Arc::new(TokenBucket::new(Some(limit)))),
+            global: Arc::clone(&self.global),
+        }
+    }
+}
+
+#[command]
+pub fn set_bandwidth_limit(bytes_per_second: Option<u64>, limiter: State<'_, BandwidthLimiter>) {
+    limiter.global.set_rate(bytes_per_second);
+}
+
+#[command]
+pub fn get_bandwidth_limit(limiter: State<'_, BandwidthLimiter>) -> Option<u64> {
+    limiter.global.rate()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The token balance measured right after `take` is off by what refilled meanwhile.
+    fn assert_about(delay: Duration, secs: f64) {
+        let delay = delay.as_secs_f64();
+        assert!(
+            (secs - 0.05..=secs).contains(&delay),
+            "{delay}s instead of {secs}s"
+        );
+    }
+
+    #[test]
+    fn caps_the_burst_at_one_second() {
+        let bucket = TokenBucket::new(Some(1000));
+        std::thread::sleep(Duration::from_millis(100));
+        // Idle time does not add tokens beyond one second worth.
+        assert_eq!(bucket.take(1000), Duration::ZERO);
+        assert_about(bucket.take(500), 0.5);
+    }
+
+    #[test]
+    fn carries_the_debt_of_oversized_chunks() {
+        let bucket = TokenBucket::new(Some(1000));
+        assert_about(bucket.take(3000), 2.0);
+        // The next chunk waits for the previous one to be paid back too.
+        assert_about(bucket.take(100), 2.1);
+    }
+
+    #[test]
+    fn changes_the_rate_at_runtime() {
+        let bucket = TokenBucket::new(Some(1000));
+        assert_about(bucket.take(3000), 2.0);
+
+        bucket.set_rate(Some(4000));
+        assert_eq!(bucket.rate(), Some(4000));
+        assert_eq!(bucket.take(4000), Duration::ZERO);
+        assert_about(bucket.take(2000), 0.5);
+
+        bucket.set_rate(None);
+        assert_eq!(bucket.rate(), None);
+        assert_eq!(bucket.take(usize::MAX), Duration::ZERO);
+        bucket.set_rate(Some(0));
+        assert_eq!(bucket.rate(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn passes_unlimited_transfers_through() {
+        let limiter = BandwidthLimiter::default();
+        for limit in [None, Some(0)] {
+            let throttle = limiter.throttle(limit);
+            assert!(throttle.limit.is_none());
+            let start = tokio::time::Instant::now();
+            throttle.consume(100 * 1024 * 1024).await;
+            assert_eq!(start.elapsed(), Duration::ZERO);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_for_the_stricter_limit() {
+        let limiter = BandwidthLimiter::default();
+        limiter.global.set_rate(Some(1000));
+        let throttle = limiter.throttle(Some(100_000));
+        let start = tokio::time::Instant::now();
+        throttle.consume(3000).await;
+        assert_about(start.elapsed(), 2.0);
+
+        // Every chunk of a stream is throttled as it is polled.
+        let throttle = BandwidthLimiter::default().throttle(Some(1000));
+        let chunks = stream::iter([0; 2].map(|_| Ok::<_, ()>(Bytes::from(vec![0; 1000]))));
+        let start = tokio::time::Instant::now();
+        let sent = throttle
+            .stream(chunks)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(sent.len(), 2);
+        assert_about(start.elapsed(), 1.0);
+    }
+}
@@ -1,4 +1,5 @@
 import React, { useEffect, useRef, useState } from 'react';
+import { convertFileSrc } from '@tauri-apps/api/core';
 import { BookDoc, getDirection } from '@/libs/document';
 import { BookConfig } from '@/types/book';
 import { FoliateView, wrappedFoliateView } from '@/types/view';
@@ -29,7 +30,7 @@ import {
   keepTextAlignment,
   transformStylesheet,
 } from '@/utils/style';
-import { mountAdditionalFonts, mountCustomFont } from '@/styles/fonts';
+import { mountAdditionalFonts, mountCustomFont, mountSystemFont } from '@/styles/fonts';
 import { getBookDirFromLanguage, getBookDirFromWritingMode } from '@/utils/book';
 import { useUICSS } from '@/hooks/useUICSS';
 import {
@@ -48,7 +49,7 @@ import { isCJKLang } from '@/utils/lang';
 import { isTauriAppPlatform } from '@/services/environment';
 import { TransformContext } from '@/services/transformers/types';
 import { transformContent } from '@/services/transformService';
-import { lockScreenOrientation } from '@/utils/bridge';
+import { getSysFontsList, lockScreenOrientation } from '@/utils/bridge';
 import { useTextTranslation } from '../hooks/useTextTranslation';
 import { useBookCoverAutoSave } from '../hooks/useAutoSaveBookCover';
 import { manageSyntaxHighlighting } from '@/utils/highlightjs';
@@ -106,6 +107,17 @@ const FoliateViewer: React.FC<{
   const { syncState, conflictDetails, resolveWithLocal, resolveWithRemote } = useKOSync(bookKey);
   useTextTranslation(bookKey, viewRef.current);
 
+  const mountSystemFonts = async (docs: Document[]) => {
+    if (!appService?.isDesktopApp) return;
+    const { faces } = await getSysFontsList();
+    const viewSettings = getViewSettings(bookKey);
+    if (!faces || !viewSettings) return;
+    const { serifFont, sansSerifFont, monospaceFont, defaultCJKFont } = viewSettings;
+    [serifFont, sansSerifFont, monospaceFont, defaultCJKFont].forEach((font) => {
+      docs.forEach((doc) => mountSystemFont(doc, font, faces, convertFileSrc));
+    });
+  };
+
   const progressRelocateHandler = (event: Event) => {
     const detail = (event as CustomEvent).detail;
     setProgress(
@@ -184,6 +196,7 @@ const FoliateViewer: React.FC<{
       getLoadedFonts().forEach((font) => {
         mountCustomFont(detail.doc, font);
       });
+      mountSystemFonts([detail.doc]);
 
       if (bookDoc.rendition?.layout === 'pre-paginated') {
         applyFixedlayoutStyles(detail.doc, viewSettings);
@@ -432,6 +445,17 @@ const FoliateViewer: React.FC<{
     // eslint-disable-next-line react-hooks/exhaustive-deps
   }, [settings.customFonts, envConfig]);
 
+  useEffect(() => {
+    const docs = viewRef.current?.renderer.getContents().map(({ doc }) => doc) ?? [];
+    mountSystemFonts([document, ...docs]);
+    // eslint-disable-next-line react-hooks/exhaustive-deps
+  }, [
+    viewSettings?.serifFont,
+    viewSettings?.sansSerifFont,
+    viewSettings?.monospaceFont,
+    viewSettings?.defaultCJKFont,
+  ]);
+
   useEffect(() => {
     if (!viewSettings) return;
     applyBackgroundTexture(envConfig, viewSettings);
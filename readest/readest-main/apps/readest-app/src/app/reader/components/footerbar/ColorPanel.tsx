@@ -35,7 +35,7 @@ export const ColorPanel: React.FC<ColorPanelProps> = ({ actionTab, bottomOffset
   );
 
   useEffect(() => {
-    if (!appService?.isMobileApp) return;
+    if (!appService?.hasScreenBrightness) return;
     if (actionTab !== 'color') return;
 
     getScreenBrightness().then((brightness) => {
@@ -58,7 +58,7 @@ export const ColorPanel: React.FC<ColorPanelProps> = ({ actionTab, bottomOffset
 
   const handleScreenBrightnessChange = useCallback(
     async (value: number) => {
-      if (!appService?.isMobileApp) return;
+      if (!appService?.hasScreenBrightness) return;
 
       setScreenBrightnessValue(value);
       debouncedSetScreenBrightness(value);
@@ -7,7 +7,7 @@ import { useBookDataStore } from '@/store/bookDataStore';
 import { useReaderStore } from '@/store/readerStore';
 import { useTranslation } from '@/hooks/useTranslation';
 import { useResponsiveSize } from '@/hooks/useResponsiveSize';
-import { TTSController, SILENCE_DATA, TTSMark } from '@/services/tts';
+import { TTSController, SILENCE_DATA, TTSMark, NativeTTSClient } from '@/services/tts';
 import { getMediaSession, TauriMediaSession } from '@/libs/mediaSession';
 import { getPopupPosition, Position } from '@/utils/sel';
 import { eventDispatcher } from '@/utils/event';
@@ -323,6 +323,15 @@ const TTSControl: React.FC<TTSControlProps> = ({ bookKey, gridInsets }) => {
       setShowIndicator(true);
       const ttsController = new TTSController(appService, view);
       await ttsController.init();
+      if (ttsController.ttsNativeClient instanceof NativeTTSClient) {
+        const nativeClient = ttsController.ttsNativeClient;
+        nativeClient.setBook(bookData.book.hash);
+        if (bookData.bookDoc) {
+          await nativeClient
+            .importBookLexicons(bookData.book.hash, bookData.bookDoc)
+            .catch((error) => console.warn('Failed to import the lexicons of the book:', error));
+        }
+      }
       await ttsController.initViewTTS(viewSettings.ttsHighlightOptions);
       const ssml = view.tts?.from(ttsFromRange);
       if (ssml) {
@@ -489,6 +498,26 @@ const TTSControl: React.FC<TTSControlProps> = ({ bookKey, gridInsets }) => {
     if (timeoutFunc) {
       clearTimeout(timeoutFunc);
     }
+    // The native timer keeps time while the app is in the background and fades out.
+    const ttsClient = ttsControllerRef.current?.ttsClient;
+    if (ttsClient instanceof NativeTTSClient) {
+      if (value > 0) {
+        ttsClient
+          .startSleepTimer({ mode: 'duration', minutes: value / 60 }, (state) => {
+            if (state.ended) {
+              setTimeoutOption(0);
+              setTimeoutTimestamp(0);
+              handleStop(bookKey);
+            }
+          })
+          .catch((error) => console.error('Failed to start sleep timer:', error));
+        setTimeoutTimestamp(Date.now() + value * 1000);
+      } else {
+        ttsClient.cancelSleepTimer().catch(() => {});
+        setTimeoutTimestamp(0);
+      }
+      return;
+    }
     if (value > 0) {
       setTimeoutFunc(
         setTimeout(() => {
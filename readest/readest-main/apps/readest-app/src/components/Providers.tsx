@@ -49,8 +49,9 @@ const Providers = ({ children }: { children: React.ReactNode }) => {
 
   useEffect(() => {
     loadDataTheme();
+    let stopSystemThemeListener: (() => void) | undefined;
     if (appService) {
-      initSystemThemeListener(appService);
+      stopSystemThemeListener = initSystemThemeListener(appService);
       appService.loadSettings().then((settings) => {
         const globalViewSettings = settings.globalViewSettings;
         applyUILanguage(globalViewSettings.uiLanguage);
@@ -65,6 +66,9 @@ const Providers = ({ children }: { children: React.ReactNode }) => {
         }
       });
     }
+    return () => {
+      stopSystemThemeListener?.();
+    };
   }, [
     envConfig,
     appService,
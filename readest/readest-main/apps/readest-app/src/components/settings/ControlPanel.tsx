@@ -21,7 +21,8 @@ const ControlPanel: React.FC<SettingsPanelPanelProp> = ({ bookKey, onRegisterRes
   const { getBookData } = useBookDataStore();
   const { settings } = useSettingsStore();
   const { applyEinkMode } = useEinkMode();
-  const { acquireVolumeKeyInterception, releaseVolumeKeyInterception } = useDeviceControlStore();
+  const { acquireVolumeKeyInterception, releaseVolumeKeyInterception, resetScreenBrightness } =
+    useDeviceControlStore();
   const bookData = getBookData(bookKey);
   const viewSettings = getViewSettings(bookKey) || settings.globalViewSettings;
 
@@ -138,6 +139,9 @@ const ControlPanel: React.FC<SettingsPanelPanelProp> = ({ bookKey, onRegisterRes
   useEffect(() => {
     if (autoScreenBrightness === settings.autoScreenBrightness) return;
     saveSysSettings(envConfig, 'autoScreenBrightness', autoScreenBrightness);
+    if (autoScreenBrightness) {
+      resetScreenBrightness();
+    }
     // eslint-disable-next-line react-hooks/exhaustive-deps
   }, [autoScreenBrightness]);
 
@@ -285,7 +289,7 @@ const ControlPanel: React.FC<SettingsPanelPanelProp> = ({ bookKey, onRegisterRes
                   />
                 </div>
               )}
-              {appService?.isMobileApp && (
+              {appService?.hasScreenBrightness && (
                 <div className='config-item'>
                   <span className=''>{_('Auto Screen Brightness')}</span>
                   <input
@@ -18,13 +18,14 @@ import {
 } from '@/services/constants';
 import { useEnv } from '@/context/EnvContext';
 import { useReaderStore } from '@/store/readerStore';
+import { useBookDataStore } from '@/store/bookDataStore';
 import { useTranslation } from '@/hooks/useTranslation';
 import { useSettingsStore } from '@/store/settingsStore';
 import { useCustomFontStore } from '@/store/customFontStore';
 import { useResponsiveSize } from '@/hooks/useResponsiveSize';
 import { getOSPlatform, isCJKEnv } from '@/utils/misc';
 import { getSysFontsList } from '@/utils/bridge';
-import { isCJKStr } from '@/utils/lang';
+import { getLangScript, isCJKStr } from '@/utils/lang';
 import { isTauriAppPlatform } from '@/services/environment';
 import { useResetViewSettings } from '@/hooks/useResetSettings';
 import { saveViewSettings } from '@/helpers/settings';
@@ -90,6 +91,7 @@ const FontPanel: React.FC<SettingsPanelPanelProp> = ({ bookKey, onRegisterReset
   const _ = useTranslation();
   const { envConfig, appService } = useEnv();
   const { getView, getViewSettings } = useReaderStore();
+  const { getBookData } = useBookDataStore();
   const { settings, fontPanelView, setFontPanelView } = useSettingsStore();
   const {
     fonts: allCustomFonts,
@@ -209,9 +211,17 @@ const FontPanel: React.FC<SettingsPanelPanelProp> = ({ bookKey, onRegisterReset
           console.error('Failed to get system fonts list:', res.error);
           return;
         }
+        // Only offer the fonts with glyphs for the script of the book when it is known
+        const script = getLangScript(getBookData(bookKey)?.book?.primaryLanguage);
+        const coveringFonts = new Set(
+          res.faces
+            ?.filter((face) => !script || face.scripts.includes(script))
+            .flatMap((face) => [face.name, face.family]),
+        );
         const processedFonts: string[] = [];
         Object.entries(res.fonts).forEach(([fontName, fontFamily]) => {
           if (!fontName || isSymbolicFontName(fontName)) return;
+          if (res.faces && !coveringFonts.has(fontName)) return;
 
           const fontsInFamily = Object.entries(res.fonts).filter(
             ([_, family]) => family === fontFamily,
@@ -226,7 +236,7 @@ const FontPanel: React.FC<SettingsPanelPanelProp> = ({ bookKey, onRegisterReset
         setSysFonts([...new Set(processedFonts)].sort((a, b) => a.localeCompare(b)));
       });
     }
-  }, [appService]);
+  }, [appService, bookKey, getBookData]);
 
   useEffect(() => {
     saveViewSettings(envConfig, bookKey, 'defaultFont', defaultFont);
@@ -66,6 +66,9 @@ export interface BookDoc {
   toc?: Array<TOCItem>;
   sections?: Array<SectionItem>;
   transformTarget?: EventTarget;
+  // The manifest of EPUB books, and the loader of the files it lists
+  resources?: { manifest: Array<{ href: string; mediaType: string }> };
+  loadText?(href: string): Promise<string | null>;
   splitTOCHref(href: string): Array<string | number>;
   getCover(): Promise<Blob | null>;
 }
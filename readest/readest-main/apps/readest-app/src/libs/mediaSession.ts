@@ -75,11 +75,18 @@ export class TauriMediaSession {
     });
     this.eventListeners.push(previousListener);
 
+    const stopListener = await addPluginListener('native-tts', 'media-session-stop', () => {
+      if (this.handlers['stop']) {
+        (this.handlers['stop'] as () => void)();
+      }
+    });
+    this.eventListeners.push(stopListener);
+
     const seekListener = await addPluginListener(
       'native-tts',
       'media-session-seek',
-      (event: { payload: { position: number } }) => {
-        const position = event.payload.position;
+      (event: { position: number }) => {
+        const position = event.position;
         if (this.handlers['seekto']) {
           (this.handlers['seekto'] as (position: number) => void)(position);
         }
@@ -115,7 +122,7 @@ export class TauriMediaSession {
   async setActive(sessionState: MediaSessionState) {
     try {
       if (sessionState.active) {
-        if (sessionState.keepAppInForeground) {
+        if (sessionState.keepAppInForeground && getOSPlatform() === 'android') {
           await this.requestPostNotificationPermission();
         }
         await this.initializeListeners();
@@ -140,7 +147,10 @@ export class TauriMediaSession {
 }
 
 export function getMediaSession() {
-  if ('mediaSession' in navigator) {
+  // On Linux the plugin publishes an MPRIS player, which the desktop media controls use.
+  if (getOSPlatform() === 'linux' && isTauriAppPlatform()) {
+    return new TauriMediaSession();
+  } else if ('mediaSession' in navigator) {
     return navigator.mediaSession;
   } else if (getOSPlatform() === 'android' && isTauriAppPlatform()) {
     return new TauriMediaSession();
@@ -4,9 +4,11 @@ import { getUserID } from '@/utils/access';
 import { fetchWithAuth } from '@/utils/fetch';
 import {
   tauriUpload,
+  tauriUploadMultipart,
   tauriDownload,
   webUpload,
   webDownload,
+  MultipartUpload,
   ProgressHandler,
   ProgressPayload,
 } from '@/utils/transfer';
@@ -17,6 +19,37 @@ const API_ENDPOINTS = {
   delete: getAPIBaseUrl() + '/storage/delete',
 };
 
+// Files from this size on are uploaded in parts by the app.
+const MULTIPART_UPLOAD_THRESHOLD = 64 * 1024 * 1024;
+
+// The multipart uploads that were interrupted, by file hash, so that retrying one resumes
+// it from the parts the storage has confirmed.
+const PENDING_MULTIPART_UPLOADS_KEY = 'pendingMultipartUploads';
+
+interface PendingMultipartUpload {
+  uploadId: string;
+  fileName: string;
+  fileSize: number;
+}
+
+const getPendingMultipartUploads = (): Record<string, PendingMultipartUpload> => {
+  try {
+    return JSON.parse(localStorage.getItem(PENDING_MULTIPART_UPLOADS_KEY) || '{}');
+  } catch {
+    return {};
+  }
+};
+
+const setPendingMultipartUpload = (key: string, upload: PendingMultipartUpload | null) => {
+  const uploads = getPendingMultipartUploads();
+  if (upload) {
+    uploads[key] = upload;
+  } else {
+    delete uploads[key];
+  }
+  localStorage.setItem(PENDING_MULTIPART_UPLOADS_KEY, JSON.stringify(uploads));
+};
+
 export const createProgressHandler = (
   totalFiles: number,
   completedFilesRef: { count: number },
@@ -43,6 +76,10 @@ export const uploadFile = async (
   bookHash?: string,
 ) => {
   try {
+    const multipart = !isWebAppPlatform() && file.size >= MULTIPART_UPLOAD_THRESHOLD;
+    const pendingKey = bookHash || fileFullPath;
+    const pending = multipart ? getPendingMultipartUploads()[pendingKey] : undefined;
+    const resumable = pending?.fileName === file.name && pending?.fileSize === file.size;
     const response = await fetchWithAuth(API_ENDPOINTS.upload, {
       method: 'POST',
       headers: {
@@ -52,12 +89,33 @@ export const uploadFile = async (
         fileName: file.name,
         fileSize: file.size,
         bookHash,
+        multipart,
+        uploadId: resumable ? pending.uploadId : undefined,
       }),
     });
 
-    const { uploadUrl } = await response.json();
+    const { uploadUrl, multipartUpload } = await response.json();
     if (isWebAppPlatform()) {
       await webUpload(file, uploadUrl, onProgress);
+    } else if (multipartUpload) {
+      const { uploadId } = multipartUpload as MultipartUpload;
+      setPendingMultipartUpload(pendingKey, {
+        uploadId,
+        fileName: file.name,
+        fileSize: file.size,
+      });
+      try {
+        await tauriUploadMultipart(fileFullPath, multipartUpload, onProgress);
+        setPendingMultipartUpload(pendingKey, null);
+      } catch (error) {
+        // A cancelled upload has been aborted by the app, and one the storage no longer
+        // knows cannot be resumed. Others are resumed by the next attempt.
+        const message = String(error);
+        if (message.includes('was cancelled') || message.includes('NoSuchUpload')) {
+          setPendingMultipartUpload(pendingKey, null);
+        }
+        throw error;
+      }
     } else {
       await tauriUpload(uploadUrl, fileFullPath, 'PUT', onProgress);
     }
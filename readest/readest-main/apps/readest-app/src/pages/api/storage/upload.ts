@@ -6,7 +6,7 @@ import {
   validateUserAndToken,
   STORAGE_QUOTA_GRACE_BYTES,
 } from '@/utils/access';
-import { getUploadSignedUrl } from '@/utils/object';
+import { createMultipartUpload, getUploadSignedUrl } from '@/utils/object';
 
 export default async function handler(req: NextApiRequest, res: NextApiResponse) {
   await runMiddleware(req, res, corsAllMethods);
@@ -21,7 +21,7 @@ export default async function handler(req: NextApiRequest, res: NextApiResponse)
       return res.status(403).json({ error: 'Not authenticated' });
     }
 
-    const { fileName, fileSize, bookHash } = req.body;
+    const { fileName, fileSize, bookHash, multipart, uploadId } = req.body;
     if (!fileName || !fileSize) {
       return res.status(400).json({ error: 'Missing file info' });
     }
@@ -65,6 +65,18 @@ export default async function handler(req: NextApiRequest, res: NextApiResponse)
     }
 
     try {
+      // The parts of a multipart upload cover the file that is uploaded now. An upload that
+      // was interrupted keeps its ID, so that the parts the storage has are not sent again.
+      if (multipart) {
+        const multipartUpload = await createMultipartUpload(fileKey, fileSize, 3600, uploadId);
+        return res.status(200).json({
+          multipartUpload,
+          fileKey,
+          usage: usage + fileSize,
+          quota,
+        });
+      }
+
       const uploadUrl = await getUploadSignedUrl(fileKey, objSize, 1800);
 
       res.status(200).json({
@@ -362,7 +362,9 @@ export class NativeAppService extends BaseAppService {
   // orientation lock is not supported on iPad
   override hasOrientationLock =
     (OS_TYPE === 'ios' && getOSPlatform() === 'ios') || OS_TYPE === 'android';
-  override hasScreenBrightness = OS_TYPE === 'ios' || OS_TYPE === 'android';
+  // Linux sets the backlight of laptops
+  override hasScreenBrightness =
+    OS_TYPE === 'ios' || OS_TYPE === 'android' || OS_TYPE === 'linux';
   override hasIAP = OS_TYPE === 'ios' || (OS_TYPE === 'android' && DIST_CHANNEL === 'playstore');
   // CustomizeRootDir has a blocker on macOS App Store builds due to Security Scoped Resource restrictions.
   // See: https://github.com/tauri-apps/tauri/issues/3716
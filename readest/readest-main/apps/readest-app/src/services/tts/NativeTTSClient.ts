@@ -1,5 +1,6 @@
-import { invoke } from '@tauri-apps/api/core';
+import { invoke, Channel } from '@tauri-apps/api/core';
 import { addPluginListener, PluginListener } from '@tauri-apps/api/core';
+import { BookDoc } from '@/libs/document';
 import { getUserLocale } from '@/utils/misc';
 import { parseSSMLMarks } from '@/utils/ssml';
 import { stubTranslation as _ } from '@/utils/misc';
@@ -12,6 +13,83 @@ type TTSEventPayload = {
   utteranceId: string;
 } & TTSMessageEvent;
 
+export interface AudiobookChapter {
+  title: string;
+  text: string;
+  ssml?: boolean;
+}
+
+export interface ExportAudiobookOptions {
+  path: string;
+  format: 'm4b' | 'opus';
+  chapters: AudiobookChapter[];
+  voice?: string;
+  lang?: string; // For the voice map, as when speaking
+  book?: string; // Whose pronunciation lexicon applies
+  title?: string;
+  artist?: string;
+  album?: string;
+  artwork?: string; // Data URL or file path
+}
+
+export interface AudiobookProgress {
+  stage: 'synthesizing' | 'encoding';
+  chapter: number;
+  chapters: number;
+  progress: number;
+}
+
+export type SleepTimerMode =
+  | { mode: 'duration'; minutes: number }
+  | { mode: 'endOfChapter' }
+  | { mode: 'paragraphs'; count: number };
+
+export type SleepTimerOptions = SleepTimerMode & {
+  fadeOut?: number; // Seconds over which a timed sleep fades out
+};
+
+export interface SleepTimerState {
+  active: boolean;
+  ended: boolean; // Set when the timer has just stopped playback
+  remaining?: number; // Seconds, of a timed sleep
+  paragraphs?: number; // Left to read, the current one included
+}
+
+// The error of an utterance that the sleep timer stopped before
+const SLEEP_TIMER_ENDED = 'The sleep timer has ended';
+
+export interface VoiceMap {
+  voices: Record<string, string>; // Voice IDs by lowercase language tag
+  detectScript: boolean;
+}
+
+// How a word is spoken, as a respelling (alias) or as phonemes, e.g. in IPA
+export interface Lexeme {
+  grapheme: string;
+  alias?: string;
+  phoneme?: string;
+  alphabet?: string;
+}
+
+export interface Lexicon {
+  lang?: string;
+  lexemes: Lexeme[];
+}
+
+export interface ImportLexiconOptions {
+  pls?: string; // A PLS document, or
+  path?: string; // the path of one
+  book?: string; // Imports into the lexicon of a book instead of the global one
+  replace?: boolean; // Replaces the lexemes of the lexicon instead of adding to them
+  keepExisting?: boolean; // Keeps the lexemes of the lexicon over imported ones
+}
+
+export interface SynthesisCacheInfo {
+  size: number; // In bytes
+  limit: number; // In bytes
+  entries: number;
+}
+
 const TTSEngines = {
   default: 'System TTS',
   msctts: 'Msc TTS',
@@ -33,6 +111,7 @@ const TTSEngines = {
   gemini: 'Gemini TTS',
   weread: 'WeRead TTS',
   aispeech: 'Aispeech',
+  piper: 'Piper TTS',
 } as Record<string, string>;
 
 export class NativeTTSClient implements TTSClient {
@@ -46,8 +125,11 @@ export class NativeTTSClient implements TTSClient {
   #currentVoiceId = '';
   #rate = 1.0;
   #pitch = 1.0;
+  #paragraph = 0; // Counts the blocks spoken, for the sleep timer
+  #book?: string; // Hash of the book read aloud, for its pronunciation lexicon
 
   #eventListener: PluginListener | null = null;
+  #sleepTimerListener: PluginListener | null = null;
   #activeUtterances = new Map<
     string,
     {
@@ -68,12 +150,19 @@ export class NativeTTSClient implements TTSClient {
         'native-tts',
         'tts_events',
         (event) => {
-          const { utteranceId, code, message, mark } = event;
+          const { utteranceId, code, message, mark, charIndex, charLength, timestamp } = event;
 
           const utteranceData = this.#activeUtterances.get(utteranceId);
           if (!utteranceData) return;
 
-          const ttsEvent: TTSMessageEvent = { code, message, mark };
+          const ttsEvent: TTSMessageEvent = {
+            code,
+            message,
+            mark,
+            charIndex,
+            charLength,
+            timestamp,
+          };
           utteranceData.eventQueue.push(ttsEvent);
           if (code === 'end' || code === 'error') {
             utteranceData.finished = true;
@@ -101,18 +190,32 @@ export class NativeTTSClient implements TTSClient {
   }
 
   async *speakMark(mark: TTSMark, preload: boolean, signal: AbortSignal) {
+    const { language: voiceLang } = mark;
+    const voiceId = await this.getVoiceIdFromLang(voiceLang);
     if (preload) {
-      yield { code: 'end', message: 'Dummy preload finished' } as TTSMessageEvent;
+      // The native side prepares the utterance in the background, e.g. renders Piper speech
+      // into its synthesis cache, so that speaking it later starts without a gap.
+      invoke('plugin:native-tts|speak', {
+        payload: { text: mark.text, preload, voice: voiceId, lang: voiceLang, book: this.#book },
+      }).catch((error) => console.warn('Failed to preload utterance:', error));
+      yield { code: 'end', message: 'Preload requested' } as TTSMessageEvent;
       return;
     }
-    const { language: voiceLang } = mark;
-    const voiceId = await this.getVoiceIdFromLang(voiceLang);
     this.#currentVoiceId = voiceId;
     this.#speakingLang = voiceLang;
     await this.setVoice(voiceId);
     try {
+      const section = this.controller?.view.renderer.getContents()[0]?.index;
       const result = await invoke<{ utteranceId: string }>('plugin:native-tts|speak', {
-        payload: { text: mark.text, preload },
+        payload: {
+          text: mark.text,
+          preload,
+          voice: voiceId,
+          lang: voiceLang,
+          book: this.#book,
+          section,
+          paragraph: this.#paragraph,
+        },
       });
 
       const utteranceId = result.utteranceId;
@@ -172,6 +275,10 @@ export class NativeTTSClient implements TTSClient {
         this.#activeUtterances.delete(utteranceId);
       }
     } catch (error) {
+      if (error === SLEEP_TIMER_ENDED) {
+        yield { code: 'error', message: error } as TTSMessageEvent;
+        return;
+      }
       console.error('Failed to speak:', error);
       throw error;
     }
@@ -179,6 +286,7 @@ export class NativeTTSClient implements TTSClient {
 
   async *speak(ssml: string, signal: AbortSignal, preload: boolean = false) {
     const { marks } = parseSSMLMarks(ssml, this.#primaryLang);
+    if (!preload) this.#paragraph++;
 
     for (const mark of marks) {
       for await (const ev of this.speakMark(mark, preload, signal)) {
@@ -255,8 +363,10 @@ export class NativeTTSClient implements TTSClient {
     const voiceGroups = new Map<string, TTSVoice[]>();
     filteredVoices.forEach((voice) => {
       const { name, lang } = voice;
-      let groupId = voice.id.split('_')[0]!;
-      if (groupId in TTSEngines) {
+      let groupId = voice.id.startsWith('piper:') ? 'piper' : voice.id.split('_')[0]!;
+      if (groupId === 'piper') {
+        // Piper voices are named after their dataset
+      } else if (groupId in TTSEngines) {
         voice.name = name
           .replace(`${groupId}_`, '')
           .replace(`${lang}-`, '')
@@ -289,6 +399,123 @@ export class NativeTTSClient implements TTSClient {
       });
   }
 
+  // Installs a Piper voice from its .onnx model, with the .onnx.json config next to it
+  async installVoicePack(modelPath: string, configPath?: string) {
+    const result = await invoke<{ voice: TTSVoice }>('plugin:native-tts|install_voice_pack', {
+      payload: { modelPath, configPath },
+    });
+    this.#voices = [];
+    return result.voice;
+  }
+
+  async removeVoicePack(voice: string) {
+    await invoke('plugin:native-tts|remove_voice_pack', { payload: { voice } });
+    this.#voices = [];
+  }
+
+  async exportAudiobook(
+    options: ExportAudiobookOptions,
+    onProgress?: (progress: AudiobookProgress) => void,
+  ) {
+    const channel = new Channel<AudiobookProgress>();
+    if (onProgress) channel.onmessage = onProgress;
+    return await invoke<{ path: string; duration: number }>('plugin:native-tts|export_audiobook', {
+      payload: options,
+      onProgress: channel,
+    });
+  }
+
+  // Stops the export in progress, which then fails with a cancellation error
+  async cancelAudiobookExport() {
+    await invoke('plugin:native-tts|cancel_audiobook_export');
+  }
+
+  async getSynthesisCacheInfo() {
+    return await invoke<SynthesisCacheInfo>('plugin:native-tts|get_synthesis_cache_info');
+  }
+
+  async setSynthesisCacheLimit(limit: number) {
+    await invoke('plugin:native-tts|set_synthesis_cache_limit', { payload: { limit } });
+  }
+
+  // Clears the cached speech of a voice, or of all voices
+  async clearSynthesisCache(voice?: string) {
+    await invoke('plugin:native-tts|clear_synthesis_cache', { payload: { voice } });
+  }
+
+  // The voices that the native side switches to for other languages within an utterance
+  async getVoiceMap() {
+    return await invoke<VoiceMap>('plugin:native-tts|get_voice_map');
+  }
+
+  async setVoiceMap(voiceMap: VoiceMap) {
+    await invoke('plugin:native-tts|set_voice_map', { payload: voiceMap });
+  }
+
+  async setLanguageVoice(lang: string, voice: string | null) {
+    await invoke('plugin:native-tts|set_language_voice', { payload: { lang, voice } });
+  }
+
+  // Pronunciations applied to the text of the book, and to that of all books
+  async getLexicon(book?: string) {
+    return await invoke<Lexicon>('plugin:native-tts|get_lexicon', { payload: { book } });
+  }
+
+  async setLexicon(lexicon: Lexicon, book?: string) {
+    await invoke('plugin:native-tts|set_lexicon', { payload: { book, lexicon } });
+  }
+
+  async importLexicon(options: ImportLexiconOptions) {
+    return await invoke<Lexicon>('plugin:native-tts|import_lexicon', { payload: options });
+  }
+
+  // Imports the PLS lexicons embedded in an EPUB 3 book into the lexicon of the book. The
+  // lexemes already there are kept, so importing again leaves the user's edits alone.
+  async importBookLexicons(book: string, bookDoc: BookDoc) {
+    const items = bookDoc.resources?.manifest.filter(
+      ({ mediaType }) => mediaType === 'application/pls+xml',
+    );
+    for (const { href } of items ?? []) {
+      const pls = await bookDoc.loadText?.(href);
+      if (pls) {
+        await this.importLexicon({ book, pls, keepExisting: true });
+      }
+    }
+  }
+
+  setBook(book: string) {
+    this.#book = book;
+  }
+
+  // Stops read aloud after a time, at the end of the chapter or after a number of paragraphs.
+  // The timer runs natively, so that it keeps time while the webview is in the background.
+  async startSleepTimer(options: SleepTimerOptions, onUpdate?: (state: SleepTimerState) => void) {
+    if (this.#sleepTimerListener) {
+      this.#sleepTimerListener.unregister();
+      this.#sleepTimerListener = null;
+    }
+    if (onUpdate) {
+      this.#sleepTimerListener = await addPluginListener<SleepTimerState>(
+        'native-tts',
+        'sleep-timer',
+        onUpdate,
+      );
+    }
+    await invoke('plugin:native-tts|start_sleep_timer', { payload: options });
+  }
+
+  async cancelSleepTimer() {
+    await invoke('plugin:native-tts|cancel_sleep_timer');
+    if (this.#sleepTimerListener) {
+      this.#sleepTimerListener.unregister();
+      this.#sleepTimerListener = null;
+    }
+  }
+
+  async getSleepTimer() {
+    return await invoke<SleepTimerState>('plugin:native-tts|get_sleep_timer');
+  }
+
   setPrimaryLang(lang: string) {
     this.#primaryLang = lang;
   }
@@ -306,6 +533,9 @@ export class NativeTTSClient implements TTSClient {
   }
 
   async shutdown() {
+    if (this.#sleepTimerListener) {
+      await this.cancelSleepTimer().catch(() => {});
+    }
     if (this.#eventListener) {
       this.#eventListener.unregister();
       this.#eventListener = null;
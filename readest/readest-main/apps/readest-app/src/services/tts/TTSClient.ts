@@ -6,6 +6,11 @@ export interface TTSMessageEvent {
   code: TTSMessageCode;
   message?: string;
   mark?: string;
+  // Word boundaries: the UTF-16 offset and length of the word in the spoken text, and the
+  // milliseconds since the utterance started.
+  charIndex?: number;
+  charLength?: number;
+  timestamp?: number;
 }
 
 export interface TTSClient {
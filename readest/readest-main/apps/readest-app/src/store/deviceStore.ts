@@ -1,5 +1,10 @@
 import { create } from 'zustand';
-import { interceptKeys, getScreenBrightness, setScreenBrightness } from '@/utils/bridge';
+import {
+  interceptKeys,
+  getScreenBrightness,
+  setScreenBrightness,
+  resetScreenBrightness,
+} from '@/utils/bridge';
 import { eventDispatcher } from '@/utils/event';
 
 declare global {
@@ -25,6 +30,7 @@ type DeviceControlState = {
   backKeyInterceptionCount: number;
   getScreenBrightness: () => Promise<number>; // 0.0 to 1.0
   setScreenBrightness: (brightness: number) => Promise<void>; // brightness: 0.0 to 1.0
+  resetScreenBrightness: () => Promise<void>; // back to the brightness of the system
   acquireVolumeKeyInterception: () => void;
   releaseVolumeKeyInterception: () => void;
   acquireBackKeyInterception: () => void;
@@ -85,4 +91,8 @@ export const useDeviceControlStore = create<DeviceControlState>((set, get) => ({
   setScreenBrightness: async (brightness: number) => {
     await setScreenBrightness({ brightness });
   },
+
+  resetScreenBrightness: async () => {
+    await resetScreenBrightness();
+  },
 }));
@@ -1,7 +1,12 @@
 import { create } from 'zustand';
 import { AppService } from '@/types/system';
 import { getThemeCode, ThemeCode } from '@/utils/style';
-import { getSystemColorScheme } from '@/utils/bridge';
+import { listen, UnlistenFn } from '@tauri-apps/api/event';
+import {
+  getSystemColorScheme,
+  GetSystemColorSchemeResponse,
+  SYSTEM_COLOR_SCHEME_CHANGED_EVENT,
+} from '@/utils/bridge';
 import { getCurrentWindow } from '@tauri-apps/api/window';
 import { CustomTheme, Palette, ThemeMode } from '@/styles/themes';
 import { EnvConfigType, isWebAppPlatform } from '@/services/environment';
@@ -150,13 +155,15 @@ export const loadDataTheme = () => {
   }
 };
 
+// Follows the color scheme of the system until the returned function is called.
 export const initSystemThemeListener = (appService: AppService) => {
-  if (typeof window === 'undefined' || !appService) return;
+  if (typeof window === 'undefined' || !appService) return () => {};
 
   const mediaQuery = window.matchMedia('(prefers-color-scheme: dark)');
   const updateColorTheme = async () => {
     let systemIsDarkMode;
-    if (appService.isIOSApp) {
+    // WebKitGTK does not follow the color scheme of the XDG desktop portal
+    if (appService.isIOSApp || appService.isLinuxApp) {
       const res = await getSystemColorScheme();
       systemIsDarkMode = res.colorScheme === 'dark';
     } else {
@@ -177,4 +184,22 @@ export const initSystemThemeListener = (appService: AppService) => {
   document.addEventListener('visibilitychange', updateColorTheme);
   window.addEventListener('resize', updateWindowTheme);
   updateColorTheme();
+  let unlisten: UnlistenFn | undefined;
+  let disposed = false;
+  if (appService.isLinuxApp) {
+    listen<GetSystemColorSchemeResponse>(SYSTEM_COLOR_SCHEME_CHANGED_EVENT, ({ payload }) => {
+      useThemeStore.getState().handleSystemThemeChange(payload.colorScheme === 'dark');
+    }).then((fn) => {
+      // The listener may be registered after the cleanup has run.
+      if (disposed) fn();
+      else unlisten = fn;
+    });
+  }
+  return () => {
+    disposed = true;
+    mediaQuery?.removeEventListener('change', updateColorTheme);
+    document.removeEventListener('visibilitychange', updateColorTheme);
+    window.removeEventListener('resize', updateWindowTheme);
+    unlisten?.();
+  };
 };
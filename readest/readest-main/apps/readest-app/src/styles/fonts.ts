@@ -1,6 +1,7 @@
 import { isCJKEnv } from '@/utils/misc';
 import { getFilename } from '@/utils/path';
 import { md5Fingerprint } from '@/utils/md5';
+import type { SystemFontFace } from '@/utils/bridge';
 
 export type FontFormat = 'ttf' | 'otf' | 'woff' | 'woff2';
 
@@ -230,3 +231,47 @@ export const mountCustomFont = (document: Document, font: CustomFont) => {
     document.head.appendChild(styleElement);
   }
 };
+
+export function createSystemFontCSS(
+  font: string,
+  faces: SystemFontFace[],
+  getURL: (path: string) => string,
+): string {
+  // A font picked by its family name brings all the faces of the family
+  const byName = faces.filter((face) => face.name === font);
+  const matched = byName.length > 0 ? byName : faces.filter((face) => face.family === font);
+  return matched
+    .map((face) => {
+      // only the first face of a collection file can be loaded from its URL
+      const url = face.index === 0 ? getURL(face.path) : '';
+      const src = `local("${face.name}")${url ? `, url("${url}")` : ''}`;
+      return `
+        @font-face {
+          font-family: "${font}";
+          ${byName.length > 0 ? '' : `font-style: ${face.italic ? 'italic' : 'normal'};`}
+          ${byName.length > 0 ? '' : `font-weight: ${face.weight};`}
+          src: ${src};
+          font-display: swap;
+        }
+      `;
+    })
+    .join('');
+}
+
+export const mountSystemFont = (
+  document: Document,
+  font: string,
+  faces: SystemFontFace[],
+  getURL: (path: string) => string,
+) => {
+  const css = createSystemFontCSS(font, faces, getURL);
+  if (!css) return;
+  const fontStyleId = `system-font-${getFontId(font)}`;
+  const styleElement = document.getElementById(fontStyleId) || document.createElement('style');
+  styleElement.id = fontStyleId;
+  styleElement.textContent = css;
+
+  if (!styleElement.parentNode) {
+    document.head.appendChild(styleElement);
+  }
+};
@@ -38,8 +38,33 @@ export interface GetStatusBarHeightResponse {
   error?: string;
 }
 
+export type FontScript =
+  | 'latin'
+  | 'greek'
+  | 'cyrillic'
+  | 'arabic'
+  | 'hebrew'
+  | 'devanagari'
+  | 'thai'
+  | 'han'
+  | 'kana'
+  | 'hangul';
+
+export interface SystemFontFace {
+  family: string;
+  name: string;
+  style: string;
+  weight: number;
+  italic: boolean;
+  monospace: boolean;
+  path: string;
+  index: number; // index of the face in a font collection file
+  scripts: FontScript[];
+}
+
 export interface GetSystemFontsListResponse {
   fonts: Record<string, string>; // { fontName: fontFamily }
+  faces?: SystemFontFace[]; // desktop only
   error?: string;
 }
 
@@ -84,6 +109,28 @@ interface GetExternalSDCardPathResponse {
   error?: string;
 }
 
+export type EReaderLayout = 'kobo' | 'kindle' | 'koreader';
+
+export interface RemovableVolume {
+  path: string; // Where the volume is mounted
+  device: string;
+  label?: string;
+  fsType: string;
+  eReaders: EReaderLayout[]; // The e-readers whose folders the volume has
+}
+
+interface ListRemovableVolumesResponse {
+  volumes: RemovableVolume[];
+}
+
+export interface RemovableVolumesChangedEvent {
+  added: RemovableVolume[];
+  removed: RemovableVolume[];
+}
+
+// Emitted on Linux when removable volumes are plugged in or unplugged
+export const REMOVABLE_VOLUMES_CHANGED_EVENT = 'removable-volumes-changed';
+
 export async function copyURIToPath(request: CopyURIRequest): Promise<CopyURIResponse> {
   const result = await invoke<CopyURIResponse>('plugin:native-bridge|copy_uri_to_path', {
     payload: request,
@@ -126,17 +173,23 @@ export async function getStatusBarHeight(): Promise<GetStatusBarHeightResponse>
   return result;
 }
 
-let cachedSysFontsResult: GetSystemFontsListResponse | null = null;
+// Shared by the callers that ask while the fonts are being listed. A failed listing is
+// not kept, so that the next call lists the fonts again.
+let cachedSysFontsResult: Promise<GetSystemFontsListResponse> | null = null;
 
 export async function getSysFontsList(): Promise<GetSystemFontsListResponse> {
-  if (cachedSysFontsResult) {
-    return cachedSysFontsResult;
+  if (!cachedSysFontsResult) {
+    const result = invoke<GetSystemFontsListResponse>('plugin:native-bridge|get_sys_fonts_list');
+    cachedSysFontsResult = result;
+    result
+      .then((res) => {
+        if (res.error) cachedSysFontsResult = null;
+      })
+      .catch(() => {
+        cachedSysFontsResult = null;
+      });
   }
-  const result = await invoke<GetSystemFontsListResponse>(
-    'plugin:native-bridge|get_sys_fonts_list',
-  );
-  cachedSysFontsResult = result;
-  return result;
+  return cachedSysFontsResult;
 }
 
 export async function interceptKeys(request: InterceptKeysRequest): Promise<void> {
@@ -151,6 +204,9 @@ export async function lockScreenOrientation(request: LockScreenRequest): Promise
   });
 }
 
+// Emitted on Linux when the desktop switches between light and dark
+export const SYSTEM_COLOR_SCHEME_CHANGED_EVENT = 'system-color-scheme-changed';
+
 export async function getSystemColorScheme(): Promise<GetSystemColorSchemeResponse> {
   const result = await invoke<GetSystemColorSchemeResponse>(
     'plugin:native-bridge|get_system_color_scheme',
@@ -184,9 +240,23 @@ export async function setScreenBrightness(
   return result;
 }
 
+export async function resetScreenBrightness(): Promise<SetScreenBrightnessResponse> {
+  const result = await invoke<SetScreenBrightnessResponse>(
+    'plugin:native-bridge|reset_screen_brightness',
+  );
+  return result;
+}
+
 export async function getExternalSDCardPath(): Promise<GetExternalSDCardPathResponse> {
   const result = await invoke<GetExternalSDCardPathResponse>(
     'plugin:native-bridge|get_external_sdcard_path',
   );
   return result;
 }
+
+export async function listRemovableVolumes(): Promise<RemovableVolume[]> {
+  const result = await invoke<ListRemovableVolumesResponse>(
+    'plugin:native-bridge|list_removable_volumes',
+  );
+  return result.volumes;
+}
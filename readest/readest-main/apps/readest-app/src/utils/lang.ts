@@ -1,6 +1,7 @@
 import { franc } from 'franc-min';
 import { iso6392 } from 'iso-639-2';
 import { iso6393To1 } from 'iso-639-3';
+import type { FontScript } from '@/utils/bridge';
 
 export const isCJKStr = (str: string) => {
   return /[\p{Script=Han}\p{Script=Hiragana}\p{Script=Katakana}\p{Script=Hangul}]/u.test(str ?? '');
@@ -12,6 +13,35 @@ export const isCJKLang = (lang: string | null | undefined): boolean => {
   return ['zh', 'ja', 'ko', 'zho', 'jpn', 'kor'].includes(normalizedLang);
 };
 
+const LANG_SCRIPTS: Record<string, FontScript> = {
+  zh: 'han',
+  ja: 'kana',
+  ko: 'hangul',
+  ar: 'arabic',
+  fa: 'arabic',
+  ur: 'arabic',
+  ps: 'arabic',
+  ug: 'arabic',
+  he: 'hebrew',
+  yi: 'hebrew',
+  hi: 'devanagari',
+  mr: 'devanagari',
+  ne: 'devanagari',
+  sa: 'devanagari',
+  th: 'thai',
+  el: 'greek',
+  ru: 'cyrillic',
+  uk: 'cyrillic',
+  be: 'cyrillic',
+  bg: 'cyrillic',
+  sr: 'cyrillic',
+  mk: 'cyrillic',
+  kk: 'cyrillic',
+  ky: 'cyrillic',
+  mn: 'cyrillic',
+  tg: 'cyrillic',
+};
+
 export const normalizeToFullLang = (langCode: string): string => {
   const mapping: Record<string, string> = {
     en: 'en-US',
@@ -146,3 +176,11 @@ export const detectLanguage = (content: string): string => {
     return 'en';
   }
 };
+
+// The script of the fonts a book in the language needs, assuming Latin for the languages
+// not listed, which are mostly written in it.
+export const getLangScript = (lang: string | null | undefined): FontScript | null => {
+  if (!lang || !isValidLang(lang)) return null;
+  const code = normalizedLangCode(lang);
+  return LANG_SCRIPTS[code6392to6391(code) || code] ?? 'latin';
+};
@@ -28,6 +28,42 @@ export const getUploadSignedUrl = async (
   }
 };
 
+// S3 allows at most 10,000 parts of at least 5 MiB.
+const MIN_MULTIPART_PART_SIZE = 8 * 1024 * 1024;
+const MAX_MULTIPART_PARTS = 10000;
+
+// Creates a multipart upload, or presigns the URLs of `uploadId` again to resume it.
+export const createMultipartUpload = async (
+  fileKey: string,
+  fileSize: number,
+  expiresIn: number,
+  uploadId?: string,
+) => {
+  const partSize = Math.max(MIN_MULTIPART_PART_SIZE, Math.ceil(fileSize / MAX_MULTIPART_PARTS));
+  const storageType = getStorageType();
+  if (storageType === 'r2') {
+    const bucketName = process.env['R2_BUCKET_NAME'] || '';
+    return await r2Storage.createMultipartUpload(
+      bucketName,
+      fileKey,
+      fileSize,
+      partSize,
+      expiresIn,
+      uploadId,
+    );
+  } else {
+    const bucketName = process.env['S3_BUCKET_NAME'] || '';
+    return await s3Storage.createMultipartUpload(
+      bucketName,
+      fileKey,
+      fileSize,
+      partSize,
+      expiresIn,
+      uploadId,
+    );
+  }
+};
+
 export const deleteObject = async (fileKey: string) => {
   const storageType = getStorageType();
   if (storageType === 'r2') {
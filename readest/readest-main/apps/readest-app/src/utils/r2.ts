@@ -54,6 +54,45 @@ export const r2Storage = {
     ).url.toString();
   },
 
+  createMultipartUpload: async (
+    bucketName: string,
+    fileKey: string,
+    fileSize: number,
+    partSize: number,
+    expiresIn: number,
+    existingUploadId?: string, // Presigns the URLs of this upload again instead
+  ) => {
+    const client = r2Storage.getR2Client();
+    const objectUrl = `${r2Storage.getR2Url()}/${bucketName}/${fileKey}`;
+    const createUpload = async () => {
+      const response = await client.fetch(`${objectUrl}?uploads`, { method: 'POST' });
+      if (!response.ok) {
+        throw new Error(`Failed to create multipart upload: ${response.status}`);
+      }
+      return (await response.text()).match(/<UploadId>(.+?)<\/UploadId>/)?.[1];
+    };
+    const uploadId = existingUploadId || (await createUpload());
+    if (!uploadId) {
+      throw new Error('No upload ID in the multipart upload response');
+    }
+
+    const sign = async (query: string, method: string) => {
+      const request = new Request(`${objectUrl}?${query}&X-Amz-Expires=${expiresIn}`, { method });
+      return (await client.sign(request, { aws: { signQuery: true } })).url.toString();
+    };
+    const id = encodeURIComponent(uploadId);
+    const partCount = Math.ceil(fileSize / partSize);
+    const partUrls = await Promise.all(
+      Array.from({ length: partCount }, (_, i) =>
+        sign(`partNumber=${i + 1}&uploadId=${id}`, 'PUT'),
+      ),
+    );
+    const completeUrl = await sign(`uploadId=${id}`, 'POST');
+    const abortUrl = await sign(`uploadId=${id}`, 'DELETE');
+
+    return { uploadId, partSize, partUrls, completeUrl, abortUrl };
+  },
+
   deleteObject: async (bucketName: string, fileKey: string) => {
     return await r2Storage.getR2Client().fetch(`${r2Storage.getR2Url()}/${bucketName}/${fileKey}`, {
       method: 'DELETE',
@@ -1,5 +1,11 @@
 import { S3Client } from '@aws-sdk/client-s3';
 import { GetObjectCommand, DeleteObjectCommand, PutObjectCommand } from '@aws-sdk/client-s3';
+import {
+  AbortMultipartUploadCommand,
+  CompleteMultipartUploadCommand,
+  CreateMultipartUploadCommand,
+  UploadPartCommand,
+} from '@aws-sdk/client-s3';
 import { getSignedUrl } from '@aws-sdk/s3-request-presigner';
 
 const S3_ENDPOINT = process.env['S3_ENDPOINT'] || '';
@@ -70,6 +76,42 @@ export const s3Storage = {
     return uploadUrl;
   },
 
+  createMultipartUpload: async (
+    bucketName: string,
+    fileKey: string,
+    fileSize: number,
+    partSize: number,
+    expiresIn: number,
+    existingUploadId?: string, // Presigns the URLs of this upload again instead
+  ) => {
+    const createUpload = async () => {
+      const command = new CreateMultipartUploadCommand({ Bucket: bucketName, Key: fileKey });
+      return (await s3Storage.getClient().send(command)).UploadId;
+    };
+    const uploadId = existingUploadId || (await createUpload());
+    if (!uploadId) {
+      throw new Error('No upload ID in the multipart upload response');
+    }
+
+    const upload = { Bucket: bucketName, Key: fileKey, UploadId: uploadId };
+    const partCount = Math.ceil(fileSize / partSize);
+    const partUrls = await Promise.all(
+      Array.from({ length: partCount }, (_, i) =>
+        getSignedUrl(s3Client, new UploadPartCommand({ ...upload, PartNumber: i + 1 }), {
+          expiresIn,
+        }),
+      ),
+    );
+    const completeUrl = await getSignedUrl(s3Client, new CompleteMultipartUploadCommand(upload), {
+      expiresIn,
+    });
+    const abortUrl = await getSignedUrl(s3Client, new AbortMultipartUploadCommand(upload), {
+      expiresIn,
+    });
+
+    return { uploadId, partSize, partUrls, completeUrl, abortUrl };
+  },
+
   deleteObject: async (bucketName: string, fileKey: string) => {
     const deleteCommand = new DeleteObjectCommand({
       Bucket: bucketName,
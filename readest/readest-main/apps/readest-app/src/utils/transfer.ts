@@ -6,10 +6,109 @@ export interface ProgressPayload {
   progress: number;
   total: number;
   transferSpeed: number;
+  elapsed?: number;
+  eta?: number | null;
 }
 
 export type ProgressHandler = (progress: ProgressPayload) => void;
 
+export interface ExpectedDigest {
+  algorithm: 'md5' | 'sha256';
+  value: string;
+}
+
+export interface DownloadResponse {
+  etag?: string;
+  lastModified?: string;
+}
+
+export interface TransferInfo {
+  id: number;
+  kind: 'download' | 'upload';
+  url: string;
+  filePath: string;
+  state: 'active' | 'paused' | 'cancelled';
+}
+
+export const newTransferId = () => {
+  const ids = new Uint32Array(1);
+  window.crypto.getRandomValues(ids);
+  return ids[0];
+};
+
+export const cancelTransfer = (id: number) => invoke<boolean>('cancel_transfer', { id });
+
+export const pauseTransfer = (id: number) => invoke<boolean>('pause_transfer', { id });
+
+export const dismissTransfer = (id: number) => invoke<boolean>('dismiss_transfer', { id });
+
+export const listTransfers = () => invoke<TransferInfo[]>('list_transfers');
+
+export interface QueuedTransfer {
+  id: number;
+  kind: 'download' | 'upload';
+  url: string;
+  filePath: string;
+  headers?: Record<string, string>;
+  method?: UploadMethod;
+  body?: string;
+  digest?: ExpectedDigest;
+  bandwidthLimit?: number; // bytes per second
+}
+
+export interface QueueItem extends QueuedTransfer {
+  status: 'pending' | 'running' | 'paused' | 'failed' | 'credentialsRequired';
+  attempts: number;
+  error?: string;
+  credentialHeaders: string[]; // not saved with the queue, to be passed again on retry
+}
+
+export interface TransferQueueEvent {
+  id: number;
+  status:
+    | 'pending'
+    | 'running'
+    | 'progress'
+    | 'completed'
+    | 'paused'
+    | 'failed'
+    | 'cancelled'
+    | 'credentialsRequired';
+  progress?: ProgressPayload;
+  error?: string;
+}
+
+export const enqueueTransfer = (transfer: QueuedTransfer) =>
+  invoke<void>('enqueue_transfer', { transfer });
+
+export const listQueuedTransfers = () => invoke<QueueItem[]>('list_queued_transfers');
+
+export const retryQueuedTransfer = (id: number, headers?: Record<string, string>) =>
+  invoke<boolean>('retry_queued_transfer', { id, headers });
+
+export const removeQueuedTransfer = (id: number) =>
+  invoke<boolean>('remove_queued_transfer', { id });
+
+export interface HttpClientConfig {
+  proxy?: string;
+  noProxy?: string[];
+  rootCertificates?: string[]; // PEM encoded
+  connectTimeout?: number; // seconds
+  readTimeout?: number; // seconds
+  userAgent?: string;
+}
+
+export const getHttpClientConfig = () => invoke<HttpClientConfig>('get_http_client_config');
+
+export const setHttpClientConfig = (config: HttpClientConfig) =>
+  invoke<void>('set_http_client_config', { config });
+
+// Limits all transfers together to `bytesPerSecond`, or lifts the limit when undefined.
+export const setBandwidthLimit = (bytesPerSecond?: number) =>
+  invoke<void>('set_bandwidth_limit', { bytesPerSecond });
+
+export const getBandwidthLimit = () => invoke<number | null>('get_bandwidth_limit');
+
 export const webUpload = (file: File, uploadUrl: string, onProgress?: ProgressHandler) => {
   return new Promise<void>((resolve, reject) => {
     const startTime = Date.now();
@@ -78,11 +177,9 @@ export const tauriUpload = async (
   method: UploadMethod,
   progressHandler?: ProgressHandler,
   headers?: Map<string, string>,
+  id: number = newTransferId(),
+  bandwidthLimit?: number,
 ): Promise<string> => {
-  const ids = new Uint32Array(1);
-  window.crypto.getRandomValues(ids);
-  const id = ids[0];
-
   const onProgress = new Channel<ProgressPayload>();
   if (progressHandler) {
     onProgress.onmessage = progressHandler;
@@ -94,32 +191,108 @@ export const tauriUpload = async (
     filePath,
     method,
     headers: headers ?? {},
+    bandwidthLimit,
     onProgress,
   });
 };
 
+export interface MultipartUpload {
+  uploadId: string;
+  partSize: number;
+  partUrls: string[];
+  completeUrl: string;
+  abortUrl?: string;
+}
+
+export const tauriUploadMultipart = async (
+  filePath: string,
+  upload: MultipartUpload,
+  progressHandler?: ProgressHandler,
+  headers?: Map<string, string>,
+  id: number = newTransferId(),
+  bandwidthLimit?: number,
+): Promise<string> => {
+  const onProgress = new Channel<ProgressPayload>();
+  if (progressHandler) {
+    onProgress.onmessage = progressHandler;
+  }
+
+  return await invoke('upload_file_multipart', {
+    id,
+    filePath,
+    upload,
+    headers: headers ?? {},
+    bandwidthLimit,
+    onProgress,
+  });
+};
+
+export const abortTauriUploadMultipart = async (upload: MultipartUpload) =>
+  invoke<void>('abort_file_multipart', { uploadId: upload.uploadId, abortUrl: upload.abortUrl });
+
 export const tauriDownload = async (
   url: string,
   filePath: string,
   progressHandler?: ProgressHandler,
   headers?: Map<string, string>,
   body?: string,
-): Promise<void> => {
-  const ids = new Uint32Array(1);
-  window.crypto.getRandomValues(ids);
-  const id = ids[0];
-
+  digest?: ExpectedDigest,
+  id: number = newTransferId(),
+  bandwidthLimit?: number,
+): Promise<DownloadResponse> => {
   const onProgress = new Channel<ProgressPayload>();
   if (progressHandler) {
     onProgress.onmessage = progressHandler;
   }
 
-  await invoke('download_file', {
+  return await invoke('download_file', {
     id,
     url,
     filePath,
     headers: headers ?? {},
     onProgress,
     body,
+    digest,
+    bandwidthLimit,
+  });
+};
+
+export interface BookToSend {
+  filePath: string;
+  title: string;
+  author?: string;
+  language?: string;
+  progress?: number; // The fraction of the book read, for the KOReader sidecar
+}
+
+export interface SentBook {
+  filePath: string;
+  devicePath: string | null;
+  error: string | null;
+}
+
+export interface SendProgressPayload extends ProgressPayload {
+  book: number; // The index of the book being sent
+  books: number;
+}
+
+// Sends books to the e-reader mounted at `volume`, converting EPUBs to KEPUBs for Kobos.
+// The volume must be one of those listed by `listRemovableVolumes`.
+export const sendBooksToDevice = async (
+  volume: string,
+  books: BookToSend[],
+  progressHandler?: (progress: SendProgressPayload) => void,
+  folder?: string,
+): Promise<SentBook[]> => {
+  const onProgress = new Channel<SendProgressPayload>();
+  if (progressHandler) {
+    onProgress.onmessage = progressHandler;
+  }
+
+  return await invoke('send_books_to_device', {
+    volume,
+    books,
+    folder,
+    onProgress,
   });
 };